@@ -0,0 +1,27 @@
+fn main() {
+    #[cfg(feature = "ffi")]
+    {
+        let crate_dir = std::env::var("CARGO_MANIFEST_DIR").expect("cargo sets CARGO_MANIFEST_DIR");
+        let out_dir = std::env::var("OUT_DIR").expect("cargo sets OUT_DIR");
+        let config = cbindgen::Config {
+            language: cbindgen::Language::C,
+            include_guard: Some("EPUBSPLIT_H".to_string()),
+            autogen_warning: Some("/* Generated by cbindgen from src/ffi.rs; do not edit. */".to_string()),
+            cpp_compat: true,
+            ..Default::default()
+        };
+        let header = cbindgen::Builder::new()
+            .with_crate(&crate_dir)
+            .with_config(config)
+            .generate()
+            .expect("Failed to generate C header");
+        header.write_to_file(std::path::Path::new(&out_dir).join("epubsplit.h"));
+        // Refreshing the checked-in copy is a deliberate step, so builds
+        // never write into the source tree on their own
+        if std::env::var_os("EPUBSPLIT_UPDATE_HEADER").is_some() {
+            header.write_to_file(std::path::Path::new(&crate_dir).join("include/epubsplit.h"));
+        }
+        println!("cargo:rerun-if-changed=src/ffi.rs");
+        println!("cargo:rerun-if-env-changed=EPUBSPLIT_UPDATE_HEADER");
+    }
+}
@@ -0,0 +1,112 @@
+#ifndef EPUBSPLIT_H
+#define EPUBSPLIT_H
+
+/* Generated by cbindgen from src/ffi.rs; do not edit. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * An open EPUB
+ */
+typedef struct EpubSplit EpubSplit;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Message for the last failure on this thread, or NULL. Valid until the
+ * next call on this thread.
+ */
+const char *epubsplit_last_error(void);
+
+/**
+ * Open the EPUB at `path`. Returns NULL on failure.
+ *
+ * # Safety
+ * `path` must be a nul-terminated string.
+ */
+struct EpubSplit *epubsplit_open(const char *path);
+
+/**
+ * Open an EPUB from `len` bytes at `data`, which are copied. Returns
+ * NULL on failure.
+ *
+ * # Safety
+ * `data` must point to `len` readable bytes.
+ */
+struct EpubSplit *epubsplit_open_buffer(const uint8_t *data, uintptr_t len);
+
+/**
+ * The split points as JSON, in the same shape as the library's
+ * `split_points_json`. Free with `epubsplit_free_string`. Returns NULL
+ * on failure.
+ *
+ * # Safety
+ * `handle` must come from `epubsplit_open` or `epubsplit_open_buffer`.
+ */
+char *epubsplit_sections_json(struct EpubSplit *handle);
+
+/**
+ * Write an EPUB of the `count` split lines in `sections` to `path`.
+ * `title` may be NULL for the default.
+ *
+ * # Safety
+ * `handle` must be open, `sections` must point to `count` values, and
+ * `title` and `path` must be nul-terminated strings.
+ */
+int epubsplit_split_to_path(struct EpubSplit *handle,
+                            const uintptr_t *sections,
+                            uintptr_t count,
+                            const char *title,
+                            const char *path);
+
+/**
+ * Build an EPUB of the `count` split lines in `sections` in memory. On
+ * success `*out` and `*out_len` hold the bytes; free them with
+ * `epubsplit_free_buffer`.
+ *
+ * # Safety
+ * `handle` must be open, `sections` must point to `count` values,
+ * `title` must be NULL or a nul-terminated string, and `out` and
+ * `out_len` must be writable.
+ */
+int epubsplit_split_to_buffer(struct EpubSplit *handle,
+                              const uintptr_t *sections,
+                              uintptr_t count,
+                              const char *title,
+                              uint8_t **out,
+                              uintptr_t *out_len);
+
+/**
+ * Free a buffer from `epubsplit_split_to_buffer`
+ *
+ * # Safety
+ * `data` and `len` must be exactly as returned, and freed only once.
+ */
+void epubsplit_free_buffer(uint8_t *data, uintptr_t len);
+
+/**
+ * Free a string from `epubsplit_sections_json`
+ *
+ * # Safety
+ * `s` must be as returned, and freed only once.
+ */
+void epubsplit_free_string(char *s);
+
+/**
+ * Close an EPUB opened with `epubsplit_open` or `epubsplit_open_buffer`
+ *
+ * # Safety
+ * `handle` must be as returned, and closed only once.
+ */
+void epubsplit_close(struct EpubSplit *handle);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* EPUBSPLIT_H */
@@ -0,0 +1,6294 @@
+use anyhow::{anyhow, bail, Context, Result};
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
+use clap_complete::Shell;
+use deunicode::deunicode;
+use image::codecs::jpeg::JpegEncoder;
+use image::{DynamicImage, ImageFormat};
+use tracing::{debug, info, instrument, warn};
+#[cfg(not(target_arch = "wasm32"))]
+use notify::{EventKind, RecursiveMode, Watcher};
+use percent_encoding::percent_decode_str;
+use regex::Regex;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sha1::Digest;
+use sha2::Sha256;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufReader, Read as IoRead, Seek, Write as IoWrite};
+use std::ops::ControlFlow;
+use std::str::FromStr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+use crate::core::*;
+
+/// Print a message for people: to stdout, or to stderr when `$style` is
+/// porcelain
+macro_rules! say {
+    ($style:expr, $($arg:tt)*) => {
+        if $style.porcelain {
+            eprintln!($($arg)*)
+        } else {
+            println!($($arg)*)
+        }
+    };
+}
+
+/// `epubsplit serve`: a small JSON API over the split machinery, for web UIs
+/// and other services. Uploaded books are kept in a temporary folder until
+/// deleted, unused for an hour or crowded out by newer uploads, and the
+/// folder is removed when the server stops.
+///
+/// - `POST /books` with the EPUB as the body: stores it and returns its id,
+///   metadata and split points
+/// - `GET /books/{id}`: the same description again
+/// - `POST /books/{id}/split` with `{"sections": [..], "title", "authors"}`:
+///   returns the split EPUB
+/// - `DELETE /books/{id}`: forgets the upload
+#[cfg(feature = "serve")]
+mod serve;
+
+/// Print one porcelain record (see `--porcelain`)
+fn porcelain_record(fields: &[&str]) {
+    let fields: Vec<String> = fields
+        .iter()
+        .map(|field| field.replace(['\t', '\n', '\r'], " "))
+        .collect();
+    println!("{}", fields.join("\t"));
+}
+
+#[derive(Parser, Debug)]
+#[command(
+    name = "epubtool",
+    about = "EPUB manipulation toolkit - split and merge EPUB files",
+    version,
+    after_long_help = "Exit codes:
+  0  success
+  1  other error
+  2  invalid arguments
+  3  unreadable input
+  4  DRM-protected input
+  5  selection out of range
+  6  batch stopped part way (some outputs were written)
+  7  validation failed (checksums, --strict-cover)"
+)]
+struct Cli {
+    /// Log more: -v for progress, -vv for debug output, -vvv for everything
+    #[arg(short, long, global = true, action = clap::ArgAction::Count, conflicts_with = "quiet")]
+    verbose: u8,
+
+    /// Only log errors
+    #[arg(short, long, global = true)]
+    quiet: bool,
+
+    /// Also write a full debug log to this file, whatever the console level
+    #[arg(long, global = true, value_name = "FILE", env = "EPUBSPLIT_LOG_FILE")]
+    log_file: Option<PathBuf>,
+
+    /// Log as human-readable text or as JSON lines for log aggregators
+    #[arg(long, global = true, value_enum, default_value_t = LogFormat::Text, env = "EPUBSPLIT_LOG_FORMAT")]
+    log_format: LogFormat,
+
+    /// Print stable tab-separated records on stdout for scripts; messages go to stderr
+    ///
+    /// One record per line, fields separated by tabs. Tabs and newlines inside
+    /// fields become spaces; missing values are empty. Listing split points:
+    ///
+    ///   line  INDEX  ID  HREF  ANCHOR  GUIDE-TYPE  LINEAR(yes|no)  TITLE
+    ///
+    /// where TITLE is the TOC titles joined by " / ". For each EPUB written:
+    ///
+    ///   output  PATH
+    ///
+    /// For each match `grep` finds:
+    ///
+    ///   match  INDEX  TARGET  MATCH  SNIPPET
+    ///
+    /// where TARGET is the line's href and #anchor, if any.
+    ///
+    /// These records won't change. New record types may be added later, so
+    /// skip records with an unknown first field.
+    #[arg(long, global = true, verbatim_doc_comment)]
+    porcelain: bool,
+
+    /// Ignore ~/.config/epubsplit/config.toml and .epubsplit.toml
+    #[arg(long, global = true)]
+    no_config: bool,
+
+    /// Don't color terminal output (also honoured: NO_COLOR)
+    #[arg(long, global = true)]
+    no_color: bool,
+
+    #[command(subcommand)]
+    command: Commands,
+}
+
+/// Listing formats of the original Python EpubSplit
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum LegacyFormat {
+    /// Its command-line listing
+    Text,
+    /// Its `get_split_lines()` dicts
+    Json,
+}
+
+/// Machine-readable ways to list split points
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+enum ListFormat {
+    /// One JSON object per line, written as the spine is scanned
+    Ndjson,
+    /// A header row, then index, ID, titles, guide type, href, anchor and word count per line
+    Csv,
+}
+
+/// Files with a JSON Schema from `schema`
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum SchemaFormat {
+    /// Split plans, as read by --plan and written by --emit-plan
+    Plan,
+    /// Run reports written by --summary-json
+    Summary,
+}
+
+/// How log lines are written
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum LogFormat {
+    Text,
+    Json,
+}
+
+#[derive(Subcommand, Debug)]
+#[allow(clippy::large_enum_variant)] // parsed once; boxing would only complicate the match
+enum Commands {
+    /// Split an EPUB file into sections
+    Split {
+        /// Input EPUB file to split
+        input: PathBuf,
+
+        /// Sections to include in output: line numbers, -N counting from the end,
+        /// N.. or -N.. for a line and all after it (after `--` for -N..), IDs
+        /// shown when listing, href:PATH[#ANCHOR], id:MANIFEST-ID or anchor:NAME.
+        /// With --split-by-section, `rest` adds an output of every section not selected
+        #[arg(value_name = "LINE", allow_negative_numbers = true)]
+        lines: Vec<LineSelector>,
+
+        /// When listing split points, also show the CSS, images and fonts each one pulls in
+        #[arg(long)]
+        show_resources: bool,
+
+        /// List split points exactly as the original Python EpubSplit does, for
+        /// scripts written against it; line numbers are the same either way
+        #[arg(long, value_enum, value_name = "FORMAT", num_args = 0..=1, default_missing_value = "text")]
+        legacy_lines: Option<LegacyFormat>,
+
+        /// When listing split points, show only those whose TOC title, guide
+        /// title or href match this regex; line numbers stay as they are
+        #[arg(long, value_name = "REGEX", conflicts_with = "legacy_lines")]
+        filter: Option<Regex>,
+
+        /// When listing split points, start at this line number
+        #[arg(long, value_name = "N", conflicts_with = "legacy_lines")]
+        from: Option<usize>,
+
+        /// When listing split points, show at most this many
+        #[arg(long, value_name = "N", conflicts_with = "legacy_lines")]
+        limit: Option<usize>,
+
+        /// List split points as the nested TOC they sit in rather than a table
+        #[arg(long, conflicts_with = "legacy_lines")]
+        tree: bool,
+
+        /// List split points one per line as index, title and href separated by tabs
+        #[arg(long, conflicts_with_all = ["legacy_lines", "tree"])]
+        brief: bool,
+
+        /// List split points in a machine-readable format
+        #[arg(long, value_enum, value_name = "FORMAT", conflicts_with_all = ["legacy_lines", "tree", "brief"])]
+        format: Option<ListFormat>,
+
+        /// When listing split points, write a JSON array describing each one
+        /// with its word count and the start of its text to FILE ('-' for stdout),
+        /// for catalogs and search indexes
+        #[arg(long, value_name = "FILE", conflicts_with_all = ["legacy_lines", "format"])]
+        export_previews: Option<PathBuf>,
+
+        /// Words of text in each --export-previews preview
+        #[arg(long, value_name = "N", default_value_t = 100, requires = "export_previews")]
+        preview_words: usize,
+
+        /// Include only the first of any identical or near-identical sections
+        #[arg(long, overrides_with = "no_dedupe")]
+        dedupe: bool,
+
+        /// Turn off --dedupe set in a config file
+        #[arg(long, overrides_with = "dedupe")]
+        no_dedupe: bool,
+
+        /// Copy every non-spine manifest item (or with =archive, every archive file)
+        /// whether or not the included sections refer to it
+        #[arg(
+            long,
+            value_enum,
+            value_name = "SCOPE",
+            num_args = 0..=1,
+            require_equals = true,
+            default_missing_value = "manifest",
+            conflicts_with = "drop_orphans"
+        )]
+        include_all_resources: Option<ResourceScope>,
+
+        /// Leave out linked resources matching this glob, e.g. "fonts/*" or "*.mp3"
+        /// (can be specified multiple times)
+        #[arg(long, value_name = "PATTERN")]
+        exclude_resource: Vec<String>,
+
+        /// Leave references to excluded resources in place instead of removing them
+        #[arg(long, requires = "exclude_resource")]
+        keep_excluded_refs: bool,
+
+        /// Copy this archive file into the output even if nothing refers to it
+        /// (can be specified multiple times)
+        #[arg(long, value_name = "PATH")]
+        include_file: Vec<String>,
+
+        /// Leave out referenced files that aren't declared in the source manifest
+        /// (unreferenced manifest items are never copied)
+        #[arg(long, overrides_with = "no_drop_orphans")]
+        drop_orphans: bool,
+
+        /// Turn off --drop-orphans set in a config file
+        #[arg(long, overrides_with = "drop_orphans")]
+        no_drop_orphans: bool,
+
+        /// Leave out ads, store links, "also by" lists and newsletter sign-ups
+        #[arg(long, overrides_with = "no_skip_boilerplate")]
+        skip_boilerplate: bool,
+
+        /// Turn off --skip-boilerplate set in a config file
+        #[arg(long, overrides_with = "skip_boilerplate")]
+        no_skip_boilerplate: bool,
+
+        /// Read more sections from a file ('-' for stdin): whitespace- or
+        /// comma-separated LINEs and ranges like 3-7, or a JSON array
+        #[arg(long, value_name = "FILE")]
+        lines_file: Option<PathBuf>,
+
+        /// Also select every section whose text matches this regex, e.g.
+        /// "Interlude: The Dragon" (can be specified multiple times)
+        #[arg(long, value_name = "REGEX")]
+        containing: Vec<Regex>,
+
+        /// Output file name; {title} is filled in with each output's title, made safe
+        /// for any filesystem, and {date} and {datetime} as set by --date-from
+        /// [default: split.epub]
+        #[arg(short, long, env = "EPUBSPLIT_NAME_TEMPLATE")]
+        output: Option<String>,
+
+        /// Write {title} in output names in ASCII, transliterating other scripts
+        #[arg(long)]
+        ascii_names: bool,
+
+        /// Date for {date} and {datetime} in output names: the time of the run, or
+        /// the source's publication date
+        #[arg(long, value_enum, value_name = "SOURCE", default_value = "now")]
+        date_from: DateSource,
+
+        /// Output directory
+        #[arg(long, env = "EPUBSPLIT_OUTPUT_DIR")]
+        output_dir: Option<PathBuf>,
+
+        /// Create a new epub from each listed section instead of one containing all
+        #[arg(long)]
+        split_by_section: bool,
+
+        /// Metadata title for output epub. May use {orig_title}, {first_toc} (the first
+        /// section's TOC entry), {index} and {total}, filled in per output; with
+        /// --split-by-section such a template titles every output
+        #[arg(short, long)]
+        title: Option<String>,
+
+        /// When a run writes several outputs, add "(Part N of M)" to each title, and
+        /// with title-and-description a "Part N of M." line to each description
+        #[arg(long, value_enum, value_name = "WHERE", num_args = 0..=1, default_missing_value = "title")]
+        number_parts: Option<PartNumbering>,
+
+        /// Title for output N alone, numbered as in its file name, e.g.
+        /// 3="Epilogue Collection"; may use the --title placeholders
+        #[arg(long, value_name = "N=TITLE", value_parser = parse_output_override)]
+        title_for: Vec<(usize, String)>,
+
+        /// Metadata description for output epub
+        #[arg(short, long)]
+        description: Option<String>,
+
+        /// Metadata author(s) for output epub (can be specified multiple times); a
+        /// MARC relator suffix gives another role, e.g. "Jane Doe:ill"
+        #[arg(short, long)]
+        author: Vec<String>,
+
+        /// Metadata contributor(s), optionally with a role, e.g. "John Roe:edt"
+        /// [default: the source's contributors]
+        #[arg(long, value_name = "NAME[:ROLE]")]
+        contributor: Vec<String>,
+
+        /// Sort key for the title (calibre:title_sort); takes the same placeholders
+        /// as --title, e.g. "Saga 0{index}"
+        #[arg(long, value_name = "TEXT")]
+        title_sort: Option<String>,
+
+        /// Sort key (file-as) for each author in turn, e.g. "Doe, Jane"
+        /// [default: the source's, where the author is kept]
+        #[arg(long, value_name = "TEXT")]
+        author_sort: Vec<String>,
+
+        /// Subject tag(s) for output epub (can be specified multiple times)
+        /// [default: the source's subjects]
+        #[arg(short = 'g', long, env = "EPUBSPLIT_TAG", value_delimiter = ',')]
+        tag: Vec<String>,
+
+        /// Without --tag, leave outputs untagged instead of copying the source's subjects
+        #[arg(long, conflicts_with = "tag")]
+        no_inherit_tags: bool,
+
+        /// Tag for output N alone, in place of --tag or the source's subjects
+        /// (repeat for several tags)
+        #[arg(long, value_name = "N=TAG", value_parser = parse_output_override)]
+        tag_for: Vec<(usize, String)>,
+
+        /// Language(s) for output epub (can be specified multiple times) [default: source language(s), or en]
+        #[arg(short, long, env = "EPUBSPLIT_LANGUAGE", value_delimiter = ',')]
+        language: Vec<String>,
+
+        /// Path to cover image (JPEG, PNG, GIF or WebP)
+        #[arg(short, long, conflicts_with = "generate_cover")]
+        cover: Option<PathBuf>,
+
+        /// Render a cover showing the title, author and part number for each output
+        #[arg(
+            long,
+            value_enum,
+            value_name = "STYLE",
+            num_args = 0..=1,
+            require_equals = true,
+            default_missing_value = "gradient"
+        )]
+        generate_cover: Option<CoverStyle>,
+
+        /// Resize and centre-crop supplied covers to WIDTHxHEIGHT (default 1600x2560)
+        #[arg(
+            long,
+            value_name = "WIDTHxHEIGHT",
+            value_parser = parse_cover_size,
+            num_args = 0..=1,
+            require_equals = true,
+            default_missing_value = "1600x2560"
+        )]
+        cover_size: Option<CoverSize>,
+
+        /// Reject tiny or oddly proportioned covers instead of warning about them
+        #[arg(long)]
+        strict_cover: bool,
+
+        /// Leave out the source cover page and image, and don't add a cover
+        #[arg(long, conflicts_with_all = ["cover", "generate_cover", "cover_size"])]
+        no_cover: bool,
+
+        /// Split according to a plan file (JSON, TOML or YAML) listing each output's sections,
+        /// and optionally its filename, title and cover
+        #[arg(long, conflicts_with_all = ["lines", "lines_file", "containing", "split_by_section"])]
+        plan: Option<PathBuf>,
+
+        /// Write one output holding lines FIRST to LAST, titled TITLE (which may use
+        /// the --title placeholders); repeat for each volume, e.g. --volume "1-12:Vol 1"
+        #[arg(
+            long,
+            value_name = "FIRST-LAST[:TITLE]",
+            value_parser = parse_volume,
+            conflicts_with_all = ["lines", "lines_file", "containing", "split_by_section", "plan"]
+        )]
+        volume: Vec<Volume>,
+
+        /// Also write the resolved selection as a JSON plan for re-running with --plan
+        #[arg(long, value_name = "PLAN")]
+        emit_plan: Option<PathBuf>,
+
+        /// Also include non-linear spine items (notes, answer keys) linked from included sections
+        #[arg(long, overrides_with = "no_include_nonlinear")]
+        include_nonlinear: bool,
+
+        /// Turn off --include-nonlinear set in a config file
+        #[arg(long, overrides_with = "include_nonlinear")]
+        no_include_nonlinear: bool,
+
+        /// Put sections in the output in the order given rather than the source's spine order
+        #[arg(long)]
+        keep_order: bool,
+
+        /// Relabel output TOC entries from a CSV of `old,new` rows, where `old` is a
+        /// split line index or a TOC title as listed
+        #[arg(long, value_name = "CSV")]
+        rename_toc: Option<PathBuf>,
+
+        /// Move copied files into a simple OEBPS/Text, OEBPS/Styles, OEBPS/Images, ... layout
+        #[arg(long)]
+        flatten: bool,
+
+        /// Keep internal file paths exactly as in the source (the default)
+        #[arg(long, conflicts_with_all = ["flatten", "ascii_filenames"])]
+        keep_layout: bool,
+
+        /// Rename internal files with spaces or non-ASCII characters to ASCII-safe names
+        #[arg(long)]
+        ascii_filenames: bool,
+
+        /// With --split-by-section or --plan, skip outputs an earlier --resume run already
+        /// wrote from the same input and options (these runs record them in
+        /// .epubsplit-state.json in the output directory)
+        #[arg(long)]
+        resume: bool,
+
+        /// Reuse the source's unique identifier instead of minting a new one, for
+        /// outputs that replace the original in a library (every output of a
+        /// batch then shares it)
+        #[arg(long)]
+        keep_uid: bool,
+
+        /// Give output N the Nth identifier listed in this file, one per line
+        /// (e.g. urn:isbn:9780000000000), instead of minting a new one
+        #[arg(long, value_name = "FILE", conflicts_with = "keep_uid")]
+        identifiers_file: Option<PathBuf>,
+
+        /// When the run ends, write a JSON summary of every output (sections, sizes,
+        /// resources, warnings, timing) to this file, even if the run fails
+        #[arg(long, value_name = "PATH")]
+        summary_json: Option<PathBuf>,
+
+        /// When the run ends, print how long parsing, scanning and each output
+        /// took, with bytes read and written and how well outputs compressed
+        #[arg(long)]
+        stats: bool,
+
+        /// Run this shell command after each output is written; {output}, {title} and
+        /// {index} (the part number) are replaced with shell-quoted values
+        #[arg(long, value_name = "COMMAND")]
+        post_hook: Option<String>,
+
+        /// Detect each output's language from its text instead of using --language
+        #[arg(long, overrides_with = "no_detect_language")]
+        detect_language: bool,
+
+        /// Turn off --detect-language set in a config file
+        #[arg(long, overrides_with = "detect_language")]
+        no_detect_language: bool,
+
+        /// Re-encode oversized JPEG/PNG images (optionally QUALITY,MAXDIM; default 80,1600)
+        #[arg(
+            long,
+            value_name = "QUALITY,MAXDIM",
+            num_args = 0..=1,
+            require_equals = true,
+            default_missing_value = "80,1600",
+            value_parser = parse_image_optimization,
+            env = "EPUBSPLIT_OPTIMIZE_IMAGES"
+        )]
+        optimize_images: Option<ImageOptimization>,
+
+        /// Remove <script> elements and on* event handler attributes from copied content
+        #[arg(long, overrides_with = "no_strip_scripts")]
+        strip_scripts: bool,
+
+        /// Turn off --strip-scripts set in a config file
+        #[arg(long, overrides_with = "strip_scripts")]
+        no_strip_scripts: bool,
+
+        /// Remove references to remote (http/https) resources from copied content and CSS
+        #[arg(long, overrides_with = "no_strip_remote")]
+        strip_remote: bool,
+
+        /// Turn off --strip-remote set in a config file
+        #[arg(long, overrides_with = "strip_remote")]
+        no_strip_remote: bool,
+
+        /// Download remote (http/https) resources into the output and rewrite links to them
+        #[arg(long)]
+        fetch_remote: bool,
+
+        /// Strip comments and collapse whitespace in copied XHTML and CSS, leaving <pre> alone
+        #[arg(long, overrides_with = "no_minify")]
+        minify: bool,
+
+        /// Turn off --minify set in a config file
+        #[arg(long, overrides_with = "minify")]
+        no_minify: bool,
+
+        /// Normalize the text of copied XHTML to Unicode NFC
+        #[arg(long, overrides_with = "no_normalize_unicode")]
+        normalize_unicode: bool,
+
+        /// Turn off --normalize-unicode set in a config file
+        #[arg(long, overrides_with = "normalize_unicode")]
+        no_normalize_unicode: bool,
+
+        /// Convert quotes and dashes in the text of copied XHTML to plain ASCII
+        /// (straight) or typographic (smart) forms
+        #[arg(long, value_enum, value_name = "STYLE")]
+        punctuation: Option<Punctuation>,
+
+        /// Remove soft hyphens from the text of copied XHTML
+        #[arg(long, overrides_with = "no_strip_soft_hyphens")]
+        strip_soft_hyphens: bool,
+
+        /// Turn off --strip-soft-hyphens set in a config file
+        #[arg(long, overrides_with = "strip_soft_hyphens")]
+        no_strip_soft_hyphens: bool,
+
+        /// Add schema.org accessibility metadata derived from the output's content
+        /// where the source declares none
+        #[arg(long, overrides_with = "no_generate_a11y")]
+        generate_a11y: bool,
+
+        /// Turn off --generate-a11y set in a config file
+        #[arg(long, overrides_with = "generate_a11y")]
+        no_generate_a11y: bool,
+
+        /// Record SHA-256 checksums of every entry, inside the EPUB (embed, the
+        /// default) or in a .sha256 file next to it (sidecar)
+        #[arg(
+            long,
+            value_enum,
+            value_name = "WHERE",
+            num_args = 0..=1,
+            require_equals = true,
+            default_missing_value = "embed",
+            env = "EPUBSPLIT_CHECKSUMS"
+        )]
+        checksums: Option<ChecksumMode>,
+
+        /// Also write an ONIX 3.0 product record for each output, to
+        /// <output>.onix.xml next to it
+        #[arg(long, overrides_with = "no_onix")]
+        onix: bool,
+
+        /// Turn off --onix set in a config file
+        #[arg(long, overrides_with = "onix")]
+        no_onix: bool,
+
+        /// Also write each output's metadata to a file next to it: <output>.opf
+        /// in the metadata.opf form Calibre reads, <output>.json, or <output>.nfo
+        /// for Jellyfin/Plex-style media libraries (json and nfo also save the
+        /// cover as <output>-cover.jpg or .png)
+        #[arg(long, value_enum, value_name = "FORMAT")]
+        sidecar: Option<SidecarFormat>,
+
+        /// Put each output in a Calibre library folder, as Author/Title (id)/
+        /// with metadata.opf and cover.jpg. Calibre picks the books up on its
+        /// next library check.
+        #[arg(long, value_name = "LIBRARY", env = "EPUBSPLIT_CALIBRE_LIBRARY")]
+        calibre_library: Option<PathBuf>,
+
+        /// With --calibre-library, write outputs as usual and print the
+        /// `calibredb add` command for each instead
+        #[arg(long, requires = "calibre_library")]
+        calibredb: bool,
+    },
+
+    /// Merge multiple EPUB files into one
+    Merge {
+        /// Input EPUB files to merge (at least 2)
+        #[arg(required = true, num_args = 2..)]
+        inputs: Vec<PathBuf>,
+
+        /// Output file name; {date} and {datetime} are filled in with the time of the run
+        #[arg(short, long, default_value = "merged.epub")]
+        output: String,
+
+        /// Metadata title for merged epub
+        #[arg(short, long)]
+        title: Option<String>,
+
+        /// Metadata description for merged epub
+        #[arg(short, long)]
+        description: Option<String>,
+
+        /// Metadata author(s) for merged epub (can be specified multiple times); a
+        /// MARC relator suffix gives another role, e.g. "Jane Doe:ill"
+        #[arg(short, long)]
+        author: Vec<String>,
+
+        /// Metadata contributor(s), optionally with a role, e.g. "John Roe:edt"
+        #[arg(long, value_name = "NAME[:ROLE]")]
+        contributor: Vec<String>,
+
+        /// Subject tag(s) for merged epub (can be specified multiple times)
+        #[arg(short = 'g', long, env = "EPUBSPLIT_TAG", value_delimiter = ',')]
+        tag: Vec<String>,
+
+        /// Language(s) for merged epub (can be specified multiple times) [default: source language(s), or en]
+        #[arg(short, long, env = "EPUBSPLIT_LANGUAGE", value_delimiter = ',')]
+        language: Vec<String>,
+
+        /// Path to cover image (JPEG, PNG, GIF or WebP)
+        #[arg(short, long)]
+        cover: Option<PathBuf>,
+
+        /// Re-encode oversized JPEG/PNG images (optionally QUALITY,MAXDIM; default 80,1600)
+        #[arg(
+            long,
+            value_name = "QUALITY,MAXDIM",
+            num_args = 0..=1,
+            require_equals = true,
+            default_missing_value = "80,1600",
+            value_parser = parse_image_optimization,
+            env = "EPUBSPLIT_OPTIMIZE_IMAGES"
+        )]
+        optimize_images: Option<ImageOptimization>,
+
+        /// Remove <script> elements and on* event handler attributes from copied content
+        #[arg(long, overrides_with = "no_strip_scripts")]
+        strip_scripts: bool,
+
+        /// Turn off --strip-scripts set in a config file
+        #[arg(long, overrides_with = "strip_scripts")]
+        no_strip_scripts: bool,
+
+        /// Remove references to remote (http/https) resources from copied content and CSS
+        #[arg(long, overrides_with = "no_strip_remote")]
+        strip_remote: bool,
+
+        /// Turn off --strip-remote set in a config file
+        #[arg(long, overrides_with = "strip_remote")]
+        no_strip_remote: bool,
+
+        /// Download remote (http/https) resources into the output and rewrite links to them
+        #[arg(long)]
+        fetch_remote: bool,
+
+        /// Strip comments and collapse whitespace in copied XHTML and CSS, leaving <pre> alone
+        #[arg(long, overrides_with = "no_minify")]
+        minify: bool,
+
+        /// Turn off --minify set in a config file
+        #[arg(long, overrides_with = "minify")]
+        no_minify: bool,
+
+        /// Normalize the text of copied XHTML to Unicode NFC
+        #[arg(long, overrides_with = "no_normalize_unicode")]
+        normalize_unicode: bool,
+
+        /// Turn off --normalize-unicode set in a config file
+        #[arg(long, overrides_with = "normalize_unicode")]
+        no_normalize_unicode: bool,
+
+        /// Convert quotes and dashes in the text of copied XHTML to plain ASCII
+        /// (straight) or typographic (smart) forms
+        #[arg(long, value_enum, value_name = "STYLE")]
+        punctuation: Option<Punctuation>,
+
+        /// Remove soft hyphens from the text of copied XHTML
+        #[arg(long, overrides_with = "no_strip_soft_hyphens")]
+        strip_soft_hyphens: bool,
+
+        /// Turn off --strip-soft-hyphens set in a config file
+        #[arg(long, overrides_with = "strip_soft_hyphens")]
+        no_strip_soft_hyphens: bool,
+
+        /// Add schema.org accessibility metadata derived from the output's content
+        /// where the source declares none
+        #[arg(long, overrides_with = "no_generate_a11y")]
+        generate_a11y: bool,
+
+        /// Turn off --generate-a11y set in a config file
+        #[arg(long, overrides_with = "generate_a11y")]
+        no_generate_a11y: bool,
+
+        /// Record SHA-256 checksums of every entry, inside the EPUB (embed, the
+        /// default) or in a .sha256 file next to it (sidecar)
+        #[arg(
+            long,
+            value_enum,
+            value_name = "WHERE",
+            num_args = 0..=1,
+            require_equals = true,
+            default_missing_value = "embed",
+            env = "EPUBSPLIT_CHECKSUMS"
+        )]
+        checksums: Option<ChecksumMode>,
+
+        /// Also write an ONIX 3.0 product record for each output, to
+        /// <output>.onix.xml next to it
+        #[arg(long, overrides_with = "no_onix")]
+        onix: bool,
+
+        /// Turn off --onix set in a config file
+        #[arg(long, overrides_with = "onix")]
+        no_onix: bool,
+
+        /// Also write each output's metadata to a file next to it: <output>.opf
+        /// in the metadata.opf form Calibre reads, <output>.json, or <output>.nfo
+        /// for Jellyfin/Plex-style media libraries (json and nfo also save the
+        /// cover as <output>-cover.jpg or .png)
+        #[arg(long, value_enum, value_name = "FORMAT")]
+        sidecar: Option<SidecarFormat>,
+
+        /// When the run ends, print how long the merge took, with bytes read
+        /// and written and how well the output compressed
+        #[arg(long)]
+        stats: bool,
+    },
+
+    /// Search the text of each section and list the split lines whose sections
+    /// match, with the text around each match
+    Grep {
+        /// EPUB file to search
+        input: PathBuf,
+
+        /// Regex to search for
+        pattern: Regex,
+
+        /// Match without regard to case
+        #[arg(short, long)]
+        ignore_case: bool,
+
+        /// Characters of text to show either side of a match
+        #[arg(short = 'C', long, value_name = "CHARS", default_value_t = 40)]
+        context: usize,
+    },
+
+    /// Print a book's word counts overall and per section, its resources by
+    /// kind, TOC depth and spine length, for choosing how to split it
+    Stats {
+        /// EPUB file to describe
+        input: PathBuf,
+
+        /// Print as JSON
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// List manifest items nothing in the book refers to, and files missing from the manifest
+    Audit {
+        /// EPUB file to audit
+        input: PathBuf,
+    },
+
+    /// Watch a folder and split each EPUB that arrives in it
+    #[cfg(not(target_arch = "wasm32"))]
+    Watch {
+        /// Folder to watch for new EPUB files
+        dir: PathBuf,
+
+        /// Split according to this plan (JSON, TOML or YAML) instead of one output per section
+        #[arg(long)]
+        plan: Option<PathBuf>,
+
+        /// Folder to write outputs to
+        #[arg(long, env = "EPUBSPLIT_OUTPUT_DIR")]
+        output_dir: PathBuf,
+
+        /// Also split EPUBs already in the folder when watching starts
+        #[arg(long)]
+        existing: bool,
+    },
+
+    /// Serve a JSON API for uploading and splitting EPUBs
+    #[cfg(feature = "serve")]
+    Serve {
+        /// Address to listen on
+        #[arg(long, default_value = "127.0.0.1:8080", env = "EPUBSPLIT_ADDR")]
+        addr: String,
+    },
+
+    /// Print a shell completion script, e.g. `epubtool completions bash > /etc/bash_completion.d/epubtool`
+    Completions {
+        /// Shell to generate completions for
+        #[arg(value_enum)]
+        shell: Shell,
+    },
+
+    /// Print the JSON Schema for a machine-readable format
+    Schema {
+        /// Format to describe
+        #[arg(value_enum)]
+        format: SchemaFormat,
+    },
+
+    /// Check an EPUB against the checksums recorded with --checksums
+    Verify {
+        /// EPUB file to verify
+        input: PathBuf,
+    },
+
+    /// Compare the metadata, spine, TOC and file contents of two EPUBs
+    Diff {
+        /// Original EPUB
+        old: PathBuf,
+
+        /// Updated EPUB
+        new: PathBuf,
+    },
+
+    /// List an EPUB's TOC entries by position (1, 2.1, ...), or write a copy with
+    /// entries added, removed, renamed or re-nested. Positions always refer to the
+    /// TOC as listed before any edits.
+    Toc {
+        /// EPUB whose TOC to list or edit
+        input: PathBuf,
+
+        /// Where to write the edited copy [default: INPUT-toc.epub]
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+
+        /// Relabel an entry
+        #[arg(long, value_name = "POS=TITLE", value_parser = parse_toc_rename)]
+        rename: Vec<(TocPosition, String)>,
+
+        /// Make an entry a child of the entry before it
+        #[arg(long, value_name = "POS")]
+        nest: Vec<TocPosition>,
+
+        /// Move a nested entry out to follow its parent
+        #[arg(long, value_name = "POS")]
+        unnest: Vec<TocPosition>,
+
+        /// Remove an entry; entries nested under it take its place
+        #[arg(long, value_name = "POS")]
+        remove: Vec<TocPosition>,
+
+        /// Add a top-level entry pointing at a package path (optionally with
+        /// #anchor), placed by where that file falls in reading order
+        #[arg(long, value_name = "TITLE=HREF", value_parser = parse_toc_addition)]
+        add: Vec<(String, String)>,
+    },
+
+    /// Write a copy of an EPUB with spine items in a new order, and its TOC
+    /// following along
+    Reorder {
+        /// EPUB to reorder
+        input: PathBuf,
+
+        /// Spine items, by manifest id or href, in the order they should be read.
+        /// They take the places the listed items held; unlisted items stay put
+        #[arg(value_name = "ITEM", required = true)]
+        items: Vec<String>,
+
+        /// Where to write the reordered copy [default: INPUT-reordered.epub]
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+}
+
+/// A split plan read from `--plan` or written by `--emit-plan`: one entry
+/// per output EPUB
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+struct Plan {
+    /// File name of the EPUB the plan was made from (informational)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    source: Option<String>,
+    /// Metadata applied to every output unless given on the command line
+    #[serde(default)]
+    metadata: PlanMetadata,
+    outputs: Vec<PlannedOutput>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, JsonSchema)]
+struct PlanMetadata {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    authors: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<String>,
+}
+
+/// Sections taken from one EPUB for a compilation output in a split plan
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+struct PlannedPart {
+    /// EPUB to take the sections from, relative to the plan file [default:
+    /// the EPUB being split]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    source: Option<PathBuf>,
+    /// Section indices, as shown when listing split points of `source`
+    #[serde(default)]
+    sections: Vec<usize>,
+    /// Section hrefs (`file` or `file#anchor`), taking precedence over `ids`
+    /// and `sections`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    hrefs: Vec<String>,
+    /// Section IDs, taking precedence over `sections`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    ids: Vec<String>,
+}
+
+/// One output EPUB in a split plan
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+struct PlannedOutput {
+    /// Section indices, as shown when listing split points
+    #[serde(default)]
+    sections: Vec<usize>,
+    /// Section hrefs (`file` or `file#anchor`); when present these take
+    /// precedence over `ids` and `sections`, so a plan survives sections being
+    /// added or removed in a new edition
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    hrefs: Vec<String>,
+    /// Section IDs, as shown when listing split points; these take precedence
+    /// over `sections`. IDs change when a section's TOC titles do, so emitted
+    /// plans give hrefs instead.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    ids: Vec<String>,
+    /// Take every section no other output lists, in place of `ids`, `hrefs`
+    /// and `sections`; at most one output can
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    rest: bool,
+    /// Build this output from sections of several EPUBs, one part after
+    /// another, in place of `ids`, `hrefs` and `sections`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    parts: Vec<PlannedPart>,
+    /// Output filename [default: numbered from --output]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    output: Option<String>,
+    /// Title for this output [default: --title, or the first section's TOC entry]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    title: Option<String>,
+    /// Subject tags for this output alone [default: --tag, or the plan's tags]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<String>,
+    /// Cover for this output [default: --cover]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cover: Option<CoverInput>,
+}
+
+impl PlannedOutput {
+    /// The output's cover, with an image path taken relative to the plan
+    fn cover(&self, plan_dir: &std::path::Path) -> Option<CoverInput> {
+        match &self.cover {
+            Some(CoverInput::Path(path)) => Some(CoverInput::Path(plan_dir.join(path))),
+            cover => cover.clone(),
+        }
+    }
+}
+
+/// One `--volume`: an inclusive range of lines and an optional title
+#[derive(Debug, Clone)]
+struct Volume {
+    first: usize,
+    last: usize,
+    title: Option<String>,
+}
+
+fn parse_volume(s: &str) -> std::result::Result<Volume, String> {
+    let (range, title) = match s.split_once(':') {
+        Some((range, title)) => (range, Some(title.trim().to_string()).filter(|t| !t.is_empty())),
+        None => (s, None),
+    };
+    let (first, last) = range.split_once('-').unwrap_or((range, range));
+    let parse = |v: &str| {
+        v.trim()
+            .parse::<usize>()
+            .map_err(|_| format!("Invalid volume '{}' (expected FIRST-LAST[:TITLE])", s))
+    };
+    let (first, last) = (parse(first)?, parse(last)?);
+    if first > last {
+        return Err(format!("Invalid volume '{}' (range ends before it starts)", s));
+    }
+    Ok(Volume { first, last, title })
+}
+
+/// `N=VALUE` for `--title-for` and `--tag-for`, N being a 1-based output number
+fn parse_output_override(s: &str) -> std::result::Result<(usize, String), String> {
+    let (number, value) = s
+        .split_once('=')
+        .ok_or_else(|| format!("Invalid override '{}' (expected N=VALUE)", s))?;
+    let number = number
+        .trim()
+        .parse::<usize>()
+        .ok()
+        .filter(|&n| n > 0)
+        .ok_or_else(|| format!("Invalid output number '{}' (outputs count from 1)", number))?;
+    Ok((number, value.to_string()))
+}
+
+/// Sections picked on the command line or in a lines file: by line number,
+/// by the stable ID listings show, or by where they are in the package
+#[derive(Debug, Clone, PartialEq)]
+enum LineSelector {
+    Index(usize),
+    /// `-N`, the Nth line from the end, so -1 is the last
+    FromEnd(usize),
+    /// `N..` or `-N..`, that line and every one after it
+    Tail(Box<LineSelector>),
+    /// `rest`, with --split-by-section: one more output of every line not otherwise selected
+    Unclaimed,
+    StableId(String),
+    /// `href:PATH`, every line in that file, or `href:PATH#ANCHOR` for just one
+    Href(String),
+    /// `id:ID`, every line in the manifest item with that id
+    ManifestId(String),
+    /// `anchor:NAME`, the line a TOC entry starts at that anchor
+    Anchor(String),
+}
+
+impl FromStr for LineSelector {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        if let Ok(index) = s.parse() {
+            return Ok(Self::Index(index));
+        }
+        if s == "rest" {
+            return Ok(Self::Unclaimed);
+        }
+        if let Some(count) = s.strip_prefix('-').and_then(|n| n.parse().ok()).filter(|&n: &usize| n > 0) {
+            return Ok(Self::FromEnd(count));
+        }
+        if let Some(start) = s.strip_suffix("..") {
+            return match start.parse()? {
+                start @ (Self::Index(_) | Self::FromEnd(_)) => Ok(Self::Tail(Box::new(start))),
+                _ => Err(format!("Invalid range '{}' (expected N.. or -N..)", s)),
+            };
+        }
+        if let Some(href) = s.strip_prefix("href:").filter(|href| !href.is_empty()) {
+            return Ok(Self::Href(href.to_string()));
+        }
+        if let Some(id) = s.strip_prefix("id:").filter(|id| !id.is_empty()) {
+            return Ok(Self::ManifestId(id.to_string()));
+        }
+        if let Some(anchor) = s.strip_prefix("anchor:").filter(|anchor| !anchor.is_empty()) {
+            return Ok(Self::Anchor(anchor.to_string()));
+        }
+        let is_stable_id = s.len() == 9
+            && s.starts_with(['s', 'S'])
+            && s[1..].chars().all(|c| c.is_ascii_hexdigit());
+        if is_stable_id {
+            Ok(Self::StableId(s.to_ascii_lowercase()))
+        } else {
+            Err(format!(
+                "Invalid line '{}' (expected a line number, -N, N.., an ID like s0123abcd, href:PATH, id:ID or anchor:NAME)",
+                s
+            ))
+        }
+    }
+}
+
+impl LineSelector {
+    /// Whether the selector picks `line`; hrefs may leave off leading
+    /// folders, so `href:Text/ch1.xhtml` finds `OEBPS/Text/ch1.xhtml`
+    fn matches(&self, line: &SplitLine) -> bool {
+        match self {
+            Self::Index(_) | Self::FromEnd(_) | Self::Tail(_) | Self::Unclaimed => false,
+            Self::StableId(id) => line.stable_id() == *id,
+            Self::Href(href) => {
+                let target = if href.contains('#') { line.target() } else { line.href.clone() };
+                target == *href || target.ends_with(&format!("/{}", href))
+            }
+            Self::ManifestId(id) => line.id == *id,
+            Self::Anchor(anchor) => line.anchor.as_ref() == Some(anchor),
+        }
+    }
+}
+
+/// Line numbers for `selectors` among `lines`, in the order given; `rest`
+/// is left for the caller
+fn resolve_line_selectors(lines: &[SplitLine], selectors: &[LineSelector]) -> Result<Vec<usize>> {
+    let from_end = |count: usize| {
+        lines.len().checked_sub(count).ok_or_else(|| {
+            anyhow!(Failure::new(
+                FailureKind::OutOfRange,
+                format!("Line number -{} is out of range (the book has {} lines)", count, lines.len()),
+            ))
+        })
+    };
+    let mut indices = Vec::new();
+    for selector in selectors {
+        match selector {
+            LineSelector::Index(index) => {
+                indices.push(*index);
+                continue;
+            }
+            LineSelector::FromEnd(count) => {
+                indices.push(from_end(*count)?);
+                continue;
+            }
+            LineSelector::Unclaimed => continue,
+            LineSelector::Tail(start) => {
+                let start = match **start {
+                    LineSelector::FromEnd(count) => from_end(count)?,
+                    LineSelector::Index(index) => index,
+                    _ => unreachable!(),
+                };
+                if start >= lines.len() {
+                    bail!(Failure::new(
+                        FailureKind::OutOfRange,
+                        format!("Line number {} is out of range (max: {})", start, lines.len().saturating_sub(1)),
+                    ));
+                }
+                indices.extend(start..lines.len());
+                continue;
+            }
+            _ => {}
+        }
+        let matched: Vec<usize> = (0..lines.len()).filter(|&index| selector.matches(&lines[index])).collect();
+        if matched.is_empty() {
+            let wanted = match selector {
+                LineSelector::StableId(id) => format!("ID {}", id),
+                LineSelector::Href(href) => format!("href {}", href),
+                LineSelector::ManifestId(id) => format!("manifest id {}", id),
+                LineSelector::Anchor(anchor) => format!("anchor {}", anchor),
+                _ => unreachable!(),
+            };
+            bail!(Failure::new(FailureKind::OutOfRange, format!("No section has {}", wanted)));
+        }
+        indices.extend(matched);
+    }
+    Ok(indices)
+}
+
+/// Open an EPUB file for reading. With the `mmap` feature the file is
+/// memory-mapped instead, so a multi-gigabyte book isn't pulled through a
+/// read buffer and jumping between entries costs no seeks.
+fn open_epub_file(path: &std::path::Path) -> std::io::Result<Box<dyn EpubSource>> {
+    let file = File::open(path)?;
+    #[cfg(all(feature = "mmap", not(target_arch = "wasm32")))]
+    // SAFETY: the map is only ever read. As with any mapping, another
+    // process truncating the file meanwhile would fault the read.
+    let source = std::io::Cursor::new(unsafe { memmap2::Mmap::map(&file)? });
+    #[cfg(not(all(feature = "mmap", not(target_arch = "wasm32"))))]
+    let source = BufReader::new(file);
+    Ok(Box::new(source))
+}
+
+/// Adds what's read through it to a run's `RunStats::read`
+struct CountingReader<R>(R, Arc<AtomicU64>);
+
+impl<R: IoRead> IoRead for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.0.read(buf)?;
+        self.1.fetch_add(read as u64, Ordering::Relaxed);
+        Ok(read)
+    }
+}
+
+impl<R: Seek> Seek for CountingReader<R> {
+    fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+        self.0.seek(pos)
+    }
+}
+
+/// Open and parse an EPUB. Failures are tagged as unreadable input unless
+/// already classified (DRM).
+pub(crate) fn open_epub(path: PathBuf) -> Result<SplitEpub> {
+    open_input(path, &StatsRecorder::default())
+}
+
+/// [`open_epub`] for an input of a run, with what's read of it counted in
+/// `stats`
+#[instrument(name = "parse", skip_all, fields(epub = %path.display()))]
+fn open_input(path: PathBuf, stats: &StatsRecorder) -> Result<SplitEpub> {
+    let display = path.display().to_string();
+    load_epub(path, stats).map_err(|e| {
+        if e.downcast_ref::<Failure>().is_some() {
+            e
+        } else {
+            e.context(Failure::new(
+                FailureKind::UnreadableInput,
+                format!("Failed to load EPUB: {}", display),
+            ))
+        }
+    })
+}
+
+fn load_epub(path: PathBuf, stats: &StatsRecorder) -> Result<SplitEpub> {
+    let source = open_epub_file(&path)
+        .with_context(|| format!("Failed to open EPUB file: {}", path.display()))?;
+    SplitEpub::from_reader(stats.counted(source), path)
+}
+
+/// Write the given sections of `epub` to a new EPUB file, then checksum,
+/// check and file it as asked. Returns the linked resources copied
+/// alongside them as (source href, bytes written).
+#[instrument(name = "write", skip_all, fields(output = %output_path.display(), sections = section_indices.len()))]
+fn write_split_epub(
+    epub: &mut SplitEpub,
+    output_path: PathBuf,
+    section_indices: &[usize],
+    authors: &[String],
+    title: Option<&str>,
+    languages: &[String],
+    opts: &RunOptions,
+) -> Result<Vec<(String, u64)>> {
+    let series_title = epub.orig_title.clone();
+    let opts = &read_cover(opts)?;
+    write_output(output_path, section_indices, &series_title, opts, |output| {
+        epub.build_split_epub(output, section_indices, authors, title, languages, &opts.build)
+    })
+}
+
+/// `{date}` and `{datetime}` for output names, from the run's time or the
+/// book's publication date
+fn name_dates(epub: &SplitEpub, source: DateSource) -> NameDates {
+    match (source, &epub.orig_date) {
+        (DateSource::Published, Some(date)) => NameDates::from_w3cdtf(date),
+        (DateSource::Published, None) => {
+            warn!("{} has no publication date; using today's date", epub.path.display());
+            NameDates::now()
+        }
+        (DateSource::Now, _) => NameDates::now(),
+    }
+}
+
+/// Options for a command-line run: what goes into each output, plus where
+/// outputs are written and how the run reports on them
+#[derive(Debug, Clone, Default)]
+pub(crate) struct RunOptions {
+    /// How each output is built
+    pub(crate) build: OutputOptions,
+    pub(crate) output_dir: Option<PathBuf>,
+    /// `--cover`, read in as each output is written
+    pub(crate) cover: Option<CoverInput>,
+    pub(crate) emit_plan: Option<PathBuf>,
+    pub(crate) calibre_library: Option<PathBuf>,
+    /// How messages and `--porcelain` records are printed
+    pub(crate) style: Style,
+    /// Where `--summary-json` records what's written
+    pub(crate) summary: SummaryRecorder,
+    /// Where `--stats` records timings and sizes
+    pub(crate) stats: StatsRecorder,
+}
+
+/// Where a cover given on the command line or in a plan comes from
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub(crate) enum CoverInput {
+    /// An image file on disk (JPEG, PNG, GIF or WebP)
+    Path(PathBuf),
+    /// The Nth (1-based) image referenced by the output's own sections
+    SectionImage { section_image: usize },
+}
+
+/// Longest one `--fetch-remote` download may take, connecting included
+#[cfg(not(target_arch = "wasm32"))]
+const DOWNLOAD_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// Downloads for `--fetch-remote`
+#[cfg(target_arch = "wasm32")]
+fn remote_fetcher() -> RemoteFetcher {
+    RemoteFetcher::new(|url: &str| -> Result<Download> {
+        bail!("Fetching remote resources isn't available in the WebAssembly build: {}", url)
+    })
+}
+
+/// Downloads for `--fetch-remote`, sharing one agent so a host's resources
+/// reuse its connection
+#[cfg(not(target_arch = "wasm32"))]
+fn remote_fetcher() -> RemoteFetcher {
+    let agent: ureq::Agent = ureq::Agent::config_builder().timeout_global(Some(DOWNLOAD_TIMEOUT)).build().into();
+    RemoteFetcher::new(move |url: &str| download(&agent, url))
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn download(agent: &ureq::Agent, url: &str) -> Result<Download> {
+    let mut response = agent
+        .get(url)
+        .call()
+        .with_context(|| format!("Request failed: {}", url))?;
+    let content_type = response
+        .headers()
+        .get("content-type")
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.split(';').next().unwrap_or(v).trim().to_lowercase());
+    let data = response
+        .body_mut()
+        .read_to_vec()
+        .with_context(|| format!("Failed to read response body: {}", url))?;
+    Ok((data, content_type))
+}
+
+/// `opts` with its cover read into memory, for building an output from
+fn read_cover(opts: &RunOptions) -> Result<RunOptions> {
+    let mut opts = opts.clone();
+    opts.build.cover = match &opts.cover {
+        Some(_) if opts.build.no_cover => None,
+        Some(CoverInput::Path(path)) => {
+            let data = std::fs::read(path).with_context(|| format!("Failed to open cover: {}", path.display()))?;
+            Some(CoverSource::Image { name: path.display().to_string(), data: Arc::new(data) })
+        }
+        Some(CoverInput::SectionImage { section_image }) => Some(CoverSource::SectionImage(*section_image)),
+        None => None,
+    };
+    Ok(opts)
+}
+
+/// How results are printed: ANSI styling for terminal output, off for pipes
+/// and under `--no-color`/`NO_COLOR`, and whether stdout is kept for
+/// `--porcelain` records
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Style {
+    enabled: bool,
+    porcelain: bool,
+}
+
+impl Style {
+    fn detect(no_color: bool, porcelain: bool) -> Self {
+        use std::io::IsTerminal;
+        let no_color_env = std::env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty());
+        Self {
+            enabled: !no_color && !no_color_env && std::io::stdout().is_terminal(),
+            porcelain,
+        }
+    }
+
+    fn paint(&self, code: &str, text: &str) -> String {
+        if self.enabled {
+            format!("\x1b[{}m{}\x1b[0m", code, text)
+        } else {
+            text.to_string()
+        }
+    }
+
+    fn bold(&self, text: &str) -> String {
+        self.paint("1", text)
+    }
+
+    fn dim(&self, text: &str) -> String {
+        self.paint("2", text)
+    }
+
+    fn cyan(&self, text: &str) -> String {
+        self.paint("36", text)
+    }
+
+    fn yellow(&self, text: &str) -> String {
+        self.paint("33", text)
+    }
+}
+
+/// Widest the title column gets before titles are cut short
+const LISTING_TITLE_WIDTH: usize = 48;
+
+/// Pad `text` to `width` characters, cutting it short with an ellipsis
+fn fit_column(text: &str, width: usize) -> String {
+    if text.chars().count() > width {
+        let cut: String = text.chars().take(width.saturating_sub(1)).collect();
+        format!("{}…", cut)
+    } else {
+        format!("{:<width$}", text, width = width)
+    }
+}
+
+/// Indices of the split lines whose TOC titles, guide title or href match `filter`
+fn filter_split_lines(lines: &[SplitLine], filter: &Regex) -> Vec<usize> {
+    lines
+        .iter()
+        .enumerate()
+        .filter(|(_, line)| line.matches(filter))
+        .map(|(index, _)| index)
+        .collect()
+}
+
+/// Print the split lines at `shown` as a table, numbered by their place in `lines`
+fn list_split_points(
+    lines: &[SplitLine],
+    shown: &[usize],
+    word_counts: &[usize],
+    duplicates: &HashMap<usize, (usize, f32)>,
+    resources: Option<&HashMap<String, Vec<(String, u64)>>>,
+    style: Style,
+) -> Result<()> {
+    let titles: Vec<String> = lines.iter().map(SplitLine::listing_title).collect();
+    let hrefs: Vec<String> = lines.iter().map(SplitLine::target).collect();
+
+    let index_width = lines.len().saturating_sub(1).to_string().len().max(1);
+    let title_width = shown
+        .iter()
+        .map(|&index| titles[index].chars().count())
+        .max()
+        .unwrap_or(0)
+        .clamp("Title".len(), LISTING_TITLE_WIDTH);
+    let guide_width = shown
+        .iter()
+        .filter_map(|&index| lines[index].guide.as_ref().map(|(ref_type, _)| ref_type.len()))
+        .max()
+        .unwrap_or(0)
+        .max("Guide".len());
+    let words_width = shown
+        .iter()
+        .filter_map(|&index| word_counts.get(index))
+        .map(|count| count.to_string().len())
+        .max()
+        .unwrap_or(0)
+        .max("Words".len());
+    // Notes go under the title column
+    let note_indent = " ".repeat(index_width + 2);
+
+    say!(
+        style,
+        "{}",
+        style.bold(&format!(
+            "{:>iw$}  {}  {:<gw$}  {:>ww$}  {:<9}  Href",
+            "#",
+            fit_column("Title", title_width),
+            "Guide",
+            "Words",
+            "ID",
+            iw = index_width,
+            gw = guide_width,
+            ww = words_width
+        ))
+    );
+
+    for &index in shown {
+        let line = &lines[index];
+        // A row starting a new content file is a section boundary; rows for
+        // anchors further into the same file are dimmed
+        let starts_file = index == 0 || lines[index - 1].href != line.href;
+        let number = format!("{:>width$}", index, width = index_width);
+        let title = fit_column(&titles[index], title_width);
+        let guide = format!(
+            "{:<width$}",
+            line.guide.as_ref().map(|(ref_type, _)| ref_type.as_str()).unwrap_or(""),
+            width = guide_width
+        );
+        let words = format!(
+            "{:>width$}",
+            word_counts.get(index).copied().unwrap_or(0),
+            width = words_width
+        );
+        let (number, title) = if starts_file {
+            (style.bold(&number), style.bold(&title))
+        } else {
+            (style.dim(&number), title)
+        };
+        say!(
+            style,
+            "{}  {}  {}  {}  {}  {}",
+            number,
+            title,
+            style.cyan(&guide),
+            words,
+            line.stable_id(),
+            style.dim(&hrefs[index])
+        );
+
+        if !line.linear {
+            say!(style, "{}{}", note_indent, style.yellow("non-linear"));
+        }
+        if let Some((original, similarity)) = duplicates.get(&index) {
+            let note = if *similarity >= 1.0 {
+                format!("duplicate of {} (identical)", original)
+            } else {
+                format!("duplicate of {} ({:.0}% similar)", original, similarity * 100.0)
+            };
+            say!(style, "{}{}", note_indent, style.yellow(&note));
+        }
+        if let Some(linked) = resources.and_then(|resources| resources.get(&line.href)) {
+            if starts_file {
+                let total: u64 = linked.iter().map(|(_, size)| size).sum();
+                say!(
+                    style,
+                    "{}resources: {} ({})",
+                    note_indent,
+                    linked.len(),
+                    format_size(total)
+                );
+                for (path, size) in linked {
+                    say!(style, "{}  {} ({})", note_indent, path, format_size(*size));
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// `--tree`: the source TOC as nested, each entry beside the split line it
+/// starts, or noting the line it falls in when it points partway into one
+struct TocTree<'a> {
+    lines: &'a [SplitLine],
+    shown: HashSet<usize>,
+    index_width: usize,
+    style: Style,
+}
+
+impl TocTree<'_> {
+    /// The split line `src` points into, and whether it's where that line starts
+    fn line_of(&self, src: &str) -> Option<(usize, bool)> {
+        let (file, fragment) = match src.split_once('#') {
+            Some((file, fragment)) => (file, Some(fragment)),
+            None => (src, None),
+        };
+        self.lines
+            .iter()
+            .position(|line| line.href == file && line.anchor.as_deref() == fragment)
+            .map(|index| (index, true))
+            .or_else(|| self.lines.iter().position(|line| line.href == file).map(|index| (index, false)))
+    }
+
+    /// Lines some nested entry starts
+    fn collect_starts(&self, points: &[NavPoint], starts: &mut HashSet<usize>) {
+        for point in points {
+            if let Some((index, true)) = self.line_of(&point.src) {
+                starts.insert(index);
+            }
+            self.collect_starts(&point.children, starts);
+        }
+    }
+
+    fn is_shown(&self, point: &NavPoint) -> bool {
+        self.line_of(&point.src).is_some_and(|(index, _)| self.shown.contains(&index))
+            || point.children.iter().any(|child| self.is_shown(child))
+    }
+
+    fn print_row(&self, index: Option<usize>, branch: &str, text: &str) {
+        let number = format!(
+            "{:>width$}",
+            index.map(|index| index.to_string()).unwrap_or_default(),
+            width = self.index_width
+        );
+        say!(self.style, "{}  {}{}", self.style.bold(&number), self.style.dim(branch), text);
+    }
+
+    /// Print `points` and their children; `prefix` is None at the top level
+    fn print(&self, points: &[NavPoint], prefix: Option<&str>) {
+        let points: Vec<&NavPoint> = points.iter().filter(|point| self.is_shown(point)).collect();
+        for (n, point) in points.iter().enumerate() {
+            let last = n + 1 == points.len();
+            let (branch, child_prefix) = match prefix {
+                None => (String::new(), String::new()),
+                Some(prefix) if last => (format!("{}└── ", prefix), format!("{}    ", prefix)),
+                Some(prefix) => (format!("{}├── ", prefix), format!("{}│   ", prefix)),
+            };
+            match self.line_of(&point.src) {
+                Some((index, true)) => self.print_row(Some(index), &branch, &point.text),
+                Some((index, false)) => self.print_row(
+                    None,
+                    &branch,
+                    &format!("{}{}", point.text, self.style.dim(&format!(" (in {})", index))),
+                ),
+                None => self.print_row(
+                    None,
+                    &branch,
+                    &format!("{}{}", point.text, self.style.dim(" (not in spine)")),
+                ),
+            }
+            self.print(&point.children, Some(&child_prefix));
+        }
+    }
+}
+
+/// Print the split lines at `shown` as the TOC tree they sit in. Lines no
+/// TOC entry starts are listed in spine order between the entries.
+fn print_toc_tree(epub: &SplitEpub, lines: &[SplitLine], shown: &[usize], style: Style) {
+    let tree = TocTree {
+        lines,
+        shown: shown.iter().copied().collect(),
+        index_width: lines.len().saturating_sub(1).to_string().len().max(1),
+        style,
+    };
+
+    let roots: Vec<Vec<NavPoint>> = lines
+        .iter()
+        .map(|line| {
+            let src = line.target();
+            line.toc
+                .iter()
+                .map(|text| NavPoint {
+                    text: text.clone(),
+                    src: src.clone(),
+                    children: epub.toc_children(line, text),
+                })
+                .collect()
+        })
+        .collect();
+    let mut nested_starts = HashSet::new();
+    for points in &roots {
+        for point in points {
+            tree.collect_starts(&point.children, &mut nested_starts);
+        }
+    }
+
+    for (index, (line, points)) in lines.iter().zip(&roots).enumerate() {
+        if !points.is_empty() {
+            tree.print(points, None);
+        } else if !nested_starts.contains(&index) && tree.shown.contains(&index) {
+            let title = match &line.guide {
+                Some((_, title)) => title.clone(),
+                None => "(untitled)".to_string(),
+            };
+            tree.print_row(Some(index), "", &format!("{}  {}", title, style.dim(&line.href)));
+        }
+    }
+}
+
+/// `--summary-json`: what a split run wrote, for pipelines to inspect
+#[derive(Debug, Serialize, JsonSchema)]
+struct RunSummary {
+    #[serde(skip)]
+    path: PathBuf,
+    #[serde(skip)]
+    started: std::time::Instant,
+    input: PathBuf,
+    outputs: Vec<OutputSummary>,
+    /// Outputs `--resume` left as they were
+    skipped: Vec<PathBuf>,
+    /// Warnings logged outside any one output
+    warnings: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    elapsed_ms: u128,
+    /// Warnings logged since the last output was recorded
+    #[serde(skip)]
+    pending_warnings: Vec<String>,
+}
+
+/// One written EPUB in a `RunSummary`
+#[derive(Debug, Serialize, JsonSchema)]
+struct OutputSummary {
+    path: PathBuf,
+    title: String,
+    sections: Vec<usize>,
+    bytes: u64,
+    /// Non-spine files copied in
+    resources: usize,
+    resource_bytes: u64,
+    broken_references: Vec<String>,
+    warnings: Vec<String>,
+    elapsed_ms: u128,
+}
+
+/// The `RunSummary` of one run, if it's being recorded; clones share it, so
+/// the writers and the warning collector add to the same summary
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SummaryRecorder(Arc<Mutex<Option<RunSummary>>>);
+
+impl SummaryRecorder {
+    fn lock(&self) -> std::sync::MutexGuard<'_, Option<RunSummary>> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn start(&self, path: PathBuf, input: &std::path::Path) {
+        *self.lock() = Some(RunSummary {
+            path,
+            started: std::time::Instant::now(),
+            input: input.to_path_buf(),
+            outputs: Vec::new(),
+            skipped: Vec::new(),
+            warnings: Vec::new(),
+            error: None,
+            elapsed_ms: 0,
+            pending_warnings: Vec::new(),
+        });
+    }
+
+    /// The start time of an output, when a summary is being recorded
+    fn timer(&self) -> Option<std::time::Instant> {
+        let mut summary = self.lock();
+        let summary = summary.as_mut()?;
+        // Anything logged before this output started belongs to the run
+        let pending = std::mem::take(&mut summary.pending_warnings);
+        summary.warnings.extend(pending);
+        Some(std::time::Instant::now())
+    }
+
+    fn record_output(&self, mut output: OutputSummary) {
+        if let Some(summary) = self.lock().as_mut() {
+            output.warnings = std::mem::take(&mut summary.pending_warnings);
+            summary.outputs.push(output);
+        }
+    }
+
+    fn record_skipped(&self, path: &std::path::Path) {
+        if let Some(summary) = self.lock().as_mut() {
+            summary.skipped.push(path.to_path_buf());
+        }
+    }
+
+    fn record_warning(&self, message: String) {
+        if let Some(summary) = self.lock().as_mut() {
+            summary.pending_warnings.push(message);
+        }
+    }
+
+    /// Write the summary, if one is being recorded, noting how the run ended
+    fn finish(&self, error: Option<&anyhow::Error>) -> Result<()> {
+        let Some(mut summary) = self.lock().take() else {
+            return Ok(());
+        };
+        let pending = std::mem::take(&mut summary.pending_warnings);
+        summary.warnings.extend(pending);
+        summary.error = error.map(|e| format!("{:#}", e));
+        summary.elapsed_ms = summary.started.elapsed().as_millis();
+        let json = serde_json::to_string_pretty(&summary).context("Failed to serialize run summary")?;
+        std::fs::write(&summary.path, json)
+            .with_context(|| format!("Failed to write run summary: {}", summary.path.display()))
+    }
+}
+
+/// `--stats`: where a split run spent its time
+struct RunStats {
+    started: std::time::Instant,
+    parse: std::time::Duration,
+    scan: std::time::Duration,
+    outputs: Vec<OutputStats>,
+    /// Bytes read from the run's input EPUBs
+    read: Arc<AtomicU64>,
+}
+
+/// One written EPUB in `RunStats`
+struct OutputStats {
+    path: PathBuf,
+    elapsed: std::time::Duration,
+    bytes: u64,
+    /// Total size of its entries before compression
+    uncompressed: u64,
+}
+
+/// The `RunStats` of one run, if `--stats` collects them; clones share them
+#[derive(Clone, Default)]
+pub(crate) struct StatsRecorder(Arc<Mutex<Option<RunStats>>>);
+
+impl StatsRecorder {
+    fn lock(&self) -> std::sync::MutexGuard<'_, Option<RunStats>> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn start(&self) {
+        *self.lock() = Some(RunStats {
+            started: std::time::Instant::now(),
+            parse: std::time::Duration::ZERO,
+            scan: std::time::Duration::ZERO,
+            outputs: Vec::new(),
+            read: Arc::new(AtomicU64::new(0)),
+        });
+    }
+
+    /// Update the stats, if they're being collected
+    fn record(&self, f: impl FnOnce(&mut RunStats)) {
+        if let Some(stats) = self.lock().as_mut() {
+            f(stats);
+        }
+    }
+
+    /// `source`, counting what's read through it while stats are collected
+    fn counted(&self, source: Box<dyn EpubSource>) -> Box<dyn EpubSource> {
+        match self.lock().as_ref() {
+            Some(stats) => Box::new(CountingReader(source, stats.read.clone())),
+            None => source,
+        }
+    }
+}
+
+impl std::fmt::Debug for StatsRecorder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("StatsRecorder")
+    }
+}
+
+/// Where an output is written before it's renamed into place
+fn partial_output_path(path: &std::path::Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".part");
+    path.with_file_name(name)
+}
+
+/// Total uncompressed size of the entries of an EPUB
+fn uncompressed_size(epub: impl IoRead + Seek) -> u64 {
+    let Ok(mut archive) = ZipArchive::new(epub) else {
+        return 0;
+    };
+    (0..archive.len())
+        .filter_map(|index| archive.by_index_raw(index).ok().map(|entry| entry.size()))
+        .sum()
+}
+
+/// Print the stats, if they were collected, however the run ended
+fn print_run_stats(stats: &StatsRecorder, style: Style) {
+    let Some(stats) = stats.lock().take() else {
+        return;
+    };
+    let ms = |duration: std::time::Duration| format!("{} ms", duration.as_millis());
+    let written: u64 = stats.outputs.iter().map(|output| output.bytes).sum();
+    let uncompressed: u64 = stats.outputs.iter().map(|output| output.uncompressed).sum();
+    let ratio = |bytes: u64, uncompressed: u64| match uncompressed {
+        0 => "-".to_string(),
+        _ => format!("{:.0}%", bytes as f64 * 100.0 / uncompressed as f64),
+    };
+
+    say!(style, "stats:");
+    say!(style, "\tparse: {}", ms(stats.parse));
+    say!(style, "\tscan: {}", ms(stats.scan));
+    for output in &stats.outputs {
+        say!(
+            style,
+            "\twrite {}: {}, {} ({} of {} uncompressed)",
+            output.path.display(),
+            ms(output.elapsed),
+            format_size(output.bytes),
+            ratio(output.bytes, output.uncompressed),
+            format_size(output.uncompressed)
+        );
+    }
+    say!(style, "\tread: {}", format_size(stats.read.load(Ordering::Relaxed)));
+    say!(
+        style,
+        "\twritten: {} in {} output(s) ({} of {} uncompressed)",
+        format_size(written),
+        stats.outputs.len(),
+        ratio(written, uncompressed),
+        format_size(uncompressed)
+    );
+    say!(style, "\ttotal: {}", ms(stats.started.elapsed()));
+}
+
+/// Tracing layer keeping warnings for the run summary while one is recorded
+struct WarningCollector(SummaryRecorder);
+
+impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for WarningCollector {
+    fn on_event(&self, event: &tracing::Event<'_>, _ctx: tracing_subscriber::layer::Context<'_, S>) {
+        if *event.metadata().level() != tracing::Level::WARN {
+            return;
+        }
+        let mut message = MessageVisitor(String::new());
+        event.record(&mut message);
+        self.0.record_warning(message.0);
+    }
+}
+
+/// Pulls the formatted message out of a tracing event
+struct MessageVisitor(String);
+
+impl tracing::field::Visit for MessageVisitor {
+    fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "message" {
+            self.0 = format!("{:?}", value);
+        }
+    }
+}
+
+/// An `output` record for `--porcelain`, naming a written EPUB
+fn porcelain_output(path: &std::path::Path, style: Style) {
+    if style.porcelain {
+        porcelain_record(&["output", &path.display().to_string()]);
+    }
+}
+
+/// One `--format ndjson` record
+#[derive(Serialize)]
+struct LineRecord<'a> {
+    index: usize,
+    stable_id: String,
+    toc: &'a [String],
+    guide_type: Option<&'a str>,
+    guide_title: Option<&'a str>,
+    href: &'a str,
+    anchor: Option<&'a str>,
+    id: &'a str,
+    linear: bool,
+}
+
+/// `--format ndjson`: each split line as a JSON object, printed as soon as
+/// the scan reaches it. `--limit` stops the scan rather than the output.
+fn stream_ndjson_lines(
+    epub: &mut SplitEpub,
+    filter: Option<&Regex>,
+    from: Option<usize>,
+    limit: Option<usize>,
+    style: Style,
+) -> Result<()> {
+    let mut index = 0;
+    let mut written = 0;
+    epub.for_each_split_line(|line| {
+        let this = index;
+        index += 1;
+        if this < from.unwrap_or(0) || filter.is_some_and(|filter| !line.matches(filter)) {
+            return Ok(ControlFlow::Continue(()));
+        }
+        let record = LineRecord {
+            index: this,
+            stable_id: line.stable_id(),
+            toc: &line.toc,
+            guide_type: line.guide.as_ref().map(|(ref_type, _)| ref_type.as_str()),
+            guide_title: line.guide.as_ref().map(|(_, title)| title.as_str()),
+            href: &line.href,
+            anchor: line.anchor.as_deref(),
+            id: &line.id,
+            linear: line.linear,
+        };
+        say!(style, "{}", serde_json::to_string(&record).context("Failed to serialize split line")?);
+        written += 1;
+        Ok(if limit.is_some_and(|limit| written >= limit) {
+            ControlFlow::Break(())
+        } else {
+            ControlFlow::Continue(())
+        })
+    })
+}
+
+/// One `--export-previews` entry
+#[derive(Serialize)]
+struct PreviewRecord<'a> {
+    #[serde(flatten)]
+    line: LineRecord<'a>,
+    title: String,
+    words: usize,
+    preview: String,
+}
+
+/// `--export-previews`: the split lines at `shown` as a JSON array, each
+/// with its word count and first `preview_words` words, written to `path`
+fn export_section_previews(
+    epub: &mut SplitEpub,
+    lines: &[SplitLine],
+    shown: &[usize],
+    preview_words: usize,
+    path: &std::path::Path,
+    style: Style,
+) -> Result<()> {
+    let word_counts = epub.section_word_counts(lines)?;
+    let previews = epub.section_previews(lines, preview_words)?;
+    let records: Vec<PreviewRecord> = shown
+        .iter()
+        .map(|&index| {
+            let line = &lines[index];
+            PreviewRecord {
+                line: LineRecord {
+                    index,
+                    stable_id: line.stable_id(),
+                    toc: &line.toc,
+                    guide_type: line.guide.as_ref().map(|(ref_type, _)| ref_type.as_str()),
+                    guide_title: line.guide.as_ref().map(|(_, title)| title.as_str()),
+                    href: &line.href,
+                    anchor: line.anchor.as_deref(),
+                    id: &line.id,
+                    linear: line.linear,
+                },
+                title: line.listing_title(),
+                words: word_counts[index],
+                preview: previews[index].clone(),
+            }
+        })
+        .collect();
+    let json = serde_json::to_string_pretty(&records).context("Failed to serialize previews")?;
+    if path.as_os_str() == "-" {
+        say!(style, "{}", json);
+        return Ok(());
+    }
+    std::fs::write(path, json + "\n").with_context(|| format!("Failed to write {}", path.display()))?;
+    say!(style, "Wrote previews of {} sections to {}", records.len(), path.display());
+    Ok(())
+}
+
+/// `--format csv`: a spreadsheet-ready row per split line at `shown`
+fn print_csv_lines(lines: &[SplitLine], shown: &[usize], word_counts: &[usize], style: Style) {
+    say!(style, "index,stable_id,titles,guide_type,href,anchor,words");
+    for &index in shown {
+        let line = &lines[index];
+        let fields = [
+            index.to_string(),
+            line.stable_id(),
+            line.toc.join(" / "),
+            line.guide.as_ref().map(|(ref_type, _)| ref_type.clone()).unwrap_or_default(),
+            line.href.clone(),
+            line.anchor.clone().unwrap_or_default(),
+            word_counts.get(index).copied().unwrap_or(0).to_string(),
+        ];
+        let fields: Vec<String> = fields.iter().map(|field| csv_field(field)).collect();
+        say!(style, "{}", fields.join(","));
+    }
+}
+
+/// A CSV field, quoted if it holds a comma, quote or line break
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// `--brief`: one `index<TAB>title<TAB>href` line per split line at `shown`
+fn print_brief_lines(lines: &[SplitLine], shown: &[usize], style: Style) {
+    for &index in shown {
+        let line = &lines[index];
+        say!(style, "{}\t{}\t{}", index, line.listing_title().replace(['\t', '\n'], " "), line.target());
+    }
+}
+
+/// `line` records for `--porcelain`, for the lines at `shown`
+fn print_porcelain_lines(lines: &[SplitLine], shown: &[usize]) {
+    for &index in shown {
+        let line = &lines[index];
+        let guide_type = line.guide.as_ref().map(|(ref_type, _)| ref_type.as_str()).unwrap_or("");
+        porcelain_record(&[
+            "line",
+            &index.to_string(),
+            &line.id,
+            &line.href,
+            line.anchor.as_deref().unwrap_or(""),
+            guide_type,
+            if line.linear { "yes" } else { "no" },
+            &line.toc.join(" / "),
+        ]);
+    }
+}
+
+/// One of the Python plugin's split line dicts, fields in its insertion order
+#[derive(Serialize)]
+struct LegacyLine<'a> {
+    href: &'a str,
+    anchor: Option<&'a str>,
+    toc: &'a [String],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    guide: Option<(&'a str, &'a str)>,
+    id: &'a str,
+    #[serde(rename = "type")]
+    media_type: &'a str,
+    num: usize,
+    sample: &'a str,
+}
+
+/// `--legacy-lines`: the Python EpubSplit listing, as text or as JSON
+fn print_legacy_lines(lines: &[SplitLine], format: LegacyFormat, style: Style) -> Result<()> {
+    match format {
+        LegacyFormat::Json => {
+            let legacy: Vec<LegacyLine> = lines
+                .iter()
+                .enumerate()
+                .map(|(num, line)| LegacyLine {
+                    href: &line.href,
+                    anchor: line.anchor.as_deref(),
+                    toc: &line.toc,
+                    guide: line.guide.as_ref().map(|(ref_type, title)| (ref_type.as_str(), title.as_str())),
+                    id: &line.id,
+                    media_type: &line.media_type,
+                    num,
+                    sample: &line.sample,
+                })
+                .collect();
+            say!(style, "{}", serde_json::to_string_pretty(&legacy).context("Failed to serialize split lines")?);
+        }
+        LegacyFormat::Text => {
+            for (num, line) in lines.iter().enumerate() {
+                say!(style, "\nLine Number: {}", num);
+                if !line.toc.is_empty() {
+                    let toc: Vec<String> = line.toc.iter().map(|text| python_repr(text)).collect();
+                    say!(style, "\ttoc: [{}]", toc.join(", "));
+                }
+                if let Some((ref_type, title)) = &line.guide {
+                    say!(style, "\tguide: ({}, {})", python_repr(ref_type), python_repr(title));
+                }
+                if let Some(anchor) = &line.anchor {
+                    say!(style, "\tanchor: {}", anchor);
+                }
+                say!(style, "\tid: {}", line.id);
+                say!(style, "\thref: {}", line.href);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// A string as Python's `repr()` writes it, which is how the original
+/// listing shows TOC titles
+fn python_repr(s: &str) -> String {
+    let quote = if s.contains('\'') && !s.contains('"') { '"' } else { '\'' };
+    let mut repr = String::with_capacity(s.len() + 2);
+    repr.push(quote);
+    for c in s.chars() {
+        match c {
+            '\\' => repr.push_str("\\\\"),
+            '\n' => repr.push_str("\\n"),
+            '\r' => repr.push_str("\\r"),
+            '\t' => repr.push_str("\\t"),
+            c if c == quote => {
+                repr.push('\\');
+                repr.push(c);
+            }
+            c if c.is_control() => repr.push_str(&format!("\\x{:02x}", c as u32)),
+            c => repr.push(c),
+        }
+    }
+    repr.push(quote);
+    repr
+}
+
+fn print_audit(epub: &mut SplitEpub, style: Style) -> Result<()> {
+    let (orphans, unmanifested) = epub.find_orphans()?;
+
+    let sizes: Vec<u64> = orphans.iter().map(|item| epub.archive_file_size(&item.href)).collect();
+    say!(
+        style,
+        "Unreferenced manifest items: {} ({})",
+        orphans.len(),
+        format_size(sizes.iter().sum())
+    );
+    for (item, size) in orphans.iter().zip(&sizes) {
+        say!(style, "\t{} [{}] {} ({})", item.href, item.id, item.media_type, format_size(*size));
+    }
+
+    let sizes: Vec<u64> = unmanifested.iter().map(|path| epub.archive_file_size(path)).collect();
+    say!(
+        style,
+        "\nFiles not in manifest: {} ({})",
+        unmanifested.len(),
+        format_size(sizes.iter().sum())
+    );
+    for (path, size) in unmanifested.iter().zip(&sizes) {
+        say!(style, "\t{} ({})", path, format_size(*size));
+    }
+
+    Ok(())
+}
+
+/// What `stats` reports about a book
+#[derive(Debug, Serialize)]
+struct BookStats {
+    title: String,
+    file_size: u64,
+    spine_items: usize,
+    linear_spine_items: usize,
+    toc_entries: usize,
+    toc_depth: usize,
+    words: usize,
+    sections: Vec<SectionStats>,
+    /// Manifest items by kind (content, image, stylesheet, font, audio,
+    /// video, other), with their uncompressed sizes
+    resources: BTreeMap<&'static str, ResourceStats>,
+}
+
+#[derive(Debug, Serialize)]
+struct SectionStats {
+    index: usize,
+    title: String,
+    words: usize,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct ResourceStats {
+    count: usize,
+    bytes: u64,
+}
+
+/// The kind of file a media type names, for `stats`
+fn resource_kind(media_type: &str) -> &'static str {
+    match media_type {
+        t if t.contains("html") => "content",
+        "text/css" => "stylesheet",
+        t if t.contains("font") || SFNT_MEDIA_TYPES.contains(&t) => "font",
+        t if t.starts_with("image/") => "image",
+        t if t.starts_with("audio/") => "audio",
+        t if t.starts_with("video/") => "video",
+        _ => "other",
+    }
+}
+
+fn book_stats(epub: &mut SplitEpub) -> Result<BookStats> {
+    fn count(points: &[NavPoint]) -> usize {
+        points.iter().map(|point| 1 + count(&point.children)).sum()
+    }
+
+    let lines = epub.get_split_lines()?;
+    let word_counts = epub.section_word_counts(&lines)?;
+    let opf = SplitEpub::read_file_from_archive(&mut epub.archive, &epub.content_opf_path)?;
+    let spine = SplitEpub::parse_spine(&opf)?;
+    let toc = if epub.has_toc() { epub.read_toc()? } else { Vec::new() };
+
+    let mut items: Vec<ManifestItem> = epub.manifest_items.values().cloned().collect();
+    items.sort_by(|a, b| a.href.cmp(&b.href));
+    let mut resources: BTreeMap<&'static str, ResourceStats> = BTreeMap::new();
+    for item in &items {
+        let stats = resources.entry(resource_kind(&item.media_type)).or_default();
+        stats.count += 1;
+        stats.bytes += epub.archive_file_size(&item.href);
+    }
+
+    Ok(BookStats {
+        title: epub.get_orig_title().to_string(),
+        file_size: std::fs::metadata(&epub.path).map(|meta| meta.len()).unwrap_or(0),
+        spine_items: spine.len(),
+        linear_spine_items: spine.iter().filter(|item| item.linear).count(),
+        toc_entries: count(&toc),
+        toc_depth: NavPoint::depth(&toc),
+        words: word_counts.iter().sum(),
+        sections: lines
+            .iter()
+            .zip(&word_counts)
+            .enumerate()
+            .map(|(index, (line, &words))| SectionStats {
+                index,
+                title: line.listing_title(),
+                words,
+            })
+            .collect(),
+        resources,
+    })
+}
+
+fn print_book_stats(stats: &BookStats, style: Style) {
+    let mut words: Vec<usize> = stats.sections.iter().map(|section| section.words).collect();
+    words.sort_unstable();
+    say!(style, "Title:        {}", stats.title);
+    say!(style, "File size:    {}", format_size(stats.file_size));
+    say!(style, "Spine:        {} items ({} linear)", stats.spine_items, stats.linear_spine_items);
+    say!(style, "TOC:          {} entries, {} levels deep", stats.toc_entries, stats.toc_depth);
+    say!(
+        style,
+        "Words:        {} in {} sections (median {}, largest {})",
+        stats.words,
+        stats.sections.len(),
+        words.get(words.len() / 2).copied().unwrap_or(0),
+        words.last().copied().unwrap_or(0)
+    );
+
+    say!(style, "\nResources:");
+    for (kind, resources) in &stats.resources {
+        say!(style, "  {:<12} {:>5}  {}", kind, resources.count, format_size(resources.bytes));
+    }
+
+    say!(style, "\nWords per section:");
+    for section in &stats.sections {
+        say!(style, "  {:>5} {:>8}  {}", section.index, section.words, section.title);
+    }
+}
+
+/// Where a TOC entry sits: its 0-based index at each level, written 1-based
+/// and dotted (`2.1` is the first entry under the second top-level one)
+#[derive(Debug, Clone, PartialEq)]
+struct TocPosition(Vec<usize>);
+
+impl FromStr for TocPosition {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        s.split('.')
+            .map(|n| n.trim().parse::<usize>().ok().filter(|&n| n > 0).map(|n| n - 1))
+            .collect::<Option<Vec<_>>>()
+            .map(TocPosition)
+            .ok_or_else(|| format!("Invalid TOC position '{}' (expected e.g. 3 or 2.1)", s))
+    }
+}
+
+impl std::fmt::Display for TocPosition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let levels: Vec<String> = self.0.iter().map(|n| (n + 1).to_string()).collect();
+        write!(f, "{}", levels.join("."))
+    }
+}
+
+fn parse_toc_rename(s: &str) -> std::result::Result<(TocPosition, String), String> {
+    let (position, title) = s
+        .split_once('=')
+        .ok_or_else(|| format!("Invalid rename '{}' (expected POS=TITLE)", s))?;
+    Ok((position.parse()?, title.to_string()))
+}
+
+fn parse_toc_addition(s: &str) -> std::result::Result<(String, String), String> {
+    // Titles are likelier than hrefs to hold an '='
+    match s.rsplit_once('=') {
+        Some((title, href)) if !title.is_empty() && !href.is_empty() => Ok((title.to_string(), href.to_string())),
+        _ => Err(format!("Invalid entry '{}' (expected TITLE=HREF)", s)),
+    }
+}
+
+/// Changes asked of the `toc` subcommand, applied in field order
+struct TocEdits {
+    rename: Vec<(TocPosition, String)>,
+    nest: Vec<TocPosition>,
+    unnest: Vec<TocPosition>,
+    remove: Vec<TocPosition>,
+    add: Vec<(String, String)>,
+}
+
+impl TocEdits {
+    fn is_empty(&self) -> bool {
+        self.rename.is_empty()
+            && self.nest.is_empty()
+            && self.unnest.is_empty()
+            && self.remove.is_empty()
+            && self.add.is_empty()
+    }
+
+    /// Make every edit but the additions to `nodes`, finding each entry by
+    /// where it was before any edits
+    fn rearrange(&self, nodes: &mut Vec<TocNode>) -> Result<()> {
+        for (origin, title) in &self.rename {
+            let at = TocNode::locate(nodes, origin)?;
+            let (list, n) = TocNode::siblings_mut(nodes, &at);
+            list[n].point.text = title.clone();
+        }
+        for origin in &self.nest {
+            let at = TocNode::locate(nodes, origin)?;
+            let (list, n) = TocNode::siblings_mut(nodes, &at);
+            if n == 0 {
+                bail!(Failure::new(
+                    FailureKind::InvalidArgs,
+                    format!("TOC entry {} has no entry before it to nest under", origin),
+                ));
+            }
+            let node = list.remove(n);
+            list[n - 1].children.push(node);
+        }
+        for origin in &self.unnest {
+            let at = TocNode::locate(nodes, origin)?;
+            if at.len() < 2 {
+                bail!(Failure::new(FailureKind::InvalidArgs, format!("TOC entry {} is already at the top level", origin)));
+            }
+            let (list, n) = TocNode::siblings_mut(nodes, &at);
+            let node = list.remove(n);
+            let (parent_list, parent) = TocNode::siblings_mut(nodes, &at[..at.len() - 1]);
+            parent_list.insert(parent + 1, node);
+        }
+        for origin in &self.remove {
+            let at = TocNode::locate(nodes, origin)?;
+            let (list, n) = TocNode::siblings_mut(nodes, &at);
+            let node = list.remove(n);
+            for (offset, child) in node.children.into_iter().enumerate() {
+                list.insert(n + offset, child);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A TOC entry being edited, remembering where it was before any edits
+struct TocNode {
+    origin: Option<TocPosition>,
+    point: NavPoint,
+    children: Vec<TocNode>,
+}
+
+impl TocNode {
+    fn from_points(points: Vec<NavPoint>, parent: &[usize]) -> Vec<TocNode> {
+        points
+            .into_iter()
+            .enumerate()
+            .map(|(n, mut point)| {
+                let mut origin = parent.to_vec();
+                origin.push(n);
+                let children = Self::from_points(std::mem::take(&mut point.children), &origin);
+                TocNode {
+                    origin: Some(TocPosition(origin)),
+                    point,
+                    children,
+                }
+            })
+            .collect()
+    }
+
+    fn into_points(nodes: Vec<TocNode>) -> Vec<NavPoint> {
+        nodes
+            .into_iter()
+            .map(|node| NavPoint {
+                children: Self::into_points(node.children),
+                ..node.point
+            })
+            .collect()
+    }
+
+    /// Where the entry first listed at `origin` is now
+    fn locate(nodes: &[TocNode], origin: &TocPosition) -> Result<Vec<usize>> {
+        fn search(nodes: &[TocNode], origin: &TocPosition, at: &mut Vec<usize>) -> bool {
+            for (n, node) in nodes.iter().enumerate() {
+                at.push(n);
+                if node.origin.as_ref() == Some(origin) || search(&node.children, origin, at) {
+                    return true;
+                }
+                at.pop();
+            }
+            false
+        }
+        let mut at = Vec::new();
+        if search(nodes, origin, &mut at) {
+            Ok(at)
+        } else {
+            bail!(Failure::new(FailureKind::OutOfRange, format!("No TOC entry at position {}", origin)))
+        }
+    }
+
+    /// The list holding the entry at `at`, and its index there
+    fn siblings_mut<'a>(nodes: &'a mut Vec<TocNode>, at: &[usize]) -> (&'a mut Vec<TocNode>, usize) {
+        let (&last, parents) = at.split_last().expect("TOC positions are never empty");
+        let mut list = nodes;
+        for &n in parents {
+            list = &mut list[n].children;
+        }
+        (list, last)
+    }
+}
+
+/// Print the TOC with the positions `toc` edits refer to
+fn print_toc_entries(epub: &mut SplitEpub, style: Style) -> Result<()> {
+    fn print(points: &[NavPoint], parent: &[usize], style: Style) {
+        for (n, point) in points.iter().enumerate() {
+            let mut position = parent.to_vec();
+            position.push(n);
+            say!(
+                style,
+                "{:<8} {}{}  {}",
+                TocPosition(position.clone()).to_string(),
+                "  ".repeat(parent.len()),
+                point.text,
+                point.src
+            );
+            print(&point.children, &position, style);
+        }
+    }
+    print(&epub.read_toc()?, &[], style);
+    Ok(())
+}
+
+/// Most matches `grep` shows for one section
+const GREP_SNIPPETS: usize = 3;
+
+/// Indices of the split lines whose sections' text matches `pattern`, for
+/// `--containing`; fails when none match
+fn sections_containing(epub: &mut SplitEpub, lines: &[SplitLine], pattern: &Regex) -> Result<Vec<usize>> {
+    let matched: Vec<usize> = epub
+        .section_snippets(lines, pattern, 0)?
+        .iter()
+        .enumerate()
+        .filter(|(_, snippets)| !snippets.is_empty())
+        .map(|(index, _)| index)
+        .collect();
+    if matched.is_empty() {
+        bail!(Failure::new(FailureKind::OutOfRange, format!("No section contains {}", pattern)));
+    }
+    info!("Sections containing {}: {:?}", pattern, matched);
+    Ok(matched)
+}
+
+/// List the split lines whose sections' text matches `pattern`, with up to
+/// `GREP_SNIPPETS` matches from each; fails when none match
+fn grep_sections(epub: &mut SplitEpub, pattern: &Regex, context: usize, style: Style) -> Result<()> {
+    let lines = epub.get_split_lines()?;
+    let snippets = epub.section_snippets(&lines, pattern, context)?;
+    let mut matching = 0;
+    for (index, (line, found)) in lines.iter().zip(&snippets).enumerate() {
+        if found.is_empty() {
+            continue;
+        }
+        matching += 1;
+        if style.porcelain {
+            for snippet in found {
+                let text = format!("{}{}{}", snippet.before, snippet.matched, snippet.after);
+                porcelain_record(&["match", &index.to_string(), &line.target(), &snippet.matched, &text]);
+            }
+            continue;
+        }
+        say!(style, "{}  {}  {}", style.bold(&index.to_string()), line.listing_title(), style.dim(&line.target()));
+        for snippet in found.iter().take(GREP_SNIPPETS) {
+            say!(style, "    {}{}{}", snippet.before, style.yellow(&snippet.matched), snippet.after);
+        }
+        if found.len() > GREP_SNIPPETS {
+            say!(style, "    {}", style.dim(&format!("({} more)", found.len() - GREP_SNIPPETS)));
+        }
+    }
+    if matching == 0 {
+        bail!("No section matches {}", pattern);
+    }
+    Ok(())
+}
+
+/// Write a copy of `epub` with `edits` made to its TOC, in its NCX and its
+/// nav document, whichever it has. Every other file is copied as it is.
+fn edit_toc(epub: &mut SplitEpub, edits: &TocEdits, output: &std::path::Path) -> Result<()> {
+    if output == epub.path {
+        bail!(Failure::new(FailureKind::InvalidArgs, "The edited copy can't replace the EPUB it's read from"));
+    }
+    let mut nodes = TocNode::from_points(epub.read_toc()?, &[]);
+
+    edits.rearrange(&mut nodes)?;
+    if !edits.add.is_empty() {
+        let spine = epub.spine_hrefs()?;
+        let reading_order = |src: &str| {
+            let file = src.split('#').next().unwrap_or_default();
+            spine.iter().position(|href| href == file)
+        };
+        for (title, href) in &edits.add {
+            let (file, fragment) = match href.split_once('#') {
+                Some((file, fragment)) => (file, Some(fragment)),
+                None => (href.as_str(), None),
+            };
+            let file = spine
+                .iter()
+                .find(|path| *path == file || path.ends_with(&format!("/{}", file)))
+                .ok_or_else(|| {
+                    anyhow!(Failure::new(FailureKind::InvalidArgs, format!("{} isn't in the spine", file)))
+                })?;
+            let src = match fragment {
+                Some(fragment) => format!("{}#{}", file, fragment),
+                None => file.clone(),
+            };
+            let order = reading_order(&src);
+            let n = nodes
+                .iter()
+                .position(|node| reading_order(&node.point.src) > order)
+                .unwrap_or(nodes.len());
+            nodes.insert(
+                n,
+                TocNode {
+                    origin: None,
+                    point: NavPoint::new(title.clone(), src),
+                    children: Vec::new(),
+                },
+            );
+        }
+    }
+
+    let replacements = epub.toc_replacements(&TocNode::into_points(nodes))?;
+    let file = File::create(output)
+        .with_context(|| format!("Failed to create output file: {}", output.display()))?;
+    epub.write_copy(&replacements, file)
+}
+
+/// Write a copy of `epub` with the spine items named in `items` (manifest
+/// ids or hrefs) read in that order, in the places they held between
+/// them. TOC entries are sorted to match so playOrder follows the new spine.
+fn reorder_spine(epub: &mut SplitEpub, items: &[String], output: &std::path::Path) -> Result<()> {
+    if output == epub.path {
+        bail!(Failure::new(FailureKind::InvalidArgs, "The reordered copy can't replace the EPUB it's read from"));
+    }
+    let opf_path = epub.content_opf_path.clone();
+    let opf = SplitEpub::read_file_from_archive(&mut epub.archive, &opf_path)?;
+    let spine: Vec<String> = SplitEpub::parse_spine(&opf)?.into_iter().map(|item| item.idref).collect();
+    let href_of = |idref: &str| epub.manifest_items.get(idref).map(|item| item.href.as_str()).unwrap_or_default();
+    let spine_hrefs: Vec<&str> = spine.iter().map(|idref| href_of(idref)).collect();
+    let order = spine_order(&spine, &spine_hrefs, items)?;
+
+    let mut replacements = HashMap::new();
+    if epub.has_toc() {
+        let hrefs: Vec<String> = order.iter().map(|idref| href_of(idref).to_string()).collect();
+        let points = sort_nav_points(epub.read_toc()?, &hrefs);
+        replacements = epub.toc_replacements(&points)?;
+    }
+    replacements.insert(opf_path, reorder_spine_xml(&opf, &order)?);
+    let file = File::create(output)
+        .with_context(|| format!("Failed to create output file: {}", output.display()))?;
+    epub.write_copy(&replacements, file)
+}
+
+/// The spine's idrefs with `items` (idrefs, or hrefs matched against the
+/// spine's `hrefs`) put in the order given, in the slots they held between
+/// them; items not listed stay where they are
+fn spine_order(spine: &[String], hrefs: &[&str], items: &[String]) -> Result<Vec<String>> {
+    let mut listed: Vec<&String> = Vec::new();
+    for item in items {
+        let idref = spine
+            .iter()
+            .find(|idref| *idref == item)
+            .or_else(|| {
+                spine.iter().zip(hrefs).find_map(|(idref, href)| {
+                    (*href == item || href.ends_with(&format!("/{}", item))).then_some(idref)
+                })
+            })
+            .ok_or_else(|| anyhow!(Failure::new(FailureKind::InvalidArgs, format!("{} isn't in the spine", item))))?;
+        if listed.contains(&idref) {
+            bail!(Failure::new(FailureKind::InvalidArgs, format!("{} is listed more than once", item)));
+        }
+        listed.push(idref);
+    }
+
+    let mut slots: Vec<usize> =
+        listed.iter().filter_map(|idref| spine.iter().position(|other| other == *idref)).collect();
+    slots.sort_unstable();
+    let mut order = spine.to_vec();
+    for (slot, idref) in slots.into_iter().zip(&listed) {
+        order[slot] = (*idref).clone();
+    }
+    Ok(order)
+}
+
+/// Sort entries at each level by where their file falls in `hrefs`,
+/// keeping entries in one file (or in none) in their existing order
+fn sort_nav_points(mut points: Vec<NavPoint>, hrefs: &[String]) -> Vec<NavPoint> {
+    for point in &mut points {
+        point.children = sort_nav_points(std::mem::take(&mut point.children), hrefs);
+    }
+    points.sort_by_key(|point| {
+        let file = point.src.split('#').next().unwrap_or_default();
+        hrefs.iter().position(|href| *href == file).unwrap_or(usize::MAX)
+    });
+    points
+}
+
+/// `opf` with its `<itemref>`s rewritten in `order` (idrefs), each keeping
+/// its own attributes
+fn reorder_spine_xml(opf: &str, order: &[String]) -> Result<String> {
+    let spine_re = Regex::new(r"(?s)<spine\b[^>]*>.*?</spine\s*>").context("Failed to compile spine regex")?;
+    let itemref_re = Regex::new(r#"<itemref\b[^>]*?\bidref=["']([^"']+)["'][^>]*>"#)
+        .context("Failed to compile itemref regex")?;
+    let spine = spine_re.find(opf).ok_or_else(|| anyhow!("No spine found in OPF"))?;
+    let tags: HashMap<&str, &str> = itemref_re
+        .captures_iter(spine.as_str())
+        .filter_map(|caps| Some((caps.get(1)?.as_str(), caps.get(0)?.as_str())))
+        .collect();
+    let mut next = order.iter();
+    let reordered = itemref_re.replace_all(spine.as_str(), |caps: &regex::Captures| {
+        next.next()
+            .and_then(|idref| tags.get(idref.as_str()))
+            .map_or_else(|| caps[0].to_string(), |tag| tag.to_string())
+    });
+    Ok(format!("{}{}{}", &opf[..spine.start()], reordered, &opf[spine.end()..]))
+}
+
+/// Print what changed between two EPUBs: metadata, spine order, TOC and
+/// the contents of individual files
+fn diff_epubs(old: &mut SplitEpub, new: &mut SplitEpub, style: Style) -> Result<()> {
+    let mut changed = false;
+
+    let metadata = |epub: &SplitEpub| {
+        vec![
+            format!("title: {}", epub.get_orig_title()),
+            format!("authors: {}", epub.get_orig_authors().join(", ")),
+            format!("languages: {}", epub.get_orig_languages().join(", ")),
+        ]
+    };
+    changed |= print_list_diff("Metadata", &metadata(old), &metadata(new), style);
+
+    changed |= print_list_diff("Spine", &old.spine_hrefs()?, &new.spine_hrefs()?, style);
+
+    let toc = |lines: Arc<[SplitLine]>| -> Vec<String> {
+        lines
+            .iter()
+            .flat_map(|line| {
+                let target = line.target();
+                line.toc.iter().map(move |text| format!("{} -> {}", text, target))
+            })
+            .collect()
+    };
+    changed |= print_list_diff("TOC", &toc(old.get_split_lines()?), &toc(new.get_split_lines()?), style);
+
+    let old_digests = old.entry_digests()?;
+    let new_digests = new.entry_digests()?;
+    let mut paths: Vec<&String> = old_digests.keys().chain(new_digests.keys()).collect();
+    paths.sort();
+    paths.dedup();
+    let mut entry_changes = Vec::new();
+    for path in paths {
+        match (old_digests.get(path), new_digests.get(path)) {
+            (Some(_), None) => entry_changes.push(format!("- {}", path)),
+            (None, Some(_)) => entry_changes.push(format!("+ {}", path)),
+            (Some(a), Some(b)) if a != b => entry_changes.push(format!("~ {}", path)),
+            _ => {}
+        }
+    }
+    if !entry_changes.is_empty() {
+        changed = true;
+        say!(style, "\nFiles:");
+        for change in &entry_changes {
+            say!(style, "\t{}", change);
+        }
+    }
+
+    if !changed {
+        say!(style, "No differences");
+    }
+    Ok(())
+}
+
+/// Print a line-by-line diff of two lists under a heading, if they differ.
+/// Returns whether anything changed.
+fn print_list_diff(heading: &str, old: &[String], new: &[String], style: Style) -> bool {
+    if old == new {
+        return false;
+    }
+    say!(style, "\n{}:", heading);
+    for (mark, line) in list_diff(old, new) {
+        say!(style, "\t{} {}", mark, line);
+    }
+    true
+}
+
+/// The lines of `old` and `new` in diff order, each marked ' ' when kept,
+/// '-' when only in `old` or '+' when only in `new`
+fn list_diff<'a>(old: &'a [String], new: &'a [String]) -> Vec<(char, &'a str)> {
+    // Longest common subsequence table, filled from the end
+    let mut lcs = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut lines = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            lines.push((' ', old[i].as_str()));
+            i += 1;
+            j += 1;
+        } else if j < new.len() && (i == old.len() || lcs[i][j + 1] >= lcs[i + 1][j]) {
+            lines.push(('+', new[j].as_str()));
+            j += 1;
+        } else {
+            lines.push(('-', old[i].as_str()));
+            i += 1;
+        }
+    }
+    lines
+}
+
+/// Placeholders a `--title` template can use
+const TITLE_PLACEHOLDERS: [&str; 4] = ["{orig_title}", "{first_toc}", "{index}", "{total}"];
+
+fn is_title_template(title: &str) -> bool {
+    TITLE_PLACEHOLDERS.iter().any(|placeholder| title.contains(placeholder))
+}
+
+/// A `--title` template filled in for the `index`th of `total` outputs,
+/// whose first section is `first`; None if it has no placeholders
+fn title_from_template(
+    template: &str,
+    orig_title: &str,
+    first: &SplitLine,
+    index: usize,
+    total: usize,
+) -> Option<String> {
+    if !is_title_template(template) {
+        return None;
+    }
+    Some(
+        template
+            .replace("{orig_title}", orig_title)
+            .replace("{first_toc}", first.toc.first().map_or("", String::as_str))
+            .replace("{index}", &index.to_string())
+            .replace("{total}", &total.to_string()),
+    )
+}
+
+/// A title template filled in for the only output of a run
+fn single_output_title(epub: &mut SplitEpub, template: &str, section_indices: &[usize]) -> Result<String> {
+    if !is_title_template(template) {
+        return Ok(template.to_string());
+    }
+    let lines = epub.get_split_lines()?;
+    let first = section_indices.first().and_then(|&idx| lines.get(idx));
+    Ok(first
+        .and_then(|line| title_from_template(template, epub.get_orig_title(), line, 1, 1))
+        .unwrap_or_else(|| template.to_string()))
+}
+
+/// Name of the file `--resume` runs keep in the output directory
+const RESUME_STATE_FILE: &str = ".epubsplit-state.json";
+
+/// What an earlier batch run wrote
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct RecordedOutput {
+    /// Hash of the input and everything that shapes this output
+    hash: String,
+    size: u64,
+}
+
+/// Outputs written by `--resume` runs into one directory, so later ones can
+/// skip those whose input and options haven't changed since
+struct ResumeState {
+    path: PathBuf,
+    resume: bool,
+    input_digest: String,
+    outputs: BTreeMap<String, RecordedOutput>,
+    written: usize,
+    updated: usize,
+    skipped: usize,
+}
+
+impl ResumeState {
+    /// The state saved in the output directory; without `--resume` it's left
+    /// alone, and the state only counts outputs
+    fn load(epub: &SplitEpub, opts: &RunOptions) -> Result<Self> {
+        let dir = opts.output_dir.clone().unwrap_or_else(|| PathBuf::from("."));
+        let path = dir.join(RESUME_STATE_FILE);
+        let mut state = ResumeState {
+            path,
+            resume: opts.build.resume,
+            input_digest: String::new(),
+            outputs: BTreeMap::new(),
+            written: 0,
+            updated: 0,
+            skipped: 0,
+        };
+        if !opts.build.resume {
+            return Ok(state);
+        }
+
+        state.outputs = match std::fs::read_to_string(&state.path) {
+            Ok(json) => serde_json::from_str(&json).unwrap_or_else(|e| {
+                warn!("Ignoring unreadable {}: {}", state.path.display(), e);
+                BTreeMap::new()
+            }),
+            Err(_) => BTreeMap::new(),
+        };
+        state.input_digest = Self::file_digest(&epub.path)?;
+        Ok(state)
+    }
+
+    fn file_digest(path: &std::path::Path) -> Result<String> {
+        let mut hasher = Sha256::new();
+        File::open(path)
+            .and_then(|mut input| std::io::copy(&mut input, &mut hasher))
+            .with_context(|| format!("Failed to read EPUB: {}", path.display()))?;
+        Ok(hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect())
+    }
+
+    /// `opts` without what doesn't change what a run writes: whether it
+    /// resumes, and how it prints and reports
+    fn hashed_options(opts: &RunOptions) -> RunOptions {
+        RunOptions {
+            build: OutputOptions { resume: false, ..opts.build.clone() },
+            style: Style::default(),
+            summary: SummaryRecorder::default(),
+            ..opts.clone()
+        }
+    }
+
+    fn output_hash(
+        &self,
+        sections: &[usize],
+        authors: &[String],
+        title: &str,
+        languages: &[String],
+        opts: &RunOptions,
+    ) -> String {
+        let opts = Self::hashed_options(opts);
+        let inputs = format!(
+            "{}\n{}\n{:?}\n{:?}\n{:?}\n{:?}\n{:?}",
+            env!("CARGO_PKG_VERSION"),
+            self.input_digest,
+            sections,
+            authors,
+            title,
+            languages,
+            opts
+        );
+        Sha256::digest(inputs.as_bytes()).iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    /// The hash of a compilation output, covering the sources of its parts
+    /// as well; only worked out when resuming
+    fn compilation_hash(
+        &self,
+        plan_dir: &std::path::Path,
+        planned: &PlannedOutput,
+        opts: &RunOptions,
+    ) -> Result<String> {
+        if !self.resume {
+            return Ok(String::new());
+        }
+        let mut sources = Vec::new();
+        for path in planned.parts.iter().filter_map(|part| part.source.as_ref()) {
+            sources.push(Self::file_digest(&plan_dir.join(path))?);
+        }
+        let inputs = format!(
+            "{}\n{}\n{:?}\n{:?}\n{:?}",
+            env!("CARGO_PKG_VERSION"),
+            self.input_digest,
+            sources,
+            planned,
+            Self::hashed_options(opts)
+        );
+        Ok(Sha256::digest(inputs.as_bytes()).iter().map(|b| format!("{:02x}", b)).collect())
+    }
+
+    fn key(path: &std::path::Path) -> String {
+        path.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default()
+    }
+
+    /// Whether `--resume` can skip this output, counting it if so
+    fn is_current(&mut self, path: &std::path::Path, hash: &str) -> bool {
+        let size = std::fs::metadata(path).map(|m| m.len()).ok();
+        let current = self.resume
+            && self
+                .outputs
+                .get(&Self::key(path))
+                .is_some_and(|recorded| recorded.hash == hash && Some(recorded.size) == size);
+        if current {
+            self.skipped += 1;
+        } else if size.is_some() {
+            self.updated += 1;
+        } else {
+            self.written += 1;
+        }
+        current
+    }
+
+    /// Note a freshly written output, saving the state straight away so a
+    /// crash later in the batch keeps it
+    fn record(&mut self, path: &std::path::Path, hash: String) -> Result<()> {
+        if !self.resume {
+            return Ok(());
+        }
+        let size = std::fs::metadata(path).map(|m| m.len()).unwrap_or_default();
+        self.outputs.insert(Self::key(path), RecordedOutput { hash, size });
+        let json = serde_json::to_string_pretty(&self.outputs).context("Failed to serialize resume state")?;
+        std::fs::write(&self.path, json)
+            .with_context(|| format!("Failed to write resume state: {}", self.path.display()))
+    }
+
+    fn report(&self, style: Style) {
+        if self.resume {
+            say!(style, "{} written, {} updated, {} skipped (unchanged)", self.written, self.updated, self.skipped);
+        }
+    }
+}
+
+fn split_by_section_fn(
+    epub: &mut SplitEpub,
+    lines: &[SplitLine],
+    section_indices: &[usize],
+    rest: &[usize],
+    opts: &RunOptions,
+) -> Result<()> {
+    let output_filename = ensure_epub_extension(&opts.build.output);
+
+    let mut splits_list: Vec<(Vec<usize>, String)> = Vec::new();
+    let mut current_sections: Vec<usize> = Vec::new();
+    let mut current_title: Option<String> = None;
+
+    for &line_no in section_indices {
+        if line_no >= lines.len() {
+            bail!(Failure::new(
+                FailureKind::OutOfRange,
+                format!("Line number {} is out of range (max: {})", line_no, lines.len() - 1),
+            ));
+        }
+
+        let line = &lines[line_no];
+        let toc_list = &line.toc;
+
+        if !current_sections.is_empty() && toc_list.is_empty() {
+            // No TOC entry - include with previous section
+            current_sections.push(line_no);
+        } else {
+            // Has TOC entry or first section - start new split
+            if !current_sections.is_empty() {
+                let title = current_title.clone().unwrap_or_else(|| {
+                    opts.build.title
+                        .clone()
+                        .unwrap_or_else(|| format!("{} Split", epub.get_orig_title()))
+                });
+                splits_list.push((current_sections.clone(), title));
+            }
+
+            let title = if !toc_list.is_empty() {
+                toc_list[0].clone()
+            } else {
+                opts.build.title
+                    .clone()
+                    .unwrap_or_else(|| format!("{} Split", epub.get_orig_title()))
+            };
+            current_title = Some(title);
+            current_sections = vec![line_no];
+        }
+    }
+
+    // Add the last section
+    if !current_sections.is_empty() {
+        let title = current_title.unwrap_or_else(|| {
+            opts.build.title
+                .clone()
+                .unwrap_or_else(|| format!("{} Split", epub.get_orig_title()))
+        });
+        splits_list.push((current_sections, title));
+    }
+
+    // Everything else, for `rest`
+    if !rest.is_empty() {
+        let title = opts
+            .build
+            .title
+            .clone()
+            .unwrap_or_else(|| format!("{} Split", epub.get_orig_title()));
+        splits_list.push((rest.to_vec(), title));
+    }
+
+    let total = splits_list.len();
+    warn_unmatched_overrides(&opts.build, total);
+    check_identifiers(&opts.build, total)?;
+    for (file_count, (section_list, title)) in splits_list.iter_mut().enumerate() {
+        let fill = |template: &str| {
+            title_from_template(template, epub.get_orig_title(), &lines[section_list[0]], file_count + 1, total)
+        };
+        if let Some(template) = opts.build.title_for.get(&(file_count + 1)) {
+            *title = fill(template).unwrap_or_else(|| template.clone());
+        } else if let Some(templated) = opts.build.title.as_deref().and_then(fill) {
+            *title = templated;
+        }
+        say!(opts.style, "title: {}", title);
+    }
+
+    // Write each split
+    let mut state = ResumeState::load(epub, opts)?;
+    let mut planned_outputs = Vec::new();
+    let mut output_resources = Vec::new();
+    for (file_count, (section_list, title)) in splits_list.iter().enumerate() {
+        let output_file = format!(
+            "{:04}-{}",
+            file_count + 1,
+            fill_title_placeholder(&output_filename, title, opts.build.ascii_names)
+        );
+        let output_path = if let Some(ref dir) = opts.output_dir {
+            dir.join(&output_file)
+        } else {
+            PathBuf::from(&output_file)
+        };
+
+        say!(opts.style, "output file: {}", output_path.display());
+
+        let authors = if opts.build.author.is_empty() {
+            epub.get_orig_authors().to_vec()
+        } else {
+            opts.build.author.clone()
+        };
+
+        let languages = output_languages(epub, section_list, opts)?;
+
+        let output_opts = RunOptions {
+            build: OutputOptions {
+                part: Some(file_count + 1),
+                part_count: Some(total),
+                title_sort: opts.build.title_sort.as_ref().map(|template| {
+                    title_from_template(template, epub.get_orig_title(), &lines[section_list[0]], file_count + 1, total)
+                        .unwrap_or_else(|| template.clone())
+                }),
+                tag: opts.build.tag_for.get(&(file_count + 1)).cloned().unwrap_or_else(|| opts.build.tag.clone()),
+                ..opts.build.clone()
+            },
+            ..opts.clone()
+        };
+
+        let hash = state.output_hash(section_list, &authors, title, &languages, &output_opts);
+        if state.is_current(&output_path, &hash) {
+            opts.summary.record_skipped(&output_path);
+            say!(opts.style, "unchanged, skipping: {}", output_path.display());
+        } else {
+            output_resources.push(
+                write_split_epub(
+                    epub,
+                    output_path.clone(),
+                    section_list,
+                    &authors,
+                    Some(title),
+                    &languages,
+                    &output_opts,
+                )
+                .map_err(|e| batch_error(e, file_count, splits_list.len()))?,
+            );
+            state.record(&output_path, hash)?;
+        }
+        planned_outputs.push(planned_output(lines, section_list, &output_file, title, &output_opts));
+    }
+
+    state.report(opts.style);
+    report_shared_resources(&output_resources, opts.style);
+    emit_plan(epub, planned_outputs, opts)
+}
+
+fn extract_sections(epub: &mut SplitEpub, section_indices: &[usize], opts: &RunOptions) -> Result<()> {
+    let title = match opts.build.title.as_deref() {
+        Some(template) => single_output_title(epub, template, section_indices)?,
+        None => format!("{} Split", epub.get_orig_title()),
+    };
+
+    let output_filename = fill_title_placeholder(&ensure_epub_extension(&opts.build.output), &title, opts.build.ascii_names);
+    let output_path = if let Some(ref dir) = opts.output_dir {
+        dir.join(&output_filename)
+    } else {
+        PathBuf::from(&output_filename)
+    };
+
+    say!(opts.style, "output file: {}", output_path.display());
+    check_identifiers(&opts.build, 1)?;
+
+    let authors = if opts.build.author.is_empty() {
+        epub.get_orig_authors().to_vec()
+    } else {
+        opts.build.author.clone()
+    };
+    let opts = &RunOptions {
+        build: OutputOptions {
+            title_sort: opts
+                .build
+                .title_sort
+                .as_deref()
+                .map(|template| single_output_title(epub, template, section_indices))
+                .transpose()?,
+            ..opts.build.clone()
+        },
+        ..opts.clone()
+    };
+
+    let languages = output_languages(epub, section_indices, opts)?;
+
+    write_split_epub(
+        epub,
+        output_path,
+        section_indices,
+        &authors,
+        Some(&title),
+        &languages,
+        opts,
+    )?;
+
+    if opts.emit_plan.is_some() {
+        let lines = epub.get_split_lines()?;
+        let planned = planned_output(&lines, section_indices, &output_filename, &title, opts);
+        emit_plan(epub, vec![planned], opts)?;
+    }
+    Ok(())
+}
+
+/// With `--skip-boilerplate`, remove sections that look like ads, store
+/// links, "also by" lists or newsletter sign-ups
+fn drop_boilerplate(
+    epub: &mut SplitEpub,
+    lines: &[SplitLine],
+    indices: Vec<usize>,
+    opts: &RunOptions,
+) -> Result<Vec<usize>> {
+    if !opts.build.skip_boilerplate {
+        return Ok(indices);
+    }
+
+    let mut kept = Vec::with_capacity(indices.len());
+    for idx in indices {
+        let Some(line) = lines.get(idx) else {
+            // Out of range; reported by the caller
+            kept.push(idx);
+            continue;
+        };
+        let content = SplitEpub::read_file_from_archive(&mut epub.archive, &line.href).unwrap_or_default();
+        match boilerplate_reason(&line.toc, &content)? {
+            Some(reason) => say!(opts.style, "skipping boilerplate section {}: {}", idx, reason),
+            None => kept.push(idx),
+        }
+    }
+    Ok(kept)
+}
+
+/// With `--dedupe`, keep only the first selected section of each group of
+/// identical or near-identical sections
+fn drop_duplicates(
+    epub: &mut SplitEpub,
+    lines: &[SplitLine],
+    indices: Vec<usize>,
+    opts: &RunOptions,
+) -> Result<Vec<usize>> {
+    if !opts.build.dedupe {
+        return Ok(indices);
+    }
+
+    let duplicates = epub.find_duplicate_sections(lines)?;
+    let mut seen_groups: HashMap<usize, usize> = HashMap::new();
+    let mut kept = Vec::with_capacity(indices.len());
+    for idx in indices {
+        let group = duplicates.get(&idx).map_or(idx, |&(original, _)| original);
+        match seen_groups.get(&group) {
+            // Several split points in one file share a group; keep them all
+            Some(&first) if lines.get(first).map(|l| &l.href) != lines.get(idx).map(|l| &l.href) => {
+                say!(opts.style, "skipping duplicate section {}: same as {}", idx, first);
+            }
+            _ => {
+                seen_groups.entry(group).or_insert(idx);
+                kept.push(idx);
+            }
+        }
+    }
+    Ok(kept)
+}
+
+/// Why a section looks like boilerplate, judging by its TOC titles and
+/// (for short sections) its content
+fn boilerplate_reason(toc: &[String], content: &str) -> Result<Option<String>> {
+    let title_re = Regex::new(
+        r"(?i)^\W*(also by|also available|other (books|titles|works) (by|from)|more (books )?(by|from)|books by|newsletter|sign up|join (my|our|the)|about the publisher|advertisement|praise for|coming soon|sneak peek|preview( of)?\b|excerpt from|get your free|free (book|ebook|gift))",
+    )
+    .context("Failed to compile boilerplate title regex")?;
+    let text_re = Regex::new(
+        r"(?i)(sign up for|subscribe to|join) (my|our|the) (newsletter|mailing list|reader list)|also by (this author|the author)|(available|buy( it)?) (now )?(at|on|from) (amazon|kobo|apple books|ibooks|barnes|google play|smashwords)|leave a review",
+    )
+    .context("Failed to compile boilerplate text regex")?;
+    let store_re = Regex::new(
+        r#"(?i)href=["']https?://[^"']*(amazon\.|kobo\.com|books\.apple\.com|barnesandnoble\.com|play\.google\.com|smashwords\.com|bookbub\.com)"#,
+    )
+    .context("Failed to compile store link regex")?;
+
+    if let Some(title) = toc.iter().find(|title| title_re.is_match(title)) {
+        return Ok(Some(format!("title \"{}\"", title)));
+    }
+
+    // Only judge short sections by their text, so real chapters that happen
+    // to mention a newsletter are kept
+    let text = html_to_text(content);
+    if text.split_whitespace().count() > 400 {
+        return Ok(None);
+    }
+    if let Some(m) = text_re.find(&text) {
+        return Ok(Some(format!("text \"{}\"", m.as_str())));
+    }
+    let store_links = store_re.find_iter(content).count();
+    if store_links >= 2 {
+        return Ok(Some(format!("{} store links", store_links)));
+    }
+    Ok(None)
+}
+
+/// Read identifiers from a file, one per line; blank lines and lines
+/// starting with `#` are skipped
+fn read_identifiers_file(path: &PathBuf) -> Result<Vec<String>> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read identifiers file: {}", path.display()))?;
+    let identifiers: Vec<String> = text
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect();
+    if identifiers.is_empty() {
+        bail!(Failure::new(FailureKind::InvalidArgs, format!("No identifiers in {}", path.display())));
+    }
+    Ok(identifiers)
+}
+
+/// Read line selectors from a file, or stdin for `-`. Accepts anything
+/// `--lines` does (numbers, `-N`, `N..`, IDs, `href:`, `id:`, `anchor:`) and
+/// `start-end` ranges of numbers, separated by whitespace or commas (`#`
+/// starts a comment), or a JSON array of them.
+fn read_lines_file(path: &PathBuf) -> Result<Vec<LineSelector>> {
+    let mut text = String::new();
+    if path.as_os_str() == "-" {
+        std::io::stdin()
+            .read_to_string(&mut text)
+            .context("Failed to read line numbers from stdin")?;
+    } else {
+        File::open(path)
+            .and_then(|mut file| file.read_to_string(&mut text))
+            .with_context(|| format!("Failed to read lines file: {}", path.display()))?;
+    }
+
+    let invalid = |message: String| anyhow!(Failure::new(FailureKind::InvalidArgs, format!("{} in {}", message, path.display())));
+    if text.trim_start().starts_with('[') {
+        let values: Vec<serde_json::Value> = serde_json::from_str(&text)
+            .with_context(|| format!("Failed to parse lines file as JSON: {}", path.display()))?;
+        return values
+            .iter()
+            .map(|value| match value {
+                serde_json::Value::String(s) => s.parse(),
+                other => other.to_string().parse(),
+            })
+            .map(|selector| selector.map_err(invalid))
+            .collect();
+    }
+
+    // '#' starts a comment, unless it's an anchor in href:PATH#ANCHOR
+    let mut entries = Vec::new();
+    for line in text.lines() {
+        for entry in line.split(|c: char| c == ',' || c.is_whitespace()) {
+            if entry.starts_with("href:") {
+                entries.push(entry);
+            } else if let Some((entry, _)) = entry.split_once('#') {
+                entries.push(entry);
+                break;
+            } else {
+                entries.push(entry);
+            }
+        }
+    }
+
+    let mut lines = Vec::new();
+    for entry in entries.into_iter().filter(|entry| !entry.is_empty()) {
+        let parse = |n: &str| {
+            n.parse::<usize>()
+                .map_err(|_| invalid(format!("Invalid line number '{}'", entry)))
+        };
+        // Only digits on both sides make a range; hrefs and ids have hyphens too
+        let is_number = |n: &str| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit());
+        match entry.split_once('-').filter(|(start, end)| is_number(start) && is_number(end)) {
+            Some((start, end)) => {
+                let (start, end) = (parse(start)?, parse(end)?);
+                if start > end {
+                    return Err(invalid(format!("Invalid range '{}'", entry)));
+                }
+                lines.extend((start..=end).map(LineSelector::Index));
+            }
+            None => lines.push(entry.parse().map_err(invalid)?),
+        }
+    }
+    Ok(lines)
+}
+
+/// Read a `--rename-toc` CSV: `old,new` per row, with fields quoted as in
+/// `--format csv` output. Blank rows and rows starting with `#` are skipped.
+fn read_toc_renames(path: &PathBuf) -> Result<TocRenames> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read TOC renames: {}", path.display()))?;
+    let mut renames = TocRenames::default();
+    for (number, row) in text.lines().enumerate() {
+        if row.trim().is_empty() || row.starts_with('#') {
+            continue;
+        }
+        let fields = parse_csv_row(row);
+        let [old, new] = fields.as_slice() else {
+            bail!(Failure::new(
+                FailureKind::InvalidArgs,
+                format!("Expected 2 fields (old,new) on line {} of {}", number + 1, path.display()),
+            ));
+        };
+        match old.trim().parse::<usize>() {
+            Ok(index) => renames.by_index.insert(index, new.clone()),
+            Err(_) => renames.by_title.insert(old.clone(), new.clone()),
+        };
+    }
+    Ok(renames)
+}
+
+/// Fields of one CSV row, undoing the quoting `csv_field` adds
+fn parse_csv_row(row: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = row.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(std::mem::take(&mut field)),
+            c => field.push(c),
+        }
+    }
+    fields.push(field);
+    fields
+}
+
+/// Name of the project-local config file, looked for in the current
+/// directory and its parents
+const PROJECT_CONFIG_FILE: &str = ".epubsplit.toml";
+
+/// Defaults for output options, from `~/.config/epubsplit/config.toml` and
+/// the nearest `.epubsplit.toml` (which wins). Keys are the long flag names.
+/// Command-line options win over both, and `--no-<switch>` turns off a
+/// switch turned on here. `output` is the name template for `split`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct Config {
+    /// Name template for split outputs
+    output: Option<String>,
+    output_dir: Option<PathBuf>,
+    language: Option<Vec<String>>,
+    author: Option<Vec<String>>,
+    contributor: Option<Vec<String>>,
+    tag: Option<Vec<String>>,
+    generate_cover: Option<CoverStyle>,
+    cover_size: Option<String>,
+    optimize_images: Option<String>,
+    checksums: Option<ChecksumMode>,
+    onix: Option<bool>,
+    sidecar: Option<SidecarFormat>,
+    calibre_library: Option<PathBuf>,
+    skip_boilerplate: Option<bool>,
+    dedupe: Option<bool>,
+    drop_orphans: Option<bool>,
+    include_nonlinear: Option<bool>,
+    flatten: Option<bool>,
+    ascii_filenames: Option<bool>,
+    detect_language: Option<bool>,
+    strip_scripts: Option<bool>,
+    strip_remote: Option<bool>,
+    minify: Option<bool>,
+    normalize_unicode: Option<bool>,
+    punctuation: Option<Punctuation>,
+    strip_soft_hyphens: Option<bool>,
+    generate_a11y: Option<bool>,
+}
+
+impl Config {
+    /// The user config overlaid with the project config, either of which may
+    /// be missing
+    fn load() -> Result<Self> {
+        let user_dir = std::env::var_os("XDG_CONFIG_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")));
+        let user = match user_dir {
+            Some(dir) => Self::read(&dir.join("epubsplit").join("config.toml"))?,
+            None => Config::default(),
+        };
+
+        let cwd = std::env::current_dir().context("Failed to get current directory")?;
+        let project = match cwd.ancestors().map(|dir| dir.join(PROJECT_CONFIG_FILE)).find(|path| path.is_file()) {
+            Some(path) => Self::read(&path)?,
+            None => Config::default(),
+        };
+
+        Ok(user.overlay(project))
+    }
+
+    /// Read one config file; a missing file is an empty config. Relative
+    /// paths in it are taken from the file's own folder.
+    fn read(path: &std::path::Path) -> Result<Self> {
+        if !path.is_file() {
+            return Ok(Config::default());
+        }
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config: {}", path.display()))?;
+        let mut config: Config = toml::from_str(&text).with_context(|| {
+            Failure::new(
+                FailureKind::InvalidArgs,
+                format!("Failed to parse config: {}", path.display()),
+            )
+        })?;
+        debug!("Loaded config {}: {:?}", path.display(), config);
+
+        let base = path.parent().unwrap_or(std::path::Path::new("."));
+        config.output_dir = config.output_dir.map(|dir| base.join(dir));
+        config.calibre_library = config.calibre_library.map(|dir| base.join(dir));
+        Ok(config)
+    }
+
+    /// This config with every setting `over` makes replaced
+    fn overlay(self, over: Config) -> Config {
+        Config {
+            output: over.output.or(self.output),
+            output_dir: over.output_dir.or(self.output_dir),
+            language: over.language.or(self.language),
+            author: over.author.or(self.author),
+            contributor: over.contributor.or(self.contributor),
+            tag: over.tag.or(self.tag),
+            generate_cover: over.generate_cover.or(self.generate_cover),
+            cover_size: over.cover_size.or(self.cover_size),
+            optimize_images: over.optimize_images.or(self.optimize_images),
+            checksums: over.checksums.or(self.checksums),
+            onix: over.onix.or(self.onix),
+            sidecar: over.sidecar.or(self.sidecar),
+            calibre_library: over.calibre_library.or(self.calibre_library),
+            skip_boilerplate: over.skip_boilerplate.or(self.skip_boilerplate),
+            dedupe: over.dedupe.or(self.dedupe),
+            drop_orphans: over.drop_orphans.or(self.drop_orphans),
+            include_nonlinear: over.include_nonlinear.or(self.include_nonlinear),
+            flatten: over.flatten.or(self.flatten),
+            ascii_filenames: over.ascii_filenames.or(self.ascii_filenames),
+            detect_language: over.detect_language.or(self.detect_language),
+            strip_scripts: over.strip_scripts.or(self.strip_scripts),
+            strip_remote: over.strip_remote.or(self.strip_remote),
+            minify: over.minify.or(self.minify),
+            normalize_unicode: over.normalize_unicode.or(self.normalize_unicode),
+            punctuation: over.punctuation.or(self.punctuation),
+            strip_soft_hyphens: over.strip_soft_hyphens.or(self.strip_soft_hyphens),
+            generate_a11y: over.generate_a11y.or(self.generate_a11y),
+        }
+    }
+
+    /// Fill in options not given on the command line
+    fn apply(&self, opts: &mut RunOptions) -> Result<()> {
+        let invalid = |e: String| anyhow!(Failure::new(FailureKind::InvalidArgs, format!("Invalid config: {}", e)));
+
+        if opts.output_dir.is_none() {
+            opts.output_dir = self.output_dir.clone();
+        }
+        for (value, default) in [
+            (&mut opts.build.language, &self.language),
+            (&mut opts.build.author, &self.author),
+            (&mut opts.build.contributor, &self.contributor),
+            (&mut opts.build.tag, &self.tag),
+        ] {
+            if value.is_empty() {
+                *value = default.clone().unwrap_or_default();
+            }
+        }
+        if opts.build.generate_cover.is_none() && opts.cover.is_none() && !opts.build.no_cover {
+            opts.build.generate_cover = self.generate_cover;
+        }
+        if opts.build.cover_size.is_none() && !opts.build.no_cover {
+            if let Some(size) = &self.cover_size {
+                opts.build.cover_size = Some(parse_cover_size(size).map_err(invalid)?);
+            }
+        }
+        if opts.build.sidecar.is_none() {
+            opts.build.sidecar = self.sidecar;
+        }
+        if opts.build.optimize_images.is_none() {
+            if let Some(settings) = &self.optimize_images {
+                opts.build.optimize_images = Some(parse_image_optimization(settings).map_err(invalid)?);
+            }
+        }
+        if opts.build.checksums.is_none() {
+            opts.build.checksums = self.checksums;
+        }
+        if opts.build.punctuation.is_none() {
+            opts.build.punctuation = self.punctuation;
+        }
+        if opts.calibre_library.is_none() {
+            opts.calibre_library = self.calibre_library.clone();
+        }
+
+        for (value, default) in [
+            (&mut opts.build.skip_boilerplate, self.skip_boilerplate),
+            (&mut opts.build.dedupe, self.dedupe),
+            (&mut opts.build.drop_orphans, self.drop_orphans),
+            (&mut opts.build.include_nonlinear, self.include_nonlinear),
+            (&mut opts.build.flatten, self.flatten),
+            (&mut opts.build.ascii_filenames, self.ascii_filenames),
+            (&mut opts.build.detect_language, self.detect_language),
+            (&mut opts.build.strip_scripts, self.strip_scripts),
+            (&mut opts.build.strip_remote, self.strip_remote),
+            (&mut opts.build.minify, self.minify),
+            (&mut opts.build.normalize_unicode, self.normalize_unicode),
+            (&mut opts.build.strip_soft_hyphens, self.strip_soft_hyphens),
+            (&mut opts.build.generate_a11y, self.generate_a11y),
+            (&mut opts.build.onix, self.onix),
+        ] {
+            *value |= default.unwrap_or(false);
+        }
+        Ok(())
+    }
+}
+
+/// Split every EPUB that arrives in `dir`, by plan or one output per section,
+/// until interrupted. Failures are logged and watching continues.
+#[cfg(not(target_arch = "wasm32"))]
+fn watch_folder(
+    dir: &PathBuf,
+    plan: Option<&PathBuf>,
+    opts: &RunOptions,
+    process_existing: bool,
+) -> Result<()> {
+    // Catch a bad plan now rather than when the first book arrives
+    if let Some(plan) = plan {
+        read_plan(plan)?;
+    }
+    if let Some(output_dir) = &opts.output_dir {
+        std::fs::create_dir_all(output_dir)
+            .with_context(|| format!("Failed to create output directory: {}", output_dir.display()))?;
+    }
+
+    let (tx, rx) = std::sync::mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx).context("Failed to start file watcher")?;
+    watcher
+        .watch(dir, RecursiveMode::NonRecursive)
+        .with_context(|| format!("Failed to watch {}", dir.display()))?;
+    say!(opts.style, "watching {}", dir.display());
+
+    let is_epub = |path: &std::path::Path| {
+        path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("epub"))
+    };
+    let mut processed: HashSet<PathBuf> = HashSet::new();
+    for entry in std::fs::read_dir(dir).with_context(|| format!("Failed to read {}", dir.display()))? {
+        let path = entry?.path();
+        if is_epub(&path) {
+            if process_existing {
+                split_arrival(&path, plan, opts);
+            }
+            processed.insert(path);
+        }
+    }
+
+    for event in rx {
+        let event = match event {
+            Ok(event) => event,
+            Err(e) => {
+                warn!("File watcher error: {}", e);
+                continue;
+            }
+        };
+        match event.kind {
+            EventKind::Remove(_) => {
+                // Let a file dropped in again under the same name be split again
+                for path in &event.paths {
+                    processed.remove(path);
+                }
+            }
+            EventKind::Create(_) | EventKind::Modify(_) => {
+                for path in event.paths {
+                    if !is_epub(&path) || processed.contains(&path) || !path.is_file() {
+                        continue;
+                    }
+                    wait_until_written(&path);
+                    split_arrival(&path, plan, opts);
+                    processed.insert(path);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// Wait for a file that's still being copied in: until its size stops
+/// changing and it opens as a ZIP archive
+#[cfg(not(target_arch = "wasm32"))]
+fn wait_until_written(path: &std::path::Path) {
+    const POLL: std::time::Duration = std::time::Duration::from_secs(1);
+    const MAX_POLLS: usize = 120;
+
+    let mut last_size = None;
+    for _ in 0..MAX_POLLS {
+        std::thread::sleep(POLL);
+        let size = std::fs::metadata(path).map(|m| m.len()).ok();
+        let readable = File::open(path)
+            .ok()
+            .and_then(|file| ZipArchive::new(BufReader::new(file)).ok())
+            .is_some();
+        if size.is_some() && size == last_size && readable {
+            return;
+        }
+        last_size = size;
+    }
+    warn!("{} is still changing; splitting it anyway", path.display());
+}
+
+/// Split one EPUB picked up by `watch`, logging rather than returning errors
+#[cfg(not(target_arch = "wasm32"))]
+#[instrument(name = "watch_split", skip_all, fields(epub = %path.display()))]
+fn split_arrival(path: &std::path::Path, plan: Option<&PathBuf>, opts: &RunOptions) {
+    say!(opts.style, "splitting {}", path.display());
+    let result = (|| -> Result<()> {
+        let mut epub = open_input(path.to_path_buf(), &opts.stats)?;
+        let opts = RunOptions {
+            build: OutputOptions {
+                output: path
+                    .file_stem()
+                    .map(|stem| stem.to_string_lossy().to_string())
+                    .unwrap_or_else(|| "split".to_string()),
+                language: default_languages(&opts.build.language, epub.get_orig_languages()),
+                ..opts.build.clone()
+            },
+            ..opts.clone()
+        };
+        match plan {
+            Some(plan) => split_by_plan(&mut epub, plan, &opts),
+            None => {
+                let lines = epub.get_split_lines()?;
+                let indices: Vec<usize> = (0..lines.len()).collect();
+                split_by_section_fn(&mut epub, &lines, &indices, &[], &opts)
+            }
+        }
+    })();
+    if let Err(e) = result {
+        warn!("Failed to split {}: {:#}", path.display(), e);
+    }
+}
+
+/// Load a plan file: TOML for `.toml` files, YAML for `.yaml`/`.yml`, JSON otherwise
+fn read_plan(plan_path: &PathBuf) -> Result<Plan> {
+    let text = std::fs::read_to_string(plan_path)
+        .with_context(|| format!("Failed to open plan: {}", plan_path.display()))?;
+    let extension = plan_path
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_ascii_lowercase())
+        .unwrap_or_default();
+    let invalid = |message: String| Failure::new(FailureKind::InvalidArgs, message);
+    let parse_error = || invalid(format!("Failed to parse plan: {}", plan_path.display()));
+    let plan: Plan = match extension.as_str() {
+        "toml" => toml::from_str(&text).with_context(parse_error)?,
+        "yaml" | "yml" => serde_yaml::from_str(&text).with_context(parse_error)?,
+        _ => serde_json::from_str(&text).with_context(parse_error)?,
+    };
+    if plan.outputs.is_empty() {
+        bail!(invalid(format!("Plan {} has no outputs", plan_path.display())));
+    }
+    Ok(plan)
+}
+
+/// The sections a plan output (or part of one) lists, by href, ID or line
+/// number in that order of preference
+fn planned_sections(
+    lines: &[SplitLine],
+    sections: &[usize],
+    hrefs: &[String],
+    ids: &[String],
+    number: usize,
+) -> Result<Vec<usize>> {
+    if !hrefs.is_empty() {
+        hrefs
+            .iter()
+            .map(|href| {
+                lines
+                    .iter()
+                    .position(|line| line.target() == *href)
+                    .ok_or_else(|| anyhow!("Plan output {}: no section at {}", number, href))
+            })
+            .collect()
+    } else if !ids.is_empty() {
+        let selectors = ids
+            .iter()
+            .map(|id| id.parse().map_err(|e| anyhow!("Plan output {}: {}", number, e)))
+            .collect::<Result<Vec<_>>>()?;
+        resolve_line_selectors(lines, &selectors).with_context(|| format!("Plan output {}", number))
+    } else {
+        Ok(sections.to_vec())
+    }
+}
+
+/// Warn about `--title-for`/`--tag-for` numbers past the last of `total` outputs
+fn warn_unmatched_overrides(opts: &OutputOptions, total: usize) {
+    for number in opts.title_for.keys().chain(opts.tag_for.keys()) {
+        if *number > total {
+            warn!("There's no output {} to override (this run writes {})", number, total);
+        }
+    }
+}
+
+/// Make sure `--identifiers-file` has an identifier for each of `total` outputs
+fn check_identifiers(opts: &OutputOptions, total: usize) -> Result<()> {
+    if opts.identifiers.is_empty() {
+        return Ok(());
+    }
+    if opts.identifiers.len() < total {
+        bail!(Failure::new(
+            FailureKind::InvalidArgs,
+            format!("--identifiers-file lists {} identifiers, but this run writes {} outputs", opts.identifiers.len(), total),
+        ));
+    }
+    if opts.identifiers.len() > total {
+        warn!("--identifiers-file lists {} identifiers; only the first {} are used", opts.identifiers.len(), total);
+    }
+    Ok(())
+}
+
+/// Write an EPUB made by `build` to `output_path`, by way of a partial file,
+/// then record, check and file it as asked. `sections` are what the summary
+/// lists; volumes of a run form a series named `series_title`. Returns the
+/// linked resources copied as (source href, bytes written).
+fn write_output(
+    output_path: PathBuf,
+    sections: &[usize],
+    series_title: &str,
+    opts: &RunOptions,
+    build: impl FnOnce(std::io::BufWriter<File>) -> Result<BuiltEpub<std::io::BufWriter<File>>>,
+) -> Result<Vec<(String, u64)>> {
+    let started = opts.summary.timer();
+    let write_started = std::time::Instant::now();
+    // Written beside the output and renamed into place, so a failure
+    // never leaves a partial EPUB under the output's name
+    let partial_path = partial_output_path(&output_path);
+    let built = File::create(&partial_path)
+        .with_context(|| format!("Failed to create output file: {}", output_path.display()))
+        .and_then(|file| build(std::io::BufWriter::new(file)))
+        .and_then(|mut built| {
+            built.output.flush().with_context(|| format!("Failed to write {}", output_path.display()))?;
+            std::fs::rename(&partial_path, &output_path)
+                .with_context(|| format!("Failed to create output file: {}", output_path.display()))?;
+            Ok(built)
+        });
+    let built = built.inspect_err(|_| {
+        let _ = std::fs::remove_file(&partial_path);
+    })?;
+    let bytes = std::fs::metadata(&output_path).map(|m| m.len()).unwrap_or_default();
+    opts.stats.record(|stats| {
+        stats.outputs.push(OutputStats {
+            path: output_path.clone(),
+            elapsed: write_started.elapsed(),
+            bytes,
+            uncompressed: File::open(&output_path).map(uncompressed_size).unwrap_or_default(),
+        })
+    });
+    info!("Successfully wrote EPUB to {}", output_path.display());
+    let broken = report_broken_references(&output_path, &built.documents, &built.written_paths, opts.style)?;
+    if let Some(started) = started {
+        opts.summary.record_output(OutputSummary {
+            path: output_path.clone(),
+            title: built.meta.title.clone(),
+            sections: sections.to_vec(),
+            bytes,
+            resources: built.copied_resources.len(),
+            resource_bytes: built.copied_resources.iter().map(|(_, size)| size).sum(),
+            broken_references: broken,
+            warnings: Vec::new(),
+            elapsed_ms: started.elapsed().as_millis(),
+        });
+    }
+    let final_path = match &opts.calibre_library {
+        Some(library) => {
+            add_to_calibre_library(&output_path, library, &built.meta, built.cover.as_deref(), opts.build.calibredb, opts.style)?
+        }
+        None => output_path,
+    };
+    // Sidecars go next to the EPUB where it ends up
+    if let Some(mode) = opts.build.checksums {
+        write_checksums(&final_path, mode)?;
+    }
+    if opts.build.onix {
+        write_onix_record(&final_path, &built.meta)?;
+    }
+    if let Some(format) = opts.build.sidecar {
+        // Volumes of one run form a series named after the source
+        let series = match (opts.build.part, opts.build.part_count) {
+            (Some(part), Some(count)) if count > 1 => Some((series_title, part)),
+            _ => None,
+        };
+        write_metadata_sidecar(&final_path, &built.meta, format, series, built.cover.as_deref())?;
+    }
+    porcelain_output(&final_path, opts.style);
+    if let Some(command) = &opts.build.post_hook {
+        run_post_hook(command, &final_path, &built.meta.title, opts.build.part.unwrap_or(1))?;
+    }
+    Ok(built.copied_resources)
+}
+
+/// Write a plan output made of sections from several EPUBs. Each part is
+/// built on its own and the parts are then merged, which keeps files from
+/// different sources apart even where their names clash. Returns the linked
+/// resources copied, as `write_output` does.
+fn write_compilation(
+    epub: &mut SplitEpub,
+    plan_dir: &std::path::Path,
+    planned: &PlannedOutput,
+    output_path: PathBuf,
+    opts: &RunOptions,
+) -> Result<Vec<(String, u64)>> {
+    let number = opts.build.part.unwrap_or(1);
+    // Parts are plain splits: image optimization is left to the merge, so
+    // images aren't recompressed twice, and only the first part carries the
+    // output's cover, which the merge then keeps
+    let part_opts = RunOptions {
+        build: OutputOptions {
+            part: None,
+            part_count: None,
+            cover: None,
+            generate_cover: None,
+            optimize_images: None,
+            ..opts.build.clone()
+        },
+        cover: None,
+        ..opts.clone()
+    };
+    let cover_opts = read_cover(&RunOptions {
+        cover: planned.cover(plan_dir).or_else(|| opts.cover.clone()),
+        ..part_opts.clone()
+    })?;
+
+    let merge_opts = RunOptions {
+        build: OutputOptions {
+            title: opts
+                .build
+                .title_for
+                .get(&number)
+                .or(planned.title.as_ref())
+                .cloned()
+                .or_else(|| opts.build.title.clone()),
+            tag: match opts.build.tag_for.get(&number) {
+                Some(tags) => tags.clone(),
+                None if !planned.tags.is_empty() => planned.tags.clone(),
+                None => opts.build.tag.clone(),
+            },
+            ..opts.build.clone()
+        },
+        ..opts.clone()
+    };
+    let series_title = epub.get_orig_title().to_string();
+    write_output(output_path, &[], &series_title, &merge_opts, |output| {
+        let mut sources: Vec<(PathBuf, Box<dyn EpubSource>)> = Vec::new();
+        let mut cover = None;
+        for (n, part) in planned.parts.iter().enumerate() {
+            let mut opened;
+            let source = match &part.source {
+                Some(path) => {
+                    opened = open_input(plan_dir.join(path), &opts.stats)?;
+                    &mut opened
+                }
+                None => &mut *epub,
+            };
+            let lines = source.get_split_lines()?;
+            let sections = planned_sections(&lines, &part.sections, &part.hrefs, &part.ids, number)?;
+            let sections = drop_boilerplate(source, &lines, sections, opts)?;
+            let sections = drop_duplicates(source, &lines, sections, opts)?;
+            if sections.is_empty() {
+                bail!("Plan output {} has a part with no sections from {}", number, source.path.display());
+            }
+
+            let authors = if opts.build.author.is_empty() {
+                source.get_orig_authors().to_vec()
+            } else {
+                opts.build.author.clone()
+            };
+            let languages = output_languages(source, &sections, opts)?;
+            let title = source.get_orig_title().to_string();
+            let part = std::io::Cursor::new(Vec::new());
+            let part_opts = if n == 0 { &cover_opts } else { &part_opts };
+            let built = source.build_split_epub(part, &sections, &authors, Some(&title), &languages, &part_opts.build)?;
+            if n == 0 {
+                cover = built.cover;
+            }
+            sources.push((source.path.clone(), Box::new(std::io::Cursor::new(built.output.into_inner()))));
+        }
+
+        let mut built = merge_sources(sources, output, &merge_opts.build)?;
+        built.cover = cover;
+        Ok(built)
+    })
+}
+
+/// Every line not among `claimed`, for a `rest` output
+fn unclaimed_sections<'a>(lines: &[SplitLine], claimed: impl IntoIterator<Item = &'a usize>) -> Vec<usize> {
+    let claimed: HashSet<usize> = claimed.into_iter().copied().collect();
+    (0..lines.len()).filter(|index| !claimed.contains(index)).collect()
+}
+
+/// Write one output per entry of a split plan file
+fn split_by_plan(epub: &mut SplitEpub, plan_path: &PathBuf, opts: &RunOptions) -> Result<()> {
+    let plan = read_plan(plan_path)?;
+    let plan_dir = plan_path.parent().unwrap_or(std::path::Path::new(""));
+    write_plan_outputs(epub, &plan, plan_dir, opts)
+}
+
+/// Split into one volume per `--volume`, each a plain plan output
+fn split_by_volumes(epub: &mut SplitEpub, volumes: &[Volume], opts: &RunOptions) -> Result<()> {
+    let plan = Plan {
+        source: None,
+        metadata: PlanMetadata::default(),
+        outputs: volumes
+            .iter()
+            .map(|volume| PlannedOutput {
+                sections: (volume.first..=volume.last).collect(),
+                hrefs: Vec::new(),
+                ids: Vec::new(),
+                rest: false,
+                parts: Vec::new(),
+                output: None,
+                title: volume.title.clone(),
+                tags: Vec::new(),
+                cover: None,
+            })
+            .collect(),
+    };
+    write_plan_outputs(epub, &plan, std::path::Path::new(""), opts)
+}
+
+/// Write every output of `plan`; part sources are looked up relative to `plan_dir`
+fn write_plan_outputs(
+    epub: &mut SplitEpub,
+    plan: &Plan,
+    plan_dir: &std::path::Path,
+    opts: &RunOptions,
+) -> Result<()> {
+    let lines = epub.get_split_lines()?;
+    let output_filename = ensure_epub_extension(&opts.build.output);
+    let name_dates = plan
+        .outputs
+        .iter()
+        .any(|planned| planned.output.as_ref().is_some_and(|name| name.contains("{date")))
+        .then(|| name_dates(epub, opts.build.date_from));
+
+    // Metadata from the plan fills in whatever wasn't given on the command line
+    let mut opts = opts.clone();
+    if opts.build.description.is_none() {
+        opts.build.description = plan.metadata.description.clone();
+    }
+    if opts.build.author.is_empty() {
+        opts.build.author = plan.metadata.authors.clone();
+    }
+    if opts.build.tag.is_empty() {
+        opts.build.tag = plan.metadata.tags.clone();
+    }
+    let opts = &opts;
+
+    warn_unmatched_overrides(&opts.build, plan.outputs.len());
+    check_identifiers(&opts.build, plan.outputs.len())?;
+    let mut state = ResumeState::load(epub, opts)?;
+    let mut planned_outputs = Vec::new();
+    let mut output_resources = Vec::new();
+    // A `rest` output takes whatever none of the others list
+    let mut resolved = plan
+        .outputs
+        .iter()
+        .enumerate()
+        .map(|(file_count, planned)| {
+            if planned.rest && !planned.parts.is_empty() {
+                bail!(Failure::new(
+                    FailureKind::InvalidArgs,
+                    format!("Plan output {} can't be both a rest output and have parts", file_count + 1),
+                ));
+            }
+            if planned.rest || !planned.parts.is_empty() {
+                Ok(Vec::new())
+            } else {
+                planned_sections(&lines, &planned.sections, &planned.hrefs, &planned.ids, file_count + 1)
+            }
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let rest_outputs: Vec<usize> = (0..plan.outputs.len()).filter(|&n| plan.outputs[n].rest).collect();
+    if rest_outputs.len() > 1 {
+        bail!(Failure::new(
+            FailureKind::InvalidArgs,
+            "Plan has more than one rest output",
+        ));
+    }
+    if let Some(&rest) = rest_outputs.first() {
+        resolved[rest] = unclaimed_sections(&lines, resolved.iter().flatten());
+    }
+
+    for (file_count, (planned, sections)) in plan.outputs.iter().zip(resolved).enumerate() {
+        let output_name = |title: &str| {
+            let output_file = match (&planned.output, &name_dates) {
+                (Some(name), Some(dates)) => ensure_epub_extension(&dates.fill(name)),
+                (Some(name), None) => ensure_epub_extension(name),
+                (None, _) => format!("{:04}-{}", file_count + 1, output_filename),
+            };
+            let output_file = fill_title_placeholder(&output_file, title, opts.build.ascii_names);
+            let output_path = if let Some(ref dir) = opts.output_dir {
+                dir.join(&output_file)
+            } else {
+                PathBuf::from(&output_file)
+            };
+            (output_file, output_path)
+        };
+
+        if !planned.parts.is_empty() {
+            let title = opts
+                .build
+                .title_for
+                .get(&(file_count + 1))
+                .or(planned.title.as_ref())
+                .or(opts.build.title.as_ref())
+                .cloned()
+                .unwrap_or_else(|| format!("{} Split", epub.get_orig_title()));
+            let (output_file, output_path) = output_name(&title);
+            say!(opts.style, "output file: {}", output_path.display());
+            let output_opts = RunOptions {
+                build: OutputOptions {
+                    part: Some(file_count + 1),
+                    part_count: Some(plan.outputs.len()),
+                    ..opts.build.clone()
+                },
+                ..opts.clone()
+            };
+            let hash = state.compilation_hash(plan_dir, planned, &output_opts)?;
+            if state.is_current(&output_path, &hash) {
+                opts.summary.record_skipped(&output_path);
+                say!(opts.style, "unchanged, skipping: {}", output_path.display());
+            } else {
+                output_resources.push(
+                    write_compilation(epub, plan_dir, planned, output_path.clone(), &output_opts)
+                        .map_err(|e| batch_error(e, file_count, plan.outputs.len()))?,
+                );
+                state.record(&output_path, hash)?;
+            }
+            planned_outputs.push(PlannedOutput {
+                output: Some(output_file),
+                ..planned.clone()
+            });
+            continue;
+        }
+
+        let sections = drop_boilerplate(epub, &lines, sections, opts)?;
+        let sections = drop_duplicates(epub, &lines, sections, opts)?;
+        if sections.is_empty() {
+            bail!("Plan output {} has no sections", file_count + 1);
+        }
+        if let Some(&idx) = sections.iter().find(|&&idx| idx >= lines.len()) {
+            bail!(Failure::new(
+                FailureKind::OutOfRange,
+                format!("Line number {} is out of range (max: {})", idx, lines.len() - 1),
+            ));
+        }
+
+        let title = opts
+            .build
+            .title_for
+            .get(&(file_count + 1))
+            .or(planned.title.as_ref())
+            .cloned()
+            .or_else(|| opts.build.title.clone())
+            .or_else(|| lines[sections[0]].toc.first().cloned())
+            .unwrap_or_else(|| format!("{} Split", epub.get_orig_title()));
+        let title = title_from_template(
+            &title,
+            epub.get_orig_title(),
+            &lines[sections[0]],
+            file_count + 1,
+            plan.outputs.len(),
+        )
+        .unwrap_or(title);
+
+        let (output_file, output_path) = output_name(&title);
+        say!(opts.style, "output file: {}", output_path.display());
+
+        let authors = if opts.build.author.is_empty() {
+            epub.get_orig_authors().to_vec()
+        } else {
+            opts.build.author.clone()
+        };
+
+        let languages = output_languages(epub, &sections, opts)?;
+
+        let mut output_opts = RunOptions {
+            build: OutputOptions {
+                part: Some(file_count + 1),
+                part_count: Some(plan.outputs.len()),
+                title_sort: opts.build.title_sort.as_ref().map(|template| {
+                    title_from_template(
+                        template,
+                        epub.get_orig_title(),
+                        &lines[sections[0]],
+                        file_count + 1,
+                        plan.outputs.len(),
+                    )
+                    .unwrap_or_else(|| template.clone())
+                }),
+                ..opts.build.clone()
+            },
+            ..opts.clone()
+        };
+        if let Some(cover) = planned.cover(plan_dir) {
+            output_opts.cover = Some(cover);
+        }
+        if let Some(tags) = opts.build.tag_for.get(&(file_count + 1)) {
+            output_opts.build.tag = tags.clone();
+        } else if !planned.tags.is_empty() {
+            output_opts.build.tag = planned.tags.clone();
+        }
+
+        let hash = state.output_hash(&sections, &authors, &title, &languages, &output_opts);
+        if state.is_current(&output_path, &hash) {
+            opts.summary.record_skipped(&output_path);
+            say!(opts.style, "unchanged, skipping: {}", output_path.display());
+        } else {
+            output_resources.push(
+                write_split_epub(
+                    epub,
+                    output_path.clone(),
+                    &sections,
+                    &authors,
+                    Some(&title),
+                    &languages,
+                    &output_opts,
+                )
+                .map_err(|e| batch_error(e, file_count, plan.outputs.len()))?,
+            );
+            state.record(&output_path, hash)?;
+        }
+        planned_outputs.push(PlannedOutput {
+            tags: output_opts.build.tag_for.get(&(file_count + 1)).cloned().unwrap_or_else(|| planned.tags.clone()),
+            ..planned_output(&lines, &sections, &output_file, &title, &output_opts)
+        });
+    }
+
+    state.report(opts.style);
+    report_shared_resources(&output_resources, opts.style);
+    emit_plan(epub, planned_outputs, opts)
+}
+
+/// Print the resources copied into more than one output, largest cumulative
+/// size first, so fonts and big images repeated in every part stand out
+fn report_shared_resources(output_resources: &[Vec<(String, u64)>], style: Style) {
+    let mut shared: HashMap<&str, (u64, usize)> = HashMap::new(); // href -> (total bytes, outputs)
+    for resources in output_resources {
+        for (href, size) in resources {
+            let entry = shared.entry(href.as_str()).or_default();
+            entry.0 += size;
+            entry.1 += 1;
+        }
+    }
+    let mut shared: Vec<(&str, u64, usize)> = shared
+        .into_iter()
+        .filter(|(_, (_, outputs))| *outputs > 1)
+        .map(|(href, (total, outputs))| (href, total, outputs))
+        .collect();
+    if shared.is_empty() {
+        return;
+    }
+    shared.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+
+    say!(style, "\nshared resources ({} outputs):", output_resources.len());
+    let mut duplicated = 0;
+    for (href, total, outputs) in &shared {
+        say!(
+            style,
+            "\t{} ({}): in {} outputs, {} total",
+            href,
+            guess_media_type_static(href),
+            outputs,
+            format_size(*total)
+        );
+        duplicated += total - total / *outputs as u64;
+    }
+    say!(style, "\tduplicated across outputs: {}", format_size(duplicated));
+}
+
+fn format_size(bytes: u64) -> String {
+    match bytes {
+        b if b >= 1024 * 1024 => format!("{:.1} MiB", b as f64 / (1024.0 * 1024.0)),
+        b if b >= 1024 => format!("{:.1} KiB", b as f64 / 1024.0),
+        b => format!("{} B", b),
+    }
+}
+
+/// Describe an output that was just written, for `--emit-plan`
+fn planned_output(
+    lines: &[SplitLine],
+    sections: &[usize],
+    output_file: &str,
+    title: &str,
+    opts: &RunOptions,
+) -> PlannedOutput {
+    PlannedOutput {
+        sections: sections.to_vec(),
+        hrefs: sections.iter().map(|&idx| lines[idx].target()).collect(),
+        // IDs hash TOC titles, so they'd stop matching where hrefs still do
+        ids: Vec::new(),
+        rest: false,
+        parts: Vec::new(),
+        output: Some(output_file.to_string()),
+        title: Some(title.to_string()),
+        tags: opts.build.part.and_then(|number| opts.build.tag_for.get(&number)).cloned().unwrap_or_default(),
+        cover: opts.cover.clone(),
+    }
+}
+
+/// Write the outputs of this run as a plan file, if `--emit-plan` was given
+fn emit_plan(epub: &SplitEpub, outputs: Vec<PlannedOutput>, opts: &RunOptions) -> Result<()> {
+    let Some(path) = &opts.emit_plan else {
+        return Ok(());
+    };
+
+    let plan = Plan {
+        source: epub
+            .path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string()),
+        metadata: PlanMetadata {
+            description: opts.build.description.clone(),
+            authors: opts.build.author.clone(),
+            tags: opts.build.tag.clone(),
+        },
+        outputs,
+    };
+
+    let file = File::create(path).with_context(|| format!("Failed to create plan: {}", path.display()))?;
+    serde_json::to_writer_pretty(file, &plan)
+        .with_context(|| format!("Failed to write plan: {}", path.display()))?;
+    say!(opts.style, "plan file: {}", path.display());
+    Ok(())
+}
+
+/// Languages for an output: detected from the section text with
+/// `--detect-language`, falling back to `--language` when detection is
+/// disabled or unreliable
+fn output_languages(
+    epub: &mut SplitEpub,
+    section_indices: &[usize],
+    opts: &RunOptions,
+) -> Result<Vec<String>> {
+    if !opts.build.detect_language {
+        return Ok(opts.build.language.clone());
+    }
+
+    let text = epub.section_text(section_indices)?;
+    match whatlang::detect(&text) {
+        Some(info) if info.is_reliable() => {
+            let lang = bcp47_language_code(info.lang());
+            info!(
+                "Detected language {} (confidence {:.2}) for sections {:?}",
+                lang,
+                info.confidence(),
+                section_indices
+            );
+            Ok(vec![lang.to_string()])
+        }
+        _ => {
+            warn!(
+                "Could not reliably detect language for sections {:?}, using {:?}",
+                section_indices, opts.build.language
+            );
+            Ok(opts.build.language.clone())
+        }
+    }
+}
+
+/// Shortest BCP 47 language tag for a detected language
+fn bcp47_language_code(lang: whatlang::Lang) -> &'static str {
+    match lang.code() {
+        "afr" => "af",
+        "aka" => "ak",
+        "amh" => "am",
+        "ara" => "ar",
+        "aze" => "az",
+        "bel" => "be",
+        "ben" => "bn",
+        "bul" => "bg",
+        "cat" => "ca",
+        "ces" => "cs",
+        "cmn" => "zh",
+        "dan" => "da",
+        "deu" => "de",
+        "ell" => "el",
+        "eng" => "en",
+        "epo" => "eo",
+        "est" => "et",
+        "fin" => "fi",
+        "fra" => "fr",
+        "guj" => "gu",
+        "heb" => "he",
+        "hin" => "hi",
+        "hrv" => "hr",
+        "hun" => "hu",
+        "hye" => "hy",
+        "ind" => "id",
+        "ita" => "it",
+        "jav" => "jv",
+        "jpn" => "ja",
+        "kan" => "kn",
+        "kat" => "ka",
+        "khm" => "km",
+        "kor" => "ko",
+        "lat" => "la",
+        "lav" => "lv",
+        "lit" => "lt",
+        "mal" => "ml",
+        "mar" => "mr",
+        "mkd" => "mk",
+        "mya" => "my",
+        "nep" => "ne",
+        "nld" => "nl",
+        "nob" => "nb",
+        "ori" => "or",
+        "pan" => "pa",
+        "pes" => "fa",
+        "pol" => "pl",
+        "por" => "pt",
+        "ron" => "ro",
+        "rus" => "ru",
+        "sin" => "si",
+        "slk" => "sk",
+        "slv" => "sl",
+        "sna" => "sn",
+        "spa" => "es",
+        "srp" => "sr",
+        "swe" => "sv",
+        "tam" => "ta",
+        "tel" => "te",
+        "tgl" => "tl",
+        "tha" => "th",
+        "tuk" => "tk",
+        "tur" => "tr",
+        "ukr" => "uk",
+        "urd" => "ur",
+        "uzb" => "uz",
+        "vie" => "vi",
+        "yid" => "yi",
+        "zul" => "zu",
+        other => other,
+    }
+}
+
+/// Values for the `{date}` and `{datetime}` placeholders in output names,
+/// kept free of characters some filesystems refuse
+#[derive(Debug, Clone)]
+struct NameDates {
+    date: String,
+    datetime: String,
+}
+
+impl NameDates {
+    fn now() -> Self {
+        Self::from_w3cdtf(&w3cdtf_utc(unix_time_secs()))
+    }
+
+    /// From a W3CDTF date such as `2021`, `2021-03-04` or `2021-03-04T10:20:30Z`
+    fn from_w3cdtf(value: &str) -> Self {
+        let (date, time) = value.split_once('T').unwrap_or((value, ""));
+        let time: String = time.chars().filter(|c| *c != ':').collect();
+        let datetime = if time.is_empty() { date.to_string() } else { format!("{}T{}", date, time) };
+        NameDates {
+            date: date.to_string(),
+            datetime,
+        }
+    }
+
+    fn fill(&self, name: &str) -> String {
+        name.replace("{datetime}", &self.datetime).replace("{date}", &self.date)
+    }
+}
+
+/// Longest a title may run in an output name, in bytes of UTF-8, leaving
+/// room for numbering and the rest of the name within common 255-byte limits
+const TITLE_NAME_LIMIT: usize = 200;
+
+/// `name` with `{title}` replaced by a form of `title` that is safe as a
+/// file name on every common filesystem
+fn fill_title_placeholder(name: &str, title: &str, ascii: bool) -> String {
+    if name.contains("{title}") {
+        name.replace("{title}", &file_name_from_title(title, ascii))
+    } else {
+        name.to_string()
+    }
+}
+
+/// `title` with path separators, characters Windows rejects and control
+/// characters removed, whitespace collapsed, trailing dots dropped, names
+/// Windows reserves avoided and the length capped. With `ascii`, other
+/// scripts are transliterated first.
+fn file_name_from_title(title: &str, ascii: bool) -> String {
+    const RESERVED: [&str; 22] = [
+        "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+        "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+    ];
+
+    let title = if ascii { deunicode(title) } else { title.to_string() };
+    let cleaned: String = title
+        .chars()
+        .filter(|c| !matches!(c, '/' | '\\' | ':' | '?' | '*' | '"' | '<' | '>' | '|') && !c.is_control())
+        .collect();
+    let cleaned = cleaned.split_whitespace().collect::<Vec<_>>().join(" ");
+    let cleaned = cleaned[..cleaned.floor_char_boundary(TITLE_NAME_LIMIT)]
+        .trim_end_matches(['.', ' '])
+        .to_string();
+
+    // Windows reserves these names whatever extension follows
+    let stem_end = cleaned.find('.').unwrap_or(cleaned.len());
+    if cleaned.is_empty() {
+        "untitled".to_string()
+    } else if RESERVED.contains(&cleaned[..stem_end].to_ascii_uppercase().as_str()) {
+        format!("{}_{}", &cleaned[..stem_end], &cleaned[stem_end..])
+    } else {
+        cleaned
+    }
+}
+
+fn ensure_epub_extension(filename: &str) -> String {
+    if filename.to_lowercase().ends_with(".epub") {
+        filename.to_string()
+    } else {
+        format!("{}.epub", filename)
+    }
+}
+
+#[instrument(name = "merge", skip_all, fields(inputs = inputs.len()))]
+fn merge_epubs(inputs: &[PathBuf], opts: &RunOptions) -> Result<()> {
+    info!("Merging {} EPUB files", inputs.len());
+
+    if inputs.len() < 2 {
+        bail!(Failure::new(
+            FailureKind::InvalidArgs,
+            "At least 2 EPUB files are required for merging",
+        ));
+    }
+
+    let output_filename = ensure_epub_extension(&opts.build.output);
+    let output_path = PathBuf::from(&output_filename);
+    say!(opts.style, "Output file: {}", output_path.display());
+
+    let mut sources: Vec<(PathBuf, Box<dyn EpubSource>)> = Vec::new();
+    for input_path in inputs {
+        let source = open_epub_file(input_path)
+            .with_context(|| Failure::new(FailureKind::UnreadableInput, format!("Failed to load EPUB: {}", input_path.display())))?;
+        sources.push((input_path.clone(), opts.stats.counted(source)));
+    }
+    let opts = &read_cover(opts)?;
+    write_output(output_path.clone(), &[], "", opts, |output| merge_sources(sources, output, &opts.build))?;
+
+    say!(opts.style, "Successfully created merged EPUB: {}", output_path.display());
+    Ok(())
+}
+
+/// Check every reference in the written XHTML and CSS against the files and
+/// ids actually in the output, and print any that don't resolve.
+/// `documents` holds (output path, content, is CSS).
+fn report_broken_references(
+    output_path: &std::path::Path,
+    documents: &[(String, String, bool)],
+    written: &HashSet<String>,
+    style: Style,
+) -> Result<Vec<String>> {
+    let tag_re = Regex::new(r"<([A-Za-z][A-Za-z0-9:]*)\b[^>]*>")
+        .context("Failed to compile tag regex")?;
+    let attr_re = Regex::new(
+        r#"(?i)\s(src|xlink:href|href|altimg|poster|data)\s*=\s*(?:"([^"]*)"|'([^']*)')"#,
+    )
+    .context("Failed to compile reference attribute regex")?;
+    let url_re = Regex::new(r#"(?i)url\(\s*["']?([^"')\s]+)["']?\s*\)"#)
+        .context("Failed to compile url() regex")?;
+    let import_re = Regex::new(r#"(?i)@import\s+["']([^"']+)["']"#)
+        .context("Failed to compile @import regex")?;
+    let id_re = Regex::new(r#"\s(?:id|xml:id|name)\s*=\s*["']([^"']+)["']"#)
+        .context("Failed to compile id regex")?;
+
+    let ids: HashMap<&str, HashSet<&str>> = documents
+        .iter()
+        .filter(|(_, _, is_css)| !is_css)
+        .map(|(path, content, _)| {
+            let ids = id_re
+                .captures_iter(content)
+                .filter_map(|caps| caps.get(1))
+                .map(|m| m.as_str())
+                .collect();
+            (path.as_str(), ids)
+        })
+        .collect();
+
+    let mut problems: Vec<String> = Vec::new();
+    for (path, content, is_css) in documents {
+        // (reference, is a hyperlink rather than a loaded resource)
+        let mut references: Vec<(&str, bool)> = Vec::new();
+        for caps in url_re.captures_iter(content).chain(import_re.captures_iter(content)) {
+            references.push((caps.get(1).map_or("", |m| m.as_str()), false));
+        }
+        if !is_css {
+            for tag_caps in tag_re.captures_iter(content) {
+                let tag = tag_caps.get(0).map_or("", |m| m.as_str());
+                let name = tag_caps[1].to_lowercase();
+                for attr_caps in attr_re.captures_iter(tag) {
+                    let reference = attr_caps
+                        .get(2)
+                        .or_else(|| attr_caps.get(3))
+                        .map_or("", |m| m.as_str());
+                    let is_link = attr_caps[1].eq_ignore_ascii_case("href") && name != "link";
+                    references.push((reference, is_link));
+                }
+            }
+        }
+
+        let base_path = SplitEpub::get_path_part(path);
+        for (reference, is_link) in references {
+            if reference.is_empty() || reference.contains(':') {
+                continue;
+            }
+            let (target_path, fragment) = match reference.split_once('#') {
+                Some((target_path, fragment)) => (target_path, Some(fragment)),
+                None => (reference, None),
+            };
+            let target = if target_path.is_empty() {
+                path.clone()
+            } else {
+                SplitEpub::join_href(&base_path, target_path)
+            };
+
+            if !written.contains(&target) {
+                let kind = if is_link { "missing file" } else { "missing resource" };
+                problems.push(format!("{}: {} -> {}", kind, path, reference));
+            } else if let Some(fragment) = fragment.filter(|f| !f.is_empty()) {
+                let fragment = percent_decode_str(fragment).decode_utf8_lossy();
+                if ids.get(target.as_str()).is_some_and(|ids| !ids.contains(fragment.as_ref())) {
+                    problems.push(format!("missing anchor: {} -> {}", path, reference));
+                }
+            }
+        }
+    }
+
+    if !problems.is_empty() {
+        problems.sort();
+        problems.dedup();
+        say!(style, "broken references in {}: {}", output_path.display(), problems.len());
+        for problem in &problems {
+            say!(style, "\t{}", problem);
+        }
+    }
+    Ok(problems)
+}
+
+/// Archive entry holding embedded checksums
+const CHECKSUMS_ENTRY: &str = "META-INF/checksums.sha256";
+
+/// SHA-256 of every file entry in an EPUB except the embedded checksum list,
+/// sorted by path
+fn entry_checksums(path: &std::path::Path) -> Result<Vec<(String, String)>> {
+    let source = open_epub_file(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let mut archive =
+        ZipArchive::new(source).with_context(|| format!("Failed to read EPUB as ZIP: {}", path.display()))?;
+
+    let mut checksums = Vec::new();
+    for i in 0..archive.len() {
+        let mut entry = archive.by_index(i).context("Failed to read archive entry")?;
+        if entry.is_dir() || entry.name() == CHECKSUMS_ENTRY {
+            continue;
+        }
+        let mut data = Vec::new();
+        entry
+            .read_to_end(&mut data)
+            .with_context(|| format!("Failed to read {}", entry.name()))?;
+        let digest: String = Sha256::digest(&data).iter().map(|b| format!("{:02x}", b)).collect();
+        checksums.push((entry.name().to_string(), digest));
+    }
+    checksums.sort();
+    Ok(checksums)
+}
+
+/// Path of the sidecar checksum file for an EPUB
+fn checksum_sidecar_path(path: &std::path::Path) -> PathBuf {
+    let mut sidecar = path.as_os_str().to_owned();
+    sidecar.push(".sha256");
+    PathBuf::from(sidecar)
+}
+
+/// Record checksums of a finished EPUB, in `sha256sum` format
+fn write_checksums(path: &std::path::Path, mode: ChecksumMode) -> Result<()> {
+    let listing: String = entry_checksums(path)?
+        .iter()
+        .map(|(name, digest)| format!("{}  {}\n", digest, name))
+        .collect();
+
+    match mode {
+        ChecksumMode::Embed => {
+            let file = std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(path)
+                .with_context(|| format!("Failed to reopen {}", path.display()))?;
+            let mut zip = ZipWriter::new_append(file)
+                .with_context(|| format!("Failed to append to {}", path.display()))?;
+            let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+            zip.start_file(CHECKSUMS_ENTRY, options)
+                .context("Failed to add checksums")?;
+            zip.write_all(listing.as_bytes())
+                .context("Failed to write checksums")?;
+            zip.finish().context("Failed to finalize EPUB file")?;
+        }
+        ChecksumMode::Sidecar => {
+            let sidecar = checksum_sidecar_path(path);
+            std::fs::write(&sidecar, listing)
+                .with_context(|| format!("Failed to write {}", sidecar.display()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Path of the ONIX record written next to an EPUB
+fn onix_sidecar_path(path: &std::path::Path) -> PathBuf {
+    path.with_extension("onix.xml")
+}
+
+/// Write an ONIX 3.0 product record for a finished EPUB next to it, with
+/// its word count and file size as the extent
+fn write_onix_record(path: &std::path::Path, meta: &PackageMetadata) -> Result<()> {
+    let mut epub = open_epub(path.to_path_buf())?;
+    let lines = epub.get_split_lines()?;
+    let words: usize = epub.section_word_counts(&lines)?.iter().sum();
+    let bytes = std::fs::metadata(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?
+        .len();
+    let sidecar = onix_sidecar_path(path);
+    std::fs::write(&sidecar, onix_record(meta, words, bytes))
+        .with_context(|| format!("Failed to write {}", sidecar.display()))
+}
+
+/// An ONIX 3.0 message holding one product record for an output
+fn onix_record(meta: &PackageMetadata, words: usize, bytes: u64) -> String {
+    let esc = SplitEpub::escape_xml;
+    let (id_type, id_value) = onix_product_id(&meta.unique_id);
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    xml.push_str("<ONIXMessage release=\"3.0\" xmlns=\"http://ns.editeur.org/onix/3.0/reference\">\n");
+    xml.push_str("  <Header>\n");
+    xml.push_str("    <Sender><SenderName>epubsplit</SenderName></Sender>\n");
+    // CCYY-MM-DDThh:mm:ssZ becomes CCYYMMDDThhmmssZ
+    let sent: String = meta.modified.chars().filter(|c| !matches!(c, '-' | ':')).collect();
+    xml.push_str(&format!("    <SentDateTime>{}</SentDateTime>\n", sent));
+    xml.push_str("  </Header>\n");
+    xml.push_str("  <Product>\n");
+    xml.push_str(&format!("    <RecordReference>{}</RecordReference>\n", esc(&meta.unique_id)));
+    xml.push_str("    <NotificationType>03</NotificationType>\n");
+    xml.push_str("    <ProductIdentifier>\n");
+    xml.push_str(&format!("      <ProductIDType>{}</ProductIDType>\n", id_type));
+    if id_type == "01" {
+        xml.push_str("      <IDTypeName>EPUB unique identifier</IDTypeName>\n");
+    }
+    xml.push_str(&format!("      <IDValue>{}</IDValue>\n", esc(&id_value)));
+    xml.push_str("    </ProductIdentifier>\n");
+
+    xml.push_str("    <DescriptiveDetail>\n");
+    xml.push_str("      <ProductComposition>00</ProductComposition>\n");
+    xml.push_str("      <ProductForm>ED</ProductForm>\n");
+    xml.push_str("      <ProductFormDetail>E101</ProductFormDetail>\n");
+    xml.push_str("      <TitleDetail>\n");
+    xml.push_str("        <TitleType>01</TitleType>\n");
+    xml.push_str("        <TitleElement>\n");
+    xml.push_str("          <TitleElementLevel>01</TitleElementLevel>\n");
+    xml.push_str(&format!("          <TitleText>{}</TitleText>\n", esc(&meta.title)));
+    xml.push_str("        </TitleElement>\n");
+    xml.push_str("      </TitleDetail>\n");
+    let contributors = meta
+        .authors
+        .iter()
+        .map(|name| (name, "A01"))
+        .chain(meta.creators.iter().map(|creator| (&creator.name, onix_contributor_role(creator.role.as_deref(), "A01"))))
+        .chain(meta.contributors.iter().map(|contributor| {
+            (&contributor.name, onix_contributor_role(contributor.role.as_deref(), "Z99"))
+        }));
+    for (number, (name, role)) in contributors.enumerate() {
+        xml.push_str("      <Contributor>\n");
+        xml.push_str(&format!("        <SequenceNumber>{}</SequenceNumber>\n", number + 1));
+        xml.push_str(&format!("        <ContributorRole>{}</ContributorRole>\n", role));
+        xml.push_str(&format!("        <PersonName>{}</PersonName>\n", esc(name)));
+        if let Some(sort) = meta.author_sort.get(name) {
+            xml.push_str(&format!("        <PersonNameInverted>{}</PersonNameInverted>\n", esc(sort)));
+        }
+        xml.push_str("      </Contributor>\n");
+    }
+    for code in meta.languages.iter().filter_map(|tag| onix_language_code(tag)) {
+        xml.push_str("      <Language>\n");
+        xml.push_str("        <LanguageRole>01</LanguageRole>\n");
+        xml.push_str(&format!("        <LanguageCode>{}</LanguageCode>\n", code));
+        xml.push_str("      </Language>\n");
+    }
+    // Words of text, then file size in bytes
+    for (extent_type, value, unit) in [("02", words as u64, "00"), ("22", bytes, "17")] {
+        xml.push_str("      <Extent>\n");
+        xml.push_str(&format!("        <ExtentType>{}</ExtentType>\n", extent_type));
+        xml.push_str(&format!("        <ExtentValue>{}</ExtentValue>\n", value));
+        xml.push_str(&format!("        <ExtentUnit>{}</ExtentUnit>\n", unit));
+        xml.push_str("      </Extent>\n");
+    }
+    // Tags as keywords
+    for tag in &meta.tags {
+        xml.push_str("      <Subject>\n");
+        xml.push_str("        <SubjectSchemeIdentifier>20</SubjectSchemeIdentifier>\n");
+        xml.push_str(&format!("        <SubjectHeadingText>{}</SubjectHeadingText>\n", esc(tag)));
+        xml.push_str("      </Subject>\n");
+    }
+    xml.push_str("    </DescriptiveDetail>\n");
+
+    if !meta.description.is_empty() {
+        xml.push_str("    <CollateralDetail>\n");
+        xml.push_str("      <TextContent>\n");
+        xml.push_str("        <TextType>03</TextType>\n");
+        xml.push_str("        <ContentAudience>00</ContentAudience>\n");
+        xml.push_str(&format!("        <Text>{}</Text>\n", esc(&meta.description)));
+        xml.push_str("      </TextContent>\n");
+        xml.push_str("    </CollateralDetail>\n");
+    }
+    xml.push_str("  </Product>\n");
+    xml.push_str("</ONIXMessage>\n");
+    xml
+}
+
+/// ONIX product identifier type (list 5) and value for a package identifier:
+/// ISBN-13 or ISBN-10 when it is one, otherwise a proprietary identifier
+fn onix_product_id(unique_id: &str) -> (&'static str, String) {
+    let bare = match unique_id.get(..9) {
+        Some(prefix) if prefix.eq_ignore_ascii_case("urn:isbn:") => &unique_id[9..],
+        _ => unique_id,
+    };
+    let isbn: String = bare.chars().filter(|c| !matches!(c, '-' | ' ')).collect();
+    let digits = |s: &str| s.chars().all(|c| c.is_ascii_digit());
+    if isbn.len() == 13 && digits(&isbn) && (isbn.starts_with("978") || isbn.starts_with("979")) {
+        ("15", isbn)
+    } else if isbn.len() == 10 && digits(&isbn[..9]) && (digits(&isbn[9..]) || isbn.ends_with(['X', 'x'])) {
+        ("02", isbn.to_ascii_uppercase())
+    } else {
+        ("01", unique_id.to_string())
+    }
+}
+
+/// ONIX contributor role (list 17) for a MARC relator code
+fn onix_contributor_role(role: Option<&str>, default: &'static str) -> &'static str {
+    match role {
+        Some("aut") => "A01",
+        Some("edt") => "B01",
+        Some("trl") => "B06",
+        Some("ill") => "A12",
+        Some("pht") => "A13",
+        Some("aui") => "A24",
+        Some("com") => "C01",
+        Some("nrt") => "E07",
+        Some("art") => "A07",
+        Some(_) => "Z99",
+        None => default,
+    }
+}
+
+/// ONIX language code (ISO 639-2/B) for a BCP 47 language tag, for the
+/// languages detection knows
+fn onix_language_code(tag: &str) -> Option<&'static str> {
+    let primary = tag.split(['-', '_']).next()?.to_ascii_lowercase();
+    let lang = whatlang::Lang::all()
+        .iter()
+        .copied()
+        .find(|&lang| lang.code() == primary || bcp47_language_code(lang) == primary)?;
+    // ISO 639-3 codes that differ from their 639-2 bibliographic form
+    Some(match lang.code() {
+        "ces" => "cze",
+        "cmn" => "chi",
+        "deu" => "ger",
+        "ell" => "gre",
+        "fra" => "fre",
+        "hye" => "arm",
+        "kat" => "geo",
+        "mkd" => "mac",
+        "mya" => "bur",
+        "nld" => "dut",
+        "pes" => "per",
+        "ron" => "rum",
+        "slk" => "slo",
+        code => code,
+    })
+}
+
+/// Re-hash an EPUB's entries and compare them with its embedded checksums,
+/// or its .sha256 sidecar when none are embedded
+fn verify_checksums(path: &std::path::Path, style: Style) -> Result<()> {
+    let reader = open_epub_file(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let mut archive =
+        ZipArchive::new(reader).with_context(|| format!("Failed to read EPUB as ZIP: {}", path.display()))?;
+    let listing = match SplitEpub::read_file_from_archive(&mut archive, CHECKSUMS_ENTRY) {
+        Ok(listing) => listing,
+        Err(_) => {
+            let sidecar = checksum_sidecar_path(path);
+            std::fs::read_to_string(&sidecar).with_context(|| {
+                format!(
+                    "No embedded checksums and no sidecar {} found",
+                    sidecar.display()
+                )
+            })?
+        }
+    };
+
+    let mut expected: HashMap<String, String> = HashMap::new();
+    for line in listing.lines().filter(|line| !line.trim().is_empty()) {
+        let (digest, name) = line
+            .split_once("  ")
+            .ok_or_else(|| anyhow!("Malformed checksum line: {}", line))?;
+        expected.insert(name.to_string(), digest.to_lowercase());
+    }
+
+    let mut problems = Vec::new();
+    for (name, digest) in entry_checksums(path)? {
+        match expected.remove(&name) {
+            Some(recorded) if recorded == digest => {}
+            Some(_) => problems.push(format!("changed: {}", name)),
+            None => problems.push(format!("not in checksums: {}", name)),
+        }
+    }
+    let mut missing: Vec<String> = expected.into_keys().collect();
+    missing.sort();
+    problems.extend(missing.into_iter().map(|name| format!("missing: {}", name)));
+
+    if problems.is_empty() {
+        say!(style, "{}: OK", path.display());
+        return Ok(());
+    }
+    for problem in &problems {
+        say!(style, "\t{}", problem);
+    }
+    bail!(Failure::new(
+        FailureKind::Validation,
+        format!("{}: {} checksum problem(s)", path.display(), problems.len()),
+    ))
+}
+
+/// Characters Calibre replaces in library folder and file names
+const CALIBRE_UNSAFE_CHARS: &[char] = &['/', '\\', ':', '*', '?', '"', '<', '>', '|'];
+
+/// Longest title or author Calibre uses in a library path component
+const CALIBRE_PATH_LIMIT: usize = 100;
+
+/// A title or author as Calibre writes it into a library path
+fn calibre_path_component(name: &str) -> String {
+    let cleaned: String = name
+        .chars()
+        .map(|c| if CALIBRE_UNSAFE_CHARS.contains(&c) || c.is_control() { '_' } else { c })
+        .take(CALIBRE_PATH_LIMIT)
+        .collect();
+    let cleaned = cleaned.trim().trim_end_matches('.').to_string();
+    if cleaned.is_empty() {
+        "Unknown".to_string()
+    } else {
+        cleaned
+    }
+}
+
+/// The next free book id in a Calibre library, going by the `Title (id)`
+/// folders already present
+fn next_calibre_book_id(library: &std::path::Path) -> Result<u64> {
+    let id_regex = Regex::new(r"\((\d+)\)$").context("Failed to compile book id regex")?;
+    let mut max_id = 0;
+    let Ok(authors) = std::fs::read_dir(library) else {
+        return Ok(1);
+    };
+    for author in authors.flatten().filter(|entry| entry.path().is_dir()) {
+        let Ok(books) = std::fs::read_dir(author.path()) else {
+            continue;
+        };
+        for book in books.flatten() {
+            let name = book.file_name().to_string_lossy().to_string();
+            if let Some(id) = id_regex.captures(&name).and_then(|c| c[1].parse::<u64>().ok()) {
+                max_id = max_id.max(id);
+            }
+        }
+    }
+    Ok(max_id + 1)
+}
+
+/// Quote a path for a POSIX shell command line
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+/// Run `--post-hook` for a written output through the shell, failing if it
+/// exits unsuccessfully
+fn run_post_hook(command: &str, output_path: &std::path::Path, title: &str, index: usize) -> Result<()> {
+    #[cfg(windows)]
+    let quote = |s: &str| format!("\"{}\"", s.replace('"', "\"\""));
+    #[cfg(not(windows))]
+    let quote = shell_quote;
+
+    let command = command
+        .replace("{output}", &quote(&output_path.display().to_string()))
+        .replace("{title}", &quote(title))
+        .replace("{index}", &index.to_string());
+    debug!("Running post-hook: {}", command);
+
+    #[cfg(windows)]
+    let status = std::process::Command::new("cmd").arg("/C").arg(&command).status();
+    #[cfg(not(windows))]
+    let status = std::process::Command::new("sh").arg("-c").arg(&command).status();
+
+    let status = status.with_context(|| format!("Failed to run post-hook: {}", command))?;
+    if !status.success() {
+        bail!("Post-hook failed ({}): {}", status, command);
+    }
+    Ok(())
+}
+
+/// With `--calibre-library`, move a written output into the library's
+/// `Author/Title (id)/` layout alongside a `metadata.opf` and `cover.jpg`,
+/// or with `--calibredb` leave it in place and print the import command.
+/// Placed files aren't in Calibre's database until it rescans the library.
+/// Returns where the EPUB ended up.
+fn add_to_calibre_library(
+    output_path: &std::path::Path,
+    library: &std::path::Path,
+    meta: &PackageMetadata,
+    cover: Option<&[u8]>,
+    print_command: bool,
+    style: Style,
+) -> Result<PathBuf> {
+    if print_command {
+        say!(
+            style,
+            "calibredb add --with-library {} {}",
+            shell_quote(&library.display().to_string()),
+            shell_quote(&output_path.display().to_string())
+        );
+        return Ok(output_path.to_path_buf());
+    }
+
+    // Convert the cover before the EPUB is moved, so a cover that can't be
+    // converted costs only cover.jpg
+    let cover_jpeg = cover.and_then(|data| match calibre_cover_jpeg(data) {
+        Ok(jpeg) => Some(jpeg),
+        Err(e) => {
+            warn!("Leaving out cover.jpg: {:#}", e);
+            None
+        }
+    });
+
+    let author = calibre_path_component(meta.authors.first().map(String::as_str).unwrap_or("Unknown"));
+    let title = calibre_path_component(&meta.title);
+    let id = next_calibre_book_id(library)?;
+    let book_dir = library.join(&author).join(format!("{} ({})", title, id));
+    std::fs::create_dir_all(&book_dir)
+        .with_context(|| format!("Failed to create {}", book_dir.display()))?;
+
+    let book_path = book_dir.join(format!("{} - {}.epub", title, author));
+    if std::fs::rename(output_path, &book_path).is_err() {
+        // Different filesystem: copy, then remove the original
+        std::fs::copy(output_path, &book_path)
+            .with_context(|| format!("Failed to copy output to {}", book_path.display()))?;
+        std::fs::remove_file(output_path)
+            .with_context(|| format!("Failed to remove {}", output_path.display()))?;
+    }
+
+    if let Some(jpeg) = &cover_jpeg {
+        std::fs::write(book_dir.join("cover.jpg"), jpeg).context("Failed to write cover.jpg")?;
+    }
+
+    let opf = calibre_metadata_opf(meta, Some(id), cover_jpeg.is_some());
+    std::fs::write(book_dir.join("metadata.opf"), opf).context("Failed to write metadata.opf")?;
+
+    say!(style, "calibre library: {}", book_path.display());
+    Ok(book_path)
+}
+
+/// A cover as the JPEG Calibre keeps beside each book
+fn calibre_cover_jpeg(data: &[u8]) -> Result<Vec<u8>> {
+    if image::guess_format(data).ok() == Some(ImageFormat::Jpeg) {
+        return Ok(data.to_vec());
+    }
+    let img = image::load_from_memory(data).context("Failed to decode cover image")?;
+    let mut jpeg = Vec::new();
+    JpegEncoder::new_with_quality(&mut jpeg, 90)
+        .encode_image(&DynamicImage::ImageRgb8(img.to_rgb8()))
+        .context("Failed to encode cover.jpg")?;
+    Ok(jpeg)
+}
+
+/// A `metadata.opf` sidecar in the form Calibre keeps in each book folder;
+/// `id` is the book's id in a Calibre library, if it has one
+fn calibre_metadata_opf(meta: &PackageMetadata, id: Option<u64>, has_cover: bool) -> String {
+    let escape = SplitEpub::escape_xml;
+    let mut opf = String::new();
+
+    opf.push_str(r#"<?xml version='1.0' encoding='utf-8'?>
+<package xmlns="http://www.idpf.org/2007/opf" unique-identifier="uuid_id" version="2.0">
+    <metadata xmlns:dc="http://purl.org/dc/elements/1.1/" xmlns:opf="http://www.idpf.org/2007/opf">
+"#);
+    if let Some(id) = id {
+        opf.push_str(&format!(
+            "        <dc:identifier opf:scheme=\"calibre\" id=\"calibre_id\">{}</dc:identifier>\n",
+            id
+        ));
+    }
+    opf.push_str(&format!(
+        "        <dc:identifier opf:scheme=\"uuid\" id=\"uuid_id\">{}</dc:identifier>\n",
+        meta.unique_id
+            .strip_prefix("urn:uuid:")
+            .map_or_else(|| uuid::Uuid::new_v4().to_string(), str::to_string)
+    ));
+    match onix_product_id(&meta.unique_id) {
+        ("01", _) => {}
+        (_, isbn) => opf.push_str(&format!("        <dc:identifier opf:scheme=\"ISBN\">{}</dc:identifier>\n", isbn)),
+    }
+    opf.push_str(&format!("        <dc:title>{}</dc:title>\n", escape(&meta.title)));
+    for author in &meta.authors {
+        opf.push_str(&format!(
+            "        <dc:creator opf:role=\"aut\"{}>{}</dc:creator>\n",
+            SplitEpub::file_as_attr(meta, author),
+            escape(author)
+        ));
+    }
+    for (element, creator) in meta
+        .creators
+        .iter()
+        .map(|creator| ("creator", creator))
+        .chain(meta.contributors.iter().map(|contributor| ("contributor", contributor)))
+    {
+        let role = creator
+            .role
+            .as_ref()
+            .map(|role| format!(" opf:role=\"{}\"", escape(role)))
+            .unwrap_or_default();
+        opf.push_str(&format!("        <dc:{0}{1}>{2}</dc:{0}>\n", element, role, escape(&creator.name)));
+    }
+    opf.push_str("        <dc:contributor opf:role=\"bkp\">epubsplit-rs</dc:contributor>\n");
+    if !meta.description.is_empty() {
+        opf.push_str(&format!(
+            "        <dc:description>{}</dc:description>\n",
+            escape(&meta.description)
+        ));
+    }
+    for lang in &meta.languages {
+        opf.push_str(&format!("        <dc:language>{}</dc:language>\n", escape(lang)));
+    }
+    for tag in &meta.tags {
+        opf.push_str(&format!("        <dc:subject>{}</dc:subject>\n", escape(tag)));
+    }
+    opf.push_str(&format!(
+        "        <meta name=\"calibre:title_sort\" content=\"{}\"/>\n",
+        escape(meta.title_sort.as_ref().unwrap_or(&meta.title))
+    ));
+    opf.push_str("    </metadata>\n");
+    if has_cover {
+        opf.push_str("    <guide>\n");
+        opf.push_str("        <reference type=\"cover\" title=\"Cover\" href=\"cover.jpg\"/>\n");
+        opf.push_str("    </guide>\n");
+    }
+    opf.push_str("</package>\n");
+
+    opf
+}
+
+/// The JSON `--sidecar`: the package metadata plus series and cover
+#[derive(Serialize)]
+struct MetadataSidecar<'a> {
+    #[serde(flatten)]
+    meta: &'a PackageMetadata,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    series: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    series_index: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cover: Option<String>,
+}
+
+/// With `--sidecar`, write an output's metadata to a file next to it.
+/// `series` is (series name, number in series); json and nfo sidecars also
+/// save `cover` beside the output and refer to it by file name.
+fn write_metadata_sidecar(
+    path: &std::path::Path,
+    meta: &PackageMetadata,
+    format: SidecarFormat,
+    series: Option<(&str, usize)>,
+    cover: Option<&[u8]>,
+) -> Result<()> {
+    let cover = match (format, cover) {
+        (SidecarFormat::Json | SidecarFormat::Nfo, Some(data)) => write_cover_sidecar(path, data)?,
+        _ => None,
+    };
+    let (sidecar, contents) = match format {
+        SidecarFormat::Opf => (path.with_extension("opf"), calibre_metadata_opf(meta, None, false)),
+        SidecarFormat::Json => {
+            let sidecar = MetadataSidecar {
+                meta,
+                series: series.map(|(name, _)| name),
+                series_index: series.map(|(_, index)| index),
+                cover,
+            };
+            let json = serde_json::to_string_pretty(&sidecar).context("Failed to serialize metadata")?;
+            (path.with_extension("json"), json + "\n")
+        }
+        SidecarFormat::Nfo => (path.with_extension("nfo"), metadata_nfo(meta, series, cover.as_deref())),
+    };
+    std::fs::write(&sidecar, contents).with_context(|| format!("Failed to write {}", sidecar.display()))
+}
+
+/// Save an output's cover as `<output>-cover.<ext>` next to it, returning
+/// the file name, or None if the image format isn't recognised
+fn write_cover_sidecar(path: &std::path::Path, data: &[u8]) -> Result<Option<String>> {
+    let Some(extension) = image::guess_format(data).ok().and_then(|format| format.extensions_str().first()) else {
+        warn!("Cover of {} is in an unknown image format; not saving it", path.display());
+        return Ok(None);
+    };
+    let stem = path.file_stem().map(|stem| stem.to_string_lossy()).unwrap_or_default();
+    let name = format!("{}-cover.{}", stem, extension);
+    let cover_path = path.with_file_name(&name);
+    std::fs::write(&cover_path, data).with_context(|| format!("Failed to write {}", cover_path.display()))?;
+    Ok(Some(name))
+}
+
+/// A Kodi-style `.nfo` for an output, as Jellyfin and Plex agents read
+fn metadata_nfo(meta: &PackageMetadata, series: Option<(&str, usize)>, cover: Option<&str>) -> String {
+    let escape = SplitEpub::escape_xml;
+    let mut nfo = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"yes\"?>\n<book>\n");
+    nfo.push_str(&format!("  <title>{}</title>\n", escape(&meta.title)));
+    if let Some(sort) = &meta.title_sort {
+        nfo.push_str(&format!("  <sorttitle>{}</sorttitle>\n", escape(sort)));
+    }
+    for author in &meta.authors {
+        nfo.push_str(&format!("  <author>{}</author>\n", escape(author)));
+    }
+    if let Some((name, index)) = series {
+        nfo.push_str(&format!("  <series>{}</series>\n", escape(name)));
+        nfo.push_str(&format!("  <seriesindex>{}</seriesindex>\n", index));
+    }
+    if !meta.description.is_empty() {
+        nfo.push_str(&format!("  <plot>{}</plot>\n", escape(&meta.description)));
+    }
+    for tag in &meta.tags {
+        nfo.push_str(&format!("  <genre>{}</genre>\n", escape(tag)));
+    }
+    for lang in &meta.languages {
+        nfo.push_str(&format!("  <language>{}</language>\n", escape(lang)));
+    }
+    let (id_type, id_value) = match onix_product_id(&meta.unique_id) {
+        ("01", id) if id.starts_with("urn:uuid:") => ("uuid", id),
+        ("01", id) => ("epub", id),
+        (_, isbn) => ("isbn", isbn),
+    };
+    nfo.push_str(&format!(
+        "  <uniqueid type=\"{}\" default=\"true\">{}</uniqueid>\n",
+        id_type,
+        escape(&id_value)
+    ));
+    if let Some(cover) = cover {
+        nfo.push_str(&format!("  <thumb aspect=\"poster\">{}</thumb>\n", escape(cover)));
+    }
+    nfo.push_str("</book>\n");
+    nfo
+}
+
+fn run(cli: Cli, style: Style, summary: &SummaryRecorder, stats: &StatsRecorder) -> Result<()> {
+    debug!("CLI arguments: {:?}", cli);
+    let load_config = || if cli.no_config { Ok(Config::default()) } else { Config::load() };
+
+    match cli.command {
+        Commands::Split {
+            input,
+            mut lines,
+            lines_file,
+            containing,
+            show_resources,
+            legacy_lines,
+            filter,
+            from,
+            limit,
+            tree,
+            brief,
+            format,
+            export_previews,
+            preview_words,
+            skip_boilerplate,
+            no_skip_boilerplate,
+            dedupe,
+            no_dedupe,
+            drop_orphans,
+            no_drop_orphans,
+            include_all_resources,
+            include_file,
+            exclude_resource,
+            keep_excluded_refs,
+            output,
+            date_from,
+            ascii_names,
+            output_dir,
+            split_by_section,
+            title,
+            number_parts,
+            title_for,
+            description,
+            author,
+            contributor,
+            title_sort,
+            author_sort,
+            tag,
+            no_inherit_tags,
+            tag_for,
+            language,
+            cover,
+            generate_cover,
+            cover_size,
+            strict_cover,
+            no_cover,
+            plan,
+            volume,
+            emit_plan,
+            optimize_images,
+            strip_scripts,
+            no_strip_scripts,
+            strip_remote,
+            no_strip_remote,
+            fetch_remote,
+            minify,
+            no_minify,
+            normalize_unicode,
+            no_normalize_unicode,
+            punctuation,
+            strip_soft_hyphens,
+            no_strip_soft_hyphens,
+            generate_a11y,
+            no_generate_a11y,
+            checksums,
+            onix,
+            no_onix,
+            sidecar,
+            calibre_library,
+            calibredb,
+            detect_language,
+            no_detect_language,
+            include_nonlinear,
+            no_include_nonlinear,
+            flatten,
+            keep_layout,
+            ascii_filenames,
+            resume,
+            summary_json,
+            stats: collect_stats,
+            post_hook,
+            keep_uid,
+            identifiers_file,
+            keep_order,
+            rename_toc,
+        } => {
+            if let Some(path) = summary_json {
+                summary.start(path, &input);
+            }
+            if collect_stats {
+                stats.start();
+            }
+
+            let config = load_config()?;
+            let output = output
+                .or_else(|| config.output.clone())
+                .unwrap_or_else(|| "split.epub".to_string());
+            info!("Output filename: {}", ensure_epub_extension(&output));
+
+            if let Some(lines_file) = lines_file {
+                lines.extend(read_lines_file(&lines_file)?);
+            }
+
+            // Load the EPUB file
+            let parse_started = std::time::Instant::now();
+            let mut epub = open_input(input.clone(), stats)?;
+            stats.record(|stats| stats.parse += parse_started.elapsed());
+
+            if format == Some(ListFormat::Ndjson)
+                && lines.is_empty()
+                && containing.is_empty()
+                && plan.is_none()
+                && volume.is_empty()
+                && !split_by_section
+            {
+                // Mode: Stream split points without reading the whole spine first
+                return stream_ndjson_lines(&mut epub, filter.as_ref(), from, limit, style);
+            }
+
+            // Get available split points
+            let scan_started = std::time::Instant::now();
+            let split_lines = epub
+                .get_split_lines()
+                .context("Failed to extract split points from EPUB")?;
+            stats.record(|stats| stats.scan += scan_started.elapsed());
+            let rest = lines.contains(&LineSelector::Unclaimed);
+            if rest && !split_by_section {
+                bail!(Failure::new(FailureKind::InvalidArgs, "'rest' only applies with --split-by-section"));
+            }
+            let mut lines = resolve_line_selectors(&split_lines, &lines)?;
+            for pattern in &containing {
+                for index in sections_containing(&mut epub, &split_lines, pattern)? {
+                    if !lines.contains(&index) {
+                        lines.push(index);
+                    }
+                }
+            }
+
+            let mut opts = RunOptions {
+                build: OutputOptions {
+                    output,
+                    date_from,
+                    ascii_names,
+                    title,
+                    title_for: title_for.into_iter().collect(),
+                    description,
+                    author,
+                    contributor,
+                    title_sort,
+                    author_sort,
+                    tag,
+                    no_inherit_tags,
+                    tag_for: tag_for.into_iter().fold(BTreeMap::new(), |mut tags, (number, tag)| {
+                        tags.entry(number).or_insert_with(Vec::new).push(tag);
+                        tags
+                    }),
+                    language,
+                    cover: None,
+                    generate_cover,
+                    cover_size,
+                    strict_cover,
+                    no_cover,
+                    skip_boilerplate,
+                    dedupe,
+                    drop_orphans,
+                    include_all_resources,
+                    include_file,
+                    exclude_resource,
+                    keep_excluded_refs,
+                    part: None,
+                    part_count: None,
+                    number_parts,
+                    optimize_images,
+                    strip_scripts,
+                    strip_remote,
+                    fetch_remote: fetch_remote.then(remote_fetcher),
+                    minify,
+                    transforms: ContentTransforms::default(),
+                    normalize_unicode,
+                    punctuation,
+                    strip_soft_hyphens,
+                    generate_a11y,
+                    checksums,
+                    onix,
+                    sidecar,
+                    calibredb,
+                    detect_language,
+                    include_nonlinear,
+                    flatten,
+                    ascii_filenames,
+                    resume,
+                    post_hook,
+                    keep_uid,
+                    identifiers: identifiers_file.map(|path| read_identifiers_file(&path)).transpose()?.unwrap_or_default(),
+                    keep_order,
+                    rename_toc: rename_toc.map(|path| read_toc_renames(&path)).transpose()?.unwrap_or_default(),
+                },
+                output_dir,
+                cover: cover.map(CoverInput::Path),
+                emit_plan,
+                calibre_library,
+                style,
+                summary: summary.clone(),
+                stats: stats.clone(),
+            };
+
+            config.apply(&mut opts)?;
+            // --no-<switch> turns off what the config turned on
+            for (value, off) in [
+                (&mut opts.build.dedupe, no_dedupe),
+                (&mut opts.build.drop_orphans, no_drop_orphans),
+                (&mut opts.build.skip_boilerplate, no_skip_boilerplate),
+                (&mut opts.build.include_nonlinear, no_include_nonlinear),
+                (&mut opts.build.detect_language, no_detect_language),
+                (&mut opts.build.strip_scripts, no_strip_scripts),
+                (&mut opts.build.strip_remote, no_strip_remote),
+                (&mut opts.build.minify, no_minify),
+                (&mut opts.build.normalize_unicode, no_normalize_unicode),
+                (&mut opts.build.strip_soft_hyphens, no_strip_soft_hyphens),
+                (&mut opts.build.generate_a11y, no_generate_a11y),
+                (&mut opts.build.onix, no_onix),
+            ] {
+                if off {
+                    *value = false;
+                }
+            }
+            if keep_layout {
+                opts.build.flatten = false;
+                opts.build.ascii_filenames = false;
+            }
+            opts.build.language = default_languages(&opts.build.language, epub.get_orig_languages());
+            if opts.build.output.contains("{date") {
+                opts.build.output = name_dates(&epub, opts.build.date_from).fill(&opts.build.output);
+            }
+
+            if let Some(plan) = plan {
+                // Mode: Split according to a plan file
+                split_by_plan(&mut epub, &plan, &opts)?;
+            } else if !volume.is_empty() {
+                // Mode: Split into the volumes given
+                split_by_volumes(&mut epub, &volume, &opts)?;
+            } else if split_by_section {
+                // Mode: Split into separate files per section
+                let indices = if lines.is_empty() {
+                    (0..split_lines.len()).collect::<Vec<_>>()
+                } else {
+                    lines
+                };
+                let rest = if rest {
+                    let rest = unclaimed_sections(&split_lines, &indices);
+                    let rest = drop_boilerplate(&mut epub, &split_lines, rest, &opts)?;
+                    drop_duplicates(&mut epub, &split_lines, rest, &opts)?
+                } else {
+                    Vec::new()
+                };
+                let indices = drop_boilerplate(&mut epub, &split_lines, indices, &opts)?;
+                let indices = drop_duplicates(&mut epub, &split_lines, indices, &opts)?;
+                split_by_section_fn(&mut epub, &split_lines, &indices, &rest, &opts)?;
+            } else if lines.is_empty() {
+                // Mode: List available split points
+                if let Some(format) = legacy_lines {
+                    return print_legacy_lines(&split_lines, format, style);
+                }
+                let resources = if show_resources {
+                    Some(epub.section_resources(&split_lines)?)
+                } else {
+                    None
+                };
+                let mut shown: Vec<usize> = match &filter {
+                    Some(filter) => {
+                        let shown = filter_split_lines(&split_lines, filter);
+                        if shown.is_empty() {
+                            warn!("No split lines match '{}'", filter);
+                        }
+                        shown
+                    }
+                    None => (0..split_lines.len()).collect(),
+                };
+                if let Some(from) = from {
+                    shown.retain(|&index| index >= from);
+                }
+                // Lines past --limit, and where to pick up from to see them
+                let rest = match limit {
+                    Some(limit) if shown.len() > limit => {
+                        let rest = (shown.len() - limit, shown[limit]);
+                        shown.truncate(limit);
+                        Some(rest)
+                    }
+                    _ => None,
+                };
+                if let Some(path) = export_previews {
+                    return export_section_previews(&mut epub, &split_lines, &shown, preview_words, &path, style);
+                }
+                if cli.porcelain {
+                    print_porcelain_lines(&split_lines, &shown);
+                    return Ok(());
+                }
+                if brief {
+                    print_brief_lines(&split_lines, &shown, style);
+                    return Ok(());
+                }
+                if format == Some(ListFormat::Csv) {
+                    let word_counts = epub.section_word_counts(&split_lines)?;
+                    print_csv_lines(&split_lines, &shown, &word_counts, style);
+                    return Ok(());
+                }
+                if tree {
+                    print_toc_tree(&epub, &split_lines, &shown, style);
+                } else {
+                    let word_counts = epub.section_word_counts(&split_lines)?;
+                    // Only the table marks duplicates, and finding them reads every section
+                    let duplicates = epub.find_duplicate_sections(&split_lines)?;
+                    list_split_points(
+                        &split_lines,
+                        &shown,
+                        &word_counts,
+                        &duplicates,
+                        resources.as_ref(),
+                        style,
+                    )?;
+                }
+                if let Some((remaining, next)) = rest {
+                    say!(style, "{}", style.dim(&format!("... {} more (--from {})", remaining, next)));
+                }
+            } else {
+                // Mode: Extract specific sections into one file
+                let lines = drop_boilerplate(&mut epub, &split_lines, lines, &opts)?;
+                if lines.is_empty() {
+                    bail!("All selected sections look like boilerplate");
+                }
+                // Always keeps one section of each group, so never empties the selection
+                let lines = drop_duplicates(&mut epub, &split_lines, lines, &opts)?;
+                extract_sections(&mut epub, &lines, &opts)?;
+            }
+        }
+        Commands::Merge {
+            inputs,
+            output,
+            title,
+            description,
+            author,
+            contributor,
+            tag,
+            language,
+            cover,
+            optimize_images,
+            strip_scripts,
+            no_strip_scripts,
+            strip_remote,
+            no_strip_remote,
+            fetch_remote,
+            minify,
+            no_minify,
+            normalize_unicode,
+            no_normalize_unicode,
+            punctuation,
+            strip_soft_hyphens,
+            no_strip_soft_hyphens,
+            generate_a11y,
+            no_generate_a11y,
+            checksums,
+            onix,
+            no_onix,
+            sidecar,
+            stats: collect_stats,
+        } => {
+            if collect_stats {
+                stats.start();
+            }
+            let mut opts = RunOptions {
+                build: OutputOptions {
+                    output,
+                    date_from: DateSource::Now,
+                    ascii_names: false,
+                    title,
+                    title_for: BTreeMap::new(),
+                    description,
+                    author,
+                    contributor,
+                    title_sort: None,
+                    author_sort: Vec::new(),
+                    tag,
+                    no_inherit_tags: false,
+                    tag_for: BTreeMap::new(),
+                    language,
+                    cover: None,
+                    generate_cover: None,
+                    cover_size: None,
+                    strict_cover: false,
+                    no_cover: false,
+                    skip_boilerplate: false,
+                    dedupe: false,
+                    drop_orphans: false,
+                    include_all_resources: None,
+                    include_file: Vec::new(),
+                    exclude_resource: Vec::new(),
+                    keep_excluded_refs: false,
+                    part: None,
+                    part_count: None,
+                    number_parts: None,
+                    optimize_images,
+                    strip_scripts,
+                    strip_remote,
+                    fetch_remote: fetch_remote.then(remote_fetcher),
+                    minify,
+                    transforms: ContentTransforms::default(),
+                    normalize_unicode,
+                    punctuation,
+                    strip_soft_hyphens,
+                    generate_a11y,
+                    checksums,
+                    onix,
+                    sidecar,
+                    calibredb: false,
+                    detect_language: false,
+                    include_nonlinear: false,
+                    flatten: false,
+                    ascii_filenames: false,
+                    resume: false,
+                    post_hook: None,
+                    keep_uid: false,
+                    identifiers: Vec::new(),
+                    keep_order: false,
+                    rename_toc: TocRenames::default(),
+                },
+                cover: cover.map(CoverInput::Path),
+                style,
+                summary: summary.clone(),
+                stats: stats.clone(),
+                ..RunOptions::default()
+            };
+            load_config()?.apply(&mut opts)?;
+            // --no-<switch> turns off what the config turned on
+            for (value, off) in [
+                (&mut opts.build.strip_scripts, no_strip_scripts),
+                (&mut opts.build.strip_remote, no_strip_remote),
+                (&mut opts.build.minify, no_minify),
+                (&mut opts.build.normalize_unicode, no_normalize_unicode),
+                (&mut opts.build.strip_soft_hyphens, no_strip_soft_hyphens),
+                (&mut opts.build.generate_a11y, no_generate_a11y),
+                (&mut opts.build.onix, no_onix),
+            ] {
+                if off {
+                    *value = false;
+                }
+            }
+            opts.build.output = NameDates::now().fill(&opts.build.output);
+
+            merge_epubs(&inputs, &opts)?;
+        }
+        Commands::Grep {
+            input,
+            pattern,
+            ignore_case,
+            context,
+        } => {
+            let pattern = regex::RegexBuilder::new(pattern.as_str())
+                .case_insensitive(ignore_case)
+                .build()
+                .context("Failed to compile search pattern")?;
+            let mut epub = open_epub(input.clone())?;
+            grep_sections(&mut epub, &pattern, context, style)?;
+        }
+        Commands::Stats { input, json } => {
+            let mut epub = open_epub(input.clone())?;
+            let stats = book_stats(&mut epub)?;
+            if json {
+                say!(style, "{}", serde_json::to_string_pretty(&stats).context("Failed to serialize stats")?);
+            } else {
+                print_book_stats(&stats, style);
+            }
+        }
+        Commands::Audit { input } => {
+            let mut epub = open_epub(input.clone())?;
+            print_audit(&mut epub, style)?;
+        }
+        #[cfg(not(target_arch = "wasm32"))]
+        Commands::Watch {
+            dir,
+            plan,
+            output_dir,
+            existing,
+        } => {
+            let mut opts = RunOptions {
+                output_dir: Some(output_dir),
+                style,
+                summary: summary.clone(),
+                stats: stats.clone(),
+                ..RunOptions::default()
+            };
+            load_config()?.apply(&mut opts)?;
+            watch_folder(&dir, plan.as_ref(), &opts, existing)?;
+        }
+        #[cfg(feature = "serve")]
+        Commands::Serve { addr } => {
+            serve::run_server(&addr, style)?;
+        }
+        Commands::Completions { shell } => {
+            let mut command = Cli::command();
+            let name = command.get_name().to_string();
+            clap_complete::generate(shell, &mut command, name, &mut std::io::stdout());
+        }
+        Commands::Schema { format } => {
+            let schema = match format {
+                SchemaFormat::Plan => schemars::schema_for!(Plan),
+                SchemaFormat::Summary => schemars::schema_for!(RunSummary),
+            };
+            say!(style, "{}", serde_json::to_string_pretty(&schema).context("Failed to serialize schema")?);
+        }
+        Commands::Verify { input } => {
+            verify_checksums(&input, style)?;
+        }
+        Commands::Diff { old, new } => {
+            let mut old_epub = open_epub(old.clone())?;
+            let mut new_epub = open_epub(new.clone())?;
+            diff_epubs(&mut old_epub, &mut new_epub, style)?;
+        }
+        Commands::Toc {
+            input,
+            output,
+            rename,
+            nest,
+            unnest,
+            remove,
+            add,
+        } => {
+            let mut epub = open_epub(input.clone())?;
+            let edits = TocEdits {
+                rename,
+                nest,
+                unnest,
+                remove,
+                add,
+            };
+            if edits.is_empty() {
+                print_toc_entries(&mut epub, style)?;
+            } else {
+                let output = output.unwrap_or_else(|| {
+                    let stem = input.file_stem().unwrap_or_default().to_string_lossy();
+                    input.with_file_name(format!("{}-toc.epub", stem))
+                });
+                edit_toc(&mut epub, &edits, &output)?;
+                say!(style, "Wrote {}", output.display());
+            }
+        }
+        Commands::Reorder { input, items, output } => {
+            let mut epub = open_epub(input.clone())?;
+            let output = output.unwrap_or_else(|| {
+                let stem = input.file_stem().unwrap_or_default().to_string_lossy();
+                input.with_file_name(format!("{}-reordered.epub", stem))
+            });
+            reorder_spine(&mut epub, &items, &output)?;
+            say!(style, "Wrote {}", output.display());
+        }
+    }
+
+    Ok(())
+}
+
+/// Tag an error from one output of a batch when earlier outputs were
+/// already written
+fn batch_error(error: anyhow::Error, written: usize, total: usize) -> anyhow::Error {
+    if written == 0 {
+        return error;
+    }
+    error.context(Failure::new(
+        FailureKind::PartialBatch,
+        format!("Stopped after writing {} of {} outputs", written, total),
+    ))
+}
+
+/// Set up logging from -v/-q (RUST_LOG still wins for the console), plus a
+/// full debug log in --log-file, both in the --log-format
+fn init_logging(cli: &Cli, summary: &SummaryRecorder) -> Result<()> {
+    use std::io::IsTerminal;
+    use tracing_subscriber::filter::{EnvFilter, LevelFilter};
+    use tracing_subscriber::layer::SubscriberExt;
+    use tracing_subscriber::util::SubscriberInitExt;
+    use tracing_subscriber::{fmt, Layer, Registry};
+
+    let console_level = match (cli.quiet, cli.verbose) {
+        (true, _) => "error",
+        (false, 0) => "warn",
+        (false, 1) => "info",
+        (false, 2) => "debug",
+        (false, _) => "trace",
+    };
+    let console_filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(console_level));
+    let console = fmt::layer()
+        .with_writer(std::io::stderr)
+        .with_ansi(!cli.no_color && std::io::stderr().is_terminal());
+
+    let mut layers: Vec<Box<dyn Layer<Registry> + Send + Sync>> = vec![match cli.log_format {
+        LogFormat::Text => console.with_filter(console_filter).boxed(),
+        LogFormat::Json => console.json().with_filter(console_filter).boxed(),
+    }];
+
+    if let Some(path) = &cli.log_file {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open log file: {}", path.display()))?;
+        let file_layer = fmt::layer().with_ansi(false).with_writer(std::sync::Mutex::new(file));
+        layers.push(match cli.log_format {
+            LogFormat::Text => file_layer.with_filter(LevelFilter::DEBUG).boxed(),
+            LogFormat::Json => file_layer.json().with_filter(LevelFilter::DEBUG).boxed(),
+        });
+    }
+
+    layers.push(WarningCollector(summary.clone()).with_filter(LevelFilter::WARN).boxed());
+
+    tracing_subscriber::registry()
+        .with(layers)
+        .try_init()
+        .context("Failed to set up logging")?;
+    Ok(())
+}
+
+/// Entry point of the `epubtool` binary
+pub fn run_cli() -> std::process::ExitCode {
+    let cli = Cli::parse();
+    let style = Style::detect(cli.no_color, cli.porcelain);
+    let summary = SummaryRecorder::default();
+    let stats = StatsRecorder::default();
+
+    let result = init_logging(&cli, &summary).and_then(|()| run(cli, style, &summary, &stats));
+    if let Err(e) = summary.finish(result.as_ref().err()) {
+        eprintln!("Error: {:?}", e);
+    }
+    print_run_stats(&stats, style);
+    if let Err(e) = result {
+        eprintln!("Error: {:?}", e);
+        return std::process::ExitCode::from(exit_code(&e));
+    }
+    std::process::ExitCode::SUCCESS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::tests::test_epub;
+    use image::RgbImage;
+
+    #[test]
+    fn compilations_get_the_plan_cover_and_resume_like_other_outputs() {
+        let dir = std::env::temp_dir().join(format!("epubsplit-compilation-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("book.epub"), test_epub("3.0", "", "<p>Two</p>")).unwrap();
+        std::fs::write(dir.join("other.epub"), test_epub("3.0", "", "<p>Other</p>")).unwrap();
+        let mut cover = Vec::new();
+        DynamicImage::ImageRgb8(RgbImage::new(4, 6))
+            .write_to(&mut std::io::Cursor::new(&mut cover), ImageFormat::Png)
+            .unwrap();
+        std::fs::write(dir.join("cover.png"), cover).unwrap();
+        let plan: Plan = serde_json::from_value(serde_json::json!({
+            "outputs": [{
+                "output": "best.epub",
+                "cover": dir.join("cover.png"),
+                "parts": [{ "sections": [0] }, { "source": "other.epub", "sections": [1] }],
+            }]
+        }))
+        .unwrap();
+        let opts = RunOptions {
+            build: OutputOptions { resume: true, ..OutputOptions::default() },
+            output_dir: Some(dir.clone()),
+            ..RunOptions::default()
+        };
+
+        let mut epub = open_epub(dir.join("book.epub")).unwrap();
+        write_plan_outputs(&mut epub, &plan, &dir, &opts).unwrap();
+        let mut archive = ZipArchive::new(File::open(dir.join("best.epub")).unwrap()).unwrap();
+        let mut opf = String::new();
+        archive.by_name("content.opf").unwrap().read_to_string(&mut opf).unwrap();
+        let state = std::fs::read_to_string(dir.join(RESUME_STATE_FILE)).unwrap();
+        let written = std::fs::metadata(dir.join("best.epub")).unwrap().modified().unwrap();
+
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        write_plan_outputs(&mut epub, &plan, &dir, &opts).unwrap();
+        let rewritten = std::fs::metadata(dir.join("best.epub")).unwrap().modified().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let cover_id = Regex::new(r#"<meta name="cover" content="([^"]+)"/>"#).unwrap().captures(&opf).unwrap()[1].to_string();
+        let cover_item = opf.lines().find(|line| line.contains(&format!(r#"id="{}""#, cover_id))).unwrap();
+        assert!(cover_item.contains(r#"href="epub0_cover."#) && cover_item.contains(r#"properties="cover-image""#));
+        assert!(opf.contains(r#"<item id="content0" href="epub0_cover.xhtml""#));
+        assert!(opf.contains(r#"<itemref idref="content0" linear="yes"/>"#));
+        assert!(opf.contains(r#"href="epub1_OEBPS_Text_ch2.xhtml""#));
+        assert!(state.contains("best.epub"));
+        assert_eq!(written, rewritten);
+    }
+
+    #[test]
+    fn plan_covers_resolve_against_the_plan_and_keep_their_format() {
+        let dir = std::env::temp_dir().join(format!("epubsplit-plan-cover-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut cover = Vec::new();
+        DynamicImage::ImageRgb8(RgbImage::new(4, 6))
+            .write_to(&mut std::io::Cursor::new(&mut cover), ImageFormat::Png)
+            .unwrap();
+        std::fs::write(dir.join("front.png"), &cover).unwrap();
+        let plan: Plan = serde_json::from_value(serde_json::json!({
+            "outputs": [{ "output": "one.epub", "sections": [0], "cover": "front.png" }]
+        }))
+        .unwrap();
+        let opts = RunOptions { output_dir: Some(dir.clone()), ..RunOptions::default() };
+
+        std::fs::write(dir.join("book.epub"), test_epub("3.0", "", "<p>Two</p>")).unwrap();
+        let mut epub = open_epub(dir.join("book.epub")).unwrap();
+        write_plan_outputs(&mut epub, &plan, &dir, &opts).unwrap();
+        let mut archive = ZipArchive::new(File::open(dir.join("one.epub")).unwrap()).unwrap();
+        let mut opf = String::new();
+        archive.by_name("content.opf").unwrap().read_to_string(&mut opf).unwrap();
+        let mut copied = Vec::new();
+        archive.by_name("cover.png").unwrap().read_to_end(&mut copied).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(opf.contains(r#"href="cover.png" media-type="image/png""#));
+        assert_eq!(copied, cover);
+    }
+
+    #[test]
+    fn stats_count_reads_of_the_input_not_of_outputs() {
+        let dir = std::env::temp_dir().join(format!("epubsplit-stats-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("book.epub"), test_epub("3.0", "", "<p>Two</p>")).unwrap();
+        let plan: Plan = serde_json::from_value(serde_json::json!({
+            "outputs": [{ "output": "one.epub", "sections": [0, 1] }]
+        }))
+        .unwrap();
+        let read = |build: OutputOptions| {
+            let stats = StatsRecorder::default();
+            stats.start();
+            let opts = RunOptions { build, output_dir: Some(dir.clone()), stats: stats.clone(), ..RunOptions::default() };
+            let mut epub = open_input(dir.join("book.epub"), &stats).unwrap();
+            write_plan_outputs(&mut epub, &plan, &dir, &opts).unwrap();
+            let read = stats.lock().as_ref().unwrap().read.load(Ordering::Relaxed);
+            read
+        };
+
+        let plain = read(OutputOptions::default());
+        let rereading = read(OutputOptions { checksums: Some(ChecksumMode::Sidecar), onix: true, ..OutputOptions::default() });
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(plain > 0);
+        assert_eq!(plain, rereading);
+    }
+
+    #[test]
+    fn watched_arrivals_are_counted_in_the_run_stats() {
+        let dir = std::env::temp_dir().join(format!("epubsplit-watch-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("out")).unwrap();
+        std::fs::write(dir.join("book.epub"), test_epub("3.0", "", "<p>Two</p>")).unwrap();
+        let stats = StatsRecorder::default();
+        stats.start();
+        let opts = RunOptions { output_dir: Some(dir.join("out")), stats: stats.clone(), ..RunOptions::default() };
+
+        split_arrival(&dir.join("book.epub"), None, &opts);
+        let written = std::fs::read_dir(dir.join("out")).map(|entries| entries.count()).unwrap_or(0);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(written > 0);
+        assert!(stats.lock().as_ref().unwrap().read.load(Ordering::Relaxed) > 0);
+    }
+
+    #[test]
+    fn spine_reorders_keep_itemref_attributes_and_unlisted_items() {
+        let spine: Vec<String> = ["a", "b", "c", "d"].map(String::from).to_vec();
+        let hrefs = ["Text/a.xhtml", "Text/b.xhtml", "Text/c.xhtml", "Text/d.xhtml"];
+        let order = |items: &[&str]| {
+            let items: Vec<String> = items.iter().map(|item| item.to_string()).collect();
+            spine_order(&spine, &hrefs, &items)
+        };
+        assert_eq!(order(&["d", "b"]).unwrap(), ["a", "d", "c", "b"]);
+        assert_eq!(order(&["c.xhtml", "a"]).unwrap(), ["c", "b", "a", "d"]);
+        assert!(order(&["b", "Text/b.xhtml"]).is_err());
+        assert!(order(&["e"]).is_err());
+
+        let opf = r#"<package><spine toc="ncx">
+    <itemref idref="a"/>
+    <itemref idref="b" linear="no"/>
+    <itemref idref="c" properties="page-spread-left"/>
+    <itemref idref="d"/>
+</spine></package>"#;
+        let reordered = reorder_spine_xml(opf, &order(&["c", "b"]).unwrap()).unwrap();
+        assert_eq!(
+            reordered,
+            r#"<package><spine toc="ncx">
+    <itemref idref="a"/>
+    <itemref idref="c" properties="page-spread-left"/>
+    <itemref idref="b" linear="no"/>
+    <itemref idref="d"/>
+</spine></package>"#
+        );
+    }
+
+    #[test]
+    fn list_diffs_show_insertions_deletions_and_moves() {
+        let diff = |old: &[&str], new: &[&str]| {
+            let old: Vec<String> = old.iter().map(|line| line.to_string()).collect();
+            let new: Vec<String> = new.iter().map(|line| line.to_string()).collect();
+            list_diff(&old, &new).iter().map(|(mark, line)| format!("{}{}", mark, line)).collect::<Vec<_>>()
+        };
+
+        assert_eq!(diff(&["a", "c"], &["a", "b", "c"]), [" a", "+b", " c"]);
+        assert_eq!(diff(&["a", "b", "c"], &["a", "c"]), [" a", "-b", " c"]);
+        assert_eq!(diff(&["a", "b", "c"], &["b", "c", "a"]), ["-a", " b", " c", "+a"]);
+        assert_eq!(diff(&[], &["a"]), ["+a"]);
+        assert_eq!(diff(&["a"], &[]), ["-a"]);
+        assert_eq!(diff(&["a", "b"], &["a", "b"]), [" a", " b"]);
+    }
+
+    #[test]
+    fn csv_rows_read_back_what_csv_field_wrote() {
+        let fields = ["plain", "Smith, John", "the \"best\" part", "\"", "", ",\",\""].map(String::from);
+        let row: Vec<String> = fields.iter().map(|field| csv_field(field)).collect();
+        assert_eq!(parse_csv_row(&row.join(",")), fields);
+        assert_eq!(parse_csv_row("a,\"b,c\",\"d \"\"e\"\"\""), ["a", "b,c", "d \"e\""]);
+    }
+
+    #[test]
+    fn config_switches_can_be_turned_off_from_the_command_line() {
+        let config: Config = toml::from_str("output = \"{title}.epub\"\nminify = true\nonix = true\n").unwrap();
+        assert_eq!(config.output.as_deref(), Some("{title}.epub"));
+
+        let cli = Cli::try_parse_from(["epubsplit", "split", "book.epub", "--no-minify", "--onix", "--no-onix", "--minify", "--no-minify"]).unwrap();
+        let Commands::Split { output, minify, no_minify, onix, no_onix, .. } = cli.command else {
+            panic!("not a split");
+        };
+        assert_eq!(output, None);
+        assert!(!minify && no_minify);
+        assert!(!onix && no_onix);
+
+        let mut opts = RunOptions::default();
+        config.apply(&mut opts).unwrap();
+        assert!(opts.build.minify && opts.build.onix);
+    }
+
+    #[test]
+    fn toc_edits_find_entries_that_earlier_edits_moved() {
+        let toc = |edits: TocEdits| {
+            let points = ["A", "B", "C", "D"].map(|title| NavPoint::new(title.to_string(), String::new())).to_vec();
+            let mut nodes = TocNode::from_points(points, &[]);
+            edits.rearrange(&mut nodes)?;
+            fn outline(nodes: &[TocNode]) -> String {
+                let entries: Vec<String> = nodes
+                    .iter()
+                    .map(|node| match node.children.is_empty() {
+                        true => node.point.text.clone(),
+                        false => format!("{}({})", node.point.text, outline(&node.children)),
+                    })
+                    .collect();
+                entries.join(" ")
+            }
+            Ok::<_, anyhow::Error>(outline(&nodes))
+        };
+        let at = |s: &str| s.parse::<TocPosition>().unwrap();
+        let edits = |nest: &[&str], unnest: &[&str], remove: &[&str]| TocEdits {
+            rename: Vec::new(),
+            nest: nest.iter().map(|s| at(s)).collect(),
+            unnest: unnest.iter().map(|s| at(s)).collect(),
+            remove: remove.iter().map(|s| at(s)).collect(),
+            add: Vec::new(),
+        };
+
+        assert_eq!(toc(edits(&["2", "3"], &[], &[])).unwrap(), "A(B C) D");
+        assert_eq!(toc(edits(&["2", "3"], &["2"], &[])).unwrap(), "A(C) B D");
+        assert_eq!(toc(edits(&["2", "3"], &[], &["1"])).unwrap(), "B C D");
+        assert_eq!(toc(edits(&["2", "3"], &["2"], &["1", "4"])).unwrap(), "C B");
+        assert!(toc(edits(&["1"], &[], &[])).is_err());
+        assert!(toc(edits(&[], &["2"], &[])).is_err());
+        assert!(toc(edits(&[], &[], &["5"])).is_err());
+    }
+
+    #[test]
+    fn lines_files_keep_hyphenated_selectors_whole() {
+        let path = std::env::temp_dir().join(format!("epubsplit-lines-{}.txt", std::process::id()));
+        std::fs::write(&path, "2-4, href:Text/chapter-12.xhtml # the ending\nid:chap-12 anchor:part-3\n").unwrap();
+        let lines = read_lines_file(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            lines.unwrap(),
+            vec![
+                LineSelector::Index(2),
+                LineSelector::Index(3),
+                LineSelector::Index(4),
+                LineSelector::Href("Text/chapter-12.xhtml".to_string()),
+                LineSelector::ManifestId("chap-12".to_string()),
+                LineSelector::Anchor("part-3".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn lines_files_accept_counting_from_the_end() {
+        let read = |name: &str, text: &str| {
+            let path = std::env::temp_dir().join(format!("epubsplit-{}-{}.txt", name, std::process::id()));
+            std::fs::write(&path, text).unwrap();
+            let lines = read_lines_file(&path);
+            std::fs::remove_file(&path).unwrap();
+            lines.unwrap()
+        };
+        let tail = |n| LineSelector::Tail(Box::new(LineSelector::FromEnd(n)));
+
+        assert_eq!(read("negative", "-1, -3..\n"), vec![LineSelector::FromEnd(1), tail(3)]);
+        assert_eq!(
+            read("negative-json", "[0, -1, \"-3..\"]"),
+            vec![LineSelector::Index(0), LineSelector::FromEnd(1), tail(3)]
+        );
+    }
+
+    #[test]
+    fn plans_find_sections_by_href_before_id() {
+        let line = |href: &str, title: &str| SplitLine {
+            toc: vec![title.to_string()],
+            guide: None,
+            anchor: None,
+            id: href.to_string(),
+            href: href.to_string(),
+            media_type: "application/xhtml+xml".to_string(),
+            linear: true,
+            sample: String::new(),
+        };
+        let lines = [line("ch1.xhtml", "One"), line("ch2.xhtml", "Two")];
+        let planned = planned_output(&lines, &[0, 1], "out.epub", "Out", &RunOptions::default());
+        assert!(planned.ids.is_empty());
+
+        // A retitled chapter keeps its href, so the plan still applies
+        let retitled = [line("ch1.xhtml", "One"), line("ch2.xhtml", "Chapter Two")];
+        assert_eq!(planned_sections(&retitled, &[], &planned.hrefs, &[], 1).unwrap(), vec![0, 1]);
+        // Given both, hrefs win over stale IDs
+        let ids = vec![lines[1].stable_id()];
+        assert_eq!(planned_sections(&retitled, &[], &["ch1.xhtml".to_string()], &ids, 1).unwrap(), vec![0]);
+        assert!(planned_sections(&retitled, &[], &[], &ids, 1).is_err());
+    }
+
+    #[test]
+    fn file_name_from_title_drops_unsafe_characters() {
+        assert_eq!(file_name_from_title("What/If?", false), "WhatIf");
+        assert_eq!(file_name_from_title(r#"A <b> "c" | d\e: f*"#, false), "A b c de f");
+        assert_eq!(file_name_from_title("Tab\there\u{7}", false), "Tabhere");
+    }
+
+    #[test]
+    fn file_name_from_title_tidies_whitespace_and_dots() {
+        assert_eq!(file_name_from_title("  Lots   of\n space  ", false), "Lots of space");
+        assert_eq!(file_name_from_title("To be continued...", false), "To be continued");
+        assert_eq!(file_name_from_title("?*?", false), "untitled");
+        assert_eq!(file_name_from_title("...", false), "untitled");
+    }
+
+    #[test]
+    fn file_name_from_title_avoids_reserved_windows_names() {
+        assert_eq!(file_name_from_title("con", false), "con_");
+        assert_eq!(file_name_from_title("LPT1.txt", false), "LPT1_.txt");
+        assert_eq!(file_name_from_title("Console", false), "Console");
+    }
+
+    #[test]
+    fn file_name_from_title_caps_length_on_character_boundaries() {
+        let name = file_name_from_title(&"é".repeat(200), false);
+        assert_eq!(name.len(), TITLE_NAME_LIMIT);
+        assert!(name.chars().all(|c| c == 'é'));
+
+        // Three bytes each, so the cap falls inside one
+        let name = file_name_from_title(&"北".repeat(100), false);
+        assert_eq!(name, "北".repeat(TITLE_NAME_LIMIT / 3));
+        assert!(file_name_from_title(&"🦀".repeat(100), false).len() <= TITLE_NAME_LIMIT);
+    }
+
+    #[test]
+    fn file_name_from_title_transliterates_when_asked() {
+        assert_eq!(file_name_from_title("Čapek: Válka s mloky", false), "Čapek Válka s mloky");
+        assert_eq!(file_name_from_title("Čapek: Válka s mloky", true), "Capek Valka s mloky");
+        assert!(file_name_from_title("北京", true).is_ascii());
+    }
+
+    #[test]
+    fn fill_title_placeholder_only_touches_title() {
+        assert_eq!(fill_title_placeholder("{title}.epub", "What/If?", false), "WhatIf.epub");
+        assert_eq!(fill_title_placeholder("split.epub", "What/If?", false), "split.epub");
+    }
+
+    fn sample_metadata() -> PackageMetadata {
+        PackageMetadata {
+            version: EpubVersion::Epub3,
+            unique_id: "urn:isbn:978-0-306-40615-7".to_string(),
+            title: "Tom & Jerry".to_string(),
+            authors: vec!["Ann Writer".to_string()],
+            description: String::new(),
+            tags: vec!["Comics".to_string()],
+            languages: vec!["en".to_string()],
+            accessibility: Vec::new(),
+            date: None,
+            modified: "2024-01-02T03:04:05Z".to_string(),
+            creators: vec![Creator::parse("Ben Drawer:ill")],
+            contributors: Vec::new(),
+            author_sort: HashMap::from([("Ann Writer".to_string(), "Writer, Ann".to_string())]),
+            title_sort: None,
+        }
+    }
+
+    #[test]
+    fn identifiers_files_skip_comments_and_must_cover_every_output() {
+        let path = std::env::temp_dir().join(format!("epubsplit-ids-{}.txt", std::process::id()));
+        std::fs::write(&path, "# ISBNs for the set\n978-0-306-40615-7\n\n  urn:uuid:1234  \n").unwrap();
+        let identifiers = read_identifiers_file(&path);
+        std::fs::write(&path, "# nothing yet\n").unwrap();
+        let empty = read_identifiers_file(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        let identifiers = identifiers.unwrap();
+        assert_eq!(identifiers, vec!["978-0-306-40615-7", "urn:uuid:1234"]);
+        assert!(empty.is_err());
+
+        let opts = OutputOptions { identifiers, ..OutputOptions::default() };
+        assert!(check_identifiers(&opts, 2).is_ok());
+        assert!(check_identifiers(&opts, 1).is_ok());
+        assert!(check_identifiers(&opts, 3).is_err());
+        assert!(check_identifiers(&OutputOptions::default(), 3).is_ok());
+        assert_eq!(output_identifier(&OutputOptions { part: Some(2), ..opts }).map(String::as_str), Some("urn:uuid:1234"));
+    }
+
+    #[test]
+    fn calibre_library_books_keep_going_without_an_unreadable_cover() {
+        let dir = std::env::temp_dir().join(format!("epubsplit-calibre-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("library")).unwrap();
+        std::fs::write(dir.join("out.epub"), test_epub("3.0", "", "<p>Two</p>")).unwrap();
+
+        let placed = add_to_calibre_library(
+            &dir.join("out.epub"),
+            &dir.join("library"),
+            &sample_metadata(),
+            Some(b"not an image"),
+            false,
+            Style::default(),
+        );
+        let placed = placed.unwrap();
+        let book = std::fs::read(&placed).ok();
+        let book_dir = placed.parent().unwrap();
+        let has_cover = book_dir.join("cover.jpg").exists();
+        let opf = std::fs::read_to_string(book_dir.join("metadata.opf")).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(book.is_some());
+        assert!(!has_cover);
+        assert!(!opf.contains("cover.jpg"));
+    }
+
+    #[test]
+    fn calibre_sidecars_leave_out_the_library_id_when_there_is_none() {
+        let opf = calibre_metadata_opf(&sample_metadata(), None, false);
+        assert!(!opf.contains("calibre_id"));
+        assert!(opf.contains(r#"<dc:identifier opf:scheme="ISBN">9780306406157</dc:identifier>"#));
+        assert!(opf.contains(r#"<dc:creator opf:role="aut" opf:file-as="Writer, Ann">Ann Writer</dc:creator>"#));
+        assert!(opf.contains(r#"<dc:creator opf:role="ill">Ben Drawer</dc:creator>"#));
+        assert!(opf.contains(r#"<meta name="calibre:title_sort" content="Tom &amp; Jerry"/>"#));
+        assert!(!opf.contains("<guide>"));
+
+        let opf = calibre_metadata_opf(&sample_metadata(), Some(42), true);
+        assert!(opf.contains(r#"<dc:identifier opf:scheme="calibre" id="calibre_id">42</dc:identifier>"#));
+        assert!(opf.contains(r#"href="cover.jpg""#));
+    }
+
+    #[test]
+    fn nfo_sidecars_carry_series_and_cover() {
+        let nfo = metadata_nfo(&sample_metadata(), Some(("Tom & Jerry Omnibus", 2)), Some("book.jpg"));
+        assert!(nfo.contains("<title>Tom &amp; Jerry</title>"));
+        assert!(nfo.contains("<author>Ann Writer</author>"));
+        assert!(!nfo.contains("Ben Drawer"));
+        assert!(nfo.contains("<series>Tom &amp; Jerry Omnibus</series>\n  <seriesindex>2</seriesindex>"));
+        assert!(nfo.contains("<genre>Comics</genre>"));
+        assert!(nfo.contains(r#"<uniqueid type="isbn" default="true">9780306406157</uniqueid>"#));
+        assert!(nfo.contains(r#"<thumb aspect="poster">book.jpg</thumb>"#));
+        assert!(!nfo.contains("<plot>"));
+
+        let nfo = metadata_nfo(&PackageMetadata { unique_id: "urn:uuid:1234".to_string(), ..sample_metadata() }, None, None);
+        assert!(nfo.contains(r#"<uniqueid type="uuid" default="true">urn:uuid:1234</uniqueid>"#));
+        assert!(!nfo.contains("<series>"));
+        assert!(!nfo.contains("<thumb"));
+    }
+
+    #[test]
+    fn onix_records_identify_isbns_and_languages() {
+        assert_eq!(onix_product_id("urn:isbn:978-0-306-40615-7"), ("15", "9780306406157".to_string()));
+        assert_eq!(onix_product_id("0-8044-2957-x"), ("02", "080442957X".to_string()));
+        assert_eq!(onix_product_id("urn:uuid:1234"), ("01", "urn:uuid:1234".to_string()));
+        assert_eq!(onix_language_code("fr-CA"), Some("fre"));
+        assert_eq!(onix_language_code("en"), Some("eng"));
+        assert_eq!(onix_language_code("x-klingon"), None);
+    }
+}
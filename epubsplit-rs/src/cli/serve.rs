@@ -0,0 +1,246 @@
+use super::*;
+use std::sync::atomic::AtomicBool;
+use std::time::{Duration, Instant};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+/// Largest EPUB accepted for upload
+const MAX_UPLOAD: u64 = 256 * 1024 * 1024;
+
+/// Most uploads kept at once; the least recently used goes first
+const MAX_UPLOADS: usize = 64;
+
+/// How long an upload is kept without being used
+const UPLOAD_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// How often the request loop checks whether it's been asked to stop
+const STOP_POLL: Duration = Duration::from_millis(500);
+
+/// Set by SIGINT and SIGTERM, so the upload folder is removed on the way out
+static STOPPING: AtomicBool = AtomicBool::new(false);
+
+/// Error carrying the HTTP status to answer with
+struct ApiError(u16, String);
+
+impl From<anyhow::Error> for ApiError {
+    fn from(e: anyhow::Error) -> Self {
+        let status = match e.downcast_ref::<Failure>().map(|failure| failure.kind) {
+            Some(FailureKind::InvalidArgs | FailureKind::OutOfRange) => 400,
+            _ => 422,
+        };
+        ApiError(status, format!("{:#}", e))
+    }
+}
+
+type Reply = std::result::Result<Response<std::io::Cursor<Vec<u8>>>, ApiError>;
+
+pub fn run_server(addr: &str, style: Style) -> Result<()> {
+    let server = Server::http(addr).map_err(|e| anyhow!("Failed to listen on {}: {}", addr, e))?;
+    let upload_dir = UploadDir::create()?;
+    stop_on_signals();
+    say!(style, "listening on http://{}", addr);
+
+    let mut books = Books {
+        uploads: HashMap::new(),
+        sessions: SessionCache::new(SESSION_BOOKS),
+    };
+    while !STOPPING.load(Ordering::Relaxed) {
+        let mut request = match server.recv_timeout(STOP_POLL) {
+            Ok(Some(request)) => request,
+            Ok(None) => continue,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e).context("Failed to receive request"),
+        };
+        books.expire();
+        let span = tracing::info_span!("request", method = %request.method(), url = request.url());
+        let _entered = span.enter();
+        info!("{} {}", request.method(), request.url());
+        let response = handle(&mut request, &mut books, &upload_dir.0).unwrap_or_else(|ApiError(status, message)| {
+            json_response(&serde_json::json!({ "error": message })).with_status_code(status)
+        });
+        if let Err(e) = request.respond(response) {
+            warn!("Failed to send response: {}", e);
+        }
+    }
+    say!(style, "stopping");
+    Ok(())
+}
+
+/// The server's temporary folder of uploads, removed when it stops
+struct UploadDir(PathBuf);
+
+impl UploadDir {
+    fn create() -> Result<Self> {
+        let path = std::env::temp_dir().join(format!("epubsplit-serve-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&path)
+            .with_context(|| format!("Failed to create upload directory: {}", path.display()))?;
+        Ok(UploadDir(path))
+    }
+}
+
+impl Drop for UploadDir {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_dir_all(&self.0) {
+            warn!("Failed to remove upload directory {}: {}", self.0.display(), e);
+        }
+    }
+}
+
+/// Have SIGINT and SIGTERM end the request loop rather than the process
+#[cfg(unix)]
+fn stop_on_signals() {
+    extern "C" fn stop(_: libc::c_int) {
+        STOPPING.store(true, Ordering::Relaxed);
+    }
+    let handler = stop as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    // SAFETY: the handler only stores to an atomic, which is async-signal-safe
+    unsafe {
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
+    }
+}
+
+#[cfg(not(unix))]
+fn stop_on_signals() {}
+
+/// Uploaded books, and the parsed ones kept between requests
+struct Books {
+    uploads: HashMap<String, Upload>,
+    sessions: SessionCache,
+}
+
+struct Upload {
+    path: PathBuf,
+    /// `SessionCache` key; identical uploads share one parsed book
+    key: String,
+    /// When the upload was last described or split
+    used: Instant,
+}
+
+impl Books {
+    /// The parsed book for an upload, reopened if it was dropped from
+    /// the session cache
+    fn open(&mut self, id: &str) -> std::result::Result<&mut SplitEpub, ApiError> {
+        let upload = self
+            .uploads
+            .get_mut(id)
+            .ok_or_else(|| ApiError(404, format!("No book with id {}", id)))?;
+        upload.used = Instant::now();
+        self.sessions
+            .get_or_open(&upload.key, || open_epub(upload.path.clone()))
+            .map_err(|e| ApiError(422, format!("Not a usable EPUB: {:#}", e)))
+    }
+
+    /// Keep an upload, making room by forgetting the least recently used
+    fn insert(&mut self, id: String, upload: Upload) {
+        while self.uploads.len() >= MAX_UPLOADS {
+            let Some(oldest) = self.uploads.iter().min_by_key(|(_, upload)| upload.used).map(|(id, _)| id.clone())
+            else {
+                break;
+            };
+            info!("Forgetting upload {} to make room", oldest);
+            self.forget(&oldest);
+        }
+        self.uploads.insert(id, upload);
+    }
+
+    /// Forget uploads that haven't been used for `UPLOAD_TTL`
+    fn expire(&mut self) {
+        let expired: Vec<String> = self
+            .uploads
+            .iter()
+            .filter(|(_, upload)| upload.used.elapsed() > UPLOAD_TTL)
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in expired {
+            info!("Upload {} expired", id);
+            self.forget(&id);
+        }
+    }
+
+    /// Drop an upload and its file, and its parsed book once no other
+    /// upload shares it
+    fn forget(&mut self, id: &str) -> Option<()> {
+        let upload = self.uploads.remove(id)?;
+        if !self.uploads.values().any(|other| other.key == upload.key) {
+            self.sessions.remove(&upload.key);
+        }
+        let _ = std::fs::remove_file(upload.path);
+        Some(())
+    }
+}
+
+fn handle(request: &mut Request, books: &mut Books, upload_dir: &std::path::Path) -> Reply {
+    let url = request.url().to_string();
+    let segments: Vec<&str> = url
+        .split('?')
+        .next()
+        .unwrap_or_default()
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .collect();
+    match (request.method(), segments.as_slice()) {
+        (Method::Post, ["books"]) => {
+            let data = read_body(request, MAX_UPLOAD)?;
+            let id = uuid::Uuid::new_v4().simple().to_string();
+            let path = upload_dir.join(format!("{}.epub", id));
+            let key = SessionCache::key(&data);
+            std::fs::write(&path, data).map_err(|e| ApiError(500, e.to_string()))?;
+            let upload = Upload { path, key, used: Instant::now() };
+            books.insert(id.clone(), upload);
+            match describe_book(books, &id) {
+                Ok(description) => Ok(json_response(&description).with_status_code(201)),
+                Err(e) => {
+                    books.forget(&id);
+                    Err(e)
+                }
+            }
+        }
+        (Method::Get, ["books", id]) => Ok(json_response(&describe_book(books, id)?)),
+        (Method::Post, ["books", id, "split"]) => {
+            let body = read_body(request, 1024 * 1024)?;
+            let split: SplitRequest =
+                serde_json::from_slice(&body).map_err(|e| ApiError(400, format!("Invalid split request: {}", e)))?;
+            let data = split_in_memory(books.open(id)?, &split)?;
+            Ok(Response::from_data(data)
+                .with_header(header("Content-Type", "application/epub+zip"))
+                .with_header(header("Content-Disposition", "attachment; filename=\"split.epub\"")))
+        }
+        (Method::Delete, ["books", id]) => {
+            books
+                .forget(id)
+                .ok_or_else(|| ApiError(404, format!("No book with id {}", id)))?;
+            Ok(Response::from_data(Vec::new()).with_status_code(204))
+        }
+        _ => Err(ApiError(404, format!("No route for {} {}", request.method(), url))),
+    }
+}
+
+fn read_body(request: &mut Request, limit: u64) -> std::result::Result<Vec<u8>, ApiError> {
+    if request.body_length().is_some_and(|len| len as u64 > limit) {
+        return Err(ApiError(413, format!("Request body is larger than {}", format_size(limit))));
+    }
+    let mut data = Vec::new();
+    request
+        .as_reader()
+        .take(limit + 1)
+        .read_to_end(&mut data)
+        .map_err(|e| ApiError(400, e.to_string()))?;
+    if data.len() as u64 > limit {
+        return Err(ApiError(413, format!("Request body is larger than {}", format_size(limit))));
+    }
+    Ok(data)
+}
+
+fn describe_book(books: &mut Books, id: &str) -> std::result::Result<serde_json::Value, ApiError> {
+    let mut description = describe_split_points(books.open(id)?)?;
+    description["id"] = serde_json::Value::from(id);
+    Ok(description)
+}
+
+fn json_response(value: &serde_json::Value) -> Response<std::io::Cursor<Vec<u8>>> {
+    Response::from_data(value.to_string().into_bytes()).with_header(header("Content-Type", "application/json"))
+}
+
+fn header(name: &str, value: &str) -> Header {
+    Header::from_bytes(name.as_bytes(), value.as_bytes()).expect("static header is valid")
+}
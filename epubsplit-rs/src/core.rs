@@ -0,0 +1,6698 @@
+use anyhow::{anyhow, bail, Context, Result};
+use ab_glyph::{point, Font, FontRef, PxScale, ScaleFont};
+use clap::ValueEnum;
+use deunicode::deunicode;
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::{CompressionType, FilterType as PngFilterType, PngEncoder};
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageFormat, Rgb, RgbImage};
+use tracing::{debug, info, instrument, warn};
+use unicode_normalization::UnicodeNormalization;
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
+use markup5ever::data::NAMED_ENTITIES;
+use quick_xml::events::{BytesText, Event};
+use quick_xml::reader::Reader;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use scraper::{Html, Selector};
+use sha1::{Digest, Sha1};
+use sha2::Sha256;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::io::{Read as IoRead, Seek, Write as IoWrite};
+use std::ops::ControlFlow;
+use std::path::PathBuf;
+use std::sync::Arc;
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+/// Where `{date}` and `{datetime}` in output names come from
+#[derive(Debug, Clone, Copy, Default, PartialEq, ValueEnum)]
+pub(crate) enum DateSource {
+    /// The time of the run, in UTC
+    #[default]
+    Now,
+    /// The source's dc:date, or the time of the run if it has none
+    Published,
+}
+
+/// What `--number-parts` adds "Part N of M" to
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub(crate) enum PartNumbering {
+    Title,
+    TitleAndDescription,
+}
+
+/// "Part N of M", with --number-parts when the run writes several outputs
+fn part_label(opts: &OutputOptions) -> Option<String> {
+    match (opts.number_parts, opts.part, opts.part_count) {
+        (Some(_), Some(part), Some(count)) if count > 1 => Some(format!("Part {} of {}", part, count)),
+        _ => None,
+    }
+}
+
+/// Common options for EPUB output
+#[derive(Debug, Clone, Default)]
+pub(crate) struct OutputOptions {
+    pub(crate) output: String,
+    pub(crate) date_from: DateSource,
+    pub(crate) ascii_names: bool,
+    pub(crate) title: Option<String>,
+    /// `--title-for`, by output number
+    pub(crate) title_for: BTreeMap<usize, String>,
+    pub(crate) description: Option<String>,
+    pub(crate) author: Vec<String>,
+    pub(crate) contributor: Vec<String>,
+    pub(crate) title_sort: Option<String>,
+    pub(crate) author_sort: Vec<String>,
+    pub(crate) tag: Vec<String>,
+    pub(crate) no_inherit_tags: bool,
+    /// `--tag-for`, by output number
+    pub(crate) tag_for: BTreeMap<usize, Vec<String>>,
+    pub(crate) language: Vec<String>,
+    pub(crate) cover: Option<CoverSource>,
+    pub(crate) generate_cover: Option<CoverStyle>,
+    pub(crate) cover_size: Option<CoverSize>,
+    pub(crate) strict_cover: bool,
+    pub(crate) no_cover: bool,
+    pub(crate) skip_boilerplate: bool,
+    pub(crate) dedupe: bool,
+    pub(crate) drop_orphans: bool,
+    pub(crate) include_all_resources: Option<ResourceScope>,
+    pub(crate) include_file: Vec<String>,
+    pub(crate) exclude_resource: Vec<String>,
+    pub(crate) keep_excluded_refs: bool,
+    /// Part number of this output when a run writes several
+    pub(crate) part: Option<usize>,
+    /// How many outputs the run writes, alongside `part`
+    pub(crate) part_count: Option<usize>,
+    pub(crate) number_parts: Option<PartNumbering>,
+    pub(crate) optimize_images: Option<ImageOptimization>,
+    pub(crate) strip_scripts: bool,
+    pub(crate) strip_remote: bool,
+    /// Downloads remote resources into the output in place of stripping
+    /// them, when set
+    pub(crate) fetch_remote: Option<RemoteFetcher>,
+    pub(crate) minify: bool,
+    /// Library-supplied rewrites, after the built-in ones
+    pub(crate) transforms: ContentTransforms,
+    pub(crate) normalize_unicode: bool,
+    pub(crate) punctuation: Option<Punctuation>,
+    pub(crate) strip_soft_hyphens: bool,
+    pub(crate) generate_a11y: bool,
+    pub(crate) checksums: Option<ChecksumMode>,
+    pub(crate) onix: bool,
+    pub(crate) sidecar: Option<SidecarFormat>,
+    pub(crate) calibredb: bool,
+    pub(crate) detect_language: bool,
+    pub(crate) include_nonlinear: bool,
+    pub(crate) flatten: bool,
+    pub(crate) ascii_filenames: bool,
+    pub(crate) resume: bool,
+    pub(crate) post_hook: Option<String>,
+    pub(crate) keep_uid: bool,
+    pub(crate) identifiers: Vec<String>,
+    pub(crate) keep_order: bool,
+    pub(crate) rename_toc: TocRenames,
+}
+
+/// Where an output's cover image comes from
+#[derive(Debug, Clone)]
+pub(crate) enum CoverSource {
+    /// The Nth (1-based) image referenced by the output's own sections
+    SectionImage(usize),
+    /// An image read into memory (JPEG, PNG, GIF or WebP); `name` is used
+    /// in messages
+    Image { name: String, data: Arc<Vec<u8>> },
+}
+
+/// Background for generated covers
+#[derive(Debug, Clone, Copy, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum CoverStyle {
+    Solid,
+    Gradient,
+}
+
+/// Which files `--include-all-resources` copies
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub(crate) enum ResourceScope {
+    /// Every manifest item outside the spine
+    Manifest,
+    /// Every file in the archive outside the spine
+    Archive,
+}
+
+/// Where `--checksums` records entry hashes
+#[derive(Debug, Clone, Copy, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum ChecksumMode {
+    /// In META-INF/checksums.sha256 inside the EPUB
+    Embed,
+    /// In <output>.sha256 next to the EPUB
+    Sidecar,
+}
+
+/// What `--sidecar` writes next to each output
+#[derive(Debug, Clone, Copy, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum SidecarFormat {
+    /// <output>.opf, in the metadata.opf form Calibre keeps with each book
+    Opf,
+    /// <output>.json
+    Json,
+    /// <output>.nfo, Kodi-style XML for self-hosted media libraries
+    Nfo,
+}
+
+/// Which way `--punctuation` converts quotes and dashes
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Punctuation {
+    /// ' " - and -- in place of curly quotes and en and em dashes
+    Straight,
+    /// Curly quotes and em dashes in place of ' " and --
+    Smart,
+}
+
+/// Remote (http/https) resources encountered while sanitizing copied content
+#[derive(Debug, Default)]
+struct RemoteResources {
+    /// URL -> archive path of the downloaded copy
+    fetched: HashMap<String, String>,
+    /// URLs that couldn't be downloaded and were stripped instead
+    failed: HashSet<String>,
+    /// Downloaded files to add to the output: (archive path, data, media type)
+    files: Vec<(String, Vec<u8>, String)>,
+}
+
+/// A downloaded resource: its bytes, and the media type the server gave if any
+pub(crate) type Download = (Vec<u8>, Option<String>);
+
+/// Downloads remote resources for `fetch_remote`. The caller supplies it, so
+/// reading and writing EPUBs needs no network access of its own; functions
+/// taking a URL implement it.
+pub(crate) trait FetchRemote: Send + Sync {
+    fn fetch(&self, url: &str) -> Result<Download>;
+}
+
+impl<F> FetchRemote for F
+where
+    F: Fn(&str) -> Result<Download> + Send + Sync,
+{
+    fn fetch(&self, url: &str) -> Result<Download> {
+        self(url)
+    }
+}
+
+/// The [`FetchRemote`] an output's remote resources are downloaded with
+#[derive(Clone)]
+pub(crate) struct RemoteFetcher(Arc<dyn FetchRemote>);
+
+impl RemoteFetcher {
+    pub(crate) fn new(fetch: impl FetchRemote + 'static) -> Self {
+        Self(Arc::new(fetch))
+    }
+}
+
+impl std::fmt::Debug for RemoteFetcher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("RemoteFetcher")
+    }
+}
+
+impl RemoteResources {
+    /// Download `url` into the package (once) and return its archive path
+    fn localize(&mut self, url: &str, fetcher: &RemoteFetcher) -> Option<String> {
+        if let Some(path) = self.fetched.get(url) {
+            return Some(path.clone());
+        }
+        if self.failed.contains(url) {
+            return None;
+        }
+
+        match fetcher.0.fetch(url) {
+            Ok((data, content_type)) => {
+                let media_type = content_type
+                    .filter(|t| !t.is_empty() && t != "application/octet-stream")
+                    .unwrap_or_else(|| guess_media_type_static(url.split(['?', '#']).next().unwrap_or(url)));
+                let mut hasher = Sha1::new();
+                hasher.update(url.as_bytes());
+                let digest: String = hasher
+                    .finalize()
+                    .iter()
+                    .take(8)
+                    .map(|b| format!("{:02x}", b))
+                    .collect();
+                let path = format!("remote/{}.{}", digest, extension_for_media_type(&media_type));
+
+                info!("Fetched remote resource {} -> {}", url, path);
+                self.fetched.insert(url.to_string(), path.clone());
+                self.files.push((path.clone(), data, media_type));
+                Some(path)
+            }
+            Err(e) => {
+                warn!("Failed to fetch remote resource {}, stripping it: {:#}", url, e);
+                self.failed.insert(url.to_string());
+                None
+            }
+        }
+    }
+}
+
+/// Settings for re-encoding images while copying them into the output
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ImageOptimization {
+    /// JPEG quality (1-100)
+    quality: u8,
+    /// Maximum width or height in pixels; larger images are downscaled
+    max_dimension: u32,
+}
+
+pub(crate) fn parse_image_optimization(s: &str) -> std::result::Result<ImageOptimization, String> {
+    let mut parts = s.splitn(2, ',');
+    let quality = match parts.next().map(str::trim) {
+        Some(q) if !q.is_empty() => q
+            .parse::<u8>()
+            .ok()
+            .filter(|q| (1..=100).contains(q))
+            .ok_or_else(|| format!("Invalid image quality '{}' (expected 1-100)", q))?,
+        _ => 80,
+    };
+    let max_dimension = match parts.next().map(str::trim) {
+        Some(d) if !d.is_empty() => d
+            .parse::<u32>()
+            .ok()
+            .filter(|d| *d > 0)
+            .ok_or_else(|| format!("Invalid maximum image dimension '{}'", d))?,
+        _ => 1600,
+    };
+    Ok(ImageOptimization {
+        quality,
+        max_dimension,
+    })
+}
+
+/// Target resolution for supplied covers
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct CoverSize {
+    width: u32,
+    height: u32,
+}
+
+pub(crate) fn parse_cover_size(s: &str) -> std::result::Result<CoverSize, String> {
+    let (width, height) = s
+        .split_once(['x', 'X'])
+        .ok_or_else(|| format!("Invalid cover size '{}' (expected WIDTHxHEIGHT)", s))?;
+    let parse = |v: &str| {
+        v.trim()
+            .parse::<u32>()
+            .ok()
+            .filter(|v| *v > 0)
+            .ok_or_else(|| format!("Invalid cover dimension '{}'", v))
+    };
+    Ok(CoverSize {
+        width: parse(width)?,
+        height: parse(height)?,
+    })
+}
+
+/// Represents a split point in the EPUB
+#[derive(Debug, Clone)]
+pub(crate) struct SplitLine {
+    pub(crate) toc: Vec<String>,
+    pub(crate) guide: Option<(String, String)>, // (type, title)
+    pub(crate) anchor: Option<String>,
+    pub(crate) id: String,
+    pub(crate) href: String,
+    pub(crate) media_type: String,
+    pub(crate) linear: bool,
+    pub(crate) sample: String,
+}
+
+impl SplitLine {
+    /// The line's href, with its anchor if it starts partway into the file
+    pub(crate) fn target(&self) -> String {
+        match &self.anchor {
+            Some(anchor) => format!("{}#{}", self.href, anchor),
+            None => self.href.clone(),
+        }
+    }
+
+    /// Whether `--filter` matches any of its TOC titles, its guide title or its href
+    pub(crate) fn matches(&self, filter: &Regex) -> bool {
+        self.toc.iter().any(|title| filter.is_match(title))
+            || self.guide.as_ref().is_some_and(|(_, title)| filter.is_match(title))
+            || filter.is_match(&self.href)
+    }
+
+    /// A short ID from the line's href, anchor and TOC titles, which unlike
+    /// its line number stays the same when sections are added elsewhere
+    pub(crate) fn stable_id(&self) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(self.href.as_bytes());
+        hasher.update(b"#");
+        hasher.update(self.anchor.as_deref().unwrap_or_default().as_bytes());
+        for title in &self.toc {
+            hasher.update(b"\0");
+            hasher.update(title.as_bytes());
+        }
+        let digest: String = hasher.finalize().iter().take(4).map(|b| format!("{:02x}", b)).collect();
+        format!("s{}", digest)
+    }
+
+    /// How listings name the line: its TOC titles, else its guide title
+    pub(crate) fn listing_title(&self) -> String {
+        if !self.toc.is_empty() {
+            self.toc.join(" / ")
+        } else if let Some((_, title)) = &self.guide {
+            title.clone()
+        } else {
+            "(untitled)".to_string()
+        }
+    }
+}
+
+/// An EPUB written to `output`, with what's needed to check and file it
+pub(crate) struct BuiltEpub<W> {
+    pub(crate) output: W,
+    pub(crate) copied_resources: Vec<(String, u64)>, // (source href, bytes written)
+    pub(crate) documents: Vec<(String, String, bool)>, // (path, content, is CSS)
+    pub(crate) written_paths: HashSet<String>,
+    pub(crate) meta: PackageMetadata,
+    pub(crate) cover: Option<Vec<u8>>,
+}
+
+/// Spine itemref info
+#[derive(Debug, Clone)]
+pub(crate) struct SpineItem {
+    pub(crate) idref: String,
+    pub(crate) linear: bool, // false for linear="no" (pop-up notes, answer keys, ...)
+    pub(crate) id: Option<String>,
+    pub(crate) properties: Vec<String>, // e.g. rendition:page-spread-left for fixed-layout spreads
+}
+
+impl SpineItem {
+    fn new(idref: impl Into<String>, linear: bool) -> Self {
+        Self {
+            idref: idref.into(),
+            linear,
+            id: None,
+            properties: Vec::new(),
+        }
+    }
+
+    /// Carry over the `id` and `properties` of a source itemref
+    fn with_itemref_attributes(mut self, source: &SpineItem) -> Self {
+        self.id = source.id.clone();
+        self.properties = source.properties.clone();
+        self
+    }
+}
+
+/// Manifest item info
+#[derive(Debug, Clone)]
+pub(crate) struct ManifestItem {
+    pub(crate) id: String,
+    pub(crate) href: String,
+    pub(crate) media_type: String,
+    pub(crate) properties: Vec<String>, // EPUB3 properties, e.g. "svg", "cover-image"
+}
+
+impl ManifestItem {
+    fn new(id: impl Into<String>, href: impl Into<String>, media_type: impl Into<String>) -> Self {
+        Self {
+            id: id.into(),
+            href: href.into(),
+            media_type: media_type.into(),
+            properties: Vec::new(),
+        }
+    }
+
+    fn with_properties(mut self, properties: Vec<String>) -> Self {
+        self.properties = properties;
+        self
+    }
+}
+
+/// Manifest properties that describe an item's content, and so are derived
+/// again from the copied content rather than carried over from the source
+const DERIVED_PROPERTIES: [&str; 4] = ["mathml", "remote-resources", "scripted", "svg"];
+
+/// Determine the manifest properties for a copied XHTML file, keeping any
+/// non-derived source properties. "nav" is dropped since the output
+/// generates its own navigation.
+fn derive_content_properties(content: &str, source_properties: &[String]) -> Result<Vec<String>> {
+    let remote_re = Regex::new(
+        r#"(?i)(?:\s(?:src|xlink:href|altimg|poster|data)\s*=\s*["']|url\(\s*["']?)https?://"#,
+    )
+    .context("Failed to compile remote reference regex")?;
+    // Match both default-namespace (<math>) and prefixed (<m:math>) elements
+    let mathml_re = Regex::new(r"<(?:[A-Za-z_][\w.-]*:)?math[\s/>]")
+        .context("Failed to compile MathML regex")?;
+    let svg_re = Regex::new(r"<(?:[A-Za-z_][\w.-]*:)?svg[\s/>]")
+        .context("Failed to compile SVG regex")?;
+
+    let mut properties: Vec<String> = source_properties
+        .iter()
+        .filter(|p| p.as_str() != "nav" && !DERIVED_PROPERTIES.contains(&p.as_str()))
+        .cloned()
+        .collect();
+
+    if mathml_re.is_match(content) {
+        properties.push("mathml".to_string());
+    }
+    if remote_re.is_match(content) {
+        properties.push("remote-resources".to_string());
+    }
+    if content.contains("<script") {
+        properties.push("scripted".to_string());
+    }
+    if svg_re.is_match(content) {
+        properties.push("svg".to_string());
+    }
+
+    Ok(properties)
+}
+
+/// The EPUB version of a package. Outputs keep their source's version, since
+/// copied EPUB 2 content isn't necessarily valid in an EPUB 3 package.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum EpubVersion {
+    Epub2,
+    Epub3,
+}
+
+impl EpubVersion {
+    /// The version an OPF's `<package version="...">` declares; anything
+    /// but 3.x is read as EPUB 2
+    fn parse(opf: &str) -> Result<Self> {
+        let mut reader = Reader::from_str(opf);
+        loop {
+            match reader.read_event() {
+                Ok(Event::Start(ref e)) | Ok(Event::Empty(ref e)) if e.local_name().as_ref() == b"package" => {
+                    let version = e
+                        .attributes()
+                        .flatten()
+                        .find(|attr| attr.key.as_ref() == b"version")
+                        .map(|attr| String::from_utf8_lossy(&attr.value).trim().to_string());
+                    return Ok(match version {
+                        Some(version) if version.starts_with('3') => EpubVersion::Epub3,
+                        _ => EpubVersion::Epub2,
+                    });
+                }
+                Ok(Event::Eof) => return Ok(EpubVersion::Epub2),
+                Err(e) => bail!("Error parsing OPF package: {}", e),
+                _ => {}
+            }
+        }
+    }
+
+    /// The version an output built from all of `sources` gets: EPUB 3 only
+    /// if every source is
+    fn common(sources: impl IntoIterator<Item = EpubVersion>) -> Self {
+        if sources.into_iter().all(|version| version == EpubVersion::Epub3) {
+            EpubVersion::Epub3
+        } else {
+            EpubVersion::Epub2
+        }
+    }
+
+    fn is_epub3(self) -> bool {
+        self == EpubVersion::Epub3
+    }
+
+    /// The `version` attribute of an output's `<package>`
+    fn package_version(self) -> &'static str {
+        match self {
+            EpubVersion::Epub2 => "2.0",
+            EpubVersion::Epub3 => "3.0",
+        }
+    }
+}
+
+/// Package metadata written to an output OPF
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct PackageMetadata {
+    /// Package version of the output
+    #[serde(skip)]
+    pub(crate) version: EpubVersion,
+    pub(crate) unique_id: String,
+    pub(crate) title: String,
+    pub(crate) authors: Vec<String>,
+    pub(crate) description: String,
+    pub(crate) tags: Vec<String>,
+    pub(crate) languages: Vec<String>,
+    pub(crate) accessibility: Vec<(String, String)>, // (property, value), e.g. schema:accessMode
+    /// Publication date, as the source gives it
+    pub(crate) date: Option<String>,
+    /// When the package was written, as a W3CDTF UTC timestamp
+    pub(crate) modified: String,
+    /// Creators in roles other than author
+    pub(crate) creators: Vec<Creator>,
+    pub(crate) contributors: Vec<Creator>,
+    /// Sort keys (`file-as`) for authors, by name
+    pub(crate) author_sort: HashMap<String, String>,
+    pub(crate) title_sort: Option<String>,
+}
+
+/// A creator or contributor and their MARC relator role
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub(crate) struct Creator {
+    pub(crate) name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) role: Option<String>,
+}
+
+impl Creator {
+    /// Parse `NAME[:ROLE]`, where ROLE is a three-letter relator code;
+    /// anything else after a colon is part of the name
+    pub(crate) fn parse(value: &str) -> Self {
+        match value.rsplit_once(':') {
+            Some((name, role))
+                if role.len() == 3 && role.bytes().all(|b| b.is_ascii_lowercase()) && !name.trim().is_empty() =>
+            {
+                Creator {
+                    name: name.trim().to_string(),
+                    role: Some(role.to_string()),
+                }
+            }
+            _ => Creator {
+                name: value.to_string(),
+                role: None,
+            },
+        }
+    }
+
+    fn is_author(&self) -> bool {
+        self.role.as_deref().is_none_or(|role| role == "aut")
+    }
+}
+
+/// Split `--author` values into plain authors and creators in other roles
+fn split_creator_roles(authors: &[String]) -> (Vec<String>, Vec<Creator>) {
+    let (authors, others): (Vec<Creator>, Vec<Creator>) =
+        authors.iter().map(|author| Creator::parse(author)).partition(Creator::is_author);
+    (authors.into_iter().map(|author| author.name).collect(), others)
+}
+
+/// Sort keys the source gives its creators and title, either as EPUB3
+/// `<meta refines="#id" property="file-as">` or EPUB2 `opf:file-as` and
+/// `calibre:title_sort`
+#[derive(Debug, Default)]
+struct Refinements {
+    /// Creator name -> file-as
+    creator_sort: HashMap<String, String>,
+    title_sort: Option<String>,
+}
+
+/// Metadata properties that make up a package's accessibility claims
+fn is_accessibility_property(property: &str) -> bool {
+    property.starts_with("schema:access")
+        || property.starts_with("a11y:")
+        || property == "dcterms:conformsTo"
+}
+
+/// What an output's content documents show about its accessibility
+#[derive(Debug, Default)]
+struct ContentAccessibility {
+    images: usize,
+    /// Images with neither `alt` nor `aria-label`; `alt=""` marks a decorative one
+    unlabelled_images: usize,
+    headings: bool,
+    /// Some content document was copied without being read, so its images
+    /// are unknown
+    incomplete: bool,
+}
+
+impl ContentAccessibility {
+    fn scan(&mut self, content: &str) -> Result<()> {
+        let image_re = Regex::new(r#"<(?:\w+:)?(?:img|image)\b[^>]*>"#)
+            .context("Failed to compile image regex")?;
+        let label_re = Regex::new(r#"\s(?:alt|aria-label)\s*=\s*["']"#)
+            .context("Failed to compile alt text regex")?;
+        let heading_re = Regex::new(r#"<(?:\w+:)?h[1-6]\b"#).context("Failed to compile heading regex")?;
+
+        for image in image_re.find_iter(content) {
+            self.images += 1;
+            if !label_re.is_match(image.as_str()) {
+                self.unlabelled_images += 1;
+            }
+        }
+        self.headings |= heading_re.is_match(content);
+        Ok(())
+    }
+
+    fn all_images_labelled(&self) -> bool {
+        !self.incomplete && self.unlabelled_images == 0
+    }
+}
+
+/// Describe the accessibility of an output package from its manifest and
+/// what its content shows. Hazards can't be told from the markup, so they're
+/// left unknown, and no summary is written on the publisher's behalf.
+fn synthesize_accessibility_metadata(
+    manifest_items: &[ManifestItem],
+    content: &ContentAccessibility,
+) -> Vec<(String, String)> {
+    let has_images = content.images > 0
+        || manifest_items
+            .iter()
+            .any(|item| item.media_type.starts_with("image/"));
+    let has_mathml = manifest_items
+        .iter()
+        .any(|item| item.properties.iter().any(|p| p == "mathml"));
+
+    let mut metadata = vec![("schema:accessMode".to_string(), "textual".to_string())];
+    if has_images {
+        metadata.push(("schema:accessMode".to_string(), "visual".to_string()));
+    }
+    let textual_alone = !has_images || content.all_images_labelled();
+    metadata.push((
+        "schema:accessModeSufficient".to_string(),
+        if textual_alone { "textual" } else { "textual,visual" }.to_string(),
+    ));
+    // Every output gets a navigation document or NCX
+    metadata.push(("schema:accessibilityFeature".to_string(), "tableOfContents".to_string()));
+    if content.headings {
+        metadata.push(("schema:accessibilityFeature".to_string(), "structuralNavigation".to_string()));
+    }
+    if content.images > 0 && textual_alone {
+        metadata.push(("schema:accessibilityFeature".to_string(), "alternativeText".to_string()));
+    }
+    if has_mathml {
+        metadata.push(("schema:accessibilityFeature".to_string(), "MathML".to_string()));
+    }
+    metadata.push(("schema:accessibilityHazard".to_string(), "unknown".to_string()));
+    metadata
+}
+
+/// Add synthesized accessibility properties that the source didn't already declare
+fn merge_accessibility_metadata(
+    existing: &mut Vec<(String, String)>,
+    synthesized: Vec<(String, String)>,
+) {
+    let declared: HashSet<String> = existing.iter().map(|(p, _)| p.clone()).collect();
+    existing.extend(
+        synthesized
+            .into_iter()
+            .filter(|(property, _)| !declared.contains(property)),
+    );
+}
+
+/// TOC entry
+#[derive(Debug, Clone)]
+struct TocEntry {
+    text: String,
+    anchor: Option<String>,
+    /// Entries nested under this one in the source NCX
+    children: Vec<NavPoint>,
+}
+
+/// An NCX navPoint and those nested under it. `src` is a package path,
+/// possibly with a fragment.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct NavPoint {
+    pub(crate) text: String,
+    pub(crate) src: String,
+    pub(crate) children: Vec<NavPoint>,
+}
+
+impl NavPoint {
+    pub(crate) fn new(text: impl Into<String>, src: impl Into<String>) -> Self {
+        NavPoint {
+            text: text.into(),
+            src: src.into(),
+            children: Vec::new(),
+        }
+    }
+
+    /// Drop entries whose file `keep` rejects, lifting any kept entries
+    /// nested under them into their place
+    fn retain_files(points: Vec<NavPoint>, keep: &dyn Fn(&str) -> bool) -> Vec<NavPoint> {
+        let mut kept = Vec::new();
+        for mut point in points {
+            let children = Self::retain_files(std::mem::take(&mut point.children), keep);
+            let file = point.src.split('#').next().unwrap_or_default();
+            if keep(file) {
+                point.children = children;
+                kept.push(point);
+            } else {
+                kept.extend(children);
+            }
+        }
+        kept
+    }
+
+    /// Rewrite the file part of every `src`, keeping fragments
+    fn map_files(points: Vec<NavPoint>, map: &dyn Fn(&str) -> String) -> Vec<NavPoint> {
+        points
+            .into_iter()
+            .map(|point| {
+                let src = match point.src.split_once('#') {
+                    Some((file, fragment)) => format!("{}#{}", map(file), fragment),
+                    None => map(&point.src),
+                };
+                NavPoint {
+                    text: point.text,
+                    src,
+                    children: Self::map_files(point.children, map),
+                }
+            })
+            .collect()
+    }
+
+    /// Relabel every entry
+    fn map_titles(points: Vec<NavPoint>, map: &dyn Fn(&str) -> String) -> Vec<NavPoint> {
+        points
+            .into_iter()
+            .map(|point| NavPoint {
+                text: map(&point.text),
+                src: point.src,
+                children: Self::map_titles(point.children, map),
+            })
+            .collect()
+    }
+
+    /// Levels of nesting, 0 for no entries
+    pub(crate) fn depth(points: &[NavPoint]) -> usize {
+        points.iter().map(|point| 1 + Self::depth(&point.children)).max().unwrap_or(0)
+    }
+}
+
+/// A complete toc.ncx for `nav_points`. `dtb:depth` is the depth of the
+/// tree, and playOrder follows reading order with entries sharing a target
+/// sharing a number, as the NCX spec requires.
+fn generate_ncx(unique_id: &str, title: &str, nav_points: &[NavPoint]) -> String {
+    fn write_points(
+        ncx: &mut String,
+        points: &[NavPoint],
+        level: usize,
+        play_orders: &mut HashMap<String, usize>,
+        next_id: &mut usize,
+    ) {
+        let indent = "   ".repeat(level + 2);
+        for point in points {
+            let next_order = play_orders.len() + 1;
+            let play_order = *play_orders.entry(point.src.clone()).or_insert(next_order);
+            *next_id += 1;
+            ncx.push_str(&format!(
+                "{}<navPoint id=\"navpoint-{}\" playOrder=\"{}\">\n",
+                indent, next_id, play_order
+            ));
+            ncx.push_str(&format!("{}   <navLabel>\n", indent));
+            ncx.push_str(&format!("{}      <text>{}</text>\n", indent, SplitEpub::escape_xml(&point.text)));
+            ncx.push_str(&format!("{}   </navLabel>\n", indent));
+            ncx.push_str(&format!("{}   <content src=\"{}\"/>\n", indent, SplitEpub::escape_xml(&point.src)));
+            write_points(ncx, &point.children, level + 1, play_orders, next_id);
+            ncx.push_str(&format!("{}</navPoint>\n", indent));
+        }
+    }
+
+    let mut ncx = String::new();
+
+    ncx.push_str(r#"<?xml version="1.0" encoding="UTF-8"?>
+<ncx version="2005-1" xmlns="http://www.daisy.org/z3986/2005/ncx/">
+   <head>
+"#);
+
+    ncx.push_str(&format!(
+        "      <meta name=\"dtb:uid\" content=\"{}\"/>\n",
+        SplitEpub::escape_xml(unique_id)
+    ));
+    ncx.push_str(&format!(
+        "      <meta name=\"dtb:depth\" content=\"{}\"/>\n",
+        NavPoint::depth(nav_points).max(1)
+    ));
+    ncx.push_str("      <meta name=\"dtb:totalPageCount\" content=\"0\"/>\n");
+    ncx.push_str("      <meta name=\"dtb:maxPageNumber\" content=\"0\"/>\n");
+    ncx.push_str("   </head>\n");
+
+    ncx.push_str("   <docTitle>\n");
+    ncx.push_str(&format!(
+        "      <text>{}</text>\n",
+        SplitEpub::escape_xml(title)
+    ));
+    ncx.push_str("   </docTitle>\n");
+
+    ncx.push_str("   <navMap>\n");
+    write_points(&mut ncx, nav_points, 0, &mut HashMap::new(), &mut 0);
+    ncx.push_str("   </navMap>\n");
+    ncx.push_str("</ncx>\n");
+
+    ncx
+}
+
+/// The nested `<ol>` of a nav document's TOC
+fn generate_nav_list(nav_points: &[NavPoint]) -> String {
+    fn write_points(nav: &mut String, points: &[NavPoint], level: usize) {
+        let indent = "   ".repeat(level * 2 + 3);
+        nav.push_str(&format!("{}<ol>\n", indent));
+        for point in points {
+            nav.push_str(&format!(
+                "{}   <li><a href=\"{}\">{}</a>",
+                indent,
+                SplitEpub::escape_xml(&point.src),
+                SplitEpub::escape_xml(&point.text)
+            ));
+            if !point.children.is_empty() {
+                nav.push('\n');
+                write_points(nav, &point.children, level + 1);
+                nav.push_str(&format!("{}   ", indent));
+            }
+            nav.push_str("</li>\n");
+        }
+        nav.push_str(&format!("{}</ol>\n", indent));
+    }
+
+    let mut list = String::new();
+    write_points(&mut list, nav_points, 0);
+    list
+}
+
+/// An EPUB 3 navigation document listing the same `nav_points` as the
+/// toc.ncx, for reading systems that only look for one or the other.
+/// Both sit at the archive root so their links resolve alike.
+fn generate_nav(title: &str, nav_points: &[NavPoint]) -> String {
+    let title = SplitEpub::escape_xml(title);
+    let mut nav = format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE html>
+<html xmlns="http://www.w3.org/1999/xhtml" xmlns:epub="http://www.idpf.org/2007/ops">
+   <head>
+      <title>{}</title>
+   </head>
+   <body>
+      <nav epub:type="toc" id="toc">
+         <h1>{}</h1>
+"#,
+        title, title
+    );
+    nav.push_str(&generate_nav_list(nav_points));
+    nav.push_str("      </nav>\n");
+    nav.push_str("   </body>\n");
+    nav.push_str("</html>\n");
+
+    nav
+}
+
+/// Swap the list in `document`'s `<nav epub:type="toc">` for one of
+/// `nav_points`, leaving its heading and the rest of the document alone
+fn replace_nav_toc(document: &str, nav_points: &[NavPoint]) -> Result<String> {
+    let nav_re = Regex::new(r#"(?s)<nav\b[^>]*epub:type=["'][^"']*\btoc\b[^"']*["'][^>]*>.*?</nav\s*>"#)
+        .context("Failed to compile nav regex")?;
+    let Some(nav) = nav_re.find(document) else {
+        bail!("Nav document has no epub:type=\"toc\" nav");
+    };
+    let block = nav.as_str();
+    let list = match (block.find("<ol"), block.rfind("</ol>")) {
+        (Some(start), Some(end)) if start < end => {
+            // Back up to the start of the line so the new list's own indent is used
+            let start = block[..start].rfind('\n').map_or(start, |n| n + 1);
+            let end = end + "</ol>".len();
+            let end = if block[end..].starts_with('\n') { end + 1 } else { end };
+            format!("{}{}{}", &block[..start], generate_nav_list(nav_points), &block[end..])
+        }
+        _ => {
+            let close = block.rfind("</nav").unwrap_or(block.len());
+            format!("{}{}{}", &block[..close], generate_nav_list(nav_points), &block[close..])
+        }
+    };
+    Ok(format!("{}{}{}", &document[..nav.start()], list, &document[nav.end()..]))
+}
+
+/// Anything an EPUB can be read from: a file, or bytes in memory
+pub(crate) trait EpubSource: IoRead + Seek {}
+
+impl<T: IoRead + Seek> EpubSource for T {}
+
+/// An open EPUB archive, whatever it's read from
+type EpubArchive = ZipArchive<Box<dyn EpubSource>>;
+
+/// Entries larger than this are scanned in chunks and copied through
+/// without being read whole
+const LARGE_ENTRY_SIZE: u64 = 16 * 1024 * 1024;
+/// Bytes read per chunk when scanning a large entry
+const SCAN_CHUNK: u64 = 1024 * 1024;
+/// Bytes carried over between chunks, so matches straddling a chunk
+/// boundary are still seen whole
+const SCAN_OVERLAP: usize = 4096;
+/// Length of the text sample shown for each split line
+const SAMPLE_LEN: usize = 1500;
+
+/// Pass the text read from `reader` to `f` in overlapping windows of about
+/// `SCAN_CHUNK` bytes. A match shorter than `SCAN_OVERLAP` bytes is always
+/// whole in some window, though it may be seen twice.
+fn for_each_text_window(mut reader: impl IoRead, mut f: impl FnMut(&str) -> Result<()>) -> Result<()> {
+    let mut window: Vec<u8> = Vec::new();
+    loop {
+        let read = (&mut reader)
+            .take(SCAN_CHUNK)
+            .read_to_end(&mut window)
+            .context("Failed to read EPUB entry")?;
+        if read == 0 {
+            return Ok(());
+        }
+        // Hold back a character cut off by the chunk boundary
+        let end = match std::str::from_utf8(&window) {
+            Err(e) if e.error_len().is_none() => e.valid_up_to(),
+            _ => window.len(),
+        };
+        f(&String::from_utf8_lossy(&window[..end]))?;
+        window.drain(..end.saturating_sub(SCAN_OVERLAP));
+    }
+}
+
+/// Threads to spread CPU-bound work over: one per core, or just this one
+/// where threads aren't available
+fn worker_threads() -> usize {
+    std::thread::available_parallelism().map_or(1, |threads| threads.get())
+}
+
+/// `items.iter().map(f)`, spread over up to `threads` threads. Results keep
+/// the order of `items`.
+fn parallel_map<T: Sync, R: Send>(items: &[T], threads: usize, f: impl Fn(&T) -> R + Sync) -> Vec<R> {
+    let threads = threads.min(items.len());
+    if threads <= 1 {
+        return items.iter().map(f).collect();
+    }
+
+    let chunk_size = items.len().div_ceil(threads);
+    let f = &f;
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = items
+            .chunks(chunk_size)
+            .map(|chunk| scope.spawn(move || chunk.iter().map(f).collect::<Vec<R>>()))
+            .collect();
+        handles
+            .into_iter()
+            .flat_map(|handle| handle.join().unwrap_or_else(|e| std::panic::resume_unwind(e)))
+            .collect()
+    })
+}
+
+/// The first `SAMPLE_LEN` bytes of `text`, marked as cut if it's longer
+fn sample_text(text: &str) -> String {
+    if text.len() > SAMPLE_LEN {
+        format!("{}...", &text[..text.floor_char_boundary(SAMPLE_LEN)])
+    } else {
+        text.to_string()
+    }
+}
+
+/// Whether `transform_content` may change a file of this media type
+fn transforms_content(media_type: &str, opts: &OutputOptions) -> bool {
+    let is_html = media_type.contains("html");
+    (is_html || media_type == "text/css")
+        && (opts.strip_scripts || opts.strip_remote || opts.fetch_remote.is_some() || opts.minify || !opts.transforms.is_empty())
+        || is_html && (opts.normalize_unicode || opts.punctuation.is_some() || opts.strip_soft_hyphens)
+}
+
+/// Main EPUB splitting engine
+pub(crate) struct SplitEpub {
+    pub(crate) archive: EpubArchive,
+    pub(crate) path: PathBuf,
+    pub(crate) content_opf_path: String,
+    #[allow(dead_code)]
+    content_relpath: String,
+    /// Package version of the source, and so of its outputs
+    version: EpubVersion,
+    pub(crate) manifest_items: HashMap<String, ManifestItem>,
+    guide_items: HashMap<String, (String, String)>, // href -> (type, title)
+    toc_map: HashMap<String, Vec<TocEntry>>,        // href -> [(text, anchor), ...]
+    pub(crate) toc_path: Option<String>,
+    pub(crate) orig_title: String,
+    orig_authors: Vec<String>,
+    orig_languages: Vec<String>,
+    orig_tags: Vec<String>,
+    /// Publication date as given in the OPF
+    pub(crate) orig_date: Option<String>,
+    orig_unique_id: Option<String>,
+    /// Creators in roles other than author, and contributors
+    orig_creators: Vec<Creator>,
+    orig_contributors: Vec<Creator>,
+    orig_refinements: Refinements,
+    accessibility: Vec<(String, String)>, // (property, value)
+    entries: EntryIndex,
+    /// Split lines, once the spine has been scanned
+    split_lines: Option<Arc<[SplitLine]>>,
+    /// Text read so far, kept for books held in a `SessionCache`
+    content_cache: Option<ContentCache>,
+}
+
+/// The archive's entry names, for finding the entry a reference means when
+/// it doesn't name one exactly: it differs in case or percent-encoding, or
+/// is rooted at the content directory rather than the archive root
+struct EntryIndex {
+    names: HashSet<String>,
+    /// Entry names by their percent-decoded, lowercased form
+    by_key: HashMap<String, String>,
+    content_relpath: String,
+}
+
+impl EntryIndex {
+    fn new(archive: &EpubArchive, content_relpath: &str) -> Self {
+        let names: HashSet<String> = archive.file_names().map(str::to_string).collect();
+        let mut by_key = HashMap::new();
+        let mut sorted: Vec<&String> = names.iter().collect();
+        sorted.sort();
+        for name in sorted {
+            by_key.entry(Self::key(name)).or_insert_with(|| name.clone());
+        }
+        EntryIndex {
+            names,
+            by_key,
+            content_relpath: content_relpath.to_string(),
+        }
+    }
+
+    fn key(path: &str) -> String {
+        SplitEpub::normalize_path(path).to_lowercase()
+    }
+
+    /// The entry a normalized archive path refers to, if any
+    fn resolve(&self, path: &str) -> Option<String> {
+        if self.names.contains(path) {
+            return Some(path.to_string());
+        }
+        let under_content = format!("{}{}", self.content_relpath, path);
+        [path, under_content.as_str()]
+            .iter()
+            .find_map(|candidate| self.by_key.get(&Self::key(candidate)).cloned())
+    }
+
+    /// `resolve`, logging when the entry differs from `path` and keeping
+    /// `path` when nothing matches
+    fn resolve_logged(&self, path: &str) -> String {
+        match self.resolve(path) {
+            Some(entry) if entry != path => {
+                info!("Resolved {} to archive entry {}", path, entry);
+                entry
+            }
+            _ => path.to_string(),
+        }
+    }
+}
+
+/// Maps NCX `src`s to manifest hrefs. Some NCXs write them relative to the
+/// package directory or archive root instead of the NCX itself, or encode
+/// them differently than the manifest does
+struct NcxHrefs {
+    toc_relpath: String,
+    content_relpath: String,
+    /// Manifest hrefs by `EntryIndex::key`
+    manifest: HashMap<String, String>,
+}
+
+impl NcxHrefs {
+    fn new(toc_path: &str, content_relpath: &str, manifest_items: &HashMap<String, ManifestItem>) -> Self {
+        let mut hrefs: Vec<&String> = manifest_items.values().map(|item| &item.href).collect();
+        hrefs.sort();
+        let mut manifest = HashMap::new();
+        for href in hrefs {
+            manifest.entry(EntryIndex::key(href)).or_insert_with(|| href.clone());
+        }
+        NcxHrefs {
+            toc_relpath: SplitEpub::get_path_part(toc_path),
+            content_relpath: content_relpath.to_string(),
+            manifest,
+        }
+    }
+
+    /// The package path `src` refers to: the first of its resolutions against
+    /// the NCX, package and archive root that names a manifest item, else
+    /// the one against the NCX
+    fn resolve(&self, src: &str) -> String {
+        let (path, fragment) = match src.split_once('#') {
+            Some((path, fragment)) => (path, Some(fragment)),
+            None => (src, None),
+        };
+        let matched = [self.toc_relpath.as_str(), self.content_relpath.as_str(), ""]
+            .iter()
+            .map(|base| SplitEpub::join_href(base, path))
+            .find_map(|candidate| self.manifest.get(&EntryIndex::key(&candidate)));
+        let Some(href) = matched else {
+            return SplitEpub::join_href(&self.toc_relpath, src);
+        };
+        match fragment {
+            Some(fragment) => format!("{}#{}", href, percent_decode_str(fragment).decode_utf8_lossy()),
+            None => href.clone(),
+        }
+    }
+}
+
+/// Most text a `ContentCache` keeps for one book
+const CONTENT_CACHE_LIMIT: usize = 64 * 1024 * 1024;
+
+/// Decompressed text entries of a book, by archive path
+#[derive(Default)]
+struct ContentCache {
+    entries: HashMap<String, String>,
+    bytes: usize,
+}
+
+/// Books kept open between requests, keyed by a SHA-256 of the EPUB, so
+/// listing a book and then splitting it (or splitting it again) skips
+/// parsing, scanning and decompressing it each time. Holds up to `capacity`
+/// books, dropping the least recently used.
+pub(crate) struct SessionCache {
+    capacity: usize,
+    books: Vec<(String, SplitEpub)>, // least recently used first
+}
+
+/// Books a `SessionCache` holds for the server and the library API
+pub(crate) const SESSION_BOOKS: usize = 2;
+
+impl SessionCache {
+    pub(crate) const fn new(capacity: usize) -> Self {
+        SessionCache {
+            capacity,
+            books: Vec::new(),
+        }
+    }
+
+    pub(crate) fn key(data: &[u8]) -> String {
+        Sha256::digest(data).iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    /// The book with this key, opened with `open` unless it's already held
+    pub(crate) fn get_or_open(&mut self, key: &str, open: impl FnOnce() -> Result<SplitEpub>) -> Result<&mut SplitEpub> {
+        match self.books.iter().position(|(held, _)| held == key) {
+            Some(index) => {
+                debug!("Reusing parsed book {}", key);
+                let book = self.books.remove(index);
+                self.books.push(book);
+            }
+            None => {
+                let mut epub = open()?;
+                epub.content_cache = Some(ContentCache::default());
+                if self.books.len() >= self.capacity {
+                    self.books.remove(0);
+                }
+                self.books.push((key.to_string(), epub));
+            }
+        }
+        Ok(&mut self.books.last_mut().expect("book was just added").1)
+    }
+
+    #[cfg(feature = "serve")]
+    pub(crate) fn remove(&mut self, key: &str) {
+        self.books.retain(|(held, _)| held != key);
+    }
+
+    fn clear(&mut self) {
+        self.books.clear();
+    }
+}
+
+thread_local! {
+    /// Books split through the library API
+    static SESSIONS: std::cell::RefCell<SessionCache> =
+        const { std::cell::RefCell::new(SessionCache::new(SESSION_BOOKS)) };
+}
+
+impl SplitEpub {
+    /// Parse an EPUB held in memory; `name` stands in for its file name
+    pub(crate) fn from_bytes(data: Vec<u8>, name: &str) -> Result<Self> {
+        Self::from_reader(Box::new(std::io::Cursor::new(data)), PathBuf::from(name))
+    }
+
+    pub(crate) fn from_reader(reader: Box<dyn EpubSource>, path: PathBuf) -> Result<Self> {
+        let mut archive = ZipArchive::new(reader).context("Failed to read EPUB as ZIP archive")?;
+        check_drm(&mut archive, &path)?;
+
+        // Find the .opf file from container.xml
+        let container_xml = Self::read_file_from_archive(&mut archive, "META-INF/container.xml")?;
+        let content_opf_path = Self::parse_container_xml(&container_xml)?;
+        let content_relpath = Self::get_path_part(&content_opf_path);
+
+        debug!("OPF path: {}", content_opf_path);
+        debug!("Content relative path: {}", content_relpath);
+
+        // Parse the OPF file, pointing hrefs at the entries they mean
+        let entries = EntryIndex::new(&archive, &content_relpath);
+        let opf_content = Self::read_file_from_archive(&mut archive, &content_opf_path)?;
+        let version = EpubVersion::parse(&opf_content)?;
+        let (mut manifest_items, toc_path) =
+            Self::parse_manifest(&opf_content, &content_relpath)?;
+        for item in manifest_items.values_mut() {
+            item.href = entries.resolve_logged(&item.href);
+        }
+        let toc_path = toc_path.map(|toc_path| entries.resolve_logged(&toc_path));
+        let guide_items: HashMap<String, (String, String)> = Self::parse_guide(&opf_content, &content_relpath)?
+            .into_iter()
+            .map(|(href, guide)| (entries.resolve_logged(&href), guide))
+            .collect();
+        let (orig_title, orig_authors) = Self::parse_metadata(&opf_content)?;
+        let orig_languages = Self::parse_languages(&opf_content)?;
+        let orig_tags = Self::parse_subjects(&opf_content)?;
+        let orig_date = Self::parse_publication_date(&opf_content)?;
+        let orig_unique_id = Self::parse_unique_identifier(&opf_content)?;
+        let (orig_creators, orig_contributors) = Self::parse_creator_roles(&opf_content)?;
+        let orig_refinements = Self::parse_refinements(&opf_content)?;
+        let accessibility = Self::parse_accessibility_metadata(&opf_content)?;
+
+        debug!("Found {} manifest items", manifest_items.len());
+        debug!("Found {} accessibility metadata entries", accessibility.len());
+        debug!("Original title: {}", orig_title);
+        debug!("Original authors: {:?}", orig_authors);
+        debug!("Original languages: {:?}", orig_languages);
+
+        // Parse TOC if available
+        let toc_map = if let Some(toc_path) = &toc_path {
+            let toc_content = Self::read_file_from_archive(&mut archive, toc_path)?;
+            Self::parse_toc(&toc_content, &NcxHrefs::new(toc_path, &content_relpath, &manifest_items))?
+        } else {
+            warn!("No TOC file found");
+            HashMap::new()
+        };
+
+        debug!("Found {} TOC entries", toc_map.len());
+
+        Ok(Self {
+            archive,
+            path,
+            content_opf_path,
+            content_relpath,
+            version,
+            manifest_items,
+            guide_items,
+            toc_map,
+            toc_path,
+            orig_title,
+            orig_authors,
+            orig_languages,
+            orig_tags,
+            orig_date,
+            orig_unique_id,
+            orig_creators,
+            orig_contributors,
+            orig_refinements,
+            accessibility,
+            entries,
+            split_lines: None,
+            content_cache: None,
+        })
+    }
+
+    /// Read a text entry, through the content cache when the book has one.
+    /// Invalid UTF-8 is replaced rather than failing the read.
+    fn read_text(&mut self, path: &str) -> Result<String> {
+        if let Some(content) = self.content_cache.as_ref().and_then(|cache| cache.entries.get(path)) {
+            return Ok(content.clone());
+        }
+        let content = String::from_utf8(self.read_binary_file_from_archive(path)?).unwrap_or_else(|e| {
+            warn!("{} is not valid UTF-8; replacing invalid sequences where it's read as text", path);
+            String::from_utf8_lossy(e.as_bytes()).into_owned()
+        });
+        if let Some(cache) = &mut self.content_cache {
+            if cache.bytes + content.len() <= CONTENT_CACHE_LIMIT {
+                cache.bytes += content.len();
+                cache.entries.insert(path.to_string(), content.clone());
+            }
+        }
+        Ok(content)
+    }
+
+    pub(crate) fn read_file_from_archive(
+        archive: &mut EpubArchive,
+        path: &str,
+    ) -> Result<String> {
+        let mut file = archive
+            .by_name(path)
+            .with_context(|| format!("File not found in EPUB: {}", path))?;
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)
+            .with_context(|| format!("Failed to read file from EPUB: {}", path))?;
+        Ok(contents)
+    }
+
+    /// Whether an entry is too large to read whole; see `LARGE_ENTRY_SIZE`
+    fn is_large_entry(archive: &mut EpubArchive, path: &str) -> bool {
+        archive
+            .by_name(path)
+            .is_ok_and(|file| file.size() > LARGE_ENTRY_SIZE)
+    }
+
+    /// Read up to `limit` bytes from the start of an entry
+    fn read_prefix_from_archive(archive: &mut EpubArchive, path: &str, limit: u64) -> Result<String> {
+        let prefix = Self::read_binary_prefix(archive, path, limit)?;
+        Ok(String::from_utf8_lossy(&prefix).into_owned())
+    }
+
+    fn read_binary_prefix(archive: &mut EpubArchive, path: &str, limit: u64) -> Result<Vec<u8>> {
+        let file = archive
+            .by_name(path)
+            .with_context(|| format!("File not found in EPUB: {}", path))?;
+        let mut prefix = Vec::new();
+        file.take(limit)
+            .read_to_end(&mut prefix)
+            .with_context(|| format!("Failed to read file from EPUB: {}", path))?;
+        Ok(prefix)
+    }
+
+    /// Pass an entry's text to `f` in overlapping windows; see
+    /// `for_each_text_window`
+    fn scan_entry(archive: &mut EpubArchive, path: &str, f: impl FnMut(&str) -> Result<()>) -> Result<()> {
+        let file = archive
+            .by_name(path)
+            .with_context(|| format!("File not found in EPUB: {}", path))?;
+        for_each_text_window(file, f).with_context(|| format!("Failed to read file from EPUB: {}", path))
+    }
+
+    /// Copy an entry into `zip` still compressed, without reading it whole
+    fn copy_entry_raw<W: IoWrite + Seek>(&mut self, zip: &mut ZipWriter<W>, path: &str) -> Result<u64> {
+        let index = self
+            .archive
+            .index_for_name(path)
+            .with_context(|| format!("File not found in EPUB: {}", path))?;
+        let entry = self
+            .archive
+            .by_index_raw(index)
+            .with_context(|| format!("Failed to read file from EPUB: {}", path))?;
+        let size = entry.size();
+        zip.raw_copy_file(entry)
+            .with_context(|| format!("Failed to copy {}", path))?;
+        Ok(size)
+    }
+
+    pub(crate) fn get_path_part(path: &str) -> String {
+        if let Some(pos) = path.rfind('/') {
+            path[..=pos].to_string()
+        } else {
+            String::new()
+        }
+    }
+
+    /// Resolve a reference against the directory `base_path` it appears in.
+    /// References starting with `/` are taken from the archive root.
+    pub(crate) fn join_href(base_path: &str, reference: &str) -> String {
+        match reference.strip_prefix('/') {
+            Some(rooted) => Self::normalize_path(rooted),
+            None => Self::normalize_path(&format!("{}{}", base_path, reference)),
+        }
+    }
+
+    fn normalize_path(path: &str) -> String {
+        // Simple path normalization - remove ../ and ./ segments
+        let decoded = percent_decode_str(path).decode_utf8_lossy().to_string();
+        let mut parts: Vec<&str> = Vec::new();
+
+        for part in decoded.split('/') {
+            match part {
+                ".." => {
+                    parts.pop();
+                }
+                "." | "" => {}
+                _ => parts.push(part),
+            }
+        }
+
+        parts.join("/")
+    }
+
+    fn parse_container_xml(xml: &str) -> Result<String> {
+        let mut reader = Reader::from_str(xml);
+        reader.config_mut().trim_text(true);
+
+        loop {
+            match reader.read_event() {
+                Ok(Event::Empty(ref e)) | Ok(Event::Start(ref e))
+                    if e.local_name().as_ref() == b"rootfile" =>
+                {
+                    for attr in e.attributes().flatten() {
+                        if attr.key.as_ref() == b"full-path" {
+                            return Ok(String::from_utf8_lossy(&attr.value).to_string());
+                        }
+                    }
+                }
+                Ok(Event::Eof) => break,
+                Err(e) => bail!("Error parsing container.xml: {}", e),
+                _ => {}
+            }
+        }
+
+        bail!("No rootfile found in container.xml")
+    }
+
+    fn parse_manifest(
+        opf: &str,
+        content_relpath: &str,
+    ) -> Result<(HashMap<String, ManifestItem>, Option<String>)> {
+        let mut items = HashMap::new();
+        let mut toc_path = None;
+        let mut reader = Reader::from_str(opf);
+        reader.config_mut().trim_text(true);
+
+        loop {
+            match reader.read_event() {
+                Ok(Event::Empty(ref e)) | Ok(Event::Start(ref e))
+                    if e.local_name().as_ref() == b"item" =>
+                {
+                    let mut id = String::new();
+                    let mut href = String::new();
+                    let mut media_type = String::new();
+                    let mut properties = Vec::new();
+
+                    for attr in e.attributes().flatten() {
+                        match attr.key.as_ref() {
+                            b"id" => id = String::from_utf8_lossy(&attr.value).to_string(),
+                            b"href" => {
+                                let raw_href = String::from_utf8_lossy(&attr.value).to_string();
+                                href = Self::join_href(content_relpath, &raw_href);
+                            }
+                            b"media-type" => {
+                                media_type = String::from_utf8_lossy(&attr.value).to_string()
+                            }
+                            b"properties" => {
+                                properties = String::from_utf8_lossy(&attr.value)
+                                    .split_whitespace()
+                                    .map(str::to_string)
+                                    .collect()
+                            }
+                            _ => {}
+                        }
+                    }
+
+                    if !id.is_empty() {
+                        // Check if this is the TOC file
+                        if media_type == "application/x-dtbncx+xml" {
+                            toc_path = Some(href.clone());
+                        }
+
+                        items.insert(
+                            id.clone(),
+                            ManifestItem {
+                                id,
+                                href,
+                                media_type,
+                                properties,
+                            },
+                        );
+                    }
+                }
+                Ok(Event::Eof) => break,
+                Err(e) => bail!("Error parsing OPF manifest: {}", e),
+                _ => {}
+            }
+        }
+
+        Ok((items, toc_path))
+    }
+
+    fn parse_guide(opf: &str, content_relpath: &str) -> Result<HashMap<String, (String, String)>> {
+        let mut items = HashMap::new();
+        let mut reader = Reader::from_str(opf);
+        reader.config_mut().trim_text(true);
+
+        loop {
+            match reader.read_event() {
+                Ok(Event::Empty(ref e)) | Ok(Event::Start(ref e))
+                    if e.local_name().as_ref() == b"reference" =>
+                {
+                    let mut href = String::new();
+                    let mut ref_type = String::new();
+                    let mut title = String::new();
+
+                    for attr in e.attributes().flatten() {
+                        match attr.key.as_ref() {
+                            b"href" => {
+                                let raw_href = String::from_utf8_lossy(&attr.value).to_string();
+                                // Remove anchor part for guide lookup
+                                let base_href = raw_href.split('#').next().unwrap_or(&raw_href);
+                                href = Self::join_href(content_relpath, base_href);
+                            }
+                            b"type" => {
+                                ref_type = String::from_utf8_lossy(&attr.value).to_string()
+                            }
+                            b"title" => title = String::from_utf8_lossy(&attr.value).to_string(),
+                            _ => {}
+                        }
+                    }
+
+                    if !href.is_empty() {
+                        items.insert(href, (ref_type, title));
+                    }
+                }
+                Ok(Event::Eof) => break,
+                Err(e) => bail!("Error parsing OPF guide: {}", e),
+                _ => {}
+            }
+        }
+
+        Ok(items)
+    }
+
+    /// The title, and the authors: creators with no role or `aut`, whether
+    /// given by attribute or EPUB3 refinement
+    fn parse_metadata(opf: &str) -> Result<(String, Vec<String>)> {
+        let mut title = String::from("(Title Missing)");
+        let mut reader = Reader::from_str(opf);
+        reader.config_mut().trim_text(true);
+
+        let mut in_title = false;
+
+        loop {
+            match reader.read_event() {
+                Ok(Event::Start(ref e)) if e.local_name().as_ref() == b"title" => in_title = true,
+                Ok(Event::Text(ref e)) if in_title => {
+                    title = xml_text(e);
+                    in_title = false;
+                }
+                Ok(Event::End(_)) => in_title = false,
+                Ok(Event::Eof) => break,
+                Err(e) => bail!("Error parsing OPF metadata: {}", e),
+                _ => {}
+            }
+        }
+
+        let mut authors = Vec::new();
+        for (is_contributor, creator) in Self::parse_creators(opf)? {
+            if !is_contributor && creator.is_author() && !authors.contains(&creator.name) {
+                authors.push(creator.name);
+            }
+        }
+        if authors.is_empty() {
+            authors.push("(Authors Missing)".to_string());
+        }
+
+        Ok((title, authors))
+    }
+
+    fn parse_refinements(opf: &str) -> Result<Refinements> {
+        let mut reader = Reader::from_str(opf);
+        reader.config_mut().trim_text(true);
+
+        let mut creators: Vec<(Option<String>, String)> = Vec::new(); // (id, name)
+        let mut title_id: Option<Option<String>> = None; // id of the first title
+        let mut file_as_by_id: HashMap<String, String> = HashMap::new();
+        let mut refinements = Refinements::default();
+
+        // What the next text belongs to
+        enum Current {
+            Creator(Option<String>, Option<String>), // (id, opf:file-as)
+            Title,
+            FileAs(String),
+            None,
+        }
+        let mut current = Current::None;
+
+        loop {
+            match reader.read_event() {
+                Ok(Event::Start(ref e)) => {
+                    let attr = |key: &[u8]| {
+                        e.attributes()
+                            .flatten()
+                            .find(|attr| attr.key.as_ref() == key)
+                            .map(|attr| String::from_utf8_lossy(&attr.value).to_string())
+                    };
+                    current = match e.local_name().as_ref() {
+                        b"creator" => Current::Creator(attr(b"id"), attr(b"opf:file-as")),
+                        b"title" if title_id.is_none() => {
+                            title_id = Some(attr(b"id"));
+                            Current::Title
+                        }
+                        b"meta" if attr(b"property").as_deref() == Some("file-as") => {
+                            match attr(b"refines") {
+                                Some(refines) => Current::FileAs(refines.trim_start_matches('#').to_string()),
+                                None => Current::None,
+                            }
+                        }
+                        _ => Current::None,
+                    };
+                }
+                Ok(Event::Empty(ref e)) if e.local_name().as_ref() == b"meta" => {
+                    let mut name = None;
+                    let mut content = None;
+                    for attr in e.attributes().flatten() {
+                        match attr.key.as_ref() {
+                            b"name" => name = Some(String::from_utf8_lossy(&attr.value).to_string()),
+                            b"content" => content = Some(String::from_utf8_lossy(&attr.value).to_string()),
+                            _ => {}
+                        }
+                    }
+                    if name.as_deref() == Some("calibre:title_sort") {
+                        refinements.title_sort = content.filter(|content| !content.is_empty());
+                    }
+                }
+                Ok(Event::Text(ref e)) => {
+                    let text = xml_text(e).trim().to_string();
+                    match std::mem::replace(&mut current, Current::None) {
+                        Current::Creator(id, file_as) if !text.is_empty() => {
+                            if let Some(file_as) = file_as {
+                                refinements.creator_sort.insert(text.clone(), file_as);
+                            }
+                            creators.push((id, text));
+                        }
+                        Current::FileAs(id) if !text.is_empty() => {
+                            file_as_by_id.insert(id, text);
+                        }
+                        _ => {}
+                    }
+                }
+                Ok(Event::End(_)) => current = Current::None,
+                Ok(Event::Eof) => break,
+                Err(e) => bail!("Error parsing OPF refinements: {}", e),
+                _ => {}
+            }
+        }
+
+        for (id, name) in creators {
+            if let Some(file_as) = id.and_then(|id| file_as_by_id.get(&id)) {
+                refinements.creator_sort.insert(name, file_as.clone());
+            }
+        }
+        if let Some(file_as) = title_id.flatten().and_then(|id| file_as_by_id.remove(&id)) {
+            refinements.title_sort = Some(file_as);
+        }
+
+        Ok(refinements)
+    }
+
+    /// Every creator and contributor in order, with roles from `opf:role`
+    /// attributes or EPUB3 `role` refinements; the flag marks contributors
+    fn parse_creators(opf: &str) -> Result<Vec<(bool, Creator)>> {
+        let mut reader = Reader::from_str(opf);
+        reader.config_mut().trim_text(true);
+
+        // (is contributor, id, name, role)
+        let mut found: Vec<(bool, Option<String>, String, Option<String>)> = Vec::new();
+        let mut roles_by_id: HashMap<String, String> = HashMap::new();
+
+        // What the next text belongs to
+        enum Current {
+            Creator(bool, Option<String>, Option<String>), // (is contributor, id, role)
+            Role(String),
+            None,
+        }
+        let mut current = Current::None;
+
+        loop {
+            match reader.read_event() {
+                Ok(Event::Start(ref e)) => {
+                    let attr = |key: &[u8]| {
+                        e.attributes()
+                            .flatten()
+                            .find(|attr| attr.key.as_ref() == key)
+                            .map(|attr| String::from_utf8_lossy(&attr.value).to_string())
+                    };
+                    current = match e.local_name().as_ref() {
+                        name @ (b"creator" | b"contributor") => Current::Creator(
+                            name == b"contributor",
+                            attr(b"id"),
+                            attr(b"opf:role").or_else(|| attr(b"role")),
+                        ),
+                        b"meta" if attr(b"property").as_deref() == Some("role") => match attr(b"refines") {
+                            Some(refines) => Current::Role(refines.trim_start_matches('#').to_string()),
+                            None => Current::None,
+                        },
+                        _ => Current::None,
+                    };
+                }
+                Ok(Event::Text(ref e)) => {
+                    let text = xml_text(e).trim().to_string();
+                    match std::mem::replace(&mut current, Current::None) {
+                        Current::Creator(is_contributor, id, role) if !text.is_empty() => {
+                            found.push((is_contributor, id, text, role));
+                        }
+                        Current::Role(id) if !text.is_empty() => {
+                            roles_by_id.insert(id, text);
+                        }
+                        _ => {}
+                    }
+                }
+                Ok(Event::End(_)) => current = Current::None,
+                Ok(Event::Eof) => break,
+                Err(e) => bail!("Error parsing OPF creators: {}", e),
+                _ => {}
+            }
+        }
+
+        // Refinements can come after the creators they refine
+        Ok(found
+            .into_iter()
+            .map(|(is_contributor, id, name, role)| {
+                let role = role.or_else(|| id.and_then(|id| roles_by_id.get(&id).cloned()));
+                (is_contributor, Creator { name, role })
+            })
+            .collect())
+    }
+
+    /// The source's creators in roles other than author, and its contributors
+    /// (except the `bkp` tool credit)
+    fn parse_creator_roles(opf: &str) -> Result<(Vec<Creator>, Vec<Creator>)> {
+        let mut creators = Vec::new();
+        let mut contributors = Vec::new();
+        for (is_contributor, creator) in Self::parse_creators(opf)? {
+            if is_contributor {
+                if creator.role.as_deref() != Some("bkp") {
+                    contributors.push(creator);
+                }
+            } else if !creator.is_author() {
+                creators.push(creator);
+            }
+        }
+        Ok((creators, contributors))
+    }
+
+    /// The text of the `dc:identifier` the package's `unique-identifier` names
+    fn parse_unique_identifier(opf: &str) -> Result<Option<String>> {
+        let mut reader = Reader::from_str(opf);
+        reader.config_mut().trim_text(true);
+
+        let mut unique_identifier = None;
+        let mut in_identifier = false;
+
+        loop {
+            match reader.read_event() {
+                Ok(Event::Start(ref e)) if e.local_name().as_ref() == b"package" => {
+                    for attr in e.attributes().flatten() {
+                        if attr.key.as_ref() == b"unique-identifier" {
+                            unique_identifier = Some(String::from_utf8_lossy(&attr.value).to_string());
+                        }
+                    }
+                }
+                Ok(Event::Start(ref e)) if e.local_name().as_ref() == b"identifier" => {
+                    in_identifier = unique_identifier.is_some()
+                        && e.attributes().flatten().any(|attr| {
+                            attr.key.as_ref() == b"id"
+                                && unique_identifier.as_deref() == Some(&*String::from_utf8_lossy(&attr.value))
+                        });
+                }
+                Ok(Event::Text(ref e)) if in_identifier => {
+                    let identifier = xml_text(e).trim().to_string();
+                    if !identifier.is_empty() {
+                        return Ok(Some(identifier));
+                    }
+                    in_identifier = false;
+                }
+                Ok(Event::End(_)) => in_identifier = false,
+                Ok(Event::Eof) => break,
+                Err(e) => bail!("Error parsing OPF identifier: {}", e),
+                _ => {}
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn parse_languages(opf: &str) -> Result<Vec<String>> {
+        Self::parse_dc_values(opf, b"language").context("Error parsing OPF languages")
+    }
+
+    fn parse_subjects(opf: &str) -> Result<Vec<String>> {
+        Self::parse_dc_values(opf, b"subject").context("Error parsing OPF subjects")
+    }
+
+    /// The first dc:date that isn't marked as some other event (EPUB 2
+    /// books may also date their creation or modification this way)
+    fn parse_publication_date(opf: &str) -> Result<Option<String>> {
+        let mut reader = Reader::from_str(opf);
+        reader.config_mut().trim_text(true);
+
+        let mut in_date = false;
+        loop {
+            match reader.read_event() {
+                Ok(Event::Start(ref e)) if e.local_name().as_ref() == b"date" => {
+                    in_date = e
+                        .attributes()
+                        .flatten()
+                        .find(|attr| attr.key.local_name().as_ref() == b"event")
+                        .is_none_or(|attr| matches!(attr.value.as_ref(), b"publication" | b"original-publication"));
+                }
+                Ok(Event::Text(ref e)) if in_date => {
+                    let value = xml_text(e).trim().to_string();
+                    if !value.is_empty() {
+                        return Ok(Some(value));
+                    }
+                    in_date = false;
+                }
+                Ok(Event::End(_)) => in_date = false,
+                Ok(Event::Eof) => return Ok(None),
+                Err(e) => bail!("Error parsing OPF dates: {}", e),
+                _ => {}
+            }
+        }
+    }
+
+    /// The distinct, non-empty values of one Dublin Core element, in order
+    fn parse_dc_values(opf: &str, element: &[u8]) -> Result<Vec<String>> {
+        let mut values = Vec::new();
+        let mut reader = Reader::from_str(opf);
+        reader.config_mut().trim_text(true);
+
+        let mut in_element = false;
+
+        loop {
+            match reader.read_event() {
+                Ok(Event::Start(ref e)) if e.local_name().as_ref() == element => {
+                    in_element = true;
+                }
+                Ok(Event::Text(ref e)) if in_element => {
+                    let value = xml_text(e).trim().to_string();
+                    if !value.is_empty() && !values.contains(&value) {
+                        values.push(value);
+                    }
+                    in_element = false;
+                }
+                Ok(Event::End(_)) => in_element = false,
+                Ok(Event::Eof) => break,
+                Err(e) => bail!("{}", e),
+                _ => {}
+            }
+        }
+
+        Ok(values)
+    }
+
+    /// Parse schema.org accessibility and conformance metadata, in either the
+    /// EPUB3 `<meta property="...">value</meta>` / `<link rel="...">` form or
+    /// the EPUB2 `<meta name="..." content="..."/>` form
+    fn parse_accessibility_metadata(opf: &str) -> Result<Vec<(String, String)>> {
+        let mut metadata = Vec::new();
+        let mut reader = Reader::from_str(opf);
+        reader.config_mut().trim_text(true);
+
+        let mut current_property: Option<String> = None;
+
+        loop {
+            let event = reader.read_event();
+            let is_empty = matches!(event, Ok(Event::Empty(_)));
+            match event {
+                Ok(Event::Start(ref e)) | Ok(Event::Empty(ref e))
+                    if e.local_name().as_ref() == b"meta" || e.local_name().as_ref() == b"link" =>
+                {
+                    let mut property = None;
+                    let mut name = None;
+                    let mut content = None;
+                    let mut refines = false;
+                    for attr in e.attributes().flatten() {
+                        let value = String::from_utf8_lossy(&attr.value).to_string();
+                        match attr.key.as_ref() {
+                            b"property" | b"rel" => property = Some(value),
+                            b"name" => name = Some(value),
+                            b"content" | b"href" => content = Some(value),
+                            b"refines" => refines = true,
+                            _ => {}
+                        }
+                    }
+
+                    if refines {
+                        continue;
+                    }
+                    if let (Some(name), Some(content)) = (&name, &content) {
+                        if is_accessibility_property(name) {
+                            metadata.push((name.clone(), content.clone()));
+                        }
+                    } else if let Some(property) = property.filter(|p| is_accessibility_property(p)) {
+                        match content {
+                            Some(content) => metadata.push((property, content)),
+                            None if !is_empty => current_property = Some(property),
+                            None => {}
+                        }
+                    }
+                }
+                Ok(Event::Text(ref e)) => {
+                    if let Some(property) = current_property.take() {
+                        let value = xml_text(e).trim().to_string();
+                        if !value.is_empty() {
+                            metadata.push((property, value));
+                        }
+                    }
+                }
+                Ok(Event::End(_)) => current_property = None,
+                Ok(Event::Eof) => break,
+                Err(e) => bail!("Error parsing OPF accessibility metadata: {}", e),
+                _ => {}
+            }
+        }
+
+        Ok(metadata)
+    }
+
+    /// Map each file to the top-level NCX entries pointing into it; entries
+    /// nested deeper come along as their children
+    fn parse_toc(toc_xml: &str, hrefs: &NcxHrefs) -> Result<HashMap<String, Vec<TocEntry>>> {
+        let mut toc_map: HashMap<String, Vec<TocEntry>> = HashMap::new();
+        for point in Self::parse_ncx(toc_xml, hrefs)? {
+            if point.src.is_empty() {
+                continue;
+            }
+
+            let (href, anchor) = match point.src.split_once('#') {
+                Some((href, anchor)) => (href.to_string(), Some(anchor.to_string())),
+                None => (point.src.clone(), None),
+            };
+            let entry = TocEntry {
+                text: point.text,
+                anchor,
+                children: point.children,
+            };
+
+            let entries = toc_map.entry(href).or_default();
+
+            // Put file links (no anchor) before anchor links
+            if entry.anchor.is_none() {
+                let insert_pos = entries.iter().take_while(|e| e.anchor.is_none()).count();
+                entries.insert(insert_pos, entry);
+            } else {
+                entries.push(entry);
+            }
+        }
+
+        Ok(toc_map)
+    }
+
+    /// The NCX's navPoints in order, nested as in the file, with `src`
+    /// resolved to package paths
+    fn parse_ncx(toc_xml: &str, hrefs: &NcxHrefs) -> Result<Vec<NavPoint>> {
+        let mut points = Vec::new();
+        let mut reader = Reader::from_str(toc_xml);
+        reader.config_mut().trim_text(true);
+
+        // navPoints open around the current position, innermost last
+        let mut open: Vec<NavPoint> = Vec::new();
+        let mut in_text = false;
+
+        loop {
+            match reader.read_event() {
+                Ok(Event::Start(ref e)) if e.local_name().as_ref() == b"navPoint" => {
+                    open.push(NavPoint::default());
+                }
+                Ok(Event::Start(ref e)) if e.local_name().as_ref() == b"text" => {
+                    in_text = !open.is_empty();
+                }
+                Ok(Event::Start(ref e)) | Ok(Event::Empty(ref e)) if e.local_name().as_ref() == b"content" => {
+                    if let Some(point) = open.last_mut() {
+                        for attr in e.attributes().flatten() {
+                            if attr.key.as_ref() == b"src" {
+                                let raw_src = String::from_utf8_lossy(&attr.value).to_string();
+                                point.src = hrefs.resolve(&raw_src);
+                            }
+                        }
+                    }
+                }
+                Ok(Event::Text(ref e)) if in_text => {
+                    if let Some(point) = open.last_mut() {
+                        point.text = xml_text(e).trim().to_string();
+                    }
+                }
+                Ok(Event::End(ref e)) if e.local_name().as_ref() == b"text" => in_text = false,
+                Ok(Event::End(ref e)) if e.local_name().as_ref() == b"navPoint" => {
+                    let Some(point) = open.pop() else {
+                        continue;
+                    };
+                    match open.last_mut() {
+                        Some(parent) => parent.children.push(point),
+                        None => points.push(point),
+                    }
+                }
+                Ok(Event::Eof) => break,
+                Err(e) => bail!("Error parsing TOC: {}", e),
+                _ => {}
+            }
+        }
+
+        Ok(points)
+    }
+
+    /// The entries of a nav document's `<nav epub:type="toc">`, nested as
+    /// its lists are. An entry's text is its link's, or its heading `<span>`'s
+    /// when it has no link.
+    fn parse_nav_toc(document: &str, hrefs: &NcxHrefs) -> Result<Vec<NavPoint>> {
+        let mut points = Vec::new();
+        let mut reader = Reader::from_str(document);
+
+        // Depth of nested <nav>s inside the TOC nav, once it's been found
+        let mut toc_depth: Option<usize> = None;
+        // <li>s open around the current position, innermost last
+        let mut open: Vec<NavPoint> = Vec::new();
+        // Depth of <a>s and <span>s inside the label being read, if any
+        let mut label_depth: Option<usize> = None;
+
+        loop {
+            match reader.read_event() {
+                Ok(Event::Eof) => break,
+                Err(e) => bail!("Error parsing nav document: {}", e),
+                Ok(Event::Start(ref e)) if e.local_name().as_ref() == b"nav" => match toc_depth.as_mut() {
+                    Some(depth) => *depth += 1,
+                    None => {
+                        let is_toc = e.attributes().flatten().any(|attr| {
+                            attr.key.as_ref() == b"epub:type"
+                                && String::from_utf8_lossy(&attr.value).split_whitespace().any(|t| t == "toc")
+                        });
+                        if is_toc {
+                            toc_depth = Some(0);
+                        }
+                    }
+                },
+                Ok(Event::End(ref e)) if e.local_name().as_ref() == b"nav" => match toc_depth {
+                    Some(0) => break,
+                    Some(depth) => toc_depth = Some(depth - 1),
+                    None => {}
+                },
+                _ if toc_depth.is_none() => {}
+                Ok(Event::Start(ref e)) if e.local_name().as_ref() == b"li" => {
+                    open.push(NavPoint::default());
+                }
+                Ok(Event::Start(ref e)) if matches!(e.local_name().as_ref(), b"a" | b"span") => match label_depth.as_mut() {
+                    Some(depth) => *depth += 1,
+                    None => {
+                        if let Some(point) = open.last_mut().filter(|point| point.text.is_empty()) {
+                            label_depth = Some(0);
+                            for attr in e.attributes().flatten() {
+                                if attr.key.as_ref() == b"href" {
+                                    point.src = hrefs.resolve(&String::from_utf8_lossy(&attr.value));
+                                }
+                            }
+                        }
+                    }
+                },
+                Ok(Event::Text(ref e)) if label_depth.is_some() => {
+                    if let Some(point) = open.last_mut() {
+                        point.text.push_str(&xml_text(e));
+                    }
+                }
+                Ok(Event::End(ref e)) if matches!(e.local_name().as_ref(), b"a" | b"span") => match label_depth {
+                    Some(0) => {
+                        label_depth = None;
+                        if let Some(point) = open.last_mut() {
+                            point.text = point.text.split_whitespace().collect::<Vec<_>>().join(" ");
+                        }
+                    }
+                    Some(depth) => label_depth = Some(depth - 1),
+                    None => {}
+                },
+                Ok(Event::End(ref e)) if e.local_name().as_ref() == b"li" => {
+                    let Some(point) = open.pop() else {
+                        continue;
+                    };
+                    match open.last_mut() {
+                        Some(parent) => parent.children.push(point),
+                        None => points.push(point),
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Ok(points)
+    }
+
+    /// The split lines, scanning the spine the first time. They're shared
+    /// rather than copied, since a book can have thousands.
+    pub(crate) fn get_split_lines(&mut self) -> Result<Arc<[SplitLine]>> {
+        if let Some(split_lines) = &self.split_lines {
+            return Ok(split_lines.clone());
+        }
+        let mut split_lines = Vec::new();
+        self.for_each_split_line(|line| {
+            split_lines.push(line);
+            Ok(ControlFlow::Continue(()))
+        })?;
+        let split_lines: Arc<[SplitLine]> = split_lines.into();
+        self.split_lines = Some(split_lines.clone());
+        Ok(split_lines)
+    }
+
+    /// Pass each split line to `f` as the spine is read, stopping early if it breaks
+    #[instrument(name = "scan", level = "debug", skip_all)]
+    pub(crate) fn for_each_split_line(&mut self, mut f: impl FnMut(SplitLine) -> Result<ControlFlow<()>>) -> Result<()> {
+        // Parse spine from OPF
+        let opf_content =
+            Self::read_file_from_archive(&mut self.archive, &self.content_opf_path)?;
+        let spine_refs = Self::parse_spine(&opf_content)?;
+
+        debug!("Found {} spine items", spine_refs.len());
+
+        for spine_item in spine_refs {
+            let item = self
+                .manifest_items
+                .get(&spine_item.idref)
+                .ok_or_else(|| {
+                    anyhow!("Spine reference not found in manifest: {}", spine_item.idref)
+                })?
+                .clone();
+
+            // Read sample content. Anchored TOC entries need the whole file
+            // to sample from; otherwise a large file is only read far enough
+            // for its own sample.
+            let anchored = self
+                .toc_map
+                .get(&item.href)
+                .is_some_and(|entries| entries.iter().any(|entry| entry.anchor.is_some()));
+            let content = if !anchored && Self::is_large_entry(&mut self.archive, &item.href) {
+                Self::read_prefix_from_archive(&mut self.archive, &item.href, SAMPLE_LEN as u64 + 4)
+            } else {
+                Self::read_file_from_archive(&mut self.archive, &item.href)
+            }
+            .unwrap_or_default();
+            let sample = sample_text(&content);
+
+            let mut current_line = SplitLine {
+                toc: Vec::new(),
+                guide: self.guide_items.get(&item.href).cloned(),
+                anchor: None,
+                id: item.id.clone(),
+                href: item.href.clone(),
+                media_type: item.media_type.clone(),
+                linear: spine_item.linear,
+                sample,
+            };
+
+            // Check if this href has TOC entries
+            if let Some(toc_entries) = self.toc_map.get(&item.href) {
+                for entry in toc_entries {
+                    if let Some(anchor) = &entry.anchor {
+                        // This TOC entry has an anchor - add current line and start a new one
+                        if f(current_line)?.is_break() {
+                            return Ok(());
+                        }
+
+                        // Get sample content from anchor point
+                        let anchor_sample =
+                            sample_text(&Self::split_html_at_anchor(&content, anchor).unwrap_or_default());
+
+                        current_line = SplitLine {
+                            toc: vec![entry.text.clone()],
+                            guide: None,
+                            anchor: Some(anchor.clone()),
+                            id: item.id.clone(),
+                            href: item.href.clone(),
+                            media_type: item.media_type.clone(),
+                            linear: spine_item.linear,
+                            sample: anchor_sample,
+                        };
+                    } else {
+                        // No anchor - add text to current line's TOC
+                        current_line.toc.push(entry.text.clone());
+                    }
+                }
+            }
+
+            if f(current_line)?.is_break() {
+                return Ok(());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// What the source NCX nests under the entry titled `toc_text` that starts `line`
+    pub(crate) fn toc_children(&self, line: &SplitLine, toc_text: &str) -> Vec<NavPoint> {
+        self.toc_map
+            .get(&line.href)
+            .and_then(|entries| {
+                entries
+                    .iter()
+                    .find(|entry| entry.text == toc_text && (entry.anchor.is_none() || entry.anchor == line.anchor))
+            })
+            .map(|entry| entry.children.clone())
+            .unwrap_or_default()
+    }
+
+    pub(crate) fn parse_spine(opf: &str) -> Result<Vec<SpineItem>> {
+        let mut spine_refs = Vec::new();
+        let mut reader = Reader::from_str(opf);
+        reader.config_mut().trim_text(true);
+
+        loop {
+            match reader.read_event() {
+                Ok(Event::Empty(ref e)) | Ok(Event::Start(ref e))
+                    if e.local_name().as_ref() == b"itemref" =>
+                {
+                    let mut idref = None;
+                    let mut linear = true;
+                    let mut id = None;
+                    let mut properties = Vec::new();
+                    for attr in e.attributes().flatten() {
+                        match attr.key.as_ref() {
+                            b"idref" => idref = Some(String::from_utf8_lossy(&attr.value).to_string()),
+                            b"linear" => linear = attr.value.as_ref() != b"no",
+                            b"id" => id = Some(String::from_utf8_lossy(&attr.value).to_string()),
+                            b"properties" => {
+                                properties = String::from_utf8_lossy(&attr.value)
+                                    .split_whitespace()
+                                    .map(str::to_string)
+                                    .collect()
+                            }
+                            _ => {}
+                        }
+                    }
+                    if let Some(idref) = idref {
+                        spine_refs.push(SpineItem {
+                            idref,
+                            linear,
+                            id,
+                            properties,
+                        });
+                    }
+                }
+                Ok(Event::Eof) => break,
+                Err(e) => bail!("Error parsing OPF spine: {}", e),
+                _ => {}
+            }
+        }
+
+        Ok(spine_refs)
+    }
+
+    fn split_html_at_anchor(html: &str, anchor: &str) -> Option<String> {
+        // Simple implementation: find the anchor and return content from there
+        let patterns = [
+            format!(r#"id="{}""#, anchor),
+            format!(r#"id='{}'"#, anchor),
+            format!(r#"name="{}""#, anchor),
+            format!(r#"name='{}'"#, anchor),
+        ];
+
+        for pattern in &patterns {
+            if let Some(pos) = html.find(pattern) {
+                return Some(html[pos..].to_string());
+            }
+        }
+
+        None
+    }
+
+    /// Build an EPUB of the given sections, writing it to `output`
+    pub(crate) fn build_split_epub<W: IoWrite + Seek>(
+        &mut self,
+        output: W,
+        section_indices: &[usize],
+        authors: &[String],
+        title: Option<&str>,
+        languages: &[String],
+        opts: &OutputOptions,
+    ) -> Result<BuiltEpub<W>> {
+        let description = opts.description.as_deref();
+        let part_label = part_label(opts);
+        let numbered_title = part_label.as_ref().map(|label| {
+            let title = title.map(str::to_string).unwrap_or_else(|| format!("{} Split", self.orig_title));
+            format!("{} ({})", title, label)
+        });
+        let title = numbered_title.as_deref().or(title);
+        let tags = if opts.tag.is_empty() && !opts.no_inherit_tags {
+            self.orig_tags.clone()
+        } else {
+            opts.tag.clone()
+        };
+
+        // Get split lines if not already loaded
+        let split_lines = self.get_split_lines()?;
+
+        // Validate indices
+        for &idx in section_indices {
+            if idx >= split_lines.len() {
+                bail!(Failure::new(
+                    FailureKind::OutOfRange,
+                    format!("Section index {} is out of range (max: {})", idx, split_lines.len() - 1),
+                ));
+            }
+        }
+
+        let mut remote = RemoteResources::default();
+
+        // Spine order, or with --keep-order the order the sections were given in
+        let order: Vec<usize> = if opts.keep_order {
+            let mut seen = HashSet::new();
+            section_indices.iter().copied().filter(|idx| seen.insert(*idx)).collect()
+        } else {
+            let selected: HashSet<usize> = section_indices.iter().copied().collect();
+            (0..split_lines.len()).filter(|idx| selected.contains(idx)).collect()
+        };
+
+        // Collect files to include and linked resources
+        let mut content_files: Vec<(String, String, String, bool)> = Vec::new(); // (href, id, media_type, linear)
+        let mut linked_files: HashSet<String> = HashSet::new();
+        let mut toc_entries: Vec<NavPoint> = Vec::new();
+        let mut included_hrefs: HashSet<String> = HashSet::new();
+
+        for idx in order {
+            let line = &split_lines[idx];
+            if opts.no_cover
+                && line.guide.as_ref().is_some_and(|(ref_type, _)| ref_type == "cover")
+            {
+                info!("Leaving out cover page {}", line.href);
+                continue;
+            }
+
+            // Add content file if not already added
+            if !included_hrefs.contains(&line.href) {
+                included_hrefs.insert(line.href.clone());
+                content_files.push((
+                    line.href.clone(),
+                    line.id.clone(),
+                    line.media_type.clone(),
+                    line.linear,
+                ));
+            }
+
+            // Add TOC entries, with whatever the source nests under them
+            for (nth, toc_text) in line.toc.iter().enumerate() {
+                let index = (nth == 0).then_some(idx);
+                toc_entries.push(NavPoint {
+                    text: opts.rename_toc.label(index, toc_text),
+                    src: line.target(),
+                    children: NavPoint::map_titles(self.toc_children(line, toc_text), &|text| {
+                        opts.rename_toc.label(None, text)
+                    }),
+                });
+            }
+        }
+
+        // Scan for linked resources
+        self.scan_content_files(&content_files, opts, &mut remote, &mut linked_files)?;
+
+        // Pull in non-linear items (pop-up notes, answer keys) linked from
+        // included content, following links from those items in turn
+        if opts.include_nonlinear {
+            let mut next = 0;
+            while next < content_files.len() {
+                let (href, _, media_type, _) = content_files[next].clone();
+                next += 1;
+
+                let targets = if Self::is_large_entry(&mut self.archive, &href)
+                    && !transforms_content(&media_type, opts)
+                {
+                    let mut targets: Vec<String> = Vec::new();
+                    Self::scan_entry(&mut self.archive, &href, |window| {
+                        for target in Self::scan_document_links(window, &href)? {
+                            if !targets.contains(&target) {
+                                targets.push(target);
+                            }
+                        }
+                        Ok(())
+                    })?;
+                    targets
+                } else {
+                    let Ok(content) = self.read_text(&href) else {
+                        continue;
+                    };
+                    let content = transform_content(content, &href, &media_type, opts, &mut remote)?;
+                    Self::scan_document_links(&content, &href)?
+                };
+
+                for target in targets {
+                    if included_hrefs.contains(&target) {
+                        continue;
+                    }
+                    let Some(line) = split_lines
+                        .iter()
+                        .find(|line| line.href == target && !line.linear)
+                    else {
+                        continue;
+                    };
+
+                    info!("Including non-linear item {} linked from {}", target, href);
+                    included_hrefs.insert(target.clone());
+                    content_files.push((
+                        line.href.clone(),
+                        line.id.clone(),
+                        line.media_type.clone(),
+                        line.linear,
+                    ));
+
+                    if Self::is_large_entry(&mut self.archive, &line.href)
+                        && !transforms_content(&line.media_type, opts)
+                    {
+                        self.scan_large_entry(&line.href, &mut linked_files)?;
+                    } else if let Ok(linked_content) =
+                        Self::read_file_from_archive(&mut self.archive, &line.href)
+                    {
+                        let linked_content = transform_content(
+                            linked_content,
+                            &line.href,
+                            &line.media_type,
+                            opts,
+                            &mut remote,
+                        )?;
+                        self.scan_for_linked_files(&linked_content, &line.href, &mut linked_files)?;
+                    }
+                }
+            }
+        }
+
+        // Point references that don't name an entry exactly at the one they
+        // mean; links to them are rewritten to match
+        let mut aliases: HashMap<String, String> = HashMap::new(); // reference -> entry
+        linked_files = linked_files
+            .into_iter()
+            .map(|href| match self.entries.resolve(&href) {
+                Some(entry) if entry != href => {
+                    info!("Resolved {} to archive entry {}", href, entry);
+                    aliases.insert(href, entry.clone());
+                    entry
+                }
+                _ => href,
+            })
+            .collect();
+
+        // Copy resources the scanner may have missed, when asked to. Spine
+        // documents, the package file and the old NCX/nav never come along.
+        if let Some(scope) = opts.include_all_resources {
+            let mut skipped: HashSet<String> = split_lines.iter().map(|line| line.href.clone()).collect();
+            skipped.extend(
+                self.manifest_items
+                    .values()
+                    .filter(|item| {
+                        item.media_type == "application/x-dtbncx+xml"
+                            || item.properties.iter().any(|p| p == "nav")
+                    })
+                    .map(|item| item.href.clone()),
+            );
+            skipped.insert(self.content_opf_path.clone());
+
+            let candidates: Vec<String> = match scope {
+                ResourceScope::Manifest => self
+                    .manifest_items
+                    .values()
+                    .map(|item| item.href.clone())
+                    .collect(),
+                ResourceScope::Archive => self
+                    .archive
+                    .file_names()
+                    .filter(|name| {
+                        !name.ends_with('/') && *name != "mimetype" && !name.starts_with("META-INF/")
+                    })
+                    .map(str::to_string)
+                    .collect(),
+            };
+            linked_files.extend(candidates.into_iter().filter(|href| !skipped.contains(href)));
+        }
+
+        if opts.drop_orphans {
+            let declared: HashSet<&str> = self
+                .manifest_items
+                .values()
+                .map(|item| item.href.as_str())
+                .collect();
+            linked_files.retain(|href| {
+                let keep = declared.contains(href.as_str());
+                if !keep {
+                    info!("Dropping {}: not in the source manifest", href);
+                }
+                keep
+            });
+        }
+
+        let mut excluded_files: HashSet<String> = HashSet::new();
+        if let Some(excluded) = resource_matcher(&opts.exclude_resource)? {
+            linked_files.retain(|href| {
+                if excluded.is_match(href) {
+                    info!("Excluding resource {}", href);
+                    excluded_files.insert(href.clone());
+                    false
+                } else {
+                    true
+                }
+            });
+        }
+        if opts.keep_excluded_refs {
+            excluded_files.clear();
+        }
+
+        // Explicitly requested files come along even if --drop-orphans or
+        // --exclude-resource would skip them
+        for path in &opts.include_file {
+            let path = Self::normalize_path(path.trim_start_matches('/'));
+            if self.archive.index_for_name(&path).is_none() {
+                bail!(Failure::new(
+                    FailureKind::InvalidArgs,
+                    format!("--include-file {}: no such file in the EPUB", path),
+                ));
+            }
+            linked_files.insert(path);
+        }
+
+        let mut zip = ZipWriter::new(output);
+
+        // Write mimetype first (must be uncompressed and first)
+        let options = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+        zip.start_file("mimetype", options)
+            .context("Failed to write mimetype")?;
+        zip.write_all(b"application/epub+zip")
+            .context("Failed to write mimetype content")?;
+
+        let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+
+        // Write META-INF/container.xml
+        let container_xml = self.generate_container_xml();
+        zip.start_file("META-INF/container.xml", options)
+            .context("Failed to create container.xml")?;
+        zip.write_all(container_xml.as_bytes())
+            .context("Failed to write container.xml")?;
+
+        // Generate unique ID, or take it from --identifiers-file or the source's with --keep-uid
+        let unique_id = match (output_identifier(opts), &self.orig_unique_id, opts.keep_uid) {
+            (Some(identifier), _, _) => identifier.clone(),
+            (None, Some(orig_unique_id), true) => orig_unique_id.clone(),
+            (None, None, true) => {
+                warn!("Source has no unique identifier to keep; minting a new one");
+                new_unique_id()
+            }
+            (None, _, false) => new_unique_id(),
+        };
+
+        // Determine title
+        let default_title = format!("{} Split", self.orig_title);
+        let final_title = title.unwrap_or(&default_title);
+
+        // Determine description
+        let mut final_description = description.map(|d| d.to_string()).unwrap_or_else(|| {
+            format!(
+                "Split from {} by {}.",
+                self.orig_title,
+                self.orig_authors.join(", ")
+            )
+        });
+        if let (Some(PartNumbering::TitleAndDescription), Some(label)) = (opts.number_parts, &part_label) {
+            final_description = format!("{} {}.", final_description, label);
+        }
+
+        // Decide where each copied file goes in the output
+        let mut path_map = if opts.flatten || opts.ascii_filenames {
+            let mut linked: Vec<&String> = linked_files.iter().collect();
+            linked.sort();
+            let mut files: Vec<(String, String)> = content_files
+                .iter()
+                .map(|(href, _, media_type, _)| (href.clone(), media_type.clone()))
+                .collect();
+            files.extend(
+                linked
+                    .into_iter()
+                    .map(|href| (href.clone(), self.guess_media_type(href))),
+            );
+            output_layout(&files, opts.flatten, opts.ascii_filenames)
+        } else {
+            HashMap::new()
+        };
+        for (reference, entry) in &aliases {
+            let target = path_map.get(entry).cloned().unwrap_or_else(|| entry.clone());
+            path_map.insert(reference.clone(), target);
+        }
+        let output_href =
+            |href: &str| path_map.get(href).cloned().unwrap_or_else(|| href.to_string());
+
+        // Resolve the cover image: (data, archive path, media type)
+        let cover = match &opts.cover {
+            _ if opts.no_cover => None,
+            None => match opts.generate_cover {
+                Some(style) => {
+                    let cover_title = title
+                        .map(str::to_string)
+                        .unwrap_or_else(|| format!("{} Split", self.orig_title));
+                    let cover_data = generate_cover_image(&cover_title, authors, opts.part, style)?;
+                    Some((cover_data, "cover.jpg".to_string(), "image/jpeg".to_string()))
+                }
+                None => None,
+            },
+            // Building never reads files; the caller reads the image in first
+            Some(CoverSource::Image { name, data }) => Some(supplied_cover(name, data, opts.strict_cover)?),
+            Some(CoverSource::SectionImage(section_image)) => {
+                let hrefs: Vec<String> = content_files.iter().map(|(href, ..)| href.clone()).collect();
+                let image_href = self.find_section_image(&hrefs, *section_image)?;
+                let media_type = self.guess_media_type(&image_href);
+                let cover_data = self
+                    .read_binary_file_from_archive(&image_href)
+                    .with_context(|| format!("Failed to read cover image: {}", image_href))?;
+                check_cover(&cover_data, &image_href, opts.strict_cover)?;
+                let cover_href = format!("cover.{}", extension_for_media_type(&media_type));
+                Some((cover_data, cover_href, media_type))
+            }
+        };
+
+        // Bring supplied covers to the requested resolution
+        let cover = match (cover, opts.cover_size) {
+            (Some((cover_data, _, _)), Some(size)) if opts.cover.is_some() => Some((
+                fit_cover(&cover_data, size)?,
+                "cover.jpg".to_string(),
+                "image/jpeg".to_string(),
+            )),
+            (cover, _) => cover,
+        };
+
+        // Build manifest items
+        let mut manifest_items: Vec<ManifestItem> = Vec::new();
+
+        // Add NCX to manifest, and for EPUB 3 the nav document
+        manifest_items.push(ManifestItem::new("ncx", "toc.ncx", "application/x-dtbncx+xml"));
+        if self.version.is_epub3() {
+            manifest_items.push(
+                ManifestItem::new("nav", "nav.xhtml", "application/xhtml+xml")
+                    .with_properties(vec!["nav".to_string()]),
+            );
+        }
+
+        // Add cover if provided
+        if let Some((_, cover_href, cover_media_type)) = &cover {
+            manifest_items.push(
+                ManifestItem::new("coverimageid", cover_href.clone(), cover_media_type.clone())
+                    .with_properties(vec!["cover-image".to_string()]),
+            );
+            manifest_items.push(ManifestItem::new(
+                "cover",
+                "cover.xhtml",
+                "application/xhtml+xml",
+            ));
+        }
+
+        // Everything written so far, for checking references once done
+        let mut written_paths: HashSet<String> = HashSet::new();
+        let mut written_documents: Vec<(String, String, bool)> = Vec::new(); // (path, content, is CSS)
+
+        // Write content files and add to manifest
+        let mut content_count = 0;
+        let mut spine_items: Vec<SpineItem> = Vec::new();
+        let opf_content = Self::read_file_from_archive(&mut self.archive, &self.content_opf_path)?;
+        let source_spine: HashMap<String, SpineItem> = Self::parse_spine(&opf_content)?
+            .into_iter()
+            .map(|item| (item.idref.clone(), item))
+            .collect();
+
+        if cover.is_some() {
+            spine_items.push(SpineItem::new("cover", true));
+        }
+
+        for (href, orig_id, media_type, linear) in &content_files {
+            let source_properties = self
+                .manifest_items
+                .get(orig_id)
+                .map(|item| item.properties.clone())
+                .unwrap_or_default();
+            let out_href = output_href(href);
+
+            let unchanged = !transforms_content(media_type, opts) && excluded_files.is_empty() && path_map.is_empty();
+            let large = Self::is_large_entry(&mut self.archive, href);
+            // Other files nothing needs changing in are copied byte for byte,
+            // so the split never re-encodes them or alters invalid sequences
+            let original = if unchanged && !large {
+                let data = self
+                    .read_binary_file_from_archive(href)
+                    .with_context(|| format!("Failed to read content file: {}", href))?;
+                let text = String::from_utf8_lossy(&data).into_owned();
+                (!has_rooted_references(&text)?).then_some((data, text))
+            } else {
+                None
+            };
+
+            // A large file that comes through unchanged is copied as is. It's
+            // left out of the broken-reference check, which needs it whole.
+            let (properties, media_type) = if unchanged && large {
+                debug!("Copying large content file {} without reading it whole", href);
+                let mut properties: Vec<String> = Vec::new();
+                Self::scan_entry(&mut self.archive, href, |window| {
+                    for property in derive_content_properties(window, &source_properties)? {
+                        if !properties.contains(&property) {
+                            properties.push(property);
+                        }
+                    }
+                    Ok(())
+                })?;
+                let prefix = Self::read_binary_prefix(&mut self.archive, href, SNIFF_LEN)?;
+                self.copy_entry_raw(&mut zip, href)?;
+                written_paths.insert(out_href.clone());
+                (properties, checked_media_type(href, Some(media_type), &prefix))
+            } else if let Some((data, text)) = original {
+                self.copy_entry_raw(&mut zip, href)?;
+                written_paths.insert(out_href.clone());
+                let properties = derive_content_properties(&text, &source_properties)?;
+                let media_type = checked_media_type(href, Some(media_type), &data);
+                written_documents.push((out_href.clone(), text, false));
+                (properties, media_type)
+            } else {
+                let content = self
+                    .read_text(href)
+                    .with_context(|| format!("Failed to read content file: {}", href))?;
+                let mut content = transform_content(content, href, media_type, opts, &mut remote)?;
+                if !excluded_files.is_empty() {
+                    content = strip_excluded_references(&content, href, false, &excluded_files)?;
+                }
+                let content = if path_map.is_empty() && !has_rooted_references(&content)? {
+                    content
+                } else {
+                    rewrite_references(&content, href, &out_href, &path_map)?
+                };
+
+                zip.start_file(out_href.as_str(), options)
+                    .with_context(|| format!("Failed to add file to EPUB: {}", href))?;
+                zip.write_all(content.as_bytes())
+                    .with_context(|| format!("Failed to write content file: {}", href))?;
+                written_paths.insert(out_href.clone());
+                let properties = derive_content_properties(&content, &source_properties)?;
+                let media_type = checked_media_type(href, Some(media_type), content.as_bytes());
+                written_documents.push((out_href.clone(), content, false));
+                (properties, media_type)
+            };
+
+            let id = format!("content{}", content_count);
+            content_count += 1;
+            manifest_items.push(
+                ManifestItem::new(id.clone(), out_href, media_type)
+                    .with_properties(properties),
+            );
+            let spine_item = SpineItem::new(id, *linear);
+            spine_items.push(match source_spine.get(orig_id) {
+                Some(source) => spine_item.with_itemref_attributes(source),
+                None => spine_item,
+            });
+        }
+
+        // Write linked files (CSS, images, fonts)
+        let mut copied_resources = Vec::new();
+        for href in &linked_files {
+            let declared = self.declared_media_type(href);
+            let passthrough = declared.as_deref().unwrap_or(&self.guess_media_type(href)) != "text/css"
+                && opts.optimize_images.is_none()
+                && path_map.is_empty()
+                && Self::is_large_entry(&mut self.archive, href);
+            if passthrough {
+                // Large media such as audio and video is copied without being
+                // read whole
+                let prefix = Self::read_binary_prefix(&mut self.archive, href, SNIFF_LEN)?;
+                let media_type = checked_media_type(href, declared.as_deref(), &prefix);
+                let size = self.copy_entry_raw(&mut zip, href)?;
+                copied_resources.push((href.clone(), size));
+                written_paths.insert(href.clone());
+                let properties = self.resource_properties(href, cover.is_some() || opts.no_cover);
+                let id = format!("resource{}", content_count);
+                content_count += 1;
+                manifest_items.push(ManifestItem::new(id, href.clone(), media_type).with_properties(properties));
+            } else if let Ok(data) = self.read_binary_file_from_archive(href) {
+                let data = match opts.optimize_images {
+                    Some(settings) => optimize_image(href, &data, settings).unwrap_or(data),
+                    None => data,
+                };
+                let media_type = checked_media_type(href, declared.as_deref(), &data);
+                let out_href = output_href(href);
+                let data = match String::from_utf8(data) {
+                    Ok(css) if media_type == "text/css" => {
+                        let mut css = transform_content(css, href, &media_type, opts, &mut remote)?;
+                        if !excluded_files.is_empty() {
+                            css = strip_excluded_references(&css, href, true, &excluded_files)?;
+                        }
+                        if path_map.is_empty() && !has_rooted_references(&css)? {
+                            css.into_bytes()
+                        } else {
+                            rewrite_references(&css, href, &out_href, &path_map)?.into_bytes()
+                        }
+                    }
+                    Ok(text) => text.into_bytes(),
+                    Err(e) => e.into_bytes(),
+                };
+
+                zip.start_file(out_href.as_str(), options)
+                    .with_context(|| format!("Failed to add linked file: {}", href))?;
+                zip.write_all(&data)
+                    .with_context(|| format!("Failed to write linked file: {}", href))?;
+                copied_resources.push((href.clone(), data.len() as u64));
+                written_paths.insert(out_href.clone());
+                if media_type == "text/css" {
+                    let css = String::from_utf8_lossy(&data).into_owned();
+                    written_documents.push((out_href.clone(), css, true));
+                }
+
+                let properties = self.resource_properties(href, cover.is_some() || opts.no_cover);
+
+                let id = format!("resource{}", content_count);
+                content_count += 1;
+                manifest_items
+                    .push(ManifestItem::new(id, out_href, media_type).with_properties(properties));
+            } else {
+                warn!("Skipping linked file that couldn't be read: {}", href);
+            }
+        }
+
+        // Write downloaded remote resources
+        for (href, data, media_type) in &remote.files {
+            zip.start_file(href.as_str(), options)
+                .with_context(|| format!("Failed to add remote resource: {}", href))?;
+            zip.write_all(data)
+                .with_context(|| format!("Failed to write remote resource: {}", href))?;
+            written_paths.insert(href.clone());
+
+            let id = format!("resource{}", content_count);
+            content_count += 1;
+            manifest_items.push(ManifestItem::new(id, href.clone(), media_type.clone()));
+        }
+
+        // Generate and write content.opf
+        let mut accessibility = self.accessibility.clone();
+        if opts.generate_a11y {
+            let mut content = ContentAccessibility::default();
+            let mut scanned = 0;
+            for (_, text, is_css) in &written_documents {
+                if !is_css {
+                    content.scan(text)?;
+                    scanned += 1;
+                }
+            }
+            // Large documents are copied without being kept for the scan
+            content.incomplete = scanned < content_files.len();
+            merge_accessibility_metadata(
+                &mut accessibility,
+                synthesize_accessibility_metadata(&manifest_items, &content),
+            );
+        }
+        // Creators in other roles come with the authors, or else from the source
+        let (authors, mut creators) = split_creator_roles(authors);
+        if opts.author.is_empty() {
+            creators.extend(self.orig_creators.iter().cloned());
+        }
+        let contributors = if opts.contributor.is_empty() {
+            self.orig_contributors.clone()
+        } else {
+            opts.contributor.iter().map(|contributor| Creator::parse(contributor)).collect()
+        };
+        let mut author_sort = self.orig_refinements.creator_sort.clone();
+        author_sort.extend(authors.iter().cloned().zip(opts.author_sort.iter().cloned()));
+        let meta = PackageMetadata {
+            version: self.version,
+            unique_id: unique_id.clone(),
+            title: final_title.to_string(),
+            authors,
+            creators,
+            contributors,
+            description: final_description.clone(),
+            tags: tags.clone(),
+            languages: languages.to_vec(),
+            accessibility,
+            date: self.orig_date.clone(),
+            modified: w3cdtf_utc(unix_time_secs()),
+            author_sort,
+            // The source's sort key only fits if the title is kept
+            title_sort: opts.title_sort.clone().or_else(|| {
+                self.orig_refinements
+                    .title_sort
+                    .clone()
+                    .filter(|_| final_title == self.orig_title)
+            }),
+        };
+        let content_opf =
+            self.generate_content_opf(&meta, &manifest_items, &spine_items, cover.is_some());
+        zip.start_file("content.opf", options)
+            .context("Failed to create content.opf")?;
+        zip.write_all(content_opf.as_bytes())
+            .context("Failed to write content.opf")?;
+
+        // Generate and write toc.ncx, and nav.xhtml for EPUB 3
+        // Nested entries only stay if their file made it into the output
+        for entry in &mut toc_entries {
+            let children = std::mem::take(&mut entry.children);
+            entry.children = NavPoint::retain_files(children, &|file| included_hrefs.contains(file));
+        }
+        let toc_entries = NavPoint::map_files(toc_entries, &output_href);
+        let toc_ncx = generate_ncx(&unique_id, final_title, &toc_entries);
+        zip.start_file("toc.ncx", options)
+            .context("Failed to create toc.ncx")?;
+        zip.write_all(toc_ncx.as_bytes())
+            .context("Failed to write toc.ncx")?;
+
+        // And the same entries as nav.xhtml
+        if meta.version.is_epub3() {
+            let nav = generate_nav(final_title, &toc_entries);
+            zip.start_file("nav.xhtml", options)
+                .context("Failed to create nav.xhtml")?;
+            zip.write_all(nav.as_bytes())
+                .context("Failed to write nav.xhtml")?;
+        }
+
+        // Write cover if provided
+        if let Some((cover_data, cover_href, _)) = &cover {
+            zip.start_file(cover_href.as_str(), options)
+                .with_context(|| format!("Failed to add {}", cover_href))?;
+            zip.write_all(cover_data)
+                .with_context(|| format!("Failed to write {}", cover_href))?;
+            written_paths.insert(cover_href.clone());
+
+            let cover_xhtml = self.generate_cover_xhtml(cover_href);
+            zip.start_file("cover.xhtml", options)
+                .context("Failed to add cover.xhtml")?;
+            zip.write_all(cover_xhtml.as_bytes())
+                .context("Failed to write cover.xhtml")?;
+        }
+
+        let output = zip.finish().context("Failed to finalize EPUB file")?;
+        Ok(BuiltEpub {
+            output,
+            copied_resources,
+            documents: written_documents,
+            written_paths,
+            meta,
+            cover: cover.map(|(cover_data, ..)| cover_data),
+        })
+    }
+
+    #[instrument(name = "scan_links", level = "debug", skip_all, fields(href = base_href))]
+    fn scan_for_linked_files(
+        &mut self,
+        content: &str,
+        base_href: &str,
+        linked_files: &mut HashSet<String>,
+    ) -> Result<()> {
+        let mut stylesheets = Vec::new();
+        Self::scan_markup_links(content, base_href, linked_files, &mut stylesheets)?;
+        self.scan_stylesheets(&stylesheets, linked_files)
+    }
+
+    /// Source manifest properties of a copied resource, such as cover-image.
+    /// cover-image is dropped when a replacement cover was supplied or
+    /// covers are left out.
+    fn resource_properties(&self, href: &str, drop_cover: bool) -> Vec<String> {
+        self.manifest_items
+            .values()
+            .find(|item| item.href == href)
+            .map(|item| {
+                item.properties
+                    .iter()
+                    .filter(|p| !(drop_cover && p.as_str() == "cover-image"))
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// `scan_for_linked_files` over the given content files. Files are read
+    /// and transformed in batches, then each batch is scanned in parallel.
+    fn scan_content_files(
+        &mut self,
+        content_files: &[(String, String, String, bool)],
+        opts: &OutputOptions,
+        remote: &mut RemoteResources,
+        linked_files: &mut HashSet<String>,
+    ) -> Result<()> {
+        const BATCH: usize = 64;
+
+        let mut stylesheets: Vec<String> = Vec::new();
+        for batch in content_files.chunks(BATCH) {
+            let mut documents: Vec<(&str, String)> = Vec::new();
+            for (href, _, media_type, _) in batch {
+                if Self::is_large_entry(&mut self.archive, href) && !transforms_content(media_type, opts) {
+                    self.scan_large_entry(href, linked_files)?;
+                } else if let Ok(content) = self.read_text(href) {
+                    documents.push((href, transform_content(content, href, media_type, opts, remote)?));
+                }
+            }
+
+            let scanned = parallel_map(&documents, worker_threads(), |(href, content)| {
+                let mut linked = HashSet::new();
+                let mut found_stylesheets = Vec::new();
+                Self::scan_markup_links(content, href, &mut linked, &mut found_stylesheets)
+                    .map(|()| (linked, found_stylesheets))
+            });
+            for result in scanned {
+                let (linked, found_stylesheets) = result?;
+                linked_files.extend(linked);
+                for stylesheet in found_stylesheets {
+                    if !stylesheets.contains(&stylesheet) {
+                        stylesheets.push(stylesheet);
+                    }
+                }
+            }
+        }
+        self.scan_stylesheets(&stylesheets, linked_files)
+    }
+
+    /// `scan_for_linked_files` for an entry too large to read whole
+    fn scan_large_entry(&mut self, href: &str, linked_files: &mut HashSet<String>) -> Result<()> {
+        let mut stylesheets = Vec::new();
+        Self::scan_entry(&mut self.archive, href, |window| {
+            Self::scan_markup_links(window, href, linked_files, &mut stylesheets)
+        })?;
+        self.scan_stylesheets(&stylesheets, linked_files)
+    }
+
+    /// Collect the images and stylesheets a document links to. Stylesheets
+    /// are also added to `stylesheets`, to be scanned in turn.
+    fn scan_markup_links(
+        content: &str,
+        base_href: &str,
+        linked_files: &mut HashSet<String>,
+        stylesheets: &mut Vec<String>,
+    ) -> Result<()> {
+        let base_path = Self::get_path_part(base_href);
+
+        // Scan for images: src="..." and xlink:href="..."
+        let img_re = Regex::new(r#"(?:src|xlink:href|altimg)=["']([^"']+)["']"#)
+            .context("Failed to compile image regex")?;
+        for cap in img_re.captures_iter(content) {
+            if let Some(src) = cap.get(1) {
+                let src_str = src.as_str();
+                if !src_str.starts_with("http://") && !src_str.starts_with("https://") {
+                    let full_path = Self::join_href(&base_path, src_str);
+                    linked_files.insert(full_path);
+                }
+            }
+        }
+
+        // Scan for CSS links: href="..." with type="text/css"
+        let css_link_re = Regex::new(r#"<link[^>]+href=["']([^"']+\.css)["'][^>]*>"#)
+            .context("Failed to compile CSS link regex")?;
+        for cap in css_link_re.captures_iter(content) {
+            if let Some(href) = cap.get(1) {
+                if href.as_str().starts_with("http://") || href.as_str().starts_with("https://") {
+                    continue;
+                }
+                let full_path = Self::join_href(&base_path, href.as_str());
+                if linked_files.insert(full_path.clone()) {
+                    stylesheets.push(full_path);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Scan stylesheets for @import and url()
+    fn scan_stylesheets(&mut self, stylesheets: &[String], linked_files: &mut HashSet<String>) -> Result<()> {
+        for path in stylesheets {
+            if let Ok(css_content) = Self::read_file_from_archive(&mut self.archive, path) {
+                self.scan_css_for_resources(&css_content, path, linked_files)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Find the Nth (1-based) image referenced by the given content files,
+    /// in reading order
+    fn find_section_image(&mut self, hrefs: &[String], n: usize) -> Result<String> {
+        let img_re = Regex::new(r#"<(?:\w+:)?(?:img|image)\b[^>]*?\s(?:src|xlink:href)=["']([^"']+)["']"#)
+            .context("Failed to compile image regex")?;
+
+        let mut images: Vec<String> = Vec::new();
+        for href in hrefs {
+            let content = Self::read_file_from_archive(&mut self.archive, href)
+                .with_context(|| format!("Failed to read content file: {}", href))?;
+            let base_path = Self::get_path_part(href);
+            for cap in img_re.captures_iter(&content) {
+                let src = &cap[1];
+                if src.contains(':') {
+                    continue;
+                }
+                let full_path = Self::join_href(&base_path, src);
+                if !images.contains(&full_path) {
+                    images.push(full_path);
+                }
+            }
+        }
+
+        if n == 0 || n > images.len() {
+            bail!(
+                "Cover image {} requested, but the selected sections reference {} image(s)",
+                n,
+                images.len()
+            );
+        }
+        Ok(images.swap_remove(n - 1))
+    }
+
+    /// Find the content documents linked to from `<a>`/`<area>` hrefs
+    fn scan_document_links(content: &str, base_href: &str) -> Result<Vec<String>> {
+        let base_path = Self::get_path_part(base_href);
+        let link_re = Regex::new(r#"<(?:a|area)\b[^>]*?\bhref=["']([^"'#]*)(?:#[^"']*)?["']"#)
+            .context("Failed to compile document link regex")?;
+
+        let mut targets = Vec::new();
+        for cap in link_re.captures_iter(content) {
+            let target = &cap[1];
+            if target.is_empty() || target.contains(':') {
+                // Same-document anchor, or an external/mailto link
+                continue;
+            }
+            let full_path = Self::join_href(&base_path, target);
+            if !targets.contains(&full_path) {
+                targets.push(full_path);
+            }
+        }
+
+        Ok(targets)
+    }
+
+    fn scan_css_for_resources(
+        &self,
+        css_content: &str,
+        base_href: &str,
+        linked_files: &mut HashSet<String>,
+    ) -> Result<()> {
+        let base_path = Self::get_path_part(base_href);
+
+        // Remove CSS comments
+        let comment_re =
+            Regex::new(r"/\*.*?\*/").context("Failed to compile CSS comment regex")?;
+        let css_clean = comment_re.replace_all(css_content, "");
+
+        // Scan for @import
+        let import_re = Regex::new(r#"@import\s+(?:url\()?["']?([^"'\)]+)["']?\)?"#)
+            .context("Failed to compile @import regex")?;
+        for cap in import_re.captures_iter(&css_clean) {
+            if let Some(url) = cap.get(1) {
+                if url.as_str().starts_with("http://") || url.as_str().starts_with("https://") {
+                    continue;
+                }
+                let full_path = Self::join_href(&base_path, url.as_str());
+                linked_files.insert(full_path);
+            }
+        }
+
+        // Scan for url()
+        let url_re =
+            Regex::new(r#"url\(["']?([^"'\)]+)["']?\)"#).context("Failed to compile url() regex")?;
+        for cap in url_re.captures_iter(&css_clean) {
+            if let Some(url) = cap.get(1) {
+                let url_str = url.as_str();
+                if !url_str.starts_with("data:")
+                    && !url_str.starts_with("http://")
+                    && !url_str.starts_with("https://")
+                {
+                    let full_path = Self::join_href(&base_path, url_str);
+                    linked_files.insert(full_path);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn read_binary_file_from_archive(&mut self, path: &str) -> Result<Vec<u8>> {
+        let mut file = self
+            .archive
+            .by_name(path)
+            .with_context(|| format!("File not found in EPUB: {}", path))?;
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents)
+            .with_context(|| format!("Failed to read file from EPUB: {}", path))?;
+        Ok(contents)
+    }
+
+    /// The media type the source manifest gives a file, if it has one
+    fn declared_media_type(&self, href: &str) -> Option<String> {
+        self.manifest_items
+            .values()
+            .find(|item| item.href == href)
+            .map(|item| item.media_type.clone())
+            .filter(|media_type| !media_type.trim().is_empty())
+    }
+
+    fn guess_media_type(&self, href: &str) -> String {
+        let lower = href.to_lowercase();
+        if lower.ends_with(".css") {
+            "text/css".to_string()
+        } else if lower.ends_with(".jpg") || lower.ends_with(".jpeg") {
+            "image/jpeg".to_string()
+        } else if lower.ends_with(".png") {
+            "image/png".to_string()
+        } else if lower.ends_with(".gif") {
+            "image/gif".to_string()
+        } else if lower.ends_with(".svg") {
+            "image/svg+xml".to_string()
+        } else if lower.ends_with(".ttf") {
+            "application/x-font-ttf".to_string()
+        } else if lower.ends_with(".otf") {
+            "application/vnd.ms-opentype".to_string()
+        } else if lower.ends_with(".woff") {
+            "application/font-woff".to_string()
+        } else if lower.ends_with(".woff2") {
+            "font/woff2".to_string()
+        } else {
+            "application/octet-stream".to_string()
+        }
+    }
+
+    fn generate_container_xml(&self) -> String {
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<container version="1.0" xmlns="urn:oasis:names:tc:opendocument:xmlns:container">
+   <rootfiles>
+      <rootfile full-path="content.opf" media-type="application/oebps-package+xml"/>
+   </rootfiles>
+</container>
+"#
+        .to_string()
+    }
+
+    fn generate_content_opf(
+        &self,
+        meta: &PackageMetadata,
+        manifest_items: &[ManifestItem],
+        spine_items: &[SpineItem],
+        has_cover: bool,
+    ) -> String {
+        let mut opf = Self::package_start_xml(meta.version, "epubsplit-id");
+
+        // Add identifier
+        opf.push_str(&format!(
+            "      <dc:identifier id=\"epubsplit-id\">{}</dc:identifier>\n",
+            Self::escape_xml(&meta.unique_id)
+        ));
+
+        // Add title
+        opf.push_str(&format!(
+            "      <dc:title>{}</dc:title>\n",
+            Self::escape_xml(&meta.title)
+        ));
+
+        // Add authors, other creators and contributors
+        opf.push_str(&Self::creators_xml(meta));
+
+        // Add languages
+        for lang in &meta.languages {
+            opf.push_str(&format!(
+                "      <dc:language>{}</dc:language>\n",
+                Self::escape_xml(lang)
+            ));
+        }
+
+        // Add description
+        opf.push_str(&format!(
+            "      <dc:description>{}</dc:description>\n",
+            Self::escape_xml(&meta.description)
+        ));
+
+        // Add publication and modification dates
+        opf.push_str(&Self::dates_xml(meta));
+
+        // Add tags/subjects
+        for tag in &meta.tags {
+            opf.push_str(&format!(
+                "      <dc:subject>{}</dc:subject>\n",
+                Self::escape_xml(tag)
+            ));
+        }
+
+        // Add accessibility metadata
+        opf.push_str(&Self::accessibility_meta_xml(meta));
+
+        if let Some(title_sort) = &meta.title_sort {
+            opf.push_str(&format!(
+                "      <meta name=\"calibre:title_sort\" content=\"{}\"/>\n",
+                Self::escape_xml(title_sort)
+            ));
+        }
+
+        // Add cover metadata if present
+        if has_cover {
+            opf.push_str("      <meta name=\"cover\" content=\"coverimageid\"/>\n");
+        }
+
+        opf.push_str("   </metadata>\n");
+
+        // Add manifest
+        opf.push_str("   <manifest>\n");
+        for item in manifest_items {
+            opf.push_str(&Self::manifest_item_xml(item, meta.version));
+        }
+        opf.push_str("   </manifest>\n");
+
+        // Add spine
+        opf.push_str("   <spine toc=\"ncx\">\n");
+        for item in spine_items {
+            opf.push_str(&Self::spine_item_xml(item, meta.version));
+        }
+        opf.push_str("   </spine>\n");
+
+        // Add guide if cover present
+        if has_cover {
+            opf.push_str("   <guide>\n");
+            opf.push_str(
+                "      <reference type=\"cover\" title=\"Cover\" href=\"cover.xhtml\"/>\n",
+            );
+            opf.push_str("   </guide>\n");
+        }
+
+        opf.push_str("</package>\n");
+
+        opf
+    }
+
+    /// The XML declaration, `<package>` and opening `<metadata>` of an OPF
+    /// whose unique identifier has the id `unique_id`
+    fn package_start_xml(version: EpubVersion, unique_id: &str) -> String {
+        // EPUB 3 packages refine metadata instead of using opf: attributes
+        let opf_namespace = if version.is_epub3() {
+            ""
+        } else {
+            " xmlns:opf=\"http://www.idpf.org/2007/opf\""
+        };
+        format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<package version="{}" xmlns="http://www.idpf.org/2007/opf" unique-identifier="{}">
+   <metadata xmlns:dc="http://purl.org/dc/elements/1.1/"{}>
+"#,
+            version.package_version(),
+            unique_id,
+            opf_namespace
+        )
+    }
+
+    /// `dc:creator` elements for the authors and then creators in other
+    /// roles, then `dc:contributor` elements ending with the `bkp` credit
+    fn creators_xml(meta: &PackageMetadata) -> String {
+        let bkp = Creator {
+            name: "epubsplit-rs".to_string(),
+            role: Some("bkp".to_string()),
+        };
+        let authors = meta.authors.iter().map(|author| Creator {
+            name: author.clone(),
+            role: Some("aut".to_string()),
+        });
+        let creators = authors.chain(meta.creators.iter().cloned()).map(|creator| ("creator", creator));
+        let contributors = meta
+            .contributors
+            .iter()
+            .cloned()
+            .chain(std::iter::once(bkp))
+            .map(|contributor| ("contributor", contributor));
+        creators
+            .chain(contributors)
+            .enumerate()
+            .map(|(n, (element, creator))| {
+                if !meta.version.is_epub3() {
+                    let role = creator
+                        .role
+                        .as_ref()
+                        .map(|role| format!(" opf:role=\"{}\"", Self::escape_xml(role)))
+                        .unwrap_or_default();
+                    return format!(
+                        "      <dc:{}{}{}>{}</dc:{}>\n",
+                        element,
+                        role,
+                        Self::file_as_attr(meta, &creator.name),
+                        Self::escape_xml(&creator.name),
+                        element
+                    );
+                }
+                let id = format!("{}{}", element, n + 1);
+                let mut xml = format!(
+                    "      <dc:{} id=\"{}\">{}</dc:{}>\n",
+                    element,
+                    id,
+                    Self::escape_xml(&creator.name),
+                    element
+                );
+                if let Some(role) = &creator.role {
+                    xml.push_str(&format!(
+                        "      <meta refines=\"#{}\" property=\"role\" scheme=\"marc:relators\">{}</meta>\n",
+                        id,
+                        Self::escape_xml(role)
+                    ));
+                }
+                if let Some(file_as) = meta.author_sort.get(&creator.name) {
+                    xml.push_str(&format!(
+                        "      <meta refines=\"#{}\" property=\"file-as\">{}</meta>\n",
+                        id,
+                        Self::escape_xml(file_as)
+                    ));
+                }
+                xml
+            })
+            .collect()
+    }
+
+    /// An ` opf:file-as="..."` attribute for an author with a sort key
+    pub(crate) fn file_as_attr(meta: &PackageMetadata, author: &str) -> String {
+        meta.author_sort
+            .get(author)
+            .map(|file_as| format!(" opf:file-as=\"{}\"", Self::escape_xml(file_as)))
+            .unwrap_or_default()
+    }
+
+    /// The source's publication date, then when the package was written:
+    /// EPUB 3's required `dcterms:modified`, or an EPUB 2 modification-event
+    /// date
+    fn dates_xml(meta: &PackageMetadata) -> String {
+        let mut xml = String::new();
+        if let Some(date) = &meta.date {
+            let event = if meta.version.is_epub3() { "" } else { " opf:event=\"publication\"" };
+            xml.push_str(&format!("      <dc:date{}>{}</dc:date>\n", event, Self::escape_xml(date)));
+        }
+        if meta.version.is_epub3() {
+            xml.push_str(&format!("      <meta property=\"dcterms:modified\">{}</meta>\n", meta.modified));
+        } else {
+            xml.push_str(&format!("      <dc:date opf:event=\"modification\">{}</dc:date>\n", meta.modified));
+        }
+        xml
+    }
+
+    fn accessibility_meta_xml(meta: &PackageMetadata) -> String {
+        meta.accessibility
+            .iter()
+            .map(|(property, value)| {
+                if meta.version.is_epub3() {
+                    format!(
+                        "      <meta property=\"{}\">{}</meta>\n",
+                        Self::escape_xml(property),
+                        Self::escape_xml(value)
+                    )
+                } else {
+                    format!(
+                        "      <meta name=\"{}\" content=\"{}\"/>\n",
+                        Self::escape_xml(property),
+                        Self::escape_xml(value)
+                    )
+                }
+            })
+            .collect()
+    }
+
+    /// A spine `<itemref>`; as with manifest items, properties are left off
+    /// in EPUB 2
+    fn spine_item_xml(item: &SpineItem, version: EpubVersion) -> String {
+        let id = item
+            .id
+            .as_ref()
+            .map(|id| format!(" id=\"{}\"", Self::escape_xml(id)))
+            .unwrap_or_default();
+        let properties = if item.properties.is_empty() || !version.is_epub3() {
+            String::new()
+        } else {
+            format!(" properties=\"{}\"", Self::escape_xml(&item.properties.join(" ")))
+        };
+        format!(
+            "      <itemref{} idref=\"{}\" linear=\"{}\"{}/>\n",
+            id,
+            Self::escape_xml(&item.idref),
+            if item.linear { "yes" } else { "no" },
+            properties
+        )
+    }
+
+    /// A manifest `<item>`; properties are EPUB 3 only, so left off in EPUB 2
+    fn manifest_item_xml(item: &ManifestItem, version: EpubVersion) -> String {
+        let properties = if item.properties.is_empty() || !version.is_epub3() {
+            String::new()
+        } else {
+            format!(" properties=\"{}\"", Self::escape_xml(&item.properties.join(" ")))
+        };
+        format!(
+            "      <item id=\"{}\" href=\"{}\" media-type=\"{}\"{}/>\n",
+            Self::escape_xml(&item.id),
+            Self::escape_xml(&item.href),
+            Self::escape_xml(&item.media_type),
+            properties
+        )
+    }
+
+    fn generate_cover_xhtml(&self, cover_href: &str) -> String {
+        format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE html PUBLIC "-//W3C//DTD XHTML 1.1//EN" "http://www.w3.org/TR/xhtml11/DTD/xhtml11.dtd">
+<html xmlns="http://www.w3.org/1999/xhtml" xml:lang="en">
+<head>
+   <title>Cover</title>
+   <style type="text/css">
+      @page {{ padding: 0pt; margin: 0pt; }}
+      body {{ text-align: center; padding: 0pt; margin: 0pt; }}
+      div {{ margin: 0pt; padding: 0pt; }}
+   </style>
+</head>
+<body>
+   <div>
+      <img src="{}" alt="cover"/>
+   </div>
+</body>
+</html>
+"#,
+            Self::escape_xml(cover_href)
+        )
+    }
+
+    pub(crate) fn escape_xml(s: &str) -> String {
+        s.replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+            .replace('"', "&quot;")
+            .replace('\'', "&apos;")
+    }
+
+    /// Readable text of the content files behind the given split lines
+    pub(crate) fn section_text(&mut self, section_indices: &[usize]) -> Result<String> {
+        let split_lines = self.get_split_lines()?;
+        let mut seen = HashSet::new();
+        let mut text = String::new();
+
+        for &idx in section_indices {
+            let Some(line) = split_lines.get(idx) else {
+                continue;
+            };
+            if !seen.insert(line.href.clone()) {
+                continue;
+            }
+            if let Ok(content) = Self::read_file_from_archive(&mut self.archive, &line.href) {
+                text.push_str(&html_to_text(&content));
+                text.push('\n');
+            }
+        }
+
+        Ok(text)
+    }
+
+    /// Number of words in each split line's section: from its anchor (or the
+    /// start of its file) up to the next line's anchor in the same file
+    pub(crate) fn section_word_counts(&mut self, lines: &[SplitLine]) -> Result<Vec<usize>> {
+        let mut contents: HashMap<String, String> = HashMap::new();
+        let mut words_from = Vec::with_capacity(lines.len());
+        let anchored: HashSet<&str> = lines
+            .iter()
+            .filter(|line| line.anchor.is_some())
+            .map(|line| line.href.as_str())
+            .collect();
+        for line in lines {
+            // A large file counted whole can be counted as it's read
+            if !anchored.contains(line.href.as_str()) && Self::is_large_entry(&mut self.archive, &line.href) {
+                let words = match self.archive.by_name(&line.href) {
+                    Ok(file) => {
+                        let mut counter = WordCounter::default();
+                        counter
+                            .feed_from(file)
+                            .with_context(|| format!("Failed to read file from EPUB: {}", line.href))?;
+                        counter.words()
+                    }
+                    Err(_) => 0,
+                };
+                words_from.push(words);
+                continue;
+            }
+            if !contents.contains_key(&line.href) {
+                let content = Self::read_file_from_archive(&mut self.archive, &line.href).unwrap_or_default();
+                contents.insert(line.href.clone(), content);
+            }
+            let content = &contents[&line.href];
+            let tail = match &line.anchor {
+                Some(anchor) => Self::split_html_at_anchor(content, anchor).unwrap_or_default(),
+                None => content.clone(),
+            };
+            words_from.push(html_to_text(&tail).split_whitespace().count());
+        }
+
+        Ok((0..lines.len())
+            .map(|idx| match lines.get(idx + 1) {
+                Some(next) if next.href == lines[idx].href => words_from[idx].saturating_sub(words_from[idx + 1]),
+                _ => words_from[idx],
+            })
+            .collect())
+    }
+
+    /// Readable text of each split line's section, which runs from its
+    /// anchor (or the start of its file) up to the next line's anchor in the
+    /// same file, with whitespace collapsed. None for a file too large to
+    /// read whole, which only ever holds one section.
+    fn section_texts(&mut self, lines: &[SplitLine]) -> Result<Vec<Option<String>>> {
+        let anchored: HashSet<&str> = lines
+            .iter()
+            .filter(|line| line.anchor.is_some())
+            .map(|line| line.href.as_str())
+            .collect();
+        let mut contents: HashMap<String, String> = HashMap::new();
+        // Text from each line's anchor on
+        let mut texts_from: Vec<Option<String>> = Vec::with_capacity(lines.len());
+        for line in lines {
+            if !anchored.contains(line.href.as_str()) && Self::is_large_entry(&mut self.archive, &line.href) {
+                texts_from.push(None);
+                continue;
+            }
+            if !contents.contains_key(&line.href) {
+                let content = self.read_text(&line.href).unwrap_or_default();
+                contents.insert(line.href.clone(), content);
+            }
+            let content = &contents[&line.href];
+            let tail = match &line.anchor {
+                Some(anchor) => Self::split_html_at_anchor(content, anchor).unwrap_or_default(),
+                None => content.clone(),
+            };
+            texts_from.push(Some(html_to_text(&tail).split_whitespace().collect::<Vec<_>>().join(" ")));
+        }
+
+        Ok((0..lines.len())
+            .map(|index| {
+                let text = texts_from[index].as_ref()?;
+                Some(match (lines.get(index + 1), texts_from.get(index + 1)) {
+                    (Some(next), Some(Some(rest))) if next.href == lines[index].href && text.ends_with(rest.as_str()) => {
+                        text[..text.len() - rest.len()].trim_end().to_string()
+                    }
+                    _ => text.clone(),
+                })
+            })
+            .collect())
+    }
+
+    /// The text around each match of `pattern` in each split line's section
+    /// (see `section_texts`)
+    pub(crate) fn section_snippets(&mut self, lines: &[SplitLine], pattern: &Regex, context: usize) -> Result<Vec<Vec<Snippet>>> {
+        let mut snippets = Vec::with_capacity(lines.len());
+        for (line, text) in lines.iter().zip(self.section_texts(lines)?) {
+            if let Some(text) = text {
+                snippets.push(Snippet::find_all(&text, pattern, context));
+                continue;
+            }
+            let mut found = Vec::new();
+            Self::scan_entry(&mut self.archive, &line.href, |window| {
+                let text = html_to_text(window).split_whitespace().collect::<Vec<_>>().join(" ");
+                for snippet in Snippet::find_all(&text, pattern, context) {
+                    // Windows overlap, so a match may be seen twice
+                    if !found.contains(&snippet) {
+                        found.push(snippet);
+                    }
+                }
+                Ok(())
+            })?;
+            snippets.push(found);
+        }
+        Ok(snippets)
+    }
+
+    /// The first `words` words of each split line's section (see
+    /// `section_texts`)
+    pub(crate) fn section_previews(&mut self, lines: &[SplitLine], words: usize) -> Result<Vec<String>> {
+        let mut previews = Vec::with_capacity(lines.len());
+        for (line, text) in lines.iter().zip(self.section_texts(lines)?) {
+            let text = match text {
+                Some(text) => text,
+                None => html_to_text(&Self::read_prefix_from_archive(&mut self.archive, &line.href, SCAN_CHUNK)?),
+            };
+            previews.push(text.split_whitespace().take(words).collect::<Vec<_>>().join(" "));
+        }
+        Ok(previews)
+    }
+
+    /// Find sections whose content file repeats an earlier one, either exactly
+    /// or nearly (by word shingles). Maps each duplicate line index to the
+    /// first line of the original and the similarity (1.0 = identical).
+    pub(crate) fn find_duplicate_sections(&mut self, lines: &[SplitLine]) -> Result<HashMap<usize, (usize, f32)>> {
+        const SHINGLE_WORDS: usize = 5;
+        const MIN_WORDS: usize = 50;
+        const NEAR_DUPLICATE_SIMILARITY: f32 = 0.9;
+
+        // (first line index, text digest, shingles) for each distinct file
+        let mut files: Vec<(usize, Vec<u8>, HashSet<u64>)> = Vec::new();
+        let mut file_of_href: HashMap<String, usize> = HashMap::new();
+        let mut duplicates = HashMap::new();
+
+        for (index, line) in lines.iter().enumerate() {
+            if let Some(&file) = file_of_href.get(&line.href) {
+                if let Some(&(original, similarity)) = duplicates.get(&files[file].0) {
+                    duplicates.insert(index, (original, similarity));
+                }
+                continue;
+            }
+
+            let (digest, shingles, has_words) = if Self::is_large_entry(&mut self.archive, &line.href) {
+                // Too large to hold as words, so only byte-identical copies
+                // are found
+                let mut hasher = Sha1::new();
+                if let Ok(mut file) = self.archive.by_name(&line.href) {
+                    std::io::copy(&mut file, &mut hasher)
+                        .with_context(|| format!("Failed to read file from EPUB: {}", line.href))?;
+                }
+                (hasher.finalize().to_vec(), HashSet::new(), true)
+            } else {
+                let content = Self::read_file_from_archive(&mut self.archive, &line.href).unwrap_or_default();
+                let words: Vec<String> = html_to_text(&content)
+                    .split_whitespace()
+                    .map(str::to_lowercase)
+                    .collect();
+                let digest = Sha1::digest(words.join(" ").as_bytes()).to_vec();
+                let shingles: HashSet<u64> = if words.len() >= MIN_WORDS {
+                    words
+                        .windows(SHINGLE_WORDS)
+                        .map(|window| {
+                            let mut hasher = std::collections::hash_map::DefaultHasher::new();
+                            window.hash(&mut hasher);
+                            hasher.finish()
+                        })
+                        .collect()
+                } else {
+                    HashSet::new()
+                };
+                (digest, shingles, !words.is_empty())
+            };
+
+            let mut best: Option<(usize, f32)> = None;
+            if has_words {
+                for (first_line, other_digest, other_shingles) in &files {
+                    let similarity = if *other_digest == digest {
+                        1.0
+                    } else if shingles.is_empty() || other_shingles.is_empty() {
+                        continue;
+                    } else {
+                        let shared = shingles.intersection(other_shingles).count();
+                        shared as f32 / (shingles.len() + other_shingles.len() - shared) as f32
+                    };
+                    if similarity >= NEAR_DUPLICATE_SIMILARITY
+                        && best.is_none_or(|(_, best_similarity)| similarity > best_similarity)
+                    {
+                        // Point at the original rather than an earlier copy
+                        let original = duplicates.get(first_line).map_or(*first_line, |&(o, _)| o);
+                        best = Some((original, similarity));
+                    }
+                }
+            }
+            if let Some(found) = best {
+                duplicates.insert(index, found);
+            }
+
+            file_of_href.insert(line.href.clone(), files.len());
+            files.push((index, digest, shingles));
+        }
+
+        Ok(duplicates)
+    }
+
+    /// Find manifest items that nothing in the spine refers to, directly or
+    /// through stylesheets and links, and archive files that aren't in the
+    /// manifest
+    pub(crate) fn find_orphans(&mut self) -> Result<(Vec<ManifestItem>, Vec<String>)> {
+        let opf_path = self.content_opf_path.clone();
+        let opf = Self::read_file_from_archive(&mut self.archive, &opf_path)?;
+
+        let mut queue: Vec<String> = Self::parse_spine(&opf)?
+            .iter()
+            .filter_map(|item| self.manifest_items.get(&item.idref))
+            .map(|item| item.href.clone())
+            .chain(self.guide_items.keys().cloned())
+            .collect();
+        let mut referenced: HashSet<String> = queue.iter().cloned().collect();
+
+        while let Some(href) = queue.pop() {
+            let media_type = self
+                .manifest_items
+                .values()
+                .find(|item| item.href == href)
+                .map(|item| item.media_type.clone())
+                .unwrap_or_else(|| self.guess_media_type(&href));
+            if media_type != "text/css" && !media_type.contains("html") {
+                continue;
+            }
+            let Ok(content) = Self::read_file_from_archive(&mut self.archive, &href) else {
+                continue;
+            };
+
+            let mut linked = HashSet::new();
+            if media_type == "text/css" {
+                self.scan_css_for_resources(&content, &href, &mut linked)?;
+            } else {
+                self.scan_for_linked_files(&content, &href, &mut linked)?;
+                linked.extend(Self::scan_document_links(&content, &href)?);
+            }
+            for target in linked {
+                if referenced.insert(target.clone()) {
+                    queue.push(target);
+                }
+            }
+        }
+
+        // The NCX, nav document and cover image are used by reading systems
+        // even when no content links to them
+        let mut orphans: Vec<ManifestItem> = self
+            .manifest_items
+            .values()
+            .filter(|item| !referenced.contains(&item.href))
+            .filter(|item| item.media_type != "application/x-dtbncx+xml")
+            .filter(|item| !item.properties.iter().any(|p| p == "nav" || p == "cover-image"))
+            .cloned()
+            .collect();
+        orphans.sort_by(|a, b| a.href.cmp(&b.href));
+
+        let declared: HashSet<&str> = self
+            .manifest_items
+            .values()
+            .map(|item| item.href.as_str())
+            .collect();
+        let mut unmanifested: Vec<String> = self
+            .archive
+            .file_names()
+            .filter(|name| !name.ends_with('/'))
+            .filter(|name| *name != "mimetype" && !name.starts_with("META-INF/") && *name != opf_path)
+            .filter(|name| !declared.contains(name))
+            .map(str::to_string)
+            .collect();
+        unmanifested.sort();
+
+        Ok((orphans, unmanifested))
+    }
+
+    /// The linked resources (CSS, images, fonts) each content file pulls in,
+    /// largest first, as (archive path, size). Keyed by content href.
+    pub(crate) fn section_resources(&mut self, lines: &[SplitLine]) -> Result<HashMap<String, Vec<(String, u64)>>> {
+        let mut resources = HashMap::new();
+        for line in lines {
+            if resources.contains_key(&line.href) {
+                continue;
+            }
+            let mut linked = HashSet::new();
+            if let Ok(content) = Self::read_file_from_archive(&mut self.archive, &line.href) {
+                self.scan_for_linked_files(&content, &line.href, &mut linked)?;
+            }
+            let mut linked: Vec<(String, u64)> = linked
+                .into_iter()
+                .map(|path| {
+                    let size = self.archive_file_size(&path);
+                    (path, size)
+                })
+                .collect();
+            linked.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+            resources.insert(line.href.clone(), linked);
+        }
+        Ok(resources)
+    }
+
+    /// Spine content hrefs in reading order
+    pub(crate) fn spine_hrefs(&mut self) -> Result<Vec<String>> {
+        let opf_path = self.content_opf_path.clone();
+        let opf = Self::read_file_from_archive(&mut self.archive, &opf_path)?;
+        Ok(Self::parse_spine(&opf)?
+            .iter()
+            .filter_map(|item| self.manifest_items.get(&item.idref))
+            .map(|item| item.href.clone())
+            .collect())
+    }
+
+    /// SHA-1 of every file in the archive, keyed by path
+    pub(crate) fn entry_digests(&mut self) -> Result<HashMap<String, String>> {
+        let mut digests = HashMap::new();
+        for i in 0..self.archive.len() {
+            let mut file = self.archive.by_index(i).context("Failed to read archive entry")?;
+            if file.is_dir() {
+                continue;
+            }
+            let mut data = Vec::new();
+            file.read_to_end(&mut data)
+                .with_context(|| format!("Failed to read {}", file.name()))?;
+            let digest: String = Sha1::digest(&data).iter().map(|b| format!("{:02x}", b)).collect();
+            digests.insert(file.name().to_string(), digest);
+        }
+        Ok(digests)
+    }
+
+    pub(crate) fn archive_file_size(&mut self, path: &str) -> u64 {
+        self.archive.by_name(path).map(|file| file.size()).unwrap_or(0)
+    }
+
+    /// The TOC's entries in order and nested, with package-path `src`s:
+    /// from the NCX, or from the nav document of an EPUB 3 without one
+    pub(crate) fn read_toc(&mut self) -> Result<Vec<NavPoint>> {
+        let (toc_path, is_ncx) = match (self.toc_path.clone(), self.nav_path()) {
+            (Some(toc_path), _) => (toc_path, true),
+            (None, Some(nav_path)) => (nav_path, false),
+            (None, None) => bail!("{} has no table of contents", self.path.display()),
+        };
+        let document = Self::read_file_from_archive(&mut self.archive, &toc_path)?;
+        let hrefs = NcxHrefs::new(&toc_path, &self.content_relpath, &self.manifest_items);
+        if is_ncx {
+            Self::parse_ncx(&document, &hrefs)
+        } else {
+            Self::parse_nav_toc(&document, &hrefs)
+        }
+    }
+
+    /// Whether the book has an NCX or a nav document to read a TOC from
+    pub(crate) fn has_toc(&self) -> bool {
+        self.toc_path.is_some() || self.nav_path().is_some()
+    }
+
+    /// Package path of the EPUB 3 nav document, if there is one
+    fn nav_path(&self) -> Option<String> {
+        self.manifest_items
+            .values()
+            .find(|item| item.properties.iter().any(|p| p == "nav"))
+            .map(|item| item.href.clone())
+    }
+
+    /// New contents for the NCX and the nav document, whichever the book
+    /// has, with their TOCs regenerated from `nav_points` (package-path
+    /// `src`s)
+    pub(crate) fn toc_replacements(&mut self, nav_points: &[NavPoint]) -> Result<HashMap<String, String>> {
+        let relative_to = |doc: &str| {
+            NavPoint::map_files(nav_points.to_vec(), &|file| relative_href(doc, file))
+        };
+
+        let unique_id = self.orig_unique_id.clone().unwrap_or_default();
+        let mut replacements = HashMap::new();
+        if let Some(nav_path) = self.nav_path() {
+            let document = Self::read_file_from_archive(&mut self.archive, &nav_path)?;
+            let nav = replace_nav_toc(&document, &relative_to(&nav_path))?;
+            replacements.insert(nav_path, nav);
+        }
+        if let Some(toc_path) = self.toc_path.clone() {
+            let ncx = generate_ncx(&unique_id, &self.orig_title, &relative_to(&toc_path));
+            replacements.insert(toc_path, ncx);
+        }
+        Ok(replacements)
+    }
+
+    /// Copy the whole archive to `output`, swapping in `replacements` (path
+    /// to new content); every other entry is copied byte for byte
+    pub(crate) fn write_copy<W: IoWrite + Seek>(&mut self, replacements: &HashMap<String, String>, output: W) -> Result<()> {
+        let mut zip = ZipWriter::new(output);
+        let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+        for index in 0..self.archive.len() {
+            let entry = self.archive.by_index_raw(index).context("Failed to read EPUB entry")?;
+            let name = entry.name().to_string();
+            match replacements.get(&name) {
+                Some(content) => {
+                    drop(entry);
+                    zip.start_file(name.as_str(), options)
+                        .with_context(|| format!("Failed to create {}", name))?;
+                    zip.write_all(content.as_bytes())
+                        .with_context(|| format!("Failed to write {}", name))?;
+                }
+                None => zip
+                    .raw_copy_file(entry)
+                    .with_context(|| format!("Failed to copy {}", name))?,
+            }
+        }
+        zip.finish().context("Failed to finalize EPUB")?;
+        Ok(())
+    }
+
+    pub(crate) fn get_orig_title(&self) -> &str {
+        &self.orig_title
+    }
+
+    pub(crate) fn get_orig_authors(&self) -> &[String] {
+        &self.orig_authors
+    }
+
+    pub(crate) fn get_orig_languages(&self) -> &[String] {
+        &self.orig_languages
+    }
+}
+
+/// New TOC labels from `--rename-toc`, by split line index or by old title
+#[derive(Debug, Clone, Default)]
+pub(crate) struct TocRenames {
+    pub(crate) by_index: BTreeMap<usize, String>,
+    pub(crate) by_title: BTreeMap<String, String>,
+}
+
+impl TocRenames {
+    /// The label for TOC entry `title`; `index` is the split line it heads,
+    /// if it's the first entry there
+    fn label(&self, index: Option<usize>, title: &str) -> String {
+        index
+            .and_then(|index| self.by_index.get(&index))
+            .or_else(|| self.by_title.get(title))
+            .cloned()
+            .unwrap_or_else(|| title.to_string())
+    }
+}
+
+/// The `--identifiers-file` entry for the output being written, if any
+pub(crate) fn output_identifier(opts: &OutputOptions) -> Option<&String> {
+    opts.identifiers.get(opts.part.unwrap_or(1) - 1)
+}
+
+/// An OPF or NCX text node with its character references decoded, HTML's
+/// named entities included. Each is decoded once, so text escaped as
+/// "L&amp;rsquo;Engle" keeps its "&rsquo;"; anything unrecognized is kept
+/// as written
+fn xml_text(text: &BytesText) -> String {
+    decode_entities(&String::from_utf8_lossy(text))
+}
+
+fn decode_entities(text: &str) -> String {
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let reference = rest[1..]
+            .find(';')
+            .filter(|end| *end <= 32)
+            .and_then(|end| Some((entity_value(&rest[1..=end])?, end + 2)));
+        match reference {
+            Some((value, len)) => {
+                decoded.push(value.0);
+                decoded.extend(value.1);
+                rest = &rest[len..];
+            }
+            None => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+/// The characters a reference (without its `&` and `;`) stands for
+fn entity_value(name: &str) -> Option<(char, Option<char>)> {
+    if let Some(number) = name.strip_prefix('#') {
+        let code = match number.strip_prefix(['x', 'X']) {
+            Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+            None => number.parse().ok()?,
+        };
+        return char::from_u32(code).map(|c| (c, None));
+    }
+    let &(first, second) = NAMED_ENTITIES.get(format!("{};", name).as_str())?;
+    Some((char::from_u32(first)?, char::from_u32(second).filter(|_| second != 0)))
+}
+
+/// Extract the readable text of an XHTML document's body
+pub(crate) fn html_to_text(html: &str) -> String {
+    let document = Html::parse_document(html);
+    let body_selector = Selector::parse("body").expect("valid body selector");
+    match document.select(&body_selector).next() {
+        Some(body) => body.text().collect::<Vec<_>>().join(" "),
+        None => document.root_element().text().collect::<Vec<_>>().join(" "),
+    }
+}
+
+/// A match of a `grep` pattern and the text either side of it
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Snippet {
+    pub(crate) before: String,
+    pub(crate) matched: String,
+    pub(crate) after: String,
+}
+
+impl Snippet {
+    /// Every match of `pattern` in `text`, with up to `context` characters
+    /// either side
+    fn find_all(text: &str, pattern: &Regex, context: usize) -> Vec<Snippet> {
+        pattern
+            .find_iter(text)
+            .map(|found| {
+                let before: Vec<char> = text[..found.start()].chars().rev().take(context + 1).collect();
+                let after: Vec<char> = text[found.end()..].chars().take(context + 1).collect();
+                let mut before: String = before.iter().take(context).rev().collect();
+                let mut after: String = after.iter().take(context).collect();
+                if found.start() > before.len() {
+                    before.insert(0, '…');
+                }
+                if text.len() - found.end() > after.len() {
+                    after.push('…');
+                }
+                Snippet {
+                    before,
+                    matched: found.as_str().to_string(),
+                    after,
+                }
+            })
+            .collect()
+    }
+}
+
+/// Counts the words of an HTML document read in pieces, split as
+/// `html_to_text` would: tags break words, and only the body counts when
+/// there is one
+#[derive(Default)]
+struct WordCounter {
+    in_tag: bool,
+    tag: Vec<u8>,
+    in_body: bool,
+    in_word: bool,
+    words: usize,
+    body_words: usize,
+    saw_body: bool,
+}
+
+impl WordCounter {
+    fn feed(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            if self.in_tag {
+                if b == b'>' {
+                    self.in_tag = false;
+                    let (closing, tag) = match self.tag.strip_prefix(b"/") {
+                        Some(tag) => (true, tag),
+                        None => (false, self.tag.as_slice()),
+                    };
+                    let name_len = tag.iter().take_while(|c| c.is_ascii_alphanumeric()).count();
+                    if &tag[..name_len] == b"body" {
+                        self.in_body = !closing;
+                        self.saw_body = true;
+                    }
+                } else if self.tag.len() < 6 {
+                    self.tag.push(b.to_ascii_lowercase());
+                }
+            } else if b == b'<' {
+                self.in_tag = true;
+                self.tag.clear();
+                self.in_word = false;
+            } else if b.is_ascii_whitespace() {
+                self.in_word = false;
+            } else if !self.in_word {
+                self.in_word = true;
+                self.words += 1;
+                if self.in_body {
+                    self.body_words += 1;
+                }
+            }
+        }
+    }
+
+    fn feed_from(&mut self, mut reader: impl IoRead) -> std::io::Result<()> {
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            match reader.read(&mut buf)? {
+                0 => return Ok(()),
+                read => self.feed(&buf[..read]),
+            }
+        }
+    }
+
+    fn words(&self) -> usize {
+        if self.saw_body {
+            self.body_words
+        } else {
+            self.words
+        }
+    }
+}
+
+/// Seconds since the Unix epoch. wasm32 has no system clock, so ask JavaScript.
+pub(crate) fn unix_time_secs() -> u64 {
+    #[cfg(target_arch = "wasm32")]
+    {
+        (js_sys::Date::now() / 1000.0) as u64
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+    }
+}
+
+/// A fresh package identifier: a random RFC 4122 UUID as a URN, so outputs
+/// written in the same second don't collide
+fn new_unique_id() -> String {
+    format!("urn:uuid:{}", uuid::Uuid::new_v4())
+}
+
+/// A Unix time as `CCYY-MM-DDThh:mm:ssZ`, the form `dcterms:modified` requires
+pub(crate) fn w3cdtf_utc(secs: u64) -> String {
+    let days = (secs / 86_400) as i64;
+    let secs_of_day = secs % 86_400;
+
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60
+    )
+}
+
+/// Languages given on the command line, or else the source's, or else "en"
+pub(crate) fn default_languages(requested: &[String], source: &[String]) -> Vec<String> {
+    if !requested.is_empty() {
+        requested.to_vec()
+    } else if !source.is_empty() {
+        source.to_vec()
+    } else {
+        vec!["en".to_string()]
+    }
+}
+
+/// Merge EPUBs read from `sources` into one written to `output`, prefixing
+/// each book's file names so they can't clash
+pub(crate) fn merge_sources<W: IoWrite + Seek>(
+    sources: Vec<(PathBuf, Box<dyn EpubSource>)>,
+    output: W,
+    opts: &OutputOptions,
+) -> Result<BuiltEpub<W>> {
+    let source_count = sources.len();
+
+    // Collect all content from input EPUBs
+    let mut all_manifest_items: Vec<ManifestItem> = Vec::new();
+    let mut all_spine_items: Vec<SpineItem> = Vec::new();
+    let mut all_toc_entries: Vec<(String, String)> = Vec::new(); // (title, href)
+    let mut all_files: HashMap<String, Vec<u8>> = HashMap::new(); // href -> content
+    let mut copied_resources: Vec<(String, u64)> = Vec::new(); // (source href, bytes written)
+    let mut remote = RemoteResources::default();
+    let mut combined_titles: Vec<String> = Vec::new();
+    let mut combined_authors: HashSet<String> = HashSet::new();
+    let mut combined_languages: Vec<String> = Vec::new();
+    let mut source_accessibility: Vec<Vec<(String, String)>> = Vec::new();
+
+    let mut versions: Vec<EpubVersion> = Vec::new();
+
+    // Add NCX to manifest
+    all_manifest_items.push(ManifestItem::new("ncx", "toc.ncx", "application/x-dtbncx+xml"));
+
+    let img_re = Regex::new(r#"(?:src|xlink:href|altimg)=["']([^"']+)["']"#)
+        .context("Failed to compile image regex")?;
+    let css_link_re = Regex::new(r#"<link[^>]+href=["']([^"']+\.css)["'][^>]*>"#)
+        .context("Failed to compile CSS link regex")?;
+
+    // Process each input EPUB
+    for (epub_idx, (input_path, reader)) in sources.into_iter().enumerate() {
+        info!("Processing EPUB {}: {}", epub_idx + 1, input_path.display());
+
+        let unreadable = || Failure::new(FailureKind::UnreadableInput, format!("Failed to load EPUB: {}", input_path.display()));
+        let mut archive = ZipArchive::new(reader).context(unreadable())?;
+        check_drm(&mut archive, &input_path)?;
+
+        // Parse container.xml
+        let container_xml = SplitEpub::read_file_from_archive(&mut archive, "META-INF/container.xml")?;
+        let opf_path = SplitEpub::parse_container_xml(&container_xml)?;
+        let content_relpath = SplitEpub::get_path_part(&opf_path);
+
+        // Parse OPF
+        let opf_content = SplitEpub::read_file_from_archive(&mut archive, &opf_path)?;
+        versions.push(EpubVersion::parse(&opf_content)?);
+        let (manifest_items, toc_path) = SplitEpub::parse_manifest(&opf_content, &content_relpath)?;
+        let spine_refs = SplitEpub::parse_spine(&opf_content)?;
+        let (orig_title, orig_authors) = SplitEpub::parse_metadata(&opf_content)?;
+        source_accessibility.push(SplitEpub::parse_accessibility_metadata(&opf_content)?);
+
+        combined_titles.push(orig_title.clone());
+        for author in orig_authors {
+            combined_authors.insert(author);
+        }
+        for language in SplitEpub::parse_languages(&opf_content)? {
+            if !combined_languages.contains(&language) {
+                combined_languages.push(language);
+            }
+        }
+
+        // Parse TOC if available
+        let toc_map = if let Some(toc_path) = &toc_path {
+            let toc_content = SplitEpub::read_file_from_archive(&mut archive, toc_path)?;
+            SplitEpub::parse_toc(&toc_content, &NcxHrefs::new(toc_path, &content_relpath, &manifest_items))?
+        } else {
+            HashMap::new()
+        };
+
+        // Prefix to make file names unique per source EPUB
+        let prefix = format!("epub{}_", epub_idx);
+        let mut href_map: HashMap<String, String> = HashMap::new(); // old href -> new href
+
+        // Collect all resources from this EPUB (content + linked files)
+        let mut linked_files: HashSet<String> = HashSet::new();
+        let mut linked_media_types: HashMap<String, String> = HashMap::new(); // new href -> media type
+        let mut content_hrefs: Vec<String> = Vec::new();
+
+        // Process spine items (main content)
+        for spine_item in &spine_refs {
+            if let Some(item) = manifest_items.get(&spine_item.idref) {
+                let old_href = &item.href;
+                let new_href = format!("{}{}", prefix, old_href.replace('/', "_"));
+                href_map.insert(old_href.clone(), new_href.clone());
+                content_hrefs.push(old_href.clone());
+
+                // Read and rewrite content
+                if let Ok(content) = SplitEpub::read_file_from_archive(&mut archive, old_href) {
+                    let content =
+                        transform_content(content, &new_href, &item.media_type, opts, &mut remote)?;
+
+                    // Scan for linked resources
+                    let base_path = SplitEpub::get_path_part(old_href);
+                    for cap in img_re.captures_iter(&content) {
+                        if let Some(src) = cap.get(1) {
+                            let src_str = src.as_str();
+                            if !src_str.starts_with("http://") && !src_str.starts_with("https://") {
+                                let full_path =
+                                    SplitEpub::join_href(&base_path, src_str);
+                                linked_files.insert(full_path);
+                            }
+                        }
+                    }
+
+                    // Scan for CSS links
+                    for cap in css_link_re.captures_iter(&content) {
+                        if let Some(href) = cap.get(1) {
+                            let full_path =
+                                SplitEpub::join_href(&base_path, href.as_str());
+                            linked_files.insert(full_path);
+                        }
+                    }
+
+                    all_files.insert(new_href, content.into_bytes());
+                }
+            }
+        }
+
+        // Process linked files (CSS, images, fonts)
+        for old_href in &linked_files {
+            if !href_map.contains_key(old_href) {
+                let new_href = format!("{}{}", prefix, old_href.replace('/', "_"));
+                href_map.insert(old_href.clone(), new_href.clone());
+
+                // Read binary file
+                if let Ok(mut file) = archive.by_name(old_href) {
+                    let mut data = Vec::new();
+                    if file.read_to_end(&mut data).is_ok() {
+                        if let Some(settings) = opts.optimize_images {
+                            if let Some(optimized) = optimize_image(old_href, &data, settings) {
+                                data = optimized;
+                            }
+                        }
+                        let declared = manifest_items
+                            .values()
+                            .find(|item| item.href == *old_href)
+                            .map(|item| item.media_type.as_str());
+                        let media_type = checked_media_type(old_href, declared, &data);
+                        if media_type == "text/css" {
+                            if let Ok(css) = String::from_utf8(data.clone()) {
+                                data = transform_content(css, &new_href, &media_type, opts, &mut remote)?
+                                    .into_bytes();
+                            }
+                        }
+                        linked_media_types.insert(new_href.clone(), media_type);
+                        copied_resources.push((old_href.clone(), data.len() as u64));
+                        all_files.insert(new_href, data);
+                    }
+                }
+            }
+        }
+
+        // Rewrite hrefs in content files
+        for old_href in &content_hrefs {
+            let new_href = href_map.get(old_href).cloned().unwrap_or_default();
+            if let Some(content_bytes) = all_files.get_mut(&new_href) {
+                let mut content = String::from_utf8_lossy(content_bytes).to_string();
+
+                // Rewrite all internal references
+                for (old_ref, new_ref) in &href_map {
+                    // Handle relative paths - strip common prefix
+                    let old_basename = old_ref.split('/').next_back().unwrap_or(old_ref);
+                    let patterns = vec![
+                        (format!(r#"href="{}""#, old_basename), format!(r#"href="{}""#, new_ref)),
+                        (format!(r#"href='{}'"#, old_basename), format!(r#"href='{}'"#, new_ref)),
+                        (format!(r#"src="{}""#, old_basename), format!(r#"src="{}""#, new_ref)),
+                        (format!(r#"src='{}'"#, old_basename), format!(r#"src='{}'"#, new_ref)),
+                        (format!(r#"altimg="{}""#, old_basename), format!(r#"altimg="{}""#, new_ref)),
+                        (format!(r#"altimg='{}'"#, old_basename), format!(r#"altimg='{}'"#, new_ref)),
+                    ];
+
+                    for (old_pattern, new_pattern) in patterns {
+                        content = content.replace(&old_pattern, &new_pattern);
+                    }
+                }
+
+                *content_bytes = content.into_bytes();
+            }
+        }
+
+        // Add manifest and spine items
+        let mut content_count = all_spine_items.len();
+        for spine_item in &spine_refs {
+            if let Some(item) = manifest_items.get(&spine_item.idref) {
+                let new_href = href_map.get(&item.href).cloned().unwrap_or_default();
+                let id = format!("content{}", content_count);
+                content_count += 1;
+                let (properties, media_type) = match all_files.get(&new_href) {
+                    Some(content) => (
+                        derive_content_properties(&String::from_utf8_lossy(content), &item.properties)?,
+                        checked_media_type(&item.href, Some(&item.media_type), content),
+                    ),
+                    None => (item.properties.clone(), item.media_type.clone()),
+                };
+                all_manifest_items.push(
+                    ManifestItem::new(id.clone(), new_href, media_type)
+                        .with_properties(properties),
+                );
+                // Itemref ids could clash between books, so only properties carry over
+                let mut merged_item = SpineItem::new(id, spine_item.linear);
+                merged_item.properties = spine_item.properties.clone();
+                all_spine_items.push(merged_item);
+            }
+        }
+
+        // Add linked files to manifest
+        for old_href in linked_files {
+            if let Some(new_href) = href_map.get(&old_href) {
+                let id = format!("resource{}", all_manifest_items.len());
+                let media_type = linked_media_types
+                    .get(new_href)
+                    .cloned()
+                    .unwrap_or_else(|| guess_media_type_static(new_href));
+                let properties = manifest_items
+                    .values()
+                    .find(|item| item.href == old_href)
+                    .map(|item| {
+                        // Only the first book's cover stays marked as the cover image
+                        item.properties
+                            .iter()
+                            .filter(|p| epub_idx == 0 || p.as_str() != "cover-image")
+                            .cloned()
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                all_manifest_items
+                    .push(ManifestItem::new(id, new_href.clone(), media_type).with_properties(properties));
+            }
+        }
+
+        // Build TOC entries for this EPUB
+        // Add a section marker for this book
+        all_toc_entries.push((orig_title.clone(), String::new()));
+
+        for spine_item in &spine_refs {
+            if let Some(item) = manifest_items.get(&spine_item.idref) {
+                let new_href = href_map.get(&item.href).cloned().unwrap_or_default();
+
+                if let Some(toc_entries) = toc_map.get(&item.href) {
+                    for entry in toc_entries {
+                        let href = if let Some(anchor) = &entry.anchor {
+                            format!("{}#{}", new_href, anchor)
+                        } else {
+                            new_href.clone()
+                        };
+                        all_toc_entries.push((entry.text.clone(), href));
+                    }
+                }
+            }
+        }
+    }
+
+    // Add downloaded remote resources
+    for (href, data, media_type) in remote.files {
+        let id = format!("resource{}", all_manifest_items.len());
+        all_manifest_items.push(ManifestItem::new(id, href.clone(), media_type));
+        all_files.insert(href, data);
+    }
+
+    // A supplied cover takes over from the first book's
+    let cover = match &opts.cover {
+        _ if opts.no_cover => None,
+        Some(CoverSource::Image { name, data }) => Some(supplied_cover(name, data, opts.strict_cover)?),
+        Some(CoverSource::SectionImage(_)) => bail!("A merged book's cover can't be one of its section images"),
+        None => None,
+    };
+    if let Some((cover_data, cover_href, media_type)) = &cover {
+        for item in &mut all_manifest_items {
+            item.properties.retain(|p| p != "cover-image");
+        }
+        all_manifest_items.push(
+            ManifestItem::new("coverimageid", cover_href.clone(), media_type.clone())
+                .with_properties(vec!["cover-image".to_string()]),
+        );
+        all_files.insert(cover_href.clone(), cover_data.clone());
+    }
+
+    // What was written, for checking references; the package sits at the
+    // archive root, so hrefs are archive paths
+    let written_paths: HashSet<String> = all_files.keys().cloned().collect();
+    let documents: Vec<(String, String, bool)> = all_manifest_items
+        .iter()
+        .filter(|item| item.media_type == "application/xhtml+xml" || item.media_type == "text/css")
+        .filter_map(|item| {
+            let content = all_files.get(&item.href)?;
+            Some((item.href.clone(), String::from_utf8_lossy(content).into_owned(), item.media_type == "text/css"))
+        })
+        .collect();
+
+    // The nav document goes after the NCX, if the output is EPUB 3
+    let version = EpubVersion::common(versions);
+    if version.is_epub3() {
+        all_manifest_items.insert(
+            1,
+            ManifestItem::new("nav", "nav.xhtml", "application/xhtml+xml")
+                .with_properties(vec!["nav".to_string()]),
+        );
+    }
+
+    // Create output EPUB
+    let mut zip = ZipWriter::new(output);
+
+    // Write mimetype first (must be uncompressed and first)
+    let stored_options = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+    zip.start_file("mimetype", stored_options)
+        .context("Failed to write mimetype")?;
+    zip.write_all(b"application/epub+zip")
+        .context("Failed to write mimetype content")?;
+
+    let deflate_options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+
+    // Write META-INF/container.xml
+    let container_xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<container version="1.0" xmlns="urn:oasis:names:tc:opendocument:xmlns:container">
+   <rootfiles>
+      <rootfile full-path="content.opf" media-type="application/oebps-package+xml"/>
+   </rootfiles>
+</container>
+"#;
+    zip.start_file("META-INF/container.xml", deflate_options)
+        .context("Failed to create container.xml")?;
+    zip.write_all(container_xml.as_bytes())
+        .context("Failed to write container.xml")?;
+
+    // Generate unique ID, unless --identifiers-file has one for this output
+    let unique_id = output_identifier(opts).cloned().unwrap_or_else(new_unique_id);
+
+    // Determine title
+    let final_title = opts.title.clone().unwrap_or_else(|| {
+        if combined_titles.len() <= 3 {
+            combined_titles.join(" + ")
+        } else {
+            format!("{} + {} more", combined_titles[0], combined_titles.len() - 1)
+        }
+    });
+
+    // Determine authors
+    let final_authors = if opts.author.is_empty() {
+        combined_authors.into_iter().collect::<Vec<_>>()
+    } else {
+        opts.author.clone()
+    };
+
+    // Determine description
+    let mut final_description = opts.description.clone().unwrap_or_else(|| {
+        format!("Merged from: {}.", combined_titles.join(", "))
+    });
+
+    // Compilations in a plan are numbered like its other outputs
+    let final_title = match part_label(opts) {
+        Some(label) => {
+            if opts.number_parts == Some(PartNumbering::TitleAndDescription) {
+                final_description = format!("{} {}.", final_description, label);
+            }
+            format!("{} ({})", final_title, label)
+        }
+        None => final_title,
+    };
+
+    // Write all content files
+    for (href, content) in &all_files {
+        zip.start_file(href.as_str(), deflate_options)
+            .with_context(|| format!("Failed to add file: {}", href))?;
+        zip.write_all(content)
+            .with_context(|| format!("Failed to write file: {}", href))?;
+    }
+
+    // Generate and write content.opf
+    // Claims carry over only where every source makes them
+    let mut accessibility = source_accessibility
+        .into_iter()
+        .reduce(|common, claims| common.into_iter().filter(|claim| claims.contains(claim)).collect())
+        .unwrap_or_default();
+    if opts.generate_a11y {
+        let mut content = ContentAccessibility::default();
+        for item in &all_manifest_items {
+            if item.media_type == "application/xhtml+xml" {
+                if let Some(data) = all_files.get(&item.href) {
+                    content.scan(&String::from_utf8_lossy(data))?;
+                }
+            }
+        }
+        merge_accessibility_metadata(
+            &mut accessibility,
+            synthesize_accessibility_metadata(&all_manifest_items, &content),
+        );
+    }
+    let (final_authors, creators) = split_creator_roles(&final_authors);
+    let meta = PackageMetadata {
+        version,
+        unique_id: unique_id.clone(),
+        title: final_title.clone(),
+        authors: final_authors,
+        creators,
+        contributors: opts.contributor.iter().map(|contributor| Creator::parse(contributor)).collect(),
+        description: final_description,
+        tags: opts.tag.clone(),
+        languages: default_languages(&opts.language, &combined_languages),
+        accessibility,
+        date: None,
+        modified: w3cdtf_utc(unix_time_secs()),
+        author_sort: HashMap::new(),
+        title_sort: None,
+    };
+    let content_opf = generate_merged_opf(&meta, &all_manifest_items, &all_spine_items);
+    zip.start_file("content.opf", deflate_options)
+        .context("Failed to create content.opf")?;
+    zip.write_all(content_opf.as_bytes())
+        .context("Failed to write content.opf")?;
+
+    // Generate and write toc.ncx, and nav.xhtml for EPUB 3
+    // Book title markers aren't TOC entries of their own
+    let nav_points: Vec<NavPoint> = all_toc_entries
+        .into_iter()
+        .filter(|(_, src)| !src.is_empty())
+        .map(|(text, src)| NavPoint::new(text, src))
+        .collect();
+    let toc_ncx = generate_ncx(&unique_id, &final_title, &nav_points);
+    zip.start_file("toc.ncx", deflate_options)
+        .context("Failed to create toc.ncx")?;
+    zip.write_all(toc_ncx.as_bytes())
+        .context("Failed to write toc.ncx")?;
+
+    // And the same entries as nav.xhtml
+    if version.is_epub3() {
+        let nav = generate_nav(&final_title, &nav_points);
+        zip.start_file("nav.xhtml", deflate_options)
+            .context("Failed to create nav.xhtml")?;
+        zip.write_all(nav.as_bytes())
+            .context("Failed to write nav.xhtml")?;
+    }
+
+    let output = zip.finish().context("Failed to finalize EPUB file")?;
+    info!("Merged {} EPUBs", source_count);
+
+    Ok(BuiltEpub {
+        output,
+        copied_resources,
+        documents,
+        written_paths,
+        meta,
+        cover: cover.map(|(data, _, _)| data),
+    })
+}
+
+/// Apply the requested content transformations to a copied XHTML or CSS file.
+/// `href` is the path the file will have in the output.
+fn transform_content(
+    content: String,
+    href: &str,
+    media_type: &str,
+    opts: &OutputOptions,
+    remote: &mut RemoteResources,
+) -> Result<String> {
+    if !transforms_content(media_type, opts) {
+        return Ok(content);
+    }
+
+    let is_css = media_type == "text/css";
+    let mut content = content;
+    if opts.strip_scripts && !is_css {
+        content = strip_scripts(&content)?;
+    }
+    if opts.strip_remote || opts.fetch_remote.is_some() {
+        content = sanitize_remote_references(&content, href, is_css, opts, remote)?;
+    }
+    if !is_css && (opts.normalize_unicode || opts.punctuation.is_some() || opts.strip_soft_hyphens) {
+        content = normalize_text(&content, opts)?;
+    }
+    if opts.minify {
+        content = if is_css { minify_css(&content)? } else { minify_xhtml(&content)? };
+    }
+    for transform in opts.transforms.iter() {
+        let transformed = transform
+            .transform(href, content.as_bytes())
+            .with_context(|| format!("Content transform failed for {}", href))?;
+        content = String::from_utf8(transformed)
+            .with_context(|| format!("Content transform returned invalid UTF-8 for {}", href))?;
+    }
+    Ok(content)
+}
+
+/// Strip references to remote (http/https) resources, or replace them with
+/// links to downloaded copies when `--fetch-remote` is given. Hyperlinks on
+/// `<a>`/`<area>` are left alone since readers don't load them automatically.
+fn sanitize_remote_references(
+    content: &str,
+    href: &str,
+    is_css: bool,
+    opts: &OutputOptions,
+    remote: &mut RemoteResources,
+) -> Result<String> {
+    let import_re = Regex::new(
+        r#"(?i)@import\s+(?:url\(\s*)?["']?(https?://[^"')\s;]+)["']?\s*\)?([^;]*);"#,
+    )
+    .context("Failed to compile @import regex")?;
+    let url_re = Regex::new(r#"(?i)url\(\s*["']?(https?://[^"')\s]+)["']?\s*\)"#)
+        .context("Failed to compile url() regex")?;
+    let tag_re = Regex::new(r"<([A-Za-z][A-Za-z0-9:]*)\b[^>]*>")
+        .context("Failed to compile tag regex")?;
+    let attr_re = Regex::new(
+        r#"(?i)\s(src|xlink:href|href|altimg|poster|data)\s*=\s*(?:"(https?://[^"]*)"|'(https?://[^']*)')"#,
+    )
+    .context("Failed to compile remote attribute regex")?;
+    const VOID_TAGS: [&str; 7] = ["img", "image", "link", "source", "embed", "track", "input"];
+
+    let mut resolve = |url: &str| -> Option<String> {
+        if let Some(fetcher) = &opts.fetch_remote {
+            remote.localize(url, fetcher).map(|path| relative_href(href, &path))
+        } else {
+            debug!("Stripping remote reference in {}: {}", href, url);
+            None
+        }
+    };
+
+    let content = import_re.replace_all(content, |caps: &regex::Captures| {
+        match resolve(&caps[1]) {
+            Some(local) => format!("@import url(\"{}\"){};", local, &caps[2]),
+            None => String::new(),
+        }
+    });
+    let content = url_re.replace_all(&content, |caps: &regex::Captures| {
+        match resolve(&caps[1]) {
+            Some(local) => format!("url(\"{}\")", local),
+            None => "none".to_string(),
+        }
+    });
+
+    if is_css {
+        return Ok(content.into_owned());
+    }
+
+    let mut result = String::with_capacity(content.len());
+    let mut last = 0;
+    for tag_caps in tag_re.captures_iter(&content) {
+        let tag_match = tag_caps.get(0).expect("capture group 0 always exists");
+        let tag = tag_match.as_str();
+        let name = tag_caps[1].to_lowercase();
+        result.push_str(&content[last..tag_match.start()]);
+        last = tag_match.end();
+
+        if name == "a" || name == "area" {
+            result.push_str(tag);
+            continue;
+        }
+
+        let mut new_tag = String::with_capacity(tag.len());
+        let mut tag_last = 0;
+        let mut drop_tag = false;
+        for attr_caps in attr_re.captures_iter(tag) {
+            let attr = attr_caps[1].to_lowercase();
+            if attr == "href" && name != "link" {
+                continue;
+            }
+            let attr_match = attr_caps.get(0).expect("capture group 0 always exists");
+            let url = attr_caps
+                .get(2)
+                .or_else(|| attr_caps.get(3))
+                .map(|m| m.as_str())
+                .unwrap_or_default();
+
+            new_tag.push_str(&tag[tag_last..attr_match.start()]);
+            tag_last = attr_match.end();
+            match resolve(url) {
+                Some(local) => new_tag.push_str(&format!(" {}=\"{}\"", &attr_caps[1], local)),
+                None if VOID_TAGS.contains(&name.as_str()) || tag.ends_with("/>") => {
+                    drop_tag = true;
+                }
+                None => {}
+            }
+        }
+        new_tag.push_str(&tag[tag_last..]);
+
+        if !drop_tag {
+            result.push_str(&new_tag);
+        }
+    }
+    result.push_str(&content[last..]);
+
+    Ok(result)
+}
+
+/// Glob patterns from `--exclude-resource`. A pattern matches an archive path
+/// or any trailing part of it, so "fonts/*" matches "OEBPS/Fonts/a.ttf".
+struct ResourceMatcher {
+    globs: GlobSet,
+}
+
+impl ResourceMatcher {
+    fn is_match(&self, path: &str) -> bool {
+        std::iter::once(path)
+            .chain(path.match_indices('/').map(|(pos, _)| &path[pos + 1..]))
+            .any(|suffix| self.globs.is_match(suffix))
+    }
+}
+
+fn resource_matcher(patterns: &[String]) -> Result<Option<ResourceMatcher>> {
+    if patterns.is_empty() {
+        return Ok(None);
+    }
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let glob = GlobBuilder::new(pattern)
+            .case_insensitive(true)
+            .build()
+            .with_context(|| format!("Invalid resource pattern: {}", pattern))?;
+        builder.add(glob);
+    }
+    let globs = builder.build().context("Failed to build resource patterns")?;
+    Ok(Some(ResourceMatcher { globs }))
+}
+
+/// Remove references to resources left out with `--exclude-resource`: tags
+/// that only load them are dropped, other attributes and CSS `url()`s and
+/// `@import`s are removed. Hyperlinks on `<a>`/`<area>` are left alone.
+fn strip_excluded_references(
+    content: &str,
+    href: &str,
+    is_css: bool,
+    excluded: &HashSet<String>,
+) -> Result<String> {
+    let import_re = Regex::new(r#"(?i)@import\s+(?:url\(\s*)?["']?([^"')\s;]+)["']?\s*\)?[^;]*;"#)
+        .context("Failed to compile @import regex")?;
+    let url_re = Regex::new(r#"(?i)url\(\s*["']?([^"')\s]+)["']?\s*\)"#)
+        .context("Failed to compile url() regex")?;
+    let tag_re = Regex::new(r"<([A-Za-z][A-Za-z0-9:]*)\b[^>]*>")
+        .context("Failed to compile tag regex")?;
+    let attr_re = Regex::new(
+        r#"(?i)\s(src|xlink:href|href|altimg|poster|data)\s*=\s*(?:"([^"]*)"|'([^']*)')"#,
+    )
+    .context("Failed to compile reference attribute regex")?;
+    const VOID_TAGS: [&str; 7] = ["img", "image", "link", "source", "embed", "track", "input"];
+
+    let base_path = SplitEpub::get_path_part(href);
+    let is_excluded = |reference: &str| {
+        if reference.is_empty() || reference.starts_with('#') || reference.contains(':') {
+            return false;
+        }
+        let path = reference.split('#').next().unwrap_or(reference);
+        excluded.contains(&SplitEpub::join_href(&base_path, path))
+    };
+
+    let content = import_re.replace_all(content, |caps: &regex::Captures| {
+        if is_excluded(&caps[1]) {
+            String::new()
+        } else {
+            caps[0].to_string()
+        }
+    });
+    let content = url_re.replace_all(&content, |caps: &regex::Captures| {
+        if is_excluded(&caps[1]) {
+            "none".to_string()
+        } else {
+            caps[0].to_string()
+        }
+    });
+
+    if is_css {
+        return Ok(content.into_owned());
+    }
+
+    let mut result = String::with_capacity(content.len());
+    let mut last = 0;
+    for tag_caps in tag_re.captures_iter(&content) {
+        let tag_match = tag_caps.get(0).expect("capture group 0 always exists");
+        let tag = tag_match.as_str();
+        let name = tag_caps[1].to_lowercase();
+        result.push_str(&content[last..tag_match.start()]);
+        last = tag_match.end();
+
+        if name == "a" || name == "area" {
+            result.push_str(tag);
+            continue;
+        }
+
+        let mut new_tag = String::with_capacity(tag.len());
+        let mut tag_last = 0;
+        let mut drop_tag = false;
+        for attr_caps in attr_re.captures_iter(tag) {
+            let attr = attr_caps[1].to_lowercase();
+            if attr == "href" && name != "link" {
+                continue;
+            }
+            let reference = attr_caps
+                .get(2)
+                .or_else(|| attr_caps.get(3))
+                .map(|m| m.as_str())
+                .unwrap_or_default();
+            if !is_excluded(reference) {
+                continue;
+            }
+
+            let attr_match = attr_caps.get(0).expect("capture group 0 always exists");
+            new_tag.push_str(&tag[tag_last..attr_match.start()]);
+            tag_last = attr_match.end();
+            if VOID_TAGS.contains(&name.as_str()) || tag.ends_with("/>") {
+                drop_tag = true;
+            }
+        }
+        new_tag.push_str(&tag[tag_last..]);
+
+        if !drop_tag {
+            result.push_str(&new_tag);
+        }
+    }
+    result.push_str(&content[last..]);
+
+    Ok(result)
+}
+
+/// Characters to percent-encode when writing a path into an href
+const HREF_ENCODE_SET: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+/// Assign every copied file its output path. With `flatten`, files move into
+/// a simple `OEBPS/Text`, `OEBPS/Styles`, `OEBPS/Images`, ... layout; with
+/// `ascii_names`, file and folder names are slugified to plain ASCII.
+/// Clashing names are de-duplicated. `files` holds (source href, media type)
+/// pairs.
+fn output_layout(
+    files: &[(String, String)],
+    flatten: bool,
+    ascii_names: bool,
+) -> HashMap<String, String> {
+    let mut path_map = HashMap::new();
+    let mut used: HashSet<String> = HashSet::new();
+
+    for (href, media_type) in files {
+        let (dir, name) = match href.rfind('/') {
+            Some(pos) => (href[..pos + 1].to_string(), &href[pos + 1..]),
+            None => (String::new(), href.as_str()),
+        };
+        let dir = if flatten {
+            let folder = if media_type.contains("html") {
+                "Text"
+            } else if media_type == "text/css" {
+                "Styles"
+            } else if media_type.starts_with("image/") {
+                "Images"
+            } else if media_type.contains("font") || media_type.contains("opentype") {
+                "Fonts"
+            } else if media_type.starts_with("audio/") {
+                "Audio"
+            } else if media_type.starts_with("video/") {
+                "Video"
+            } else {
+                "Misc"
+            };
+            format!("OEBPS/{}/", folder)
+        } else if ascii_names {
+            dir.split_terminator('/')
+                .map(|segment| format!("{}/", ascii_file_name(segment)))
+                .collect()
+        } else {
+            dir
+        };
+        let name = if ascii_names {
+            ascii_file_name(name)
+        } else {
+            name.to_string()
+        };
+        let (stem, extension) = match name.rfind('.') {
+            Some(pos) if pos > 0 => (&name[..pos], &name[pos..]),
+            _ => (name.as_str(), ""),
+        };
+
+        // Compare case-insensitively so outputs extract cleanly on any filesystem
+        let mut candidate = format!("{}{}", dir, name);
+        let mut suffix = 1;
+        while !used.insert(candidate.to_lowercase()) {
+            candidate = format!("{}{}-{}{}", dir, stem, suffix, extension);
+            suffix += 1;
+        }
+
+        if candidate != *href {
+            debug!("Renaming {} -> {}", href, candidate);
+        }
+        path_map.insert(href.clone(), candidate);
+    }
+
+    path_map
+}
+
+/// Slugify a single file or folder name to ASCII letters, digits, `-`, `_`
+/// and `.`, transliterating where possible
+fn ascii_file_name(name: &str) -> String {
+    let mut slug = String::new();
+    for c in deunicode(name).chars() {
+        if c.is_ascii_alphanumeric() || matches!(c, '.' | '_') {
+            slug.push(c);
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    let slug = slug.trim_end_matches('-').replace("-.", ".");
+    if slug.is_empty() || slug.starts_with('.') {
+        format!("file{}", slug)
+    } else {
+        slug
+    }
+}
+
+/// Whether a document or stylesheet links anything by a rooted path (`/x`),
+/// which `rewrite_references` turns into a relative one
+fn has_rooted_references(content: &str) -> Result<bool> {
+    let rooted_re = Regex::new(
+        r#"(?:\s(?:href|src|xlink:href|altimg|poster|data)\s*=\s*["']|url\(\s*["']?|@import\s+["'])/[^/]"#,
+    )
+    .context("Failed to compile rooted reference regex")?;
+    Ok(rooted_re.is_match(content))
+}
+
+/// Rewrite the relative references in a copied XHTML or CSS file after files
+/// have moved. `source_href` is the file's path in the source archive and
+/// `output_href` its path in the output; `path_map` maps moved source paths
+/// to their output paths.
+fn rewrite_references(
+    content: &str,
+    source_href: &str,
+    output_href: &str,
+    path_map: &HashMap<String, String>,
+) -> Result<String> {
+    let attr_re = Regex::new(
+        r#"(\s(?:href|src|xlink:href|altimg|poster|data)\s*=\s*)(?:"([^"]*)"|'([^']*)')"#,
+    )
+    .context("Failed to compile reference attribute regex")?;
+    let url_re = Regex::new(r#"url\(\s*(?:"([^"]*)"|'([^']*)'|([^"')\s]*))\s*\)"#)
+        .context("Failed to compile url() regex")?;
+    let import_re = Regex::new(r#"(@import\s+)(?:"([^"]*)"|'([^']*)')"#)
+        .context("Failed to compile @import regex")?;
+
+    let base_path = SplitEpub::get_path_part(source_href);
+    let relink = |reference: &str| -> Option<String> {
+        if reference.is_empty() || reference.starts_with('#') || reference.contains(':') {
+            return None;
+        }
+        let (path, fragment) = match reference.split_once('#') {
+            Some((path, fragment)) => (path, Some(fragment)),
+            None => (reference, None),
+        };
+        let target = SplitEpub::join_href(&base_path, path);
+        let new_target = path_map.get(&target).cloned().unwrap_or_else(|| target.clone());
+        // Rooted references become relative ones even if nothing moved
+        if new_target == target && source_href == output_href && !path.starts_with('/') {
+            return None;
+        }
+
+        let mut link = utf8_percent_encode(&relative_href(output_href, &new_target), HREF_ENCODE_SET)
+            .to_string();
+        if let Some(fragment) = fragment {
+            link.push('#');
+            link.push_str(fragment);
+        }
+        Some(link)
+    };
+    let quoted = |caps: &regex::Captures, first: usize| -> (String, char) {
+        match (caps.get(first), caps.get(first + 1)) {
+            (Some(value), _) => (value.as_str().to_string(), '"'),
+            (_, Some(value)) => (value.as_str().to_string(), '\''),
+            _ => (String::new(), '"'),
+        }
+    };
+
+    let content = attr_re.replace_all(content, |caps: &regex::Captures| {
+        let (reference, quote) = quoted(caps, 2);
+        match relink(&reference) {
+            Some(link) => format!("{}{}{}{}", &caps[1], quote, link, quote),
+            None => caps[0].to_string(),
+        }
+    });
+    let content = import_re.replace_all(&content, |caps: &regex::Captures| {
+        let (reference, quote) = quoted(caps, 2);
+        match relink(&reference) {
+            Some(link) => format!("{}{}{}{}", &caps[1], quote, link, quote),
+            None => caps[0].to_string(),
+        }
+    });
+    let content = url_re.replace_all(&content, |caps: &regex::Captures| {
+        let reference = caps
+            .get(1)
+            .or_else(|| caps.get(2))
+            .or_else(|| caps.get(3))
+            .map(|m| m.as_str())
+            .unwrap_or_default();
+        match relink(reference) {
+            Some(link) => format!("url(\"{}\")", link),
+            None => caps[0].to_string(),
+        }
+    });
+
+    Ok(content.into_owned())
+}
+
+/// Build a link from the file at `from` to the file at `to`, both given as
+/// paths from the archive root
+fn relative_href(from: &str, to: &str) -> String {
+    let from_parts: Vec<&str> = from.split('/').collect();
+    let from_dir = &from_parts[..from_parts.len() - 1];
+    let to_parts: Vec<&str> = to.split('/').collect();
+
+    let common = from_dir
+        .iter()
+        .zip(to_parts.iter())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let mut parts: Vec<&str> = vec![".."; from_dir.len() - common];
+    parts.extend_from_slice(&to_parts[common..]);
+    parts.join("/")
+}
+
+/// Remove `<script>` elements and `on*` event handler attributes from XHTML
+fn strip_scripts(content: &str) -> Result<String> {
+    let script_re = Regex::new(r"(?is)<script\b[^>]*?(?:/>|>.*?</script\s*>)")
+        .context("Failed to compile script regex")?;
+    let tag_re = Regex::new(r"<[A-Za-z][^>]*>").context("Failed to compile tag regex")?;
+    let handler_re = Regex::new(r#"(?i)\s+on[a-z]+\s*=\s*(?:"[^"]*"|'[^']*')"#)
+        .context("Failed to compile event handler regex")?;
+
+    let without_scripts = script_re.replace_all(content, "");
+    Ok(tag_re
+        .replace_all(&without_scripts, |caps: &regex::Captures| {
+            handler_re.replace_all(&caps[0], "").into_owned()
+        })
+        .into_owned())
+}
+
+/// Apply `--strip-soft-hyphens`, `--punctuation` and `--normalize-unicode`
+/// to the text of an XHTML document, leaving markup alone. Punctuation in
+/// <script>, <style>, <pre> and <code> is kept as written.
+fn normalize_text(content: &str, opts: &OutputOptions) -> Result<String> {
+    let token_re = Regex::new(
+        r"(?is)(?P<kept><!--.*?-->|<script\b.*?</script\s*>|<style\b.*?</style\s*>|<pre\b.*?</pre\s*>|<code\b.*?</code\s*>)|<(?P<tag>/?[A-Za-z0-9:]*)[^>]*>|[^<]+",
+    )
+    .context("Failed to compile text token regex")?;
+    let reference_re = Regex::new(r"&(#[0-9]+|#[xX][0-9a-fA-F]+|[A-Za-z][A-Za-z0-9]*);")
+        .context("Failed to compile character reference regex")?;
+    const PUNCTUATION: &str = "'\"\u{2018}\u{2019}\u{201a}\u{201b}\u{201c}\u{201d}\u{201e}\u{201f}\u{2013}\u{2014}";
+    const BLOCKS: &[&str] = &[
+        "address", "article", "aside", "blockquote", "body", "br", "dd", "div", "dt", "figcaption", "h1", "h2",
+        "h3", "h4", "h5", "h6", "header", "footer", "hr", "li", "p", "section", "td", "th", "title", "tr",
+    ];
+
+    let mut normalized = String::with_capacity(content.len());
+    // The character before the current text, for telling opening quotes
+    // from closing ones; None at the start of a block
+    let mut previous: Option<char> = None;
+    for caps in token_re.captures_iter(content) {
+        if let Some(tag) = caps.name("tag") {
+            let name = tag.as_str().trim_start_matches('/').to_ascii_lowercase();
+            if BLOCKS.contains(&name.as_str()) {
+                previous = None;
+            }
+            normalized.push_str(&caps[0]);
+            continue;
+        }
+        let kept = caps.name("kept").is_some();
+        let token = &caps[0];
+        // Quotes, dashes and soft hyphens written as references are
+        // converted like the characters themselves
+        let text = reference_re.replace_all(token, |reference: &regex::Captures| {
+            match entity_value(&reference[1]) {
+                Some(('\u{ad}', None)) if opts.strip_soft_hyphens => String::new(),
+                Some((c, None)) if opts.punctuation.is_some() && !kept && PUNCTUATION.contains(c) => c.to_string(),
+                _ => reference[0].to_string(),
+            }
+        });
+        let mut text = text.into_owned();
+        if opts.strip_soft_hyphens {
+            text.retain(|c| c != '\u{ad}');
+        }
+        match opts.punctuation {
+            Some(_) if kept => {}
+            Some(Punctuation::Straight) => text = straighten_punctuation(&text),
+            Some(Punctuation::Smart) => text = smarten_punctuation(&text, previous),
+            None => {}
+        }
+        if let Some(last) = text.chars().next_back() {
+            previous = Some(last);
+        }
+        normalized.push_str(&text);
+    }
+
+    if opts.normalize_unicode {
+        normalized = normalized.nfc().collect();
+    }
+    Ok(normalized)
+}
+
+/// Curly quotes as ' and ", en dashes as - and em dashes as --
+fn straighten_punctuation(text: &str) -> String {
+    let mut straight = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\u{2018}' | '\u{2019}' | '\u{201a}' | '\u{201b}' => straight.push('\''),
+            '\u{201c}' | '\u{201d}' | '\u{201e}' | '\u{201f}' => straight.push('"'),
+            '\u{2013}' => straight.push('-'),
+            '\u{2014}' => straight.push_str("--"),
+            c => straight.push(c),
+        }
+    }
+    straight
+}
+
+/// ' and " as curly quotes, opening after whitespace, an opening bracket or
+/// a dash and closing elsewhere, and -- as an em dash. `previous` is the
+/// character before `text`, if it continues a block.
+fn smarten_punctuation(text: &str, previous: Option<char>) -> String {
+    let mut smart = String::with_capacity(text.len());
+    let mut previous = previous;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        let opens = previous.is_none_or(|p| p.is_whitespace() || "([{\u{2013}\u{2014}\u{2018}\u{201c}".contains(p));
+        let converted = match c {
+            '"' if opens => '\u{201c}',
+            '"' => '\u{201d}',
+            '\'' if opens => '\u{2018}',
+            '\'' => '\u{2019}',
+            '-' if chars.peek() == Some(&'-') => {
+                chars.next();
+                '\u{2014}'
+            }
+            c => c,
+        };
+        smart.push(converted);
+        previous = Some(converted);
+    }
+    smart
+}
+
+/// Drop comments from XHTML and collapse runs of whitespace to one space,
+/// in text and between attributes. `<pre>`, `<textarea>` and `<script>`
+/// are left as written; `<style>` is minified as CSS.
+fn minify_xhtml(content: &str) -> Result<String> {
+    let token_re = Regex::new(
+        r"(?is)(?P<comment><!--.*?-->)|(?P<kept><pre\b.*?</pre\s*>|<textarea\b.*?</textarea\s*>|<script\b.*?</script\s*>)|(?P<open><style\b[^>]*>)(?P<css>.*?)(?P<close></style\s*>)|(?P<tag><[^>]*>)|[^<]+",
+    )
+    .context("Failed to compile minify regex")?;
+    let tag_space_re = Regex::new(r#""[^"]*"|'[^']*'|\s+"#).context("Failed to compile tag whitespace regex")?;
+    let space_re = Regex::new(r"\s+").context("Failed to compile whitespace regex")?;
+
+    let mut minified = String::with_capacity(content.len());
+    for caps in token_re.captures_iter(content) {
+        if caps.name("comment").is_some() {
+            continue;
+        } else if let Some(kept) = caps.name("kept") {
+            minified.push_str(kept.as_str());
+        } else if let (Some(open), Some(css), Some(close)) = (caps.name("open"), caps.name("css"), caps.name("close")) {
+            minified.push_str(&tag_space_re.replace_all(open.as_str(), collapse_unquoted));
+            minified.push_str(&minify_css(css.as_str())?);
+            minified.push_str(close.as_str());
+        } else if let Some(tag) = caps.name("tag") {
+            minified.push_str(&tag_space_re.replace_all(tag.as_str(), collapse_unquoted));
+        } else {
+            // Text either side of a dropped comment
+            let text = space_re.replace_all(&caps[0], " ");
+            let text = match minified.ends_with(' ') {
+                true => text.strip_prefix(' ').unwrap_or(&text),
+                false => &text,
+            };
+            minified.push_str(text);
+        }
+    }
+    Ok(minified)
+}
+
+/// A `tag_space_re` match with whitespace collapsed and quoted values kept
+fn collapse_unquoted(caps: &regex::Captures) -> String {
+    match &caps[0] {
+        quoted if quoted.starts_with(['"', '\'']) => quoted.to_string(),
+        _ => " ".to_string(),
+    }
+}
+
+/// Drop comments from CSS, collapse whitespace, and remove it around `{`,
+/// `}`, `;` and `,`. Strings are kept as written.
+fn minify_css(css: &str) -> Result<String> {
+    let token_re = Regex::new(r#"(?s)"(?:[^"\\]|\\.)*"|'(?:[^'\\]|\\.)*'|(?:\s|/\*.*?\*/)+"#)
+        .context("Failed to compile CSS minify regex")?;
+    const TIGHT: &[char] = &['{', '}', ';', ','];
+
+    let mut minified = String::with_capacity(css.len());
+    let mut last = 0;
+    for token in token_re.find_iter(css) {
+        minified.push_str(&css[last..token.start()]);
+        last = token.end();
+        if token.as_str().starts_with(['"', '\'']) {
+            minified.push_str(token.as_str());
+            continue;
+        }
+        let next = css[token.end()..].chars().next();
+        let needed = !minified.is_empty()
+            && !minified.ends_with(TIGHT)
+            && next.is_some_and(|next| !TIGHT.contains(&next));
+        if needed {
+            minified.push(' ');
+        }
+    }
+    minified.push_str(&css[last..]);
+    Ok(minified)
+}
+
+/// Shortest cover side readers and stores commonly accept
+const MIN_COVER_SIDE: u32 = 625;
+
+/// Acceptable range of cover height / width ratios
+const COVER_ASPECT_RANGE: std::ops::RangeInclusive<f32> = 1.2..=2.0;
+
+/// A cover image read in by the caller, checked and given its archive path:
+/// (data, archive path, media type)
+fn supplied_cover(name: &str, data: &[u8], strict: bool) -> Result<(Vec<u8>, String, String)> {
+    check_cover(data, name, strict)?;
+    let media_type = image::guess_format(data)
+        .map(|format| format.to_mime_type().to_string())
+        .unwrap_or_else(|_| "image/jpeg".to_string());
+    let cover_href = format!("cover.{}", extension_for_media_type(&media_type));
+    Ok((data.to_vec(), cover_href, media_type))
+}
+
+/// Warn about (or with `strict`, reject) covers that are too small or far
+/// from the usual portrait proportions
+fn check_cover(data: &[u8], name: &str, strict: bool) -> Result<()> {
+    let problem = match image::load_from_memory(data) {
+        Ok(img) => {
+            let (width, height) = (img.width(), img.height());
+            let aspect = height as f32 / width as f32;
+            if width.min(height) < MIN_COVER_SIDE {
+                Some(format!(
+                    "cover {} is only {}x{} (shortest side should be at least {}px)",
+                    name, width, height, MIN_COVER_SIDE
+                ))
+            } else if !COVER_ASPECT_RANGE.contains(&aspect) {
+                Some(format!(
+                    "cover {} is {}x{}, an unusual aspect ratio of 1:{:.2} (expected 1:{}-1:{})",
+                    name,
+                    width,
+                    height,
+                    aspect,
+                    COVER_ASPECT_RANGE.start(),
+                    COVER_ASPECT_RANGE.end()
+                ))
+            } else {
+                None
+            }
+        }
+        Err(e) => Some(format!("cover {} could not be read as an image: {}", name, e)),
+    };
+
+    match problem {
+        Some(problem) if strict => bail!(Failure::new(FailureKind::Validation, format!("Rejected {}", problem))),
+        Some(problem) => warn!("{}", problem),
+        None => {}
+    }
+    Ok(())
+}
+
+/// Scale a cover to cover `size` and crop the overflow evenly from both sides,
+/// re-encoding it as JPEG
+fn fit_cover(data: &[u8], size: CoverSize) -> Result<Vec<u8>> {
+    let img = image::load_from_memory(data).context("Failed to decode cover image")?;
+    let fitted = img.resize_to_fill(size.width, size.height, FilterType::Lanczos3);
+    let mut out = Vec::new();
+    JpegEncoder::new_with_quality(&mut out, 90)
+        .encode_image(&fitted.to_rgb8())
+        .context("Failed to encode resized cover")?;
+    Ok(out)
+}
+
+/// Font used to draw generated covers
+const COVER_FONT: &[u8] = include_bytes!("../assets/DejaVuSerif-Bold.ttf");
+
+/// Size of generated covers in pixels (width, height)
+const GENERATED_COVER_SIZE: (u32, u32) = (1600, 2560);
+
+/// Render a simple JPEG cover: part number, title and author in light text
+/// over a background coloured from the title
+fn generate_cover_image(
+    title: &str,
+    authors: &[String],
+    part: Option<usize>,
+    style: CoverStyle,
+) -> Result<Vec<u8>> {
+    let font = FontRef::try_from_slice(COVER_FONT).context("Failed to load cover font")?;
+    let (width, height) = GENERATED_COVER_SIZE;
+    let margin = width as f32 * 0.08;
+    let max_width = width as f32 - 2.0 * margin;
+
+    // Colour from the title so neighbouring parts are easy to tell apart
+    let mut hasher = Sha1::new();
+    hasher.update(title.as_bytes());
+    let hue = hasher.finalize()[0] as f32 / 255.0 * 360.0;
+    let top = hsl_to_rgb(hue, 0.45, 0.32);
+    let bottom = match style {
+        CoverStyle::Solid => top,
+        CoverStyle::Gradient => hsl_to_rgb(hue + 40.0, 0.5, 0.10),
+    };
+    let mut img = RgbImage::from_fn(width, height, |_, y| {
+        let t = y as f32 / (height - 1) as f32;
+        Rgb([0, 1, 2].map(|i| (top[i] as f32 * (1.0 - t) + bottom[i] as f32 * t).round() as u8))
+    });
+    let text_colour = [245, 240, 230];
+
+    if let Some(part) = part {
+        draw_centered_text(&mut img, &font, 110.0, &format!("Part {}", part), height as f32 * 0.14, text_colour);
+    }
+
+    // Shrink the title until it fits in a handful of lines
+    let mut scale = 170.0;
+    let mut lines = wrap_text(&font, scale, title, max_width);
+    while scale > 60.0
+        && (lines.len() > 5 || lines.iter().any(|l| text_width(&font, scale, l) > max_width))
+    {
+        scale *= 0.9;
+        lines = wrap_text(&font, scale, title, max_width);
+    }
+    let line_height = scale * 1.25;
+    let mut baseline = height as f32 * 0.42 - line_height * (lines.len() as f32 - 1.0) / 2.0;
+    for line in &lines {
+        draw_centered_text(&mut img, &font, scale, line, baseline, text_colour);
+        baseline += line_height;
+    }
+
+    if !authors.is_empty() {
+        let author = authors.join(", ");
+        let mut author_scale = 90.0;
+        while author_scale > 40.0 && text_width(&font, author_scale, &author) > max_width {
+            author_scale *= 0.9;
+        }
+        draw_centered_text(&mut img, &font, author_scale, &author, height as f32 * 0.85, text_colour);
+    }
+
+    let mut data = Vec::new();
+    JpegEncoder::new_with_quality(&mut data, 90)
+        .encode_image(&img)
+        .context("Failed to encode generated cover")?;
+    Ok(data)
+}
+
+fn hsl_to_rgb(hue: f32, saturation: f32, lightness: f32) -> [u8; 3] {
+    let c = (1.0 - (2.0 * lightness - 1.0).abs()) * saturation;
+    let h = (hue.rem_euclid(360.0)) / 60.0;
+    let x = c * (1.0 - (h % 2.0 - 1.0).abs());
+    let (r, g, b) = match h as u32 {
+        0 => (c, x, 0.0),
+        1 => (x, c, 0.0),
+        2 => (0.0, c, x),
+        3 => (0.0, x, c),
+        4 => (x, 0.0, c),
+        _ => (c, 0.0, x),
+    };
+    let m = lightness - c / 2.0;
+    [r, g, b].map(|v| ((v + m) * 255.0).round().clamp(0.0, 255.0) as u8)
+}
+
+fn text_width(font: &FontRef, scale: f32, text: &str) -> f32 {
+    let scaled = font.as_scaled(PxScale::from(scale));
+    let mut width = 0.0;
+    let mut previous = None;
+    for c in text.chars() {
+        let id = scaled.glyph_id(c);
+        if let Some(previous) = previous {
+            width += scaled.kern(previous, id);
+        }
+        width += scaled.h_advance(id);
+        previous = Some(id);
+    }
+    width
+}
+
+/// Greedily break `text` into lines no wider than `max_width`
+fn wrap_text(font: &FontRef, scale: f32, text: &str, max_width: f32) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    let mut current = String::new();
+    for word in text.split_whitespace() {
+        let candidate = if current.is_empty() {
+            word.to_string()
+        } else {
+            format!("{} {}", current, word)
+        };
+        if !current.is_empty() && text_width(font, scale, &candidate) > max_width {
+            lines.push(std::mem::replace(&mut current, word.to_string()));
+        } else {
+            current = candidate;
+        }
+    }
+    if !current.is_empty() {
+        lines.push(current);
+    }
+    lines
+}
+
+fn draw_centered_text(img: &mut RgbImage, font: &FontRef, scale: f32, text: &str, baseline: f32, colour: [u8; 3]) {
+    let px_scale = PxScale::from(scale);
+    let scaled = font.as_scaled(px_scale);
+    let mut x = (img.width() as f32 - text_width(font, scale, text)) / 2.0;
+    let mut previous = None;
+
+    for c in text.chars() {
+        let id = scaled.glyph_id(c);
+        if let Some(previous) = previous {
+            x += scaled.kern(previous, id);
+        }
+        let glyph = id.with_scale_and_position(px_scale, point(x, baseline));
+        x += scaled.h_advance(id);
+        previous = Some(id);
+
+        let Some(outlined) = font.outline_glyph(glyph) else {
+            continue;
+        };
+        let bounds = outlined.px_bounds();
+        outlined.draw(|gx, gy, coverage| {
+            let px = bounds.min.x as i64 + gx as i64;
+            let py = bounds.min.y as i64 + gy as i64;
+            if px < 0 || py < 0 || px >= img.width() as i64 || py >= img.height() as i64 {
+                return;
+            }
+            let pixel = img.get_pixel_mut(px as u32, py as u32);
+            for (channel, &target) in pixel.0.iter_mut().zip(&colour) {
+                *channel = (*channel as f32 * (1.0 - coverage) + target as f32 * coverage).round() as u8;
+            }
+        });
+    }
+}
+
+/// Re-encode a JPEG or PNG image, downscaling it to fit within the configured
+/// maximum dimension. Returns `None` when the image isn't a supported format,
+/// already fits, can't be decoded, or the re-encoded result wouldn't be smaller.
+fn optimize_image(href: &str, data: &[u8], settings: ImageOptimization) -> Option<Vec<u8>> {
+    let format = match image::guess_format(data) {
+        Ok(format @ (ImageFormat::Jpeg | ImageFormat::Png)) => format,
+        _ => return None,
+    };
+
+    // Reading the header is enough to leave images that already fit alone;
+    // re-encoding those would only cost time and quality
+    let max = settings.max_dimension;
+    let (width, height) =
+        match image::ImageReader::with_format(std::io::Cursor::new(data), format).into_dimensions() {
+            Ok(dimensions) => dimensions,
+            Err(e) => {
+                warn!("Could not read image {}, copying unchanged: {}", href, e);
+                return None;
+            }
+        };
+    if width <= max && height <= max {
+        debug!("Keeping original {}: {}x{} already fits {}px", href, width, height, max);
+        return None;
+    }
+
+    let img = match image::load_from_memory_with_format(data, format) {
+        Ok(img) => img,
+        Err(e) => {
+            warn!("Could not decode image {}, copying unchanged: {}", href, e);
+            return None;
+        }
+    };
+
+    debug!("Downscaling {} from {}x{} to fit {}px", href, width, height, max);
+    let img = img.resize(max, max, FilterType::Lanczos3);
+
+    let mut encoded = Vec::new();
+    let result = match format {
+        ImageFormat::Jpeg => {
+            let encoder = JpegEncoder::new_with_quality(&mut encoded, settings.quality);
+            DynamicImage::ImageRgb8(img.to_rgb8()).write_with_encoder(encoder)
+        }
+        _ => {
+            let encoder = PngEncoder::new_with_quality(
+                &mut encoded,
+                CompressionType::Best,
+                PngFilterType::Adaptive,
+            );
+            img.write_with_encoder(encoder)
+        }
+    };
+
+    if let Err(e) = result {
+        warn!("Could not re-encode image {}, copying unchanged: {}", href, e);
+        return None;
+    }
+
+    if encoded.len() < data.len() {
+        info!(
+            "Optimized {}: {} -> {} bytes",
+            href,
+            data.len(),
+            encoded.len()
+        );
+        Some(encoded)
+    } else {
+        debug!("Keeping original {}: re-encoding did not reduce size", href);
+        None
+    }
+}
+
+/// File extension to use for a downloaded resource of the given media type
+fn extension_for_media_type(media_type: &str) -> &'static str {
+    match media_type {
+        "text/css" => "css",
+        "image/jpeg" => "jpg",
+        "image/png" => "png",
+        "image/gif" => "gif",
+        "image/svg+xml" => "svg",
+        "image/webp" => "webp",
+        "application/x-font-ttf" | "font/ttf" => "ttf",
+        "application/vnd.ms-opentype" | "font/otf" => "otf",
+        "application/font-woff" | "font/woff" => "woff",
+        "font/woff2" => "woff2",
+        _ => "bin",
+    }
+}
+
+/// Bytes read from the start of a file to recognise its format
+const SNIFF_LEN: u64 = 1024;
+
+/// Declared media types that agree with a sniffed TrueType/OpenType font;
+/// books use all of these for either kind
+pub(crate) const SFNT_MEDIA_TYPES: &[&str] = &[
+    "font/ttf",
+    "font/otf",
+    "font/sfnt",
+    "application/font-sfnt",
+    "application/x-font-ttf",
+    "application/x-font-truetype",
+    "application/x-font-otf",
+    "application/x-font-opentype",
+    "application/vnd.ms-opentype",
+];
+
+/// Recognise a file's format from its first bytes: the media type to
+/// declare for it, and other declared types that agree with it
+fn sniff_media_type(data: &[u8]) -> Option<(&'static str, &'static [&'static str])> {
+    let root = || {
+        let head = &data[..data.len().min(SNIFF_LEN as usize)];
+        root_element_name(&String::from_utf8_lossy(head))
+    };
+    match data {
+        [0xFF, 0xD8, 0xFF, ..] => Some(("image/jpeg", &[])),
+        [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A, ..] => Some(("image/png", &[])),
+        [b'G', b'I', b'F', b'8', b'7' | b'9', b'a', ..] => Some(("image/gif", &[])),
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => Some(("image/webp", &[])),
+        [0x00, 0x01, 0x00, 0x00, ..] | [b't', b'r', b'u', b'e', ..] => Some(("font/ttf", SFNT_MEDIA_TYPES)),
+        [b'O', b'T', b'T', b'O', ..] => Some(("font/otf", SFNT_MEDIA_TYPES)),
+        [b'w', b'O', b'F', b'F', ..] => Some(("font/woff", &["application/font-woff", "application/x-font-woff"])),
+        [b'w', b'O', b'F', b'2', ..] => Some(("font/woff2", &["application/font-woff2"])),
+        [b'I', b'D', b'3', ..] => Some(("audio/mpeg", &["audio/mp3"])),
+        [b'O', b'g', b'g', b'S', ..] => Some(("audio/ogg", &["video/ogg", "application/ogg", "audio/opus"])),
+        [_, _, _, _, b'f', b't', b'y', b'p', a, b, c, d, ..] => iso_media_type(&[*a, *b, *c, *d]),
+        _ => match root()?.as_str() {
+            "svg" => Some(("image/svg+xml", &[])),
+            "html" => Some(("application/xhtml+xml", &["text/html"])),
+            _ => None,
+        },
+    }
+}
+
+/// The media type of an ISO base media file (MP4, HEIF and relatives) by
+/// its `ftyp` major brand; None for brands we don't know, so their declared
+/// type is kept
+fn iso_media_type(brand: &[u8]) -> Option<(&'static str, &'static [&'static str])> {
+    match brand {
+        b"avif" | b"avis" => Some(("image/avif", &[])),
+        b"heic" | b"heix" | b"mif1" => Some(("image/heic", &["image/heif"])),
+        b"qt  " => Some(("video/quicktime", &[])),
+        b"M4A " | b"M4B " => Some(("audio/mp4", &["video/mp4", "audio/x-m4a", "audio/m4a"])),
+        [b'm', b'p', b'4', _] | [b'i', b's', b'o', _] => {
+            Some(("video/mp4", &["audio/mp4", "audio/x-m4a", "audio/m4a"]))
+        }
+        _ => None,
+    }
+}
+
+/// The local name of a markup document's root element, lowercased, skipping
+/// any XML declaration, doctype and comments before it
+fn root_element_name(text: &str) -> Option<String> {
+    let mut rest = text.trim_start_matches('\u{feff}').trim_start();
+    loop {
+        if rest.starts_with("<?") {
+            rest = &rest[rest.find("?>")? + 2..];
+        } else if rest.starts_with("<!--") {
+            rest = &rest[rest.find("-->")? + 3..];
+        } else if rest.starts_with("<!") {
+            rest = &rest[rest.find('>')? + 1..];
+        } else {
+            let name: String = rest
+                .strip_prefix('<')?
+                .chars()
+                .take_while(|c| c.is_alphanumeric() || matches!(c, ':' | '-' | '_' | '.'))
+                .collect();
+            let local = name.rsplit(':').next().unwrap_or_default();
+            return (!local.is_empty()).then(|| local.to_ascii_lowercase());
+        }
+        rest = rest.trim_start();
+    }
+}
+
+/// The media type to declare for a copied file: `declared` unless it's
+/// missing or the file's first bytes show it's something else, in which
+/// case the sniffed type (or a guess from the extension) is used and the
+/// correction logged
+fn checked_media_type(href: &str, declared: Option<&str>, data: &[u8]) -> String {
+    let declared = declared.filter(|media_type| !media_type.trim().is_empty());
+    match (declared, sniff_media_type(data)) {
+        (Some(declared), Some((sniffed, aliases)))
+            if !declared.eq_ignore_ascii_case(sniffed)
+                && !aliases.iter().any(|alias| declared.eq_ignore_ascii_case(alias)) =>
+        {
+            warn!("{} is declared as {} but is {}; correcting its media type", href, declared, sniffed);
+            sniffed.to_string()
+        }
+        (Some(declared), _) => declared.to_string(),
+        (None, Some((sniffed, _))) => {
+            info!("{} has no declared media type; using {}", href, sniffed);
+            sniffed.to_string()
+        }
+        (None, None) => guess_media_type_static(href),
+    }
+}
+
+pub(crate) fn guess_media_type_static(href: &str) -> String {
+    let lower = href.to_lowercase();
+    if lower.ends_with(".css") {
+        "text/css".to_string()
+    } else if lower.ends_with(".jpg") || lower.ends_with(".jpeg") {
+        "image/jpeg".to_string()
+    } else if lower.ends_with(".png") {
+        "image/png".to_string()
+    } else if lower.ends_with(".gif") {
+        "image/gif".to_string()
+    } else if lower.ends_with(".svg") {
+        "image/svg+xml".to_string()
+    } else if lower.ends_with(".ttf") {
+        "application/x-font-ttf".to_string()
+    } else if lower.ends_with(".otf") {
+        "application/vnd.ms-opentype".to_string()
+    } else if lower.ends_with(".woff") {
+        "application/font-woff".to_string()
+    } else if lower.ends_with(".woff2") {
+        "font/woff2".to_string()
+    } else {
+        "application/octet-stream".to_string()
+    }
+}
+
+fn generate_merged_opf(
+    meta: &PackageMetadata,
+    manifest_items: &[ManifestItem],
+    spine_items: &[SpineItem],
+) -> String {
+    let mut opf = SplitEpub::package_start_xml(meta.version, "epubmerge-id");
+
+    opf.push_str(&format!(
+        "      <dc:identifier id=\"epubmerge-id\">{}</dc:identifier>\n",
+        SplitEpub::escape_xml(&meta.unique_id)
+    ));
+
+    opf.push_str(&format!(
+        "      <dc:title>{}</dc:title>\n",
+        SplitEpub::escape_xml(&meta.title)
+    ));
+
+    opf.push_str(&SplitEpub::creators_xml(meta));
+
+    for lang in &meta.languages {
+        opf.push_str(&format!(
+            "      <dc:language>{}</dc:language>\n",
+            SplitEpub::escape_xml(lang)
+        ));
+    }
+
+    opf.push_str(&format!(
+        "      <dc:description>{}</dc:description>\n",
+        SplitEpub::escape_xml(&meta.description)
+    ));
+
+    opf.push_str(&SplitEpub::dates_xml(meta));
+
+    for tag in &meta.tags {
+        opf.push_str(&format!(
+            "      <dc:subject>{}</dc:subject>\n",
+            SplitEpub::escape_xml(tag)
+        ));
+    }
+
+    opf.push_str(&SplitEpub::accessibility_meta_xml(meta));
+
+    // The cover kept from the first book, for readers that look for EPUB 2's
+    if let Some(cover) = manifest_items.iter().find(|item| item.properties.iter().any(|p| p == "cover-image")) {
+        opf.push_str(&format!(
+            "      <meta name=\"cover\" content=\"{}\"/>\n",
+            SplitEpub::escape_xml(&cover.id)
+        ));
+    }
+
+    opf.push_str("   </metadata>\n");
+
+    opf.push_str("   <manifest>\n");
+    for item in manifest_items {
+        opf.push_str(&SplitEpub::manifest_item_xml(item, meta.version));
+    }
+    opf.push_str("   </manifest>\n");
+
+    opf.push_str("   <spine toc=\"ncx\">\n");
+    for item in spine_items {
+        opf.push_str(&SplitEpub::spine_item_xml(item, meta.version));
+    }
+    opf.push_str("   </spine>\n");
+
+    opf.push_str("</package>\n");
+
+    opf
+}
+
+/// Rewrites XHTML and CSS files as they're copied into an output, after the
+/// built-in transforms; see [`SplitRequest::transforms`]. Closures taking
+/// `(href, content)` implement it.
+pub trait ContentTransform: Send + Sync {
+    /// New contents for the file at `href`, its path in the source EPUB. It
+    /// may be asked for the same file more than once while an output is
+    /// built, and should give the same answer each time.
+    fn transform(&self, href: &str, content: &[u8]) -> Result<Vec<u8>>;
+}
+
+impl<F> ContentTransform for F
+where
+    F: Fn(&str, &[u8]) -> Result<Vec<u8>> + Send + Sync,
+{
+    fn transform(&self, href: &str, content: &[u8]) -> Result<Vec<u8>> {
+        self(href, content)
+    }
+}
+
+/// The [`ContentTransform`]s for one output, applied in the order added
+#[derive(Clone, Default)]
+pub struct ContentTransforms(Vec<Arc<dyn ContentTransform>>);
+
+impl ContentTransforms {
+    /// Apply `transform` after those added before it
+    pub fn push(&mut self, transform: impl ContentTransform + 'static) {
+        self.0.push(Arc::new(transform));
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &Arc<dyn ContentTransform>> {
+        self.0.iter()
+    }
+}
+
+impl std::fmt::Debug for ContentTransforms {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ContentTransforms({})", self.0.len())
+    }
+}
+
+/// A split asked for through the library API: the split lines to include
+/// and optional metadata for the output
+#[derive(Debug, Default, Deserialize)]
+pub struct SplitRequest {
+    pub sections: Vec<usize>,
+    #[serde(default)]
+    pub title: Option<String>,
+    #[serde(default)]
+    pub authors: Vec<String>,
+    /// Rewrites for the XHTML and CSS copied into this output
+    #[serde(skip)]
+    pub transforms: ContentTransforms,
+}
+
+/// List the split points of an EPUB held in memory, as a JSON object with the
+/// source's `title`, `authors` and `languages` and a `split_points` array
+///
+/// The last few books seen are kept parsed, so listing a book and then
+/// splitting it, or splitting it again, is quick; [`clear_sessions`] lets
+/// them go.
+pub fn split_points_json(epub: &[u8]) -> Result<String> {
+    SESSIONS.with_borrow_mut(|sessions| {
+        let epub = sessions.get_or_open(&SessionCache::key(epub), || SplitEpub::from_bytes(epub.to_vec(), "input.epub"))?;
+        Ok(describe_split_points(epub)?.to_string())
+    })
+}
+
+/// Build a new EPUB from some sections of an EPUB held in memory
+pub fn split_epub_bytes(epub: &[u8], request: &SplitRequest) -> Result<Vec<u8>> {
+    SESSIONS.with_borrow_mut(|sessions| {
+        let epub = sessions.get_or_open(&SessionCache::key(epub), || SplitEpub::from_bytes(epub.to_vec(), "input.epub"))?;
+        split_in_memory(epub, request)
+    })
+}
+
+/// Forget the books [`split_points_json`] and [`split_epub_bytes`] keep
+/// parsed on this thread, freeing their memory
+pub fn clear_sessions() {
+    SESSIONS.with_borrow_mut(SessionCache::clear);
+}
+
+/// The JSON description behind [`split_points_json`]
+pub(crate) fn describe_split_points(epub: &mut SplitEpub) -> Result<serde_json::Value> {
+    let lines = epub.get_split_lines()?;
+    let split_points: Vec<serde_json::Value> = lines
+        .iter()
+        .enumerate()
+        .map(|(index, line)| {
+            serde_json::json!({
+                "index": index,
+                "toc": line.toc,
+                "guide": line.guide.as_ref().map(|(ref_type, title)| serde_json::json!({ "type": ref_type, "title": title })),
+                "anchor": line.anchor,
+                "id": line.id,
+                "href": line.href,
+                "linear": line.linear,
+            })
+        })
+        .collect();
+    Ok(serde_json::json!({
+        "title": epub.get_orig_title(),
+        "authors": epub.get_orig_authors(),
+        "languages": epub.get_orig_languages(),
+        "split_points": split_points,
+    }))
+}
+
+/// Build the EPUB for a [`SplitRequest`] without touching the filesystem
+pub(crate) fn split_in_memory(epub: &mut SplitEpub, request: &SplitRequest) -> Result<Vec<u8>> {
+    if request.sections.is_empty() {
+        bail!(Failure::new(FailureKind::InvalidArgs, "No sections requested"));
+    }
+    let title = request
+        .title
+        .clone()
+        .unwrap_or_else(|| format!("{} Split", epub.get_orig_title()));
+    let authors = if request.authors.is_empty() {
+        epub.get_orig_authors().to_vec()
+    } else {
+        request.authors.clone()
+    };
+    let languages = default_languages(&[], epub.get_orig_languages());
+    let output = std::io::Cursor::new(Vec::new());
+    let opts = OutputOptions { transforms: request.transforms.clone(), ..OutputOptions::default() };
+    let built = epub.build_split_epub(output, &request.sections, &authors, Some(&title), &languages, &opts)?;
+    Ok(built.output.into_inner())
+}
+
+/// Classes of failure with their own exit codes, so wrapper scripts can
+/// branch on them. Anything unclassified exits with 1; clap's own argument
+/// errors already exit with 2.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum FailureKind {
+    InvalidArgs,
+    UnreadableInput,
+    Drm,
+    OutOfRange,
+    PartialBatch,
+    Validation,
+}
+
+impl FailureKind {
+    fn exit_code(self) -> u8 {
+        match self {
+            FailureKind::InvalidArgs => 2,
+            FailureKind::UnreadableInput => 3,
+            FailureKind::Drm => 4,
+            FailureKind::OutOfRange => 5,
+            FailureKind::PartialBatch => 6,
+            FailureKind::Validation => 7,
+        }
+    }
+}
+
+/// An error tagged with the exit code it should produce
+#[derive(Debug)]
+pub(crate) struct Failure {
+    pub(crate) kind: FailureKind,
+    message: String,
+}
+
+impl Failure {
+    pub(crate) fn new(kind: FailureKind, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            message: message.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for Failure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for Failure {}
+
+/// Exit code for an error: the outermost tagged failure in its chain wins
+pub(crate) fn exit_code(error: &anyhow::Error) -> u8 {
+    error
+        .downcast_ref::<Failure>()
+        .map_or(1, |failure| failure.kind.exit_code())
+}
+
+/// Refuse DRM-protected books: Adobe ADEPT rights, or encrypted entries other
+/// than obfuscated fonts
+fn check_drm(archive: &mut EpubArchive, path: &std::path::Path) -> Result<()> {
+    const FONT_OBFUSCATION: [&str; 2] = ["http://www.idpf.org/2008/embedding", "http://ns.adobe.com/pdf/enc#RC"];
+
+    let drm = || Failure::new(FailureKind::Drm, format!("{} is DRM-protected", path.display()));
+    if archive.by_name("META-INF/rights.xml").is_ok() {
+        bail!(drm());
+    }
+    let Ok(encryption) = SplitEpub::read_file_from_archive(archive, "META-INF/encryption.xml") else {
+        return Ok(());
+    };
+    let algorithm_regex = Regex::new(r#"EncryptionMethod[^>]*Algorithm\s*=\s*["']([^"']+)["']"#)
+        .context("Failed to compile encryption algorithm regex")?;
+    if algorithm_regex
+        .captures_iter(&encryption)
+        .any(|c| !FONT_OBFUSCATION.contains(&&c[1]))
+    {
+        bail!(drm());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    fn nav_point(text: &str, src: &str, children: Vec<NavPoint>) -> NavPoint {
+        NavPoint {
+            text: text.to_string(),
+            src: src.to_string(),
+            children,
+        }
+    }
+
+    /// Book > Part > Chapter > Section > Subsection, twice over
+    fn deep_toc() -> Vec<NavPoint> {
+        let chapter = |n: usize| {
+            nav_point(
+                &format!("Chapter {}", n),
+                &format!("ch{}.xhtml", n),
+                vec![nav_point(
+                    &format!("Section {}.1", n),
+                    &format!("ch{}.xhtml#s1", n),
+                    vec![nav_point(&format!("Subsection {}.1.1", n), &format!("ch{}.xhtml#s1-1", n), vec![])],
+                )],
+            )
+        };
+        vec![
+            nav_point("Part One", "part1.xhtml", vec![chapter(1), chapter(2)]),
+            nav_point("Part Two", "part2.xhtml", vec![chapter(3)]),
+        ]
+    }
+
+    /// A two-chapter EPUB whose package has `version` and `metadata`, and
+    /// whose second chapter is `chapter2`
+    pub(crate) fn test_epub(version: &str, metadata: &str, chapter2: &str) -> Vec<u8> {
+        let opf = format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<package version="{}" xmlns="http://www.idpf.org/2007/opf" unique-identifier="uid">
+  <metadata xmlns:dc="http://purl.org/dc/elements/1.1/" xmlns:opf="http://www.idpf.org/2007/opf">
+    <dc:identifier id="uid">urn:uuid:test</dc:identifier>
+    <dc:title>Test Book</dc:title>
+    <dc:language>en</dc:language>
+    {}
+  </metadata>
+  <manifest>
+    <item id="ncx" href="toc.ncx" media-type="application/x-dtbncx+xml"/>
+    <item id="ch1" href="Text/ch1.xhtml" media-type="application/xhtml+xml"/>
+    <item id="ch2" href="Text/ch2.xhtml" media-type="application/xhtml+xml"/>
+  </manifest>
+  <spine toc="ncx">
+    <itemref idref="ch1"/>
+    <itemref idref="ch2" properties="page-spread-left"/>
+  </spine>
+</package>"#,
+            version, metadata
+        );
+        let ncx = r#"<ncx xmlns="http://www.daisy.org/z3986/2005/ncx/" version="2005-1"><navMap>
+            <navPoint id="n1" playOrder="1"><navLabel><text>Chapter 1</text></navLabel><content src="Text/ch1.xhtml"/></navPoint>
+            <navPoint id="n2" playOrder="2"><navLabel><text>Chapter 2</text></navLabel><content src="Text/ch2.xhtml"/></navPoint>
+        </navMap></ncx>"#;
+        let container = r#"<?xml version="1.0"?>
+<container version="1.0" xmlns="urn:oasis:names:tc:opendocument:xmlns:container">
+  <rootfiles><rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml"/></rootfiles>
+</container>"#;
+        let chapter = |body: &str| format!("<html xmlns=\"http://www.w3.org/1999/xhtml\"><body>{}</body></html>", body);
+
+        let mut zip = ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        for (name, content) in [
+            ("mimetype", "application/epub+zip".to_string()),
+            ("META-INF/container.xml", container.to_string()),
+            ("OEBPS/content.opf", opf),
+            ("OEBPS/toc.ncx", ncx.to_string()),
+            ("OEBPS/Text/ch1.xhtml", chapter("<p>One</p>")),
+            ("OEBPS/Text/ch2.xhtml", chapter(chapter2)),
+        ] {
+            zip.start_file(name, SimpleFileOptions::default()).unwrap();
+            zip.write_all(content.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap().into_inner()
+    }
+
+    /// The names of an output's entries, and its content.opf
+    fn built_package(built: &BuiltEpub<std::io::Cursor<Vec<u8>>>) -> (Vec<String>, String) {
+        let mut archive = ZipArchive::new(std::io::Cursor::new(built.output.get_ref())).unwrap();
+        let names = archive.file_names().map(str::to_string).collect();
+        let mut opf = String::new();
+        archive.by_name("content.opf").unwrap().read_to_string(&mut opf).unwrap();
+        (names, opf)
+    }
+
+    fn split_all(epub: Vec<u8>, opts: &OutputOptions) -> BuiltEpub<std::io::Cursor<Vec<u8>>> {
+        let mut epub = SplitEpub::from_bytes(epub, "test.epub").unwrap();
+        let authors = epub.get_orig_authors().to_vec();
+        let output = std::io::Cursor::new(Vec::new());
+        epub.build_split_epub(output, &[0, 1], &authors, None, &["en".to_string()], opts).unwrap()
+    }
+
+    #[test]
+    fn generated_accessibility_claims_follow_the_content() {
+        let claims = |body: &str| {
+            let mut content = ContentAccessibility::default();
+            content.scan(body).unwrap();
+            synthesize_accessibility_metadata(&[], &content)
+        };
+        let feature = |value: &str| ("schema:accessibilityFeature".to_string(), value.to_string());
+        let sufficient = |value: &str| ("schema:accessModeSufficient".to_string(), value.to_string());
+
+        let plain = claims("<p>Text</p>");
+        assert!(plain.contains(&sufficient("textual")));
+        assert!(!plain.contains(&feature("structuralNavigation")) && !plain.contains(&feature("alternativeText")));
+
+        let unlabelled = claims(r#"<h1>One</h1><img src="a.png"/><img src="b.png" alt=""/>"#);
+        assert!(unlabelled.contains(&sufficient("textual,visual")));
+        assert!(unlabelled.contains(&feature("structuralNavigation")));
+        assert!(!unlabelled.contains(&feature("alternativeText")));
+
+        let labelled = claims(r#"<img src="a.png" alt="A map"/><svg:image xlink:href="b.png" aria-label="B"/>"#);
+        assert!(labelled.contains(&sufficient("textual")) && labelled.contains(&feature("alternativeText")));
+        assert!(labelled.contains(&("schema:accessMode".to_string(), "visual".to_string())));
+    }
+
+    #[test]
+    fn merges_keep_the_accessibility_claims_every_source_makes() {
+        let source = |summary: &str| -> (PathBuf, Box<dyn EpubSource>) {
+            let metadata = format!(
+                r#"<meta property="schema:accessMode">textual</meta>
+                <meta property="schema:accessibilitySummary">{}</meta>"#,
+                summary
+            );
+            (PathBuf::from("a.epub"), Box::new(std::io::Cursor::new(test_epub("3.0", &metadata, "<p/>"))))
+        };
+        let built = merge_sources(
+            vec![source("First."), source("Second.")],
+            std::io::Cursor::new(Vec::new()),
+            &OutputOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(built.meta.accessibility, vec![("schema:accessMode".to_string(), "textual".to_string())]);
+    }
+
+    #[test]
+    fn numbered_compilations_get_their_part_label() {
+        let sources = || -> Vec<(PathBuf, Box<dyn EpubSource>)> {
+            ["a.epub", "b.epub"]
+                .into_iter()
+                .map(|name| (PathBuf::from(name), Box::new(std::io::Cursor::new(test_epub("3.0", "", "<p/>"))) as Box<dyn EpubSource>))
+                .collect()
+        };
+        let opts = OutputOptions {
+            title: Some("Best Of".to_string()),
+            description: Some("Stories.".to_string()),
+            number_parts: Some(PartNumbering::TitleAndDescription),
+            part: Some(2),
+            part_count: Some(3),
+            ..OutputOptions::default()
+        };
+        let built = merge_sources(sources(), std::io::Cursor::new(Vec::new()), &opts).unwrap();
+        assert_eq!(built.meta.title, "Best Of (Part 2 of 3)");
+        assert_eq!(built.meta.description, "Stories. Part 2 of 3.");
+
+        let built = merge_sources(sources(), std::io::Cursor::new(Vec::new()), &OutputOptions { part: None, ..opts }).unwrap();
+        assert_eq!(built.meta.title, "Best Of");
+    }
+
+    #[test]
+    fn merges_return_what_they_wrote_for_reference_checks() {
+        let sources = [("a.epub", "<p>Two</p>"), ("b.epub", r#"<img src="gone.png"/>"#)]
+            .into_iter()
+            .map(|(name, chapter2)| {
+                (PathBuf::from(name), Box::new(std::io::Cursor::new(test_epub("3.0", "", chapter2))) as Box<dyn EpubSource>)
+            })
+            .collect();
+        let built = merge_sources(sources, std::io::Cursor::new(Vec::new()), &OutputOptions::default()).unwrap();
+
+        let documents: Vec<&str> = built.documents.iter().map(|(path, ..)| path.as_str()).collect();
+        assert_eq!(
+            documents,
+            ["epub0_OEBPS_Text_ch1.xhtml", "epub0_OEBPS_Text_ch2.xhtml", "epub1_OEBPS_Text_ch1.xhtml", "epub1_OEBPS_Text_ch2.xhtml"]
+        );
+        assert!(built.documents[3].1.contains(r#"<img src="epub1_OEBPS_Text_gone.png"/>"#));
+        assert!(documents.iter().all(|path| built.written_paths.contains(*path)));
+        assert!(!built.written_paths.contains("epub1_OEBPS_Text_gone.png"));
+    }
+
+    #[test]
+    fn merges_take_a_supplied_cover() {
+        let mut data = Vec::new();
+        DynamicImage::ImageRgb8(RgbImage::new(60, 80))
+            .write_to(&mut std::io::Cursor::new(&mut data), ImageFormat::Gif)
+            .unwrap();
+        let sources = ["a.epub", "b.epub"]
+            .into_iter()
+            .map(|name| (PathBuf::from(name), Box::new(std::io::Cursor::new(test_epub("3.0", "", "<p/>"))) as Box<dyn EpubSource>))
+            .collect();
+        let opts = OutputOptions {
+            cover: Some(CoverSource::Image { name: "cover.gif".to_string(), data: Arc::new(data.clone()) }),
+            ..OutputOptions::default()
+        };
+        let built = merge_sources(sources, std::io::Cursor::new(Vec::new()), &opts).unwrap();
+        let (names, opf) = built_package(&built);
+        assert!(names.contains(&"cover.gif".to_string()));
+        assert!(opf.contains(r#"href="cover.gif" media-type="image/gif" properties="cover-image""#));
+        assert_eq!(built.cover, Some(data));
+    }
+
+    #[test]
+    fn outputs_keep_the_source_epub_version() {
+        let metadata = r#"<dc:creator opf:role="aut" opf:file-as="Writer, Ann">Ann Writer</dc:creator>
+            <dc:date opf:event="publication">1962-01-01</dc:date>
+            <dc:date opf:event="modification">2001-05-06</dc:date>"#;
+        let (names, opf) = built_package(&split_all(test_epub("2.0", metadata, "<svg/>"), &OutputOptions::default()));
+        assert!(opf.contains(r#"<package version="2.0""#));
+        assert!(opf.contains(r#"<dc:creator opf:role="aut" opf:file-as="Writer, Ann">Ann Writer</dc:creator>"#));
+        assert!(!opf.contains("nav.xhtml"));
+        assert!(opf.contains(r#"<dc:date opf:event="publication">1962-01-01</dc:date>"#));
+        assert!(!opf.contains("2001-05-06"));
+        assert!(!opf.contains(r#"properties="svg""#));
+        assert!(!opf.contains(r#"properties="page-spread-left""#));
+        assert!(!names.iter().any(|name| name == "nav.xhtml"));
+
+        let metadata = r##"<dc:creator id="c1">Ann Writer</dc:creator>
+            <meta refines="#c1" property="file-as">Writer, Ann</meta>
+            <dc:date>1962-01-01</dc:date>
+            <meta property="dcterms:modified">2001-05-06T00:00:00Z</meta>"##;
+        let (names, opf) = built_package(&split_all(test_epub("3.0", metadata, "<svg/>"), &OutputOptions::default()));
+        assert!(opf.contains(r#"<package version="3.0""#));
+        assert!(!opf.contains("opf:"));
+        assert!(opf.contains(r#"<dc:creator id="creator1">Ann Writer</dc:creator>"#));
+        assert!(opf.contains(r##"<meta refines="#creator1" property="role" scheme="marc:relators">aut</meta>"##));
+        assert!(opf.contains(r##"<meta refines="#creator1" property="file-as">Writer, Ann</meta>"##));
+        assert!(opf.contains("<dc:date>1962-01-01</dc:date>"));
+        assert!(opf.contains(r#"<meta property="dcterms:modified">"#));
+        assert!(!opf.contains("2001-05-06"));
+        assert!(opf.contains(r#"properties="nav""#));
+        assert!(opf.contains(r#"properties="svg""#));
+        assert!(opf.contains(r#"<itemref idref="content1" linear="yes" properties="page-spread-left"/>"#));
+        assert!(names.iter().any(|name| name == "nav.xhtml"));
+    }
+
+    fn ncx_values<'a>(ncx: &'a str, attribute: &str) -> Vec<&'a str> {
+        let pattern = format!("{}=\"", attribute);
+        ncx.match_indices(&pattern)
+            .map(|(pos, _)| {
+                let rest = &ncx[pos + pattern.len()..];
+                &rest[..rest.find('"').unwrap()]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn ncx_depth_matches_the_tree() {
+        let ncx = generate_ncx("uid", "Deep", &deep_toc());
+        assert!(ncx.contains(r#"<meta name="dtb:depth" content="4"/>"#));
+
+        let ncx = generate_ncx("uid", "Flat", &[nav_point("Only", "a.xhtml", vec![])]);
+        assert!(ncx.contains(r#"<meta name="dtb:depth" content="1"/>"#));
+
+        // An empty navMap still declares depth 1
+        let ncx = generate_ncx("uid", "Empty", &[]);
+        assert!(ncx.contains(r#"<meta name="dtb:depth" content="1"/>"#));
+    }
+
+    #[test]
+    fn ncx_play_order_follows_reading_order_through_nesting() {
+        let ncx = generate_ncx("uid", "Deep", &deep_toc());
+        let play_orders: Vec<usize> = ncx_values(&ncx, "playOrder").iter().map(|n| n.parse().unwrap()).collect();
+        assert_eq!(play_orders, (1..=11).collect::<Vec<_>>());
+
+        let ids = ncx_values(&ncx, "id");
+        let unique: HashSet<&&str> = ids.iter().collect();
+        assert_eq!(ids.len(), 11);
+        assert_eq!(unique.len(), ids.len());
+    }
+
+    #[test]
+    fn ncx_entries_sharing_a_target_share_a_play_order() {
+        let toc = vec![
+            nav_point("Part One", "part1.xhtml", vec![nav_point("Opening", "part1.xhtml", vec![])]),
+            nav_point("Chapter 1", "ch1.xhtml", vec![]),
+        ];
+        let ncx = generate_ncx("uid", "Shared", &toc);
+        assert_eq!(ncx_values(&ncx, "playOrder"), vec!["1", "1", "2"]);
+    }
+
+    #[test]
+    fn ncx_nests_nav_points() {
+        let ncx = generate_ncx("uid", "Deep", &deep_toc());
+        let parsed = SplitEpub::parse_toc(&ncx, &NcxHrefs::new("toc.ncx", "", &HashMap::new())).unwrap();
+
+        let part_one = &parsed["part1.xhtml"][0];
+        assert_eq!(part_one.text, "Part One");
+        assert_eq!(part_one.children, deep_toc()[0].children);
+        assert_eq!(NavPoint::depth(&part_one.children), 3);
+        assert_eq!(parsed["part2.xhtml"][0].children, deep_toc()[1].children);
+    }
+
+    #[test]
+    fn parse_toc_keeps_top_level_titles_of_nested_entries() {
+        let ncx = r#"<ncx xmlns="http://www.daisy.org/z3986/2005/ncx/"><navMap>
+            <navPoint><navLabel><text>Part One</text></navLabel><content src="Text/part1.xhtml"/>
+              <navPoint><navLabel><text>Chapter 1</text></navLabel><content src="Text/ch1.xhtml"/>
+                <navPoint><navLabel><text>Section</text></navLabel><content src="Text/ch1.xhtml#s"/>
+                  <navPoint><navLabel><text>Subsection</text></navLabel><content src="Text/ch1.xhtml#ss"/></navPoint>
+                </navPoint>
+              </navPoint>
+            </navPoint>
+            <navPoint><navLabel><text>Part Two</text></navLabel><content src="Text/part2.xhtml#top"/></navPoint>
+        </navMap></ncx>"#;
+        let toc = SplitEpub::parse_toc(ncx, &NcxHrefs::new("OEBPS/toc.ncx", "OEBPS/", &HashMap::new())).unwrap();
+
+        assert_eq!(toc.len(), 2);
+        let part_one = &toc["OEBPS/Text/part1.xhtml"][0];
+        assert_eq!(part_one.text, "Part One");
+        assert_eq!(part_one.anchor, None);
+        assert_eq!(NavPoint::depth(&part_one.children), 3);
+        assert_eq!(part_one.children[0].children[0].children[0].src, "OEBPS/Text/ch1.xhtml#ss");
+
+        let part_two = &toc["OEBPS/Text/part2.xhtml"][0];
+        assert_eq!(part_two.text, "Part Two");
+        assert_eq!(part_two.anchor.as_deref(), Some("top"));
+    }
+
+    #[test]
+    fn ncx_srcs_resolve_against_package_when_ncx_relative_misses() {
+        let manifest: HashMap<String, ManifestItem> = ["OEBPS/Text/ch 1.xhtml", "OEBPS/Text/ch2.xhtml"]
+            .iter()
+            .enumerate()
+            .map(|(i, href)| (i.to_string(), ManifestItem::new(i.to_string(), *href, "application/xhtml+xml")))
+            .collect();
+        let hrefs = NcxHrefs::new("OEBPS/toc/toc.ncx", "OEBPS/", &manifest);
+
+        assert_eq!(hrefs.resolve("Text/CH%201.xhtml#a%20b"), "OEBPS/Text/ch 1.xhtml#a b");
+        assert_eq!(hrefs.resolve("OEBPS/Text/ch2.xhtml"), "OEBPS/Text/ch2.xhtml");
+        assert_eq!(hrefs.resolve("../Text/ch2.xhtml"), "OEBPS/Text/ch2.xhtml");
+        assert_eq!(hrefs.resolve("missing.xhtml"), "OEBPS/toc/missing.xhtml");
+    }
+
+    #[test]
+    fn metadata_text_decodes_html_entities() {
+        let opf = r#"<package><metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+            <dc:title>A&nbsp;Wrinkle &#x2014; &amp;lt;Time&amp;gt;</dc:title>
+            <dc:creator>Madeleine L&rsquo;Engle</dc:creator>
+            <dc:creator>Escaped &amp;nbsp; L&amp;rsquo;Engle</dc:creator>
+            <dc:creator>R&D &unknown; Dept</dc:creator>
+        </metadata></package>"#;
+        let (title, authors) = SplitEpub::parse_metadata(opf).unwrap();
+
+        // Decoded once: escaped references stay references
+        assert_eq!(title, "A\u{a0}Wrinkle \u{2014} &lt;Time&gt;");
+        assert_eq!(
+            authors,
+            vec!["Madeleine L\u{2019}Engle", "Escaped &nbsp; L&rsquo;Engle", "R&D &unknown; Dept"]
+        );
+    }
+
+    #[test]
+    fn refined_roles_keep_other_creators_out_of_the_authors() {
+        let opf = r##"<package version="3.0"><metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+            <dc:title>Illustrated</dc:title>
+            <dc:creator id="a">Ann Writer</dc:creator>
+            <dc:creator id="b">Ben Drawer</dc:creator>
+            <dc:creator id="c">Cy Translator</dc:creator>
+            <meta refines="#a" property="role" scheme="marc:relators">aut</meta>
+            <meta refines="#b" property="role" scheme="marc:relators">ill</meta>
+            <meta refines="#c" property="role" scheme="marc:relators">trl</meta>
+        </metadata></package>"##;
+        let (_, authors) = SplitEpub::parse_metadata(opf).unwrap();
+        let (creators, _) = SplitEpub::parse_creator_roles(opf).unwrap();
+
+        assert_eq!(authors, vec!["Ann Writer"]);
+        assert_eq!(creators, vec![Creator::parse("Ben Drawer:ill"), Creator::parse("Cy Translator:trl")]);
+    }
+
+    #[test]
+    fn sessions_share_split_lines_and_can_be_cleared() {
+        let epub = test_epub("3.0", "", "<p>Two</p>");
+        split_points_json(&epub).unwrap();
+        let lines = SESSIONS.with_borrow_mut(|sessions| {
+            let book = sessions.get_or_open(&SessionCache::key(&epub), || unreachable!()).unwrap();
+            let (first, second) = (book.get_split_lines().unwrap(), book.get_split_lines().unwrap());
+            assert!(Arc::ptr_eq(&first, &second));
+            first
+        });
+        assert_eq!(lines.len(), 2);
+
+        clear_sessions();
+        assert!(SESSIONS.with_borrow(|sessions| sessions.books.is_empty()));
+    }
+
+    #[test]
+    fn minify_keeps_preformatted_text_and_strings() {
+        let xhtml = "<html>\n  <head><style>\n  p  { margin : 0 ;\n }  /* x */ q::before { content: \"a  ,  b\" }\n</style></head>\n\
+            <body>\n<!-- note -->\n  <p   class=\"a  b\">Some \n\t text</p>\n  <pre>  keep\n    this</pre>\n</body></html>";
+
+        assert_eq!(
+            minify_xhtml(xhtml).unwrap(),
+            "<html> <head><style>p{margin : 0;}q::before{content: \"a  ,  b\"}</style></head> \
+             <body> <p class=\"a  b\">Some text</p> <pre>  keep\n    this</pre> </body></html>"
+        );
+    }
+
+    #[test]
+    fn normalize_text_converts_punctuation_in_text_only() {
+        let xhtml = "<p title=\"a 'b'\">\"Don't,\" she said -- <i>'fine'</i>.</p><p>\"Co\u{ad}op&shy;erate\"</p>\
+            <code>x = \"y\"</code><p>Cafe\u{301} &ldquo;done&rdquo;&mdash;</p>";
+        let opts = |punctuation| OutputOptions {
+            punctuation: Some(punctuation),
+            strip_soft_hyphens: true,
+            normalize_unicode: true,
+            ..OutputOptions::default()
+        };
+
+        assert_eq!(
+            normalize_text(xhtml, &opts(Punctuation::Smart)).unwrap(),
+            "<p title=\"a 'b'\">\u{201c}Don\u{2019}t,\u{201d} she said \u{2014} <i>\u{2018}fine\u{2019}</i>.</p>\
+             <p>\u{201c}Cooperate\u{201d}</p><code>x = \"y\"</code><p>Caf\u{e9} \u{201c}done\u{201d}\u{2014}</p>"
+        );
+        assert_eq!(
+            normalize_text(xhtml, &opts(Punctuation::Straight)).unwrap(),
+            "<p title=\"a 'b'\">\"Don't,\" she said -- <i>'fine'</i>.</p><p>\"Cooperate\"</p>\
+             <code>x = \"y\"</code><p>Caf\u{e9} \"done\"--</p>"
+        );
+    }
+
+    #[test]
+    fn requested_transforms_rewrite_copied_content() {
+        assert!(!transforms_content("application/xhtml+xml", &OutputOptions::default()));
+
+        let mut transforms = ContentTransforms::default();
+        transforms.push(|href: &str, content: &[u8]| {
+            let mut content = content.to_vec();
+            if href == "OEBPS/watermark.xhtml" {
+                content.extend_from_slice(b"<!-- copy 7 -->");
+            }
+            Ok(content)
+        });
+        let opts = OutputOptions { transforms, ..OutputOptions::default() };
+        assert!(transforms_content("application/xhtml+xml", &opts));
+
+        let mut remote = RemoteResources::default();
+        let content = transform_content("<p/>".to_string(), "OEBPS/watermark.xhtml", "application/xhtml+xml", &opts, &mut remote);
+        assert_eq!(content.unwrap(), "<p/><!-- copy 7 -->");
+        let content = transform_content("p {}".to_string(), "OEBPS/style.css", "text/css", &opts, &mut remote);
+        assert_eq!(content.unwrap(), "p {}");
+    }
+
+    #[test]
+    fn snippets_keep_context_on_character_boundaries() {
+        let pattern = Regex::new("storm").unwrap();
+        let snippets = Snippet::find_all("It was a dark and stormy night — très stormy", &pattern, 6);
+
+        assert_eq!(snippets.len(), 2);
+        assert_eq!(snippets[0].before, "…k and ");
+        assert_eq!(snippets[0].after, "y nigh…");
+        assert_eq!(snippets[1].before, "… très ");
+        assert_eq!(snippets[1].after, "y");
+    }
+
+    #[test]
+    fn retain_files_lifts_kept_entries_out_of_dropped_ones() {
+        let kept = NavPoint::retain_files(deep_toc(), &|file| file != "ch1.xhtml" && file != "part2.xhtml");
+
+        assert_eq!(kept.len(), 2);
+        let part_one = &kept[0];
+        assert_eq!(part_one.children.iter().map(|p| p.text.as_str()).collect::<Vec<_>>(), vec!["Chapter 2"]);
+        // Chapter 3 moves up in place of Part Two
+        assert_eq!(kept[1].text, "Chapter 3");
+        assert_eq!(NavPoint::depth(&kept), 4);
+
+        let ncx = generate_ncx("uid", "Pruned", &kept);
+        assert!(ncx.contains(r#"<meta name="dtb:depth" content="4"/>"#));
+        assert_eq!(ncx_values(&ncx, "playOrder"), vec!["1", "2", "3", "4", "5", "6", "7"]);
+    }
+
+    #[test]
+    fn map_files_keeps_fragments() {
+        let mapped = NavPoint::map_files(deep_toc(), &|file| format!("OEBPS/Text/{}", file));
+        assert_eq!(mapped[0].children[0].children[0].src, "OEBPS/Text/ch1.xhtml#s1");
+        assert_eq!(NavPoint::depth(&mapped), 4);
+    }
+
+    #[test]
+    fn streamed_scans_see_matches_across_chunk_boundaries() {
+        // Put an image reference and a multi-byte character over the first
+        // chunk boundary
+        let mut html = "<html><head><title>Three words here</title></head><body><p>".to_string();
+        html.push_str(&"word ".repeat((SCAN_CHUNK as usize - html.len() - 20) / 5));
+        html.push_str("é <img src=\"../Images/a.png\"/> word</p></body></html>");
+
+        let mut linked = HashSet::new();
+        let mut stylesheets = Vec::new();
+        for_each_text_window(html.as_bytes(), |window| {
+            SplitEpub::scan_markup_links(window, "Text/ch1.xhtml", &mut linked, &mut stylesheets)
+        })
+        .unwrap();
+        assert_eq!(linked, HashSet::from(["Images/a.png".to_string()]));
+
+        let mut counter = WordCounter::default();
+        counter.feed_from(html.as_bytes()).unwrap();
+        assert_eq!(counter.words(), html_to_text(&html).split_whitespace().count());
+    }
+
+    #[test]
+    fn parallel_map_keeps_order() {
+        let items: Vec<usize> = (0..103).collect();
+        for threads in [1, 4, 200] {
+            assert_eq!(parallel_map(&items, threads, |n| n * 2), items.iter().map(|n| n * 2).collect::<Vec<_>>());
+        }
+        assert!(parallel_map(&[] as &[usize], 4, |n| *n).is_empty());
+    }
+
+    #[test]
+    fn sniffing_corrects_only_clear_mislabels() {
+        let png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+        assert_eq!(checked_media_type("a.jpg", Some("image/jpeg"), png), "image/png");
+        assert_eq!(checked_media_type("a.png", None, png), "image/png");
+        // Font aliases are all fine for either kind of sfnt font
+        assert_eq!(
+            checked_media_type("f.ttf", Some("application/vnd.ms-opentype"), b"\0\x01\0\0\0\x0e"),
+            "application/vnd.ms-opentype"
+        );
+        // XHTML with inline SVG, and CSS with an SVG data URI, stay as declared
+        let xhtml = br#"<?xml version="1.0"?><!DOCTYPE html><html><body><svg/></body></html>"#;
+        assert_eq!(checked_media_type("c.xhtml", Some("application/xhtml+xml"), xhtml), "application/xhtml+xml");
+        let css = br#"a { background: url("data:image/svg+xml,<svg/>") }"#;
+        assert_eq!(checked_media_type("s.css", Some("text/css"), css), "text/css");
+        assert_eq!(checked_media_type("i.svg", Some("image/png"), b"<!-- x --><svg:svg/>"), "image/svg+xml");
+    }
+
+    #[test]
+    fn sniffing_tells_iso_media_files_apart_by_brand() {
+        let ftyp = |brand: &[u8]| [b"\0\0\0\x1cftyp".as_slice(), brand, b"\0\0\0\0"].concat();
+        assert_eq!(checked_media_type("a.avif", Some("image/avif"), &ftyp(b"avif")), "image/avif");
+        assert_eq!(checked_media_type("a.jpg", Some("image/jpeg"), &ftyp(b"avif")), "image/avif");
+        assert_eq!(checked_media_type("h.heic", Some("image/heic"), &ftyp(b"heic")), "image/heic");
+        assert_eq!(checked_media_type("h.heif", Some("image/heif"), &ftyp(b"mif1")), "image/heif");
+        assert_eq!(checked_media_type("v.mp4", Some("video/mp4"), &ftyp(b"isom")), "video/mp4");
+        assert_eq!(checked_media_type("v.mp4", Some("image/png"), &ftyp(b"mp42")), "video/mp4");
+        assert_eq!(checked_media_type("s.m4a", Some("audio/mp4"), &ftyp(b"M4A ")), "audio/mp4");
+        assert_eq!(checked_media_type("v.mov", Some("video/mp4"), &ftyp(b"qt  ")), "video/quicktime");
+        // Unknown brands keep what's declared
+        assert_eq!(checked_media_type("x.3gp", Some("video/3gpp"), &ftyp(b"3gp5")), "video/3gpp");
+    }
+
+    #[test]
+    fn references_resolve_to_real_entries() {
+        let mut zip = ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        for name in ["OEBPS/Images/Cover Art.png", "OEBPS/Text/ch1.xhtml"] {
+            zip.start_file(name, SimpleFileOptions::default()).unwrap();
+        }
+        let data = zip.finish().unwrap().into_inner();
+        let archive: EpubArchive = ZipArchive::new(Box::new(std::io::Cursor::new(data)) as Box<dyn EpubSource>).unwrap();
+        let entries = EntryIndex::new(&archive, "OEBPS/");
+
+        assert_eq!(SplitEpub::join_href("OEBPS/Text/", "/OEBPS/Text/ch1.xhtml"), "OEBPS/Text/ch1.xhtml");
+        let resolve = |base: &str, reference: &str| entries.resolve(&SplitEpub::join_href(base, reference));
+        assert_eq!(resolve("OEBPS/Text/", "../images/cover%20art.PNG").as_deref(), Some("OEBPS/Images/Cover Art.png"));
+        // Rooted at the content directory rather than the archive root
+        assert_eq!(resolve("OEBPS/Text/", "/Text/ch1.xhtml").as_deref(), Some("OEBPS/Text/ch1.xhtml"));
+        assert_eq!(resolve("OEBPS/Text/", "missing.png"), None);
+    }
+
+    #[test]
+    fn image_optimization_only_touches_oversized_images() {
+        let encode = |size: u32| {
+            let mut data = Vec::new();
+            DynamicImage::ImageRgb8(RgbImage::from_fn(size, size, |x, y| Rgb([(x * 7) as u8, (y * 3) as u8, 90])))
+                .write_to(&mut std::io::Cursor::new(&mut data), ImageFormat::Png)
+                .unwrap();
+            data
+        };
+        let settings = ImageOptimization { quality: 80, max_dimension: 64 };
+
+        assert!(optimize_image("small.png", &encode(64), settings).is_none());
+
+        let large = encode(256);
+        let optimized = optimize_image("large.png", &large, settings).unwrap();
+        assert!(optimized.len() < large.len());
+        let img = image::load_from_memory(&optimized).unwrap();
+        assert_eq!((img.width(), img.height()), (64, 64));
+    }
+
+    #[test]
+    fn books_without_an_ncx_read_and_rewrite_the_nav_toc() {
+        let opf = r#"<?xml version="1.0" encoding="UTF-8"?>
+<package version="3.0" xmlns="http://www.idpf.org/2007/opf" unique-identifier="uid">
+  <metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+    <dc:identifier id="uid">urn:uuid:test</dc:identifier>
+    <dc:title>Test Book</dc:title>
+    <dc:language>en</dc:language>
+  </metadata>
+  <manifest>
+    <item id="nav" href="nav.xhtml" media-type="application/xhtml+xml" properties="nav"/>
+    <item id="ch1" href="Text/ch1.xhtml" media-type="application/xhtml+xml"/>
+    <item id="ch2" href="Text/ch2.xhtml" media-type="application/xhtml+xml"/>
+  </manifest>
+  <spine>
+    <itemref idref="ch1"/>
+    <itemref idref="ch2"/>
+  </spine>
+</package>"#;
+        let nav = r#"<html xmlns="http://www.w3.org/1999/xhtml" xmlns:epub="http://www.idpf.org/2007/ops"><body>
+<nav epub:type="landmarks"><ol><li><a href="Text/ch2.xhtml">Start</a></li></ol></nav>
+<nav epub:type="toc"><ol>
+  <li><span>Part <em>One</em></span><ol>
+    <li><a href="Text/ch1.xhtml">Chapter
+      1</a></li>
+    <li><a href="Text/ch2.xhtml#s2">Chapter 2</a></li>
+  </ol></li>
+</ol></nav>
+</body></html>"#;
+        let container = r#"<?xml version="1.0"?>
+<container version="1.0" xmlns="urn:oasis:names:tc:opendocument:xmlns:container">
+  <rootfiles><rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml"/></rootfiles>
+</container>"#;
+        let chapter = "<html xmlns=\"http://www.w3.org/1999/xhtml\"><body><p>One</p></body></html>";
+        let mut zip = ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        for (name, content) in [
+            ("mimetype", "application/epub+zip"),
+            ("META-INF/container.xml", container),
+            ("OEBPS/content.opf", opf),
+            ("OEBPS/nav.xhtml", nav),
+            ("OEBPS/Text/ch1.xhtml", chapter),
+            ("OEBPS/Text/ch2.xhtml", chapter),
+        ] {
+            zip.start_file(name, SimpleFileOptions::default()).unwrap();
+            zip.write_all(content.as_bytes()).unwrap();
+        }
+        let mut epub = SplitEpub::from_bytes(zip.finish().unwrap().into_inner(), "test.epub").unwrap();
+
+        assert!(epub.has_toc());
+        let toc = epub.read_toc().unwrap();
+        assert_eq!(toc.len(), 1);
+        assert_eq!(toc[0].text, "Part One");
+        let chapters: Vec<(&str, &str)> =
+            toc[0].children.iter().map(|point| (point.text.as_str(), point.src.as_str())).collect();
+        assert_eq!(chapters, [("Chapter 1", "OEBPS/Text/ch1.xhtml"), ("Chapter 2", "OEBPS/Text/ch2.xhtml#s2")]);
+
+        let replacements = epub.toc_replacements(&toc).unwrap();
+        assert_eq!(replacements.keys().collect::<Vec<_>>(), ["OEBPS/nav.xhtml"]);
+        assert!(replacements["OEBPS/nav.xhtml"].contains(r#"href="Text/ch2.xhtml#s2""#));
+    }
+}
@@ -0,0 +1,231 @@
+use crate::core::*;
+use anyhow::{anyhow, bail, Context, Result};
+use std::path::PathBuf;
+use std::cell::RefCell;
+use std::ffi::{c_char, c_int, CStr, CString};
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// An open EPUB
+pub struct EpubSplit {
+    epub: SplitEpub,
+}
+
+fn set_error(e: &anyhow::Error) {
+    let message = CString::new(format!("{:#}", e).replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+}
+
+fn failure_code(e: anyhow::Error) -> c_int {
+    set_error(&e);
+    c_int::from(exit_code(&e))
+}
+
+/// Run `f`, turning a panic into an error rather than unwinding into C
+fn guarded<T>(f: impl FnOnce() -> Result<T>) -> Result<T> {
+    std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)).unwrap_or_else(|panic| {
+        let message = panic
+            .downcast_ref::<&str>()
+            .map(|s| s.to_string())
+            .or_else(|| panic.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "unknown cause".to_string());
+        Err(anyhow!("Internal error: {}", message))
+    })
+}
+
+/// A nul-terminated UTF-8 argument, or None for NULL
+unsafe fn optional_str(s: *const c_char) -> Result<Option<String>> {
+    if s.is_null() {
+        return Ok(None);
+    }
+    let s = CStr::from_ptr(s).to_str().context("String argument is not UTF-8")?;
+    Ok(Some(s.to_string()))
+}
+
+unsafe fn split_request(sections: *const usize, count: usize, title: *const c_char) -> Result<SplitRequest> {
+    if sections.is_null() && count > 0 {
+        bail!(Failure::new(FailureKind::InvalidArgs, "sections is NULL"));
+    }
+    let sections = if count == 0 {
+        Vec::new()
+    } else {
+        std::slice::from_raw_parts(sections, count).to_vec()
+    };
+    Ok(SplitRequest {
+        sections,
+        title: optional_str(title)?,
+        ..SplitRequest::default()
+    })
+}
+
+/// Message for the last failure on this thread, or NULL. Valid until the
+/// next call on this thread.
+#[no_mangle]
+pub extern "C" fn epubsplit_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ref().map_or(std::ptr::null(), |message| message.as_ptr()))
+}
+
+/// Open the EPUB at `path`. Returns NULL on failure.
+///
+/// # Safety
+/// `path` must be a nul-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn epubsplit_open(path: *const c_char) -> *mut EpubSplit {
+    let opened = guarded(|| {
+        let path = optional_str(path)?
+            .ok_or_else(|| anyhow!(Failure::new(FailureKind::InvalidArgs, "path is NULL")))?;
+        crate::cli::open_epub(PathBuf::from(path))
+    });
+    match opened {
+        Ok(epub) => Box::into_raw(Box::new(EpubSplit { epub })),
+        Err(e) => {
+            set_error(&e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Open an EPUB from `len` bytes at `data`, which are copied. Returns
+/// NULL on failure.
+///
+/// # Safety
+/// `data` must point to `len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn epubsplit_open_buffer(data: *const u8, len: usize) -> *mut EpubSplit {
+    if data.is_null() {
+        set_error(&anyhow!("data is NULL"));
+        return std::ptr::null_mut();
+    }
+    let bytes = std::slice::from_raw_parts(data, len).to_vec();
+    match guarded(|| SplitEpub::from_bytes(bytes, "input.epub")) {
+        Ok(epub) => Box::into_raw(Box::new(EpubSplit { epub })),
+        Err(e) => {
+            set_error(&e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// The split points as JSON, in the same shape as the library's
+/// `split_points_json`. Free with `epubsplit_free_string`. Returns NULL
+/// on failure.
+///
+/// # Safety
+/// `handle` must come from `epubsplit_open` or `epubsplit_open_buffer`.
+#[no_mangle]
+pub unsafe extern "C" fn epubsplit_sections_json(handle: *mut EpubSplit) -> *mut c_char {
+    let Some(handle) = handle.as_mut() else {
+        set_error(&anyhow!("handle is NULL"));
+        return std::ptr::null_mut();
+    };
+    match guarded(|| describe_split_points(&mut handle.epub)) {
+        Ok(json) => CString::new(json.to_string()).map_or(std::ptr::null_mut(), CString::into_raw),
+        Err(e) => {
+            set_error(&e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Write an EPUB of the `count` split lines in `sections` to `path`.
+/// `title` may be NULL for the default.
+///
+/// # Safety
+/// `handle` must be open, `sections` must point to `count` values, and
+/// `title` and `path` must be nul-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn epubsplit_split_to_path(
+    handle: *mut EpubSplit,
+    sections: *const usize,
+    count: usize,
+    title: *const c_char,
+    path: *const c_char,
+) -> c_int {
+    let Some(handle) = handle.as_mut() else {
+        return failure_code(anyhow!(Failure::new(FailureKind::InvalidArgs, "handle is NULL")));
+    };
+    let result = guarded(|| {
+        let request = split_request(sections, count, title)?;
+        let path = optional_str(path)?
+            .ok_or_else(|| anyhow!(Failure::new(FailureKind::InvalidArgs, "path is NULL")))?;
+        let data = split_in_memory(&mut handle.epub, &request)?;
+        std::fs::write(&path, data).with_context(|| format!("Failed to create output file: {}", path))
+    });
+    result.map_or_else(failure_code, |()| 0)
+}
+
+/// Build an EPUB of the `count` split lines in `sections` in memory. On
+/// success `*out` and `*out_len` hold the bytes; free them with
+/// `epubsplit_free_buffer`.
+///
+/// # Safety
+/// `handle` must be open, `sections` must point to `count` values,
+/// `title` must be NULL or a nul-terminated string, and `out` and
+/// `out_len` must be writable.
+#[no_mangle]
+pub unsafe extern "C" fn epubsplit_split_to_buffer(
+    handle: *mut EpubSplit,
+    sections: *const usize,
+    count: usize,
+    title: *const c_char,
+    out: *mut *mut u8,
+    out_len: *mut usize,
+) -> c_int {
+    let Some(handle) = handle.as_mut() else {
+        return failure_code(anyhow!(Failure::new(FailureKind::InvalidArgs, "handle is NULL")));
+    };
+    if out.is_null() || out_len.is_null() {
+        return failure_code(anyhow!(Failure::new(FailureKind::InvalidArgs, "out or out_len is NULL")));
+    }
+    let result = guarded(|| split_in_memory(&mut handle.epub, &split_request(sections, count, title)?));
+    match result {
+        Ok(data) => {
+            let data = data.into_boxed_slice();
+            *out_len = data.len();
+            *out = Box::into_raw(data).cast::<u8>();
+            0
+        }
+        Err(e) => failure_code(e),
+    }
+}
+
+/// Free a buffer from `epubsplit_split_to_buffer`
+///
+/// # Safety
+/// `data` and `len` must be exactly as returned, and freed only once.
+#[no_mangle]
+pub unsafe extern "C" fn epubsplit_free_buffer(data: *mut u8, len: usize) {
+    if !data.is_null() {
+        drop(Box::from_raw(std::ptr::slice_from_raw_parts_mut(data, len)));
+    }
+}
+
+/// Free a string from `epubsplit_sections_json`
+///
+/// # Safety
+/// `s` must be as returned, and freed only once.
+#[no_mangle]
+pub unsafe extern "C" fn epubsplit_free_string(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
+}
+
+/// Close an EPUB opened with `epubsplit_open` or `epubsplit_open_buffer`
+///
+/// # Safety
+/// `handle` must be as returned, and closed only once.
+#[no_mangle]
+pub unsafe extern "C" fn epubsplit_close(handle: *mut EpubSplit) {
+    if !handle.is_null() {
+        let closed = guarded(|| {
+            drop(Box::from_raw(handle));
+            Ok(())
+        });
+        if let Err(e) = closed {
+            set_error(&e);
+        }
+    }
+}
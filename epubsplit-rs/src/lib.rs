@@ -0,0 +1,20 @@
+/// Reading, splitting and merging EPUBs, all in memory
+mod core;
+/// The command line: argument parsing, printing, and everything that
+/// reads or writes files
+mod cli;
+
+/// C API for embedding the splitter, built with the `ffi` feature; the
+/// header is generated into the build's `OUT_DIR`, and building with
+/// `EPUBSPLIT_UPDATE_HEADER` set refreshes `include/epubsplit.h`. Functions
+/// returning `int` give 0 on success or one of the CLI's exit codes; on
+/// failure `epubsplit_last_error` describes what went wrong.
+#[cfg(feature = "ffi")]
+pub mod ffi;
+
+/// JavaScript bindings for splitting entirely in the browser
+#[cfg(target_arch = "wasm32")]
+mod wasm;
+
+pub use crate::cli::run_cli;
+pub use crate::core::{clear_sessions, split_epub_bytes, split_points_json, ContentTransform, ContentTransforms, SplitRequest};
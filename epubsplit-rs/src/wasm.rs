@@ -0,0 +1,34 @@
+use wasm_bindgen::prelude::*;
+
+fn js_error(e: anyhow::Error) -> JsError {
+    JsError::new(&format!("{:#}", e))
+}
+
+/// The split points of an EPUB, as a JSON string
+#[wasm_bindgen(js_name = listSplitPoints)]
+pub fn list_split_points(epub: &[u8]) -> Result<String, JsError> {
+    super::split_points_json(epub).map_err(js_error)
+}
+
+/// Build an EPUB from the given split lines, returning its bytes
+#[wasm_bindgen(js_name = splitEpub)]
+pub fn split_epub(
+    epub: &[u8],
+    sections: Vec<u32>,
+    title: Option<String>,
+    authors: Vec<String>,
+) -> Result<Vec<u8>, JsError> {
+    let request = super::SplitRequest {
+        sections: sections.into_iter().map(|n| n as usize).collect(),
+        title,
+        authors,
+        ..Default::default()
+    };
+    super::split_epub_bytes(epub, &request).map_err(js_error)
+}
+
+/// Free the books kept parsed between calls
+#[wasm_bindgen(js_name = clearSessions)]
+pub fn clear_sessions() {
+    super::clear_sessions();
+}